@@ -7,6 +7,7 @@ use std::{
 
 use libabbs::apml::{
 	ast::{ApmlAst, AstNode},
+	intern::Interner,
 	lst::ApmlLst,
 };
 
@@ -32,13 +33,31 @@ fn main() {
 	let mut srcs = Vec::new();
 	collect_apml(Path::new(&tree), &mut srcs);
 
+	let interned = env::var("INTERN").is_ok_and(|val| val == "1");
+	let mut interner = Interner::new();
+
 	let start = Instant::now();
 	for _ in 0..10 {
 		for src in &srcs {
 			let lst = ApmlLst::parse(src).expect(src);
-			let _ = ApmlAst::emit_from(&lst).expect(src);
+			if interned {
+				let _ = ApmlAst::emit_from_interned(&lst, &mut interner)
+					.expect(src);
+			} else {
+				let _ = ApmlAst::emit_from(&lst).expect(src);
+			}
 		}
 	}
 	let elapsed = start.elapsed();
 	println!("emitted {} files in {:?}", srcs.len(), elapsed);
+	if interned {
+		let stats = interner.stats();
+		println!(
+			"interned {} nodes: {} lookups, {:.2}% hits, {} bytes saved",
+			interner.len(),
+			stats.lookups,
+			stats.hit_rate() * 100.0,
+			stats.bytes_saved
+		);
+	}
 }
@@ -13,20 +13,35 @@
 //! ASTs are guaranteed in structure to be grammatically valid,
 //! while the evaluation process may fail due to context-dependent
 //! constraints such as the `${name:?text}` expansion modifier.
+//! ASTs built programmatically may still break rules that cannot be
+//! encoded in types, such as variable names with spaces. Use
+//! [`ApmlAst::validate`] to check them before lowering.
 //!
 //! [`ApmlAst`] cannot be parsed directly from string. Instead, a parsed LST
 //! needs to be [emitted][ApmlAst::emit_from] to get an AST.
 //! When emitter gets a LST containing grammatically invalid nodes, for
 //! example, a variable definitions followed by another,
 //! am [`EmitError`] is produced.
+//! Use [`ApmlAst::emit_from_partial`] to get as much of the AST as
+//! possible from such LSTs.
 //!
 //! On the contrary, AST can be lowered to produce a LST. The LST
 //! produced by lowering is also guaranteed to be valid.
 //!
 //! Although not all LST nodes can be represented in AST form, all AST
-//! nodes must have a valid LST form.
+//! nodes must have a valid LST form. AST nodes are displayed in their
+//! lowered form.
+//!
+//! To lower a edited AST while keeping the styling of the LST it was
+//! emitted from, see [`lower_against`].
 
-use std::{borrow::Cow, cmp::max, num::ParseIntError, sync::Arc};
+use std::{
+	borrow::Cow,
+	collections::{HashMap, HashSet, VecDeque},
+	fmt::Display,
+	num::ParseIntError,
+	sync::Arc,
+};
 
 use thiserror::Error;
 
@@ -45,8 +60,47 @@ pub trait AstNode: Sized {
 	fn lower(&self) -> Self::LST;
 }
 
+/// A error produced when emitting AST.
 #[derive(Debug, Error)]
-pub enum EmitError {
+#[error("{description} (token {token_index})")]
+pub struct EmitError {
+	/// Kind of the error.
+	pub kind: EmitErrorKind,
+	/// Index of the root LST token where the error occurs.
+	///
+	/// This is always zero when emitting nodes other than [`ApmlAst`].
+	pub token_index: usize,
+	/// Human-readable description.
+	pub description: String,
+}
+
+impl EmitError {
+	/// Locates the error at a root LST token.
+	fn at(mut self, token_index: usize, context: &str) -> Self {
+		self.token_index = token_index;
+		self.description = format!("{} in {}", self.description, context);
+		self
+	}
+}
+
+impl From<EmitErrorKind> for EmitError {
+	fn from(kind: EmitErrorKind) -> Self {
+		Self {
+			description: kind.to_string(),
+			kind,
+			token_index: 0,
+		}
+	}
+}
+
+impl From<ParseIntError> for EmitError {
+	fn from(err: ParseIntError) -> Self {
+		EmitErrorKind::from(err).into()
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum EmitErrorKind {
 	#[error("Unrepresentable LST node")]
 	Unrepresentable,
 	#[error("Unparsable integer: {0}")]
@@ -59,6 +113,33 @@ pub enum EmitError {
 
 pub type EmitResult<T> = std::result::Result<T, EmitError>;
 
+/// A AST emitted from a possibly invalid LST.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PartialApmlAst<'a> {
+	/// The variable definitions that were emitted successfully.
+	pub ast: ApmlAst<'a>,
+	/// Index of the root LST token of each definition in the AST.
+	pub token_indexes: Vec<usize>,
+}
+
+/// A violation of well-formedness rules in a AST.
+///
+/// Each error records the name of the variable definition containing
+/// the offending node.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AstError {
+	#[error("Invalid name of variable definition: {0:?}")]
+	InvalidDefinitionName(String),
+	#[error("Invalid name of variable expansion in {variable}: {name:?}")]
+	InvalidExpansionName { variable: String, name: String },
+	#[error("Invalid name of array inclusion in {variable}: {name:?}")]
+	InvalidInclusionName { variable: String, name: String },
+	#[error("Subcommand in {variable} is not wrapped in $(): {text:?}")]
+	MalformedSubcommand { variable: String, text: String },
+	#[error("Empty pattern in expansion of {name} in {variable}")]
+	EmptyPattern { variable: String, name: String },
+}
+
 /// A APML abstract syntax tree.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ApmlAst<'a>(pub Vec<VariableDefinition<'a>>);
@@ -67,6 +148,40 @@ impl<'a> AstNode for ApmlAst<'a> {
 	type LST = lst::ApmlLst<'a>;
 
 	fn emit_from(lst: &Self::LST) -> EmitResult<Self> {
+		let (partial, errors) = Self::emit_from_partial(lst);
+		match errors.into_iter().next() {
+			Some(err) => Err(err),
+			None => Ok(partial.ast),
+		}
+	}
+
+	fn lower(&self) -> Self::LST {
+		debug_assert_eq!(self.validate(), Ok(()));
+		let mut result = Vec::new();
+		for def in &self.0 {
+			result.push(lst::Token::Variable(def.lower()));
+			result.push(lst::Token::Newline);
+		}
+		result.pop();
+		lst::ApmlLst(result)
+	}
+}
+
+impl Display for ApmlAst<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.lower(), f)
+	}
+}
+
+impl<'a> ApmlAst<'a> {
+	/// Emits a LST into AST, continuing past emit errors.
+	///
+	/// Unknown tokens and definitions that cannot be emitted are skipped,
+	/// while definitions missing delimiters are still included.
+	/// All errors are returned in the order of tokens.
+	pub fn emit_from_partial(
+		lst: &lst::ApmlLst<'a>,
+	) -> (PartialApmlAst<'a>, Vec<EmitError>) {
 		enum State {
 			/// Ready for elements
 			Ready,
@@ -76,34 +191,188 @@ impl<'a> AstNode for ApmlAst<'a> {
 			NeedNewline,
 		}
 		let mut state = State::Ready;
-		let mut result = Vec::new();
-		for token in &lst.0 {
+		let mut result = PartialApmlAst {
+			ast: ApmlAst(Vec::new()),
+			token_indexes: Vec::new(),
+		};
+		let mut errors = Vec::new();
+		for (index, token) in lst.0.iter().enumerate() {
 			match token {
 				lst::Token::Spacy(_) => {}
 				lst::Token::Newline => state = State::Ready,
 				lst::Token::Comment(_) => state = State::NeedNewline,
+				lst::Token::Unknown(text) => {
+					errors.push(
+						EmitError::from(EmitErrorKind::Unrepresentable)
+							.at(index, &format!("unknown token {text:?}")),
+					);
+				}
 				lst::Token::Variable(def) => {
-					if matches!(state, State::Ready) {
-						result.push(VariableDefinition::emit_from(def)?);
-						state = State::NeedDelimiter;
-					} else {
-						return Err(EmitError::MissingRootElementDelimiter);
+					let context = format!("definition of {}", def.name);
+					if !matches!(state, State::Ready) {
+						errors.push(
+							EmitError::from(
+								EmitErrorKind::MissingRootElementDelimiter,
+							)
+							.at(index, &context),
+						);
+					}
+					match VariableDefinition::emit_from(def) {
+						Ok(def) => {
+							result.ast.0.push(def);
+							result.token_indexes.push(index);
+						}
+						Err(err) => errors.push(err.at(index, &context)),
 					}
+					state = State::NeedDelimiter;
 				}
 			}
 		}
-		Ok(Self(result))
+		(result, errors)
 	}
+}
 
-	fn lower(&self) -> Self::LST {
+impl ApmlAst<'_> {
+	/// Checks well-formedness rules of all nodes, returning all violations.
+	pub fn validate(&self) -> Result<(), Vec<AstError>> {
+		let mut errors = Vec::new();
+		for def in &self.0 {
+			def.validate_into(&mut errors);
+		}
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors)
+		}
+	}
+}
+
+impl ApmlAst<'_> {
+	/// Returns names of variables not reachable from the given roots.
+	///
+	/// Starting from `roots`, for example the fields read by autobuild
+	/// like `VER`, `SRCS` and `PKGDEP`, variables referenced by the values
+	/// of reachable variables are reachable too. See [`referenced_vars`].
+	/// Expansions inside sub-commands are opaque, so sub-commands are
+	/// treated as referencing nothing.
+	///
+	/// Name expansions (`${!PREFIX@}`) reference all defined variables
+	/// with the prefix. As the targets of indirect expansions (`${!NAME}`)
+	/// are only known at runtime, all variables are considered used if a
+	/// reachable variable contains one.
+	///
+	/// Names are returned in order of their first definition.
+	pub fn unused_vars(&self, roots: &[&str]) -> Vec<String> {
+		let mut refs = HashMap::<&str, Vec<_>>::new();
+		for def in &self.0 {
+			let refs = refs.entry(def.name.as_ref()).or_default();
+			visit_references(&def.value, &mut |name, modifier| {
+				refs.push((name, modifier))
+			});
+		}
+		let mut reachable = HashSet::new();
+		let mut queue = roots.iter().copied().collect::<VecDeque<_>>();
+		while let Some(name) = queue.pop_front() {
+			if !reachable.insert(name) {
+				continue;
+			}
+			for (name, modifier) in refs.get(name).into_iter().flatten() {
+				match modifier {
+					Some(ExpansionModifier::Indirect) => return Vec::new(),
+					Some(
+						ExpansionModifier::NamesWithPrefix
+						| ExpansionModifier::SingleWordNamesWithPrefix,
+					) => queue.extend(
+						self.0
+							.iter()
+							.map(|def| def.name.as_ref())
+							.filter(|defined| defined.starts_with(name)),
+					),
+					_ => queue.push_back(name),
+				}
+			}
+		}
 		let mut result = Vec::new();
 		for def in &self.0 {
+			let name = def.name.as_ref();
+			if !reachable.contains(name) && !result.iter().any(|n| n == name) {
+				result.push(name.to_string());
+			}
+		}
+		result
+	}
+}
+
+/// Lowers a AST, reusing tokens from the original LST where possible.
+///
+/// Variable definitions in the original LST are matched with definitions
+/// in the AST by name, in order of occurrence. Definitions that are
+/// semantically unchanged keep their original tokens, changed ones are
+/// lowered in place, and definitions missing in the AST are removed
+/// together with the rest of their lines.
+/// Definitions only present in the AST are appended to the end.
+///
+/// Spaces, newlines and comments in the original LST are kept as-is,
+/// and so are declaration keywords of changed definitions.
+pub fn lower_against<'a>(
+	ast: &ApmlAst<'a>,
+	original: &lst::ApmlLst<'a>,
+) -> lst::ApmlLst<'a> {
+	let mut pending = HashMap::<&str, VecDeque<usize>>::new();
+	for (idx, def) in ast.0.iter().enumerate() {
+		pending.entry(def.name.as_ref()).or_default().push_back(idx);
+	}
+	let mut used = vec![false; ast.0.len()];
+	let mut result = Vec::with_capacity(original.0.len());
+	let mut skip_line = false;
+	for token in &original.0 {
+		if skip_line {
+			skip_line = !matches!(token, lst::Token::Newline);
+			continue;
+		}
+		let lst::Token::Variable(def) = token else {
+			result.push(token.clone());
+			continue;
+		};
+		match pending
+			.get_mut(def.name.as_ref())
+			.and_then(VecDeque::pop_front)
+		{
+			Some(idx) => {
+				used[idx] = true;
+				let new = &ast.0[idx];
+				if VariableDefinition::emit_from(def)
+					.is_ok_and(|old| &old == new)
+				{
+					result.push(token.clone());
+				} else {
+					result.push(lst::Token::Variable(
+						lst::VariableDefinition {
+							declaration: def.declaration.clone(),
+							..new.lower()
+						},
+					));
+				}
+			}
+			None => {
+				// removed definition
+				while matches!(result.last(), Some(lst::Token::Spacy(_))) {
+					result.pop();
+				}
+				skip_line = true;
+			}
+		}
+	}
+	for (def, used) in ast.0.iter().zip(used) {
+		if !used {
+			if !matches!(result.last(), None | Some(lst::Token::Newline)) {
+				result.push(lst::Token::Newline);
+			}
 			result.push(lst::Token::Variable(def.lower()));
 			result.push(lst::Token::Newline);
 		}
-		result.pop();
-		lst::ApmlLst(result)
 	}
+	lst::ApmlLst(result)
 }
 
 /// A variable definition.
@@ -112,6 +381,11 @@ impl<'a> AstNode for ApmlAst<'a> {
 /// is omitted. All appending-to operations are desugared. `NAME+="VALUE"`
 /// are desugared into `NAME="${NAME}VALUE"` and `NAME+=(VALUES)` are desugared
 /// into `NAME=("${NAME[@]}" VALUES)`.
+///
+/// Declaration keywords like `export` and `declare` are not kept either,
+/// except that string values declared with `-a` are converted to arrays.
+/// Lowering a definition thus always produces a plain assignment.
+/// Use [`lower_against`] to keep the declarations of existing definitions.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct VariableDefinition<'a> {
 	/// Name of the variable.
@@ -120,11 +394,153 @@ pub struct VariableDefinition<'a> {
 	pub value: VariableValue<'a>,
 }
 
+/// Variable definitions are ordered by their names.
+///
+/// Note that two definitions with the same name but different values are
+/// considered equivalent in ordering while they are not equal.
+impl PartialOrd for VariableDefinition<'_> {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for VariableDefinition<'_> {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.name.cmp(&other.name)
+	}
+}
+
+impl VariableDefinition<'_> {
+	/// Checks well-formedness rules of the definition.
+	///
+	/// See [`ApmlAst::validate`].
+	pub fn validate(&self) -> Result<(), Vec<AstError>> {
+		let mut errors = Vec::new();
+		self.validate_into(&mut errors);
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors)
+		}
+	}
+
+	fn validate_into(&self, errors: &mut Vec<AstError>) {
+		let variable = self.name.as_ref();
+		if !is_valid_var_name(variable) {
+			errors.push(AstError::InvalidDefinitionName(variable.to_string()));
+		}
+		match &self.value {
+			VariableValue::String(text) => {
+				validate_text(variable, text, errors)
+			}
+			VariableValue::Array(elements) => {
+				for element in elements {
+					match element {
+						ArrayElement::ArrayInclusion(name) => {
+							if !is_valid_var_name(name) {
+								errors.push(AstError::InvalidInclusionName {
+									variable: variable.to_string(),
+									name: name.to_string(),
+								});
+							}
+						}
+						ArrayElement::Text(text) => {
+							validate_text(variable, text, errors)
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Checks well-formedness rules of words in a text.
+fn validate_text(variable: &str, text: &Text, errors: &mut Vec<AstError>) {
+	for word in &text.0 {
+		match word {
+			Word::Literal(_) => {}
+			Word::Variable(expansion) => {
+				if !is_valid_var_name(&expansion.name) {
+					errors.push(AstError::InvalidExpansionName {
+						variable: variable.to_string(),
+						name: expansion.name.to_string(),
+					});
+				}
+				let Some(modifier) = &expansion.modifier else {
+					continue;
+				};
+				let (pattern, text) = match modifier {
+					ExpansionModifier::StripShortestPrefix(pattern)
+					| ExpansionModifier::StripLongestPrefix(pattern)
+					| ExpansionModifier::StripShortestSuffix(pattern)
+					| ExpansionModifier::StripLongestSuffix(pattern)
+					| ExpansionModifier::UpperOnce(pattern)
+					| ExpansionModifier::UpperAll(pattern)
+					| ExpansionModifier::LowerOnce(pattern)
+					| ExpansionModifier::LowerAll(pattern) => (Some(pattern), None),
+					ExpansionModifier::ReplaceOnce { pattern, string }
+					| ExpansionModifier::ReplaceAll { pattern, string }
+					| ExpansionModifier::ReplacePrefix { pattern, string }
+					| ExpansionModifier::ReplaceSuffix { pattern, string } => {
+						(Some(pattern), Some(string))
+					}
+					ExpansionModifier::ErrorOnUnset(text)
+					| ExpansionModifier::WhenUnset(text)
+					| ExpansionModifier::WhenSet(text) => (None, Some(text)),
+					ExpansionModifier::Substring { .. }
+					| ExpansionModifier::Length
+					| ExpansionModifier::Indirect
+					| ExpansionModifier::NamesWithPrefix
+					| ExpansionModifier::SingleWordNamesWithPrefix
+					| ExpansionModifier::Index(_)
+					| ExpansionModifier::ArrayLength
+					| ExpansionModifier::SingleWordArrayLength => (None, None),
+				};
+				if pattern.is_some_and(|pattern| pattern.0.is_empty()) {
+					errors.push(AstError::EmptyPattern {
+						variable: variable.to_string(),
+						name: expansion.name.to_string(),
+					});
+				}
+				if let Some(text) = text {
+					validate_text(variable, text, errors);
+				}
+			}
+			Word::Subcommand(text) => {
+				if !text.starts_with("$(") || !text.ends_with(')') {
+					errors.push(AstError::MalformedSubcommand {
+						variable: variable.to_string(),
+						text: text.to_string(),
+					});
+				}
+			}
+		}
+	}
+}
+
+/// Checks if a string is a valid variable name.
+pub(crate) fn is_valid_var_name(name: &str) -> bool {
+	!name.is_empty()
+		&& !name.starts_with(|ch: char| ch.is_ascii_digit())
+		&& name.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+}
+
 impl<'a> AstNode for VariableDefinition<'a> {
 	type LST = lst::VariableDefinition<'a>;
 
 	fn emit_from(lst: &Self::LST) -> EmitResult<Self> {
-		let mut value = VariableValue::emit_from(&lst.value)?;
+		let mut value = match VariableValue::emit_from(&lst.value)? {
+			// string values of arrays declared with `-a` are the first element
+			VariableValue::String(text)
+				if lst
+					.declaration
+					.as_ref()
+					.is_some_and(lst::Declaration::is_array) =>
+			{
+				VariableValue::Array(vec![ArrayElement::Text(Arc::new(text))])
+			}
+			value => value,
+		};
 		match lst.op {
 			lst::VariableOp::Assignment => {}
 			lst::VariableOp::Append => match &mut value {
@@ -153,6 +569,7 @@ impl<'a> AstNode for VariableDefinition<'a> {
 
 	fn lower(&self) -> Self::LST {
 		lst::VariableDefinition {
+			declaration: None,
 			name: self.name.clone(),
 			op: lst::VariableOp::Assignment,
 			value: self.value.lower(),
@@ -160,7 +577,17 @@ impl<'a> AstNode for VariableDefinition<'a> {
 	}
 }
 
+impl Display for VariableDefinition<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.lower(), f)
+	}
+}
+
 /// A variable value.
+///
+/// Array values are emitted element by element, discarding spaces, newlines
+/// and comments between elements. When lowered, elements are delimited
+/// with single spaces.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum VariableValue<'a> {
 	/// A text value.
@@ -208,7 +635,8 @@ impl<'a> AstNode for VariableValue<'a> {
 								state = State::NeedDelimiter;
 							} else {
 								return Err(
-									EmitError::MissingArrayElementDelimiter,
+									EmitErrorKind::MissingArrayElementDelimiter
+										.into(),
 								);
 							}
 						}
@@ -237,6 +665,190 @@ impl<'a> AstNode for VariableValue<'a> {
 	}
 }
 
+impl Display for VariableValue<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.lower(), f)
+	}
+}
+
+/// Returns if a value contains any variable or sub-command expansions,
+/// including array inclusions.
+///
+/// Values without expansions are pure literals.
+pub fn contains_expansion(value: &VariableValue) -> bool {
+	let text_contains = |text: &Text| {
+		text.0
+			.iter()
+			.any(|word| matches!(word, Word::Variable(_) | Word::Subcommand(_)))
+	};
+	match value {
+		VariableValue::String(text) => text_contains(text),
+		VariableValue::Array(elements) => {
+			elements.iter().any(|element| match element {
+				ArrayElement::ArrayInclusion(_) => true,
+				ArrayElement::Text(text) => text_contains(text),
+			})
+		}
+	}
+}
+
+/// Returns names of variables referenced by a value, in order of
+/// occurrence and without duplicates.
+///
+/// Expansions in texts of expansion modifiers and array inclusions are
+/// included. Sub-commands are opaque and treated as referencing nothing.
+/// Name expansions (`${!PREFIX@}`) do not reference a single variable,
+/// so their prefixes are not included. For indirect expansions
+/// (`${!NAME}`), only `NAME` is included.
+pub fn referenced_vars<'v>(value: &'v VariableValue) -> Vec<&'v str> {
+	let mut result = Vec::new();
+	visit_references(value, &mut |name, modifier| {
+		if !matches!(
+			modifier,
+			Some(
+				ExpansionModifier::NamesWithPrefix
+					| ExpansionModifier::SingleWordNamesWithPrefix
+			)
+		) && !result.contains(&name)
+		{
+			result.push(name);
+		}
+	});
+	result
+}
+
+/// Visits names and modifiers of expansions in a value, in order of
+/// occurrence, including texts of expansion modifiers.
+///
+/// Array inclusions are visited without modifiers.
+fn visit_references<'v, 'a>(
+	value: &'v VariableValue<'a>,
+	f: &mut dyn FnMut(&'v str, Option<&'v ExpansionModifier<'a>>),
+) {
+	fn visit<'v, 'a>(
+		text: &'v Text<'a>,
+		f: &mut dyn FnMut(&'v str, Option<&'v ExpansionModifier<'a>>),
+	) {
+		for word in &text.0 {
+			let Word::Variable(expansion) = word else {
+				continue;
+			};
+			f(&expansion.name, expansion.modifier.as_ref());
+			if let Some(
+				ExpansionModifier::ReplaceOnce { string, .. }
+				| ExpansionModifier::ReplaceAll { string, .. }
+				| ExpansionModifier::ReplacePrefix { string, .. }
+				| ExpansionModifier::ReplaceSuffix { string, .. }
+				| ExpansionModifier::ErrorOnUnset(string)
+				| ExpansionModifier::WhenUnset(string)
+				| ExpansionModifier::WhenSet(string),
+			) = &expansion.modifier
+			{
+				visit(string, f);
+			}
+		}
+	}
+
+	match value {
+		VariableValue::String(text) => visit(text, f),
+		VariableValue::Array(elements) => {
+			for element in elements {
+				match element {
+					ArrayElement::ArrayInclusion(name) => f(name, None),
+					ArrayElement::Text(text) => visit(text, f),
+				}
+			}
+		}
+	}
+}
+
+/// Returns glob patterns used in expansion modifiers of a value,
+/// in order of occurrence.
+///
+/// Patterns of stripping, replacing and case-modifying modifiers are
+/// included, and texts of modifiers are searched recursively.
+pub fn glob_patterns<'v, 'a>(
+	value: &'v VariableValue<'a>,
+) -> Vec<&'v BashPattern<'a>> {
+	let mut result = Vec::new();
+	visit_glob_patterns(value, &mut result);
+	result.into_iter().map(|pattern| pattern.as_ref()).collect()
+}
+
+pub(crate) fn visit_glob_patterns<'v, 'a>(
+	value: &'v VariableValue<'a>,
+	result: &mut Vec<&'v Arc<BashPattern<'a>>>,
+) {
+	fn visit<'v, 'a>(
+		text: &'v Text<'a>,
+		result: &mut Vec<&'v Arc<BashPattern<'a>>>,
+	) {
+		for word in &text.0 {
+			let Word::Variable(VariableExpansion {
+				modifier: Some(modifier),
+				..
+			}) = word
+			else {
+				continue;
+			};
+			match modifier {
+				ExpansionModifier::StripShortestPrefix(pattern)
+				| ExpansionModifier::StripLongestPrefix(pattern)
+				| ExpansionModifier::StripShortestSuffix(pattern)
+				| ExpansionModifier::StripLongestSuffix(pattern)
+				| ExpansionModifier::UpperOnce(pattern)
+				| ExpansionModifier::UpperAll(pattern)
+				| ExpansionModifier::LowerOnce(pattern)
+				| ExpansionModifier::LowerAll(pattern) => result.push(pattern),
+				ExpansionModifier::ReplaceOnce { pattern, string }
+				| ExpansionModifier::ReplaceAll { pattern, string }
+				| ExpansionModifier::ReplacePrefix { pattern, string }
+				| ExpansionModifier::ReplaceSuffix { pattern, string } => {
+					result.push(pattern);
+					visit(string, result);
+				}
+				ExpansionModifier::ErrorOnUnset(text)
+				| ExpansionModifier::WhenUnset(text)
+				| ExpansionModifier::WhenSet(text) => visit(text, result),
+				ExpansionModifier::Substring { .. }
+				| ExpansionModifier::Length
+				| ExpansionModifier::Indirect
+				| ExpansionModifier::NamesWithPrefix
+				| ExpansionModifier::SingleWordNamesWithPrefix
+				| ExpansionModifier::Index(_)
+				| ExpansionModifier::ArrayLength
+				| ExpansionModifier::SingleWordArrayLength => {}
+			}
+		}
+	}
+
+	match value {
+		VariableValue::String(text) => visit(text, result),
+		VariableValue::Array(elements) => {
+			for element in elements {
+				if let ArrayElement::Text(text) = element {
+					visit(text, result);
+				}
+			}
+		}
+	}
+}
+
+impl<'a> ApmlAst<'a> {
+	/// Returns glob patterns used in all variable definitions with names
+	/// of the variables. See [`glob_patterns`].
+	pub fn glob_patterns(&self) -> Vec<(&str, &BashPattern<'a>)> {
+		self.0
+			.iter()
+			.flat_map(|def| {
+				glob_patterns(&def.value)
+					.into_iter()
+					.map(|pattern| (def.name.as_ref(), pattern))
+			})
+			.collect()
+	}
+}
+
 /// A text made by a list of [`Word`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Text<'a>(pub Vec<Word<'a>>);
@@ -259,6 +871,32 @@ impl<'a> AstNode for Text<'a> {
 	}
 }
 
+impl Text<'_> {
+	/// Gets the value of the text if it is made up of only literal words.
+	///
+	/// See also [`lst::Text::literal_value`].
+	pub(crate) fn literal_value(&self) -> Option<Cow<'_, str>> {
+		let mut result = Cow::Borrowed("");
+		for word in &self.0 {
+			let Word::Literal(literal) = word else {
+				return None;
+			};
+			if result.is_empty() {
+				result = Cow::Borrowed(literal);
+			} else {
+				result.to_mut().push_str(literal);
+			}
+		}
+		Some(result)
+	}
+}
+
+impl Display for Text<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.lower(), f)
+	}
+}
+
 /// Emits a LST literal string part as string.
 fn emit_text_unit<'a>(lst: &lst::TextUnit<'a>) -> EmitResult<Vec<Word<'a>>> {
 	match lst {
@@ -287,6 +925,264 @@ impl From<&'static str> for Text<'_> {
 	}
 }
 
+/// Substitutes template placeholders in literals of a text.
+///
+/// Placeholders are keys delimited by `delim`, for example `@VER@`.
+/// A key must be made up of alphanumeric characters and underscores,
+/// and the placeholder must be contained in a single literal word.
+/// As AST literals are unescaped, the substituted values are escaped
+/// properly when the text is lowered.
+///
+/// Returns keys of placeholders without a value in order of occurrence,
+/// without duplicates. Such placeholders are kept as-is.
+pub fn substitute_placeholders(
+	text: &mut Text,
+	delim: char,
+	values: &HashMap<&str, &str>,
+) -> Vec<String> {
+	let mut unresolved = Vec::new();
+	for word in &mut text.0 {
+		let Word::Literal(literal) = word else {
+			continue;
+		};
+		let pieces =
+			split_placeholders(literal, delim, values, &mut unresolved);
+		if pieces
+			.iter()
+			.any(|piece| matches!(piece, Placeholder::Value(_)))
+		{
+			*literal =
+				Cow::Owned(pieces.iter().map(Placeholder::as_str).collect());
+		}
+	}
+	unresolved
+}
+
+/// A piece of a string split by [`split_placeholders`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Placeholder<'s, 'v> {
+	/// Text outside of placeholders, or a placeholder without a value.
+	Text(&'s str),
+	/// Value of a placeholder.
+	Value(&'v str),
+}
+
+impl Placeholder<'_, '_> {
+	pub(crate) fn as_str(&self) -> &str {
+		match self {
+			Placeholder::Text(text) | Placeholder::Value(text) => text,
+		}
+	}
+}
+
+/// Splits a string at template placeholders, see
+/// [`substitute_placeholders`].
+///
+/// Keys of placeholders without a value are pushed to `unresolved`
+/// unless already present.
+pub(crate) fn split_placeholders<'s, 'v>(
+	text: &'s str,
+	delim: char,
+	values: &HashMap<&str, &'v str>,
+	unresolved: &mut Vec<String>,
+) -> Vec<Placeholder<'s, 'v>> {
+	let mut result = Vec::new();
+	let mut rest = text;
+	let mut pos = 0;
+	while let Some(start) = rest[pos..].find(delim).map(|start| pos + start) {
+		let key_start = start + delim.len_utf8();
+		let key = rest[key_start..]
+			.find(delim)
+			.map(|end| &rest[key_start..key_start + end])
+			.filter(|key| {
+				!key.is_empty()
+					&& key.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+			});
+		let Some(key) = key else {
+			pos = key_start;
+			continue;
+		};
+		let end = key_start + key.len() + delim.len_utf8();
+		match values.get(key) {
+			Some(value) => {
+				if start != 0 {
+					result.push(Placeholder::Text(&rest[..start]));
+				}
+				result.push(Placeholder::Value(value));
+				rest = &rest[end..];
+				pos = 0;
+			}
+			None => {
+				if !unresolved.iter().any(|name| name == key) {
+					unresolved.push(key.to_string());
+				}
+				pos = end;
+			}
+		}
+	}
+	if !rest.is_empty() {
+		result.push(Placeholder::Text(rest));
+	}
+	result
+}
+
+/// Result of [`quote_analysis`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct QuoteReport {
+	/// Reasons forcing the text to be quoted, in order of occurrence.
+	pub reasons: Vec<QuoteReason>,
+}
+
+impl QuoteReport {
+	/// Returns if the text must be quoted.
+	pub fn needs_quoting(&self) -> bool {
+		!self.reasons.is_empty()
+	}
+}
+
+/// A character or construct that forces a text to be quoted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuoteReason {
+	/// Index of the word in the text.
+	pub word: usize,
+	/// Byte offset in the literal word, or zero for other words.
+	pub offset: usize,
+	/// What forces quoting.
+	pub kind: QuoteReasonKind,
+}
+
+/// Kinds of [`QuoteReason`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuoteReasonKind {
+	/// The value is empty.
+	Empty,
+	/// A whitespace character, which splits words.
+	Whitespace(char),
+	/// A glob character (`*`, `?` or `[`).
+	Glob(char),
+	/// A brace (`{` or `}`), which may start brace expansions.
+	Brace(char),
+	/// A literal `$`.
+	Dollar,
+	/// A quote character.
+	Quote(char),
+	/// A backslash.
+	Backslash,
+	/// A shell metacharacter such as `;`, `|` and `(`.
+	Metachar(char),
+	/// A `#` at the start of value, which starts a comment.
+	LeadingHash,
+	/// A `~` at the start of value, which triggers tilde expansion.
+	LeadingTilde,
+	/// A variable expansion, which is subject to word splitting.
+	Expansion,
+	/// A sub-command expansion, which is subject to word splitting.
+	Subcommand,
+}
+
+/// Analyzes why a text needs to be quoted when written as a value.
+///
+/// This is the single source of truth for deciding whether quotes
+/// can be omitted. A text needs no quotes if and only if the report
+/// contains no reasons.
+pub fn quote_analysis(text: &Text) -> QuoteReport {
+	let mut reasons = Vec::new();
+	if text
+		.0
+		.iter()
+		.all(|word| matches!(word, Word::Literal(lit) if lit.is_empty()))
+	{
+		reasons.push(QuoteReason {
+			word: 0,
+			offset: 0,
+			kind: QuoteReasonKind::Empty,
+		});
+	}
+	let mut at_start = true;
+	for (idx, word) in text.0.iter().enumerate() {
+		let literal = match word {
+			Word::Literal(literal) => literal,
+			Word::Variable(_) | Word::Subcommand(_) => {
+				reasons.push(QuoteReason {
+					word: idx,
+					offset: 0,
+					kind: if matches!(word, Word::Variable(_)) {
+						QuoteReasonKind::Expansion
+					} else {
+						QuoteReasonKind::Subcommand
+					},
+				});
+				at_start = false;
+				continue;
+			}
+		};
+		for (offset, ch) in literal.char_indices() {
+			let kind = match ch {
+				'#' if at_start => Some(QuoteReasonKind::LeadingHash),
+				'~' if at_start => Some(QuoteReasonKind::LeadingTilde),
+				' ' | '\t' | '\n' => Some(QuoteReasonKind::Whitespace(ch)),
+				'*' | '?' | '[' => Some(QuoteReasonKind::Glob(ch)),
+				'{' | '}' => Some(QuoteReasonKind::Brace(ch)),
+				'$' => Some(QuoteReasonKind::Dollar),
+				'\'' | '"' | '`' => Some(QuoteReasonKind::Quote(ch)),
+				'\\' => Some(QuoteReasonKind::Backslash),
+				';' | '&' | '|' | '<' | '>' | '(' | ')' => {
+					Some(QuoteReasonKind::Metachar(ch))
+				}
+				_ => None,
+			};
+			if let Some(kind) = kind {
+				reasons.push(QuoteReason {
+					word: idx,
+					offset,
+					kind,
+				});
+			}
+			at_start = false;
+		}
+	}
+	QuoteReport { reasons }
+}
+
+/// Quoting style used when lowering literal strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LowerStyle {
+	/// Leaves the string unquoted if [`quote_analysis`] permits,
+	/// and double-quotes it otherwise.
+	#[default]
+	Auto,
+	/// Always double-quotes the string.
+	DoubleQuoted,
+	/// Single-quotes the string if it contains no single quote,
+	/// and double-quotes it otherwise.
+	SingleQuoted,
+}
+
+impl LowerStyle {
+	/// Lowers a literal string into LST text in this style.
+	pub fn lower_str<'a>(&self, value: &str) -> lst::Text<'a> {
+		let unit = match self {
+			LowerStyle::Auto
+				if !quote_analysis(&Text(vec![Word::Literal(
+					value.into(),
+				)]))
+				.needs_quoting() =>
+			{
+				lst::TextUnit::Unquoted(vec![lst::Word::Literal(vec![
+					lst::LiteralPart::String(value.to_string().into()),
+				])])
+			}
+			LowerStyle::SingleQuoted if !value.contains('\'') => {
+				lst::TextUnit::SingleQuote(value.to_string().into())
+			}
+			_ => lst::TextUnit::DoubleQuote(vec![lst::Word::Literal(
+				lst::LiteralPart::escape(value),
+			)]),
+		};
+		lst::Text(vec![unit])
+	}
+}
+
 /// A word is a part of a text.
 ///
 /// When emitted from [`lst::Word`], the subcommand variant is emitted as a literal,
@@ -425,10 +1321,11 @@ pub enum ExpansionModifier<'a> {
 	/// Reference to a substring.
 	///
 	/// The range is [offset, (offset+length)) (indexing from zero).
+	/// If the offset is negative, it counts from the end of the value.
 	/// If the length is negative, the range is [offset, total+length].
 	Substring {
 		/// Offset.
-		offset: usize,
+		offset: isize,
 		/// Length.
 		length: Option<isize>,
 	},
@@ -476,6 +1373,26 @@ pub enum ExpansionModifier<'a> {
 	WhenUnset(Arc<Text<'a>>),
 	/// Returning a text when the variable is set.
 	WhenSet(Arc<Text<'a>>),
+	/// Returning the value of the variable whose name is the value of
+	/// the variable.
+	Indirect,
+	/// Returning names of variables starting with the name, as elements.
+	///
+	/// In strings, the names are concatenated with space.
+	NamesWithPrefix,
+	/// Returning names of variables starting with the name, concatenated
+	/// with space.
+	SingleWordNamesWithPrefix,
+	/// Returning a single element of array.
+	///
+	/// The index is a arithmetic expression, which can be a integer or
+	/// the name of a variable. Negative indexes count from the end.
+	Index(Cow<'a, str>),
+	/// Returning the number of array elements.
+	ArrayLength,
+	/// Returning the number of array elements, with the subscript written
+	/// as `[*]`.
+	SingleWordArrayLength,
 }
 
 impl<'a> AstNode for ExpansionModifier<'a> {
@@ -485,8 +1402,7 @@ impl<'a> AstNode for ExpansionModifier<'a> {
 		match lst {
 			lst::ExpansionModifier::Substring { offset, length } => {
 				Ok(Self::Substring {
-					offset: max(offset.as_ref().trim().parse::<isize>()?, 0)
-						as usize,
+					offset: offset.as_ref().trim().parse::<isize>()?,
 					length: if let Some(length) = length {
 						Some(length.as_ref().trim().parse::<isize>()?)
 					} else {
@@ -568,11 +1484,25 @@ impl<'a> AstNode for ExpansionModifier<'a> {
 			lst::ExpansionModifier::WhenSet(text) => {
 				Ok(Self::WhenSet(Arc::new(Text::emit_from(text)?)))
 			}
+			lst::ExpansionModifier::Indirect => Ok(Self::Indirect),
+			lst::ExpansionModifier::NamesWithPrefix => {
+				Ok(Self::NamesWithPrefix)
+			}
+			lst::ExpansionModifier::SingleWordNamesWithPrefix => {
+				Ok(Self::SingleWordNamesWithPrefix)
+			}
+			lst::ExpansionModifier::Index(index) => {
+				Ok(Self::Index(index.clone()))
+			}
+			lst::ExpansionModifier::ArrayLength => Ok(Self::ArrayLength),
+			lst::ExpansionModifier::SingleWordArrayLength => {
+				Ok(Self::SingleWordArrayLength)
+			}
 			lst::ExpansionModifier::ArrayElements => {
-				Err(EmitError::Unrepresentable)
+				Err(EmitErrorKind::Unrepresentable.into())
 			}
 			lst::ExpansionModifier::SingleWordElements => {
-				Err(EmitError::Unrepresentable)
+				Err(EmitErrorKind::Unrepresentable.into())
 			}
 		}
 	}
@@ -581,7 +1511,12 @@ impl<'a> AstNode for ExpansionModifier<'a> {
 		match self {
 			ExpansionModifier::Substring { offset, length } => {
 				lst::ExpansionModifier::Substring {
-					offset: offset.to_string().into(),
+					// separate from the colon to avoid ":-"
+					offset: if *offset < 0 {
+						format!(" {offset}").into()
+					} else {
+						offset.to_string().into()
+					},
 					length: length.map(|length| length.to_string().into()),
 				}
 			}
@@ -643,6 +1578,22 @@ impl<'a> AstNode for ExpansionModifier<'a> {
 			ExpansionModifier::WhenSet(text) => {
 				lst::ExpansionModifier::WhenSet(Arc::new(text.lower()))
 			}
+			ExpansionModifier::Indirect => lst::ExpansionModifier::Indirect,
+			ExpansionModifier::NamesWithPrefix => {
+				lst::ExpansionModifier::NamesWithPrefix
+			}
+			ExpansionModifier::SingleWordNamesWithPrefix => {
+				lst::ExpansionModifier::SingleWordNamesWithPrefix
+			}
+			ExpansionModifier::Index(index) => {
+				lst::ExpansionModifier::Index(index.clone())
+			}
+			ExpansionModifier::ArrayLength => {
+				lst::ExpansionModifier::ArrayLength
+			}
+			ExpansionModifier::SingleWordArrayLength => {
+				lst::ExpansionModifier::SingleWordArrayLength
+			}
 		}
 	}
 }
@@ -665,7 +1616,7 @@ impl<'a> AstNode for ArrayElement<'a> {
 		match lst {
 			lst::ArrayToken::Spacy(_)
 			| lst::ArrayToken::Newline
-			| lst::ArrayToken::Comment(_) => Err(EmitError::Unrepresentable),
+			| lst::ArrayToken::Comment(_) => Err(EmitErrorKind::Unrepresentable.into()),
 			lst::ArrayToken::Element(text) => {
 				let units = &text.0;
 				if units.len() == 1 {
@@ -740,6 +1691,382 @@ mod test {
 		assert_eq!(result.lower().to_string(), lst);
 	}
 
+	#[test]
+	fn test_substitute_placeholders() {
+		let values = HashMap::from([("VER", "1.0"), ("NAME", "a$b\"c'd")]);
+		let lst =
+			lst::ApmlLst::parse("A=\"@NAME@-@VER@ @UNKNOWN@ a@b.c @@\"$A@VER@")
+				.unwrap();
+		let ast = ApmlAst::emit_from(&lst).unwrap();
+		let VariableValue::String(mut text) = ast.0[0].value.clone() else {
+			unreachable!()
+		};
+		let unresolved = substitute_placeholders(&mut text, '@', &values);
+		assert_eq!(unresolved, vec!["UNKNOWN"]);
+		assert_eq!(text.0, vec![
+			Word::Literal("a$b\"c'd-1.0 @UNKNOWN@ a@b.c @@".into()),
+			Word::Variable(VariableExpansion {
+				name: "A".into(),
+				modifier: None
+			}),
+			Word::Literal("1.0".into()),
+		]);
+		assert_eq!(
+			text.lower().to_string(),
+			"\"a\\$b\\\"c'd-1.0 @UNKNOWN@ a@b.c @@${A}1.0\""
+		);
+	}
+
+	#[test]
+	fn test_lower_against() {
+		let src = "# header\nA=1\nB='2' # two\nC=\"3\"\n\nD=(4  5)\nE=${A}\n\
+		           F+=6\nG=\"7\"\nH=8\n  I=9\nJ=(\n\t10\n)\n";
+		let lst = lst::ApmlLst::parse(src).unwrap();
+		let mut ast = ApmlAst::emit_from(&lst).unwrap();
+		assert_eq!(ast.0.len(), 10);
+		assert_eq!(lower_against(&ast, &lst).to_string(), src);
+		ast.0[6].value =
+			VariableValue::String(Text(vec![Word::Literal("changed".into())]));
+		let lowered = lower_against(&ast, &lst).to_string();
+		let lines = lowered.lines().collect::<Vec<_>>();
+		let expected = src.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), expected.len());
+		for (idx, (line, expected)) in lines.iter().zip(&expected).enumerate() {
+			if idx == 8 {
+				assert_eq!(*line, "G=\"changed\"");
+			} else {
+				assert_eq!(line, expected);
+			}
+		}
+
+		ast.0.remove(1);
+		ast.0.push(VariableDefinition {
+			name: "K".into(),
+			value: VariableValue::String(Text(vec![Word::Literal(
+				"11".into(),
+			)])),
+		});
+		assert_eq!(
+			lower_against(&ast, &lst).to_string(),
+			"# header\nA=1\nC=\"3\"\n\nD=(4  5)\nE=${A}\nF+=6\n\
+			 G=\"changed\"\nH=8\n  I=9\nJ=(\n\t10\n)\nK=\"11\"\n"
+		);
+	}
+
+	#[test]
+	fn test_validate() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse(
+				"A=\"${B%%*.*}$(c)\"\nD=(\"${A[@]}\" \"${A:-$B}\")",
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		assert_eq!(ast.validate(), Ok(()));
+		assert_eq!(ApmlAst(vec![]).validate(), Ok(()));
+
+		let expansion = |name: &'static str, modifier| {
+			Word::Variable(VariableExpansion {
+				name: name.into(),
+				modifier,
+			})
+		};
+		let ast = ApmlAst(vec![
+			VariableDefinition {
+				name: "A B".into(),
+				value: VariableValue::String("a".into()),
+			},
+			VariableDefinition {
+				name: "C".into(),
+				value: VariableValue::String(Text(vec![
+					expansion("1A", None),
+					Word::Subcommand("echo".into()),
+					expansion(
+						"B",
+						Some(ExpansionModifier::WhenUnset(Arc::new(Text(
+							vec![expansion(
+								"D",
+								Some(ExpansionModifier::StripShortestPrefix(
+									Arc::new(BashPattern(vec![])),
+								)),
+							)],
+						)))),
+					),
+				])),
+			},
+			VariableDefinition {
+				name: "E".into(),
+				value: VariableValue::Array(vec![
+					ArrayElement::ArrayInclusion("".into()),
+					ArrayElement::Text(Arc::new(Text(vec![expansion(
+						"a-b", None,
+					)]))),
+				]),
+			},
+		]);
+		assert_eq!(
+			ast.validate(),
+			Err(vec![
+				AstError::InvalidDefinitionName("A B".into()),
+				AstError::InvalidExpansionName {
+					variable: "C".into(),
+					name: "1A".into()
+				},
+				AstError::MalformedSubcommand {
+					variable: "C".into(),
+					text: "echo".into()
+				},
+				AstError::EmptyPattern {
+					variable: "C".into(),
+					name: "D".into()
+				},
+				AstError::InvalidInclusionName {
+					variable: "E".into(),
+					name: "".into()
+				},
+				AstError::InvalidExpansionName {
+					variable: "E".into(),
+					name: "a-b".into()
+				},
+			])
+		);
+		assert_eq!(
+			ast.0[0].validate(),
+			Err(vec![AstError::InvalidDefinitionName("A B".into())])
+		);
+		assert_eq!(ast.0[1].validate().unwrap_err().len(), 3);
+	}
+
+	#[test]
+	fn test_contains_expansion() {
+		let value = |src| {
+			ApmlAst::emit_from(&lst::ApmlLst::parse(src).unwrap())
+				.unwrap()
+				.0
+				.remove(0)
+				.value
+		};
+		assert!(contains_expansion(&value("A=\"$A\"")));
+		assert!(contains_expansion(&value("A=\"a${B}\"")));
+		assert!(contains_expansion(&value("A=\"$(cmd)\"")));
+		assert!(contains_expansion(&value("A=(a \"${B[@]}\")")));
+		assert!(contains_expansion(&value("A=(a \"${B:-c}\")")));
+		assert!(!contains_expansion(&value("A=\"plain text\"")));
+		assert!(!contains_expansion(&value("A='raw $A'")));
+		assert!(!contains_expansion(&value("A=(a 'b' \\$c)")));
+		assert!(!contains_expansion(&value("A=")));
+	}
+
+	#[test]
+	fn test_unused_vars() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse(
+				"_V=1\nVER=\"$_V.${_P:-$_Q}\"\nSRCS=(\"${_S[@]}\")\n_S=(a)\n\
+				 _P=1\n_Q=1\n_OLD=1\n_CMD=$(echo $_OLD)\n_OLD=2\n",
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		assert_eq!(referenced_vars(&ast.0[1].value), vec!["_V", "_P", "_Q"]);
+		assert_eq!(referenced_vars(&ast.0[2].value), vec!["_S"]);
+		assert_eq!(ast.unused_vars(&["VER", "SRCS"]), vec!["_OLD", "_CMD"]);
+		assert_eq!(ast.unused_vars(&["VER", "SRCS", "_OLD"]), vec!["_CMD"]);
+		assert_eq!(ast.unused_vars(&[]).len(), 8);
+
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse(
+				"PRE_X=1\nPRE=1\nPR=1\nA=\"${!PRE_@}\"\nB=${!PRE*}\nF=G\nG=1\n\
+				 E=${!F}\n",
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		assert!(referenced_vars(&ast.0[3].value).is_empty());
+		assert_eq!(referenced_vars(&ast.0[7].value), vec!["F"]);
+		assert_eq!(ast.unused_vars(&["A"]), vec![
+			"PRE", "PR", "B", "F", "G", "E"
+		]);
+		assert_eq!(ast.unused_vars(&["B"]), vec!["PR", "A", "F", "G", "E"]);
+		assert!(ast.unused_vars(&["A", "E"]).is_empty());
+		assert_eq!(ast.unused_vars(&["F"]), vec![
+			"PRE_X", "PRE", "PR", "A", "B", "G", "E"
+		]);
+	}
+
+	#[test]
+	fn test_glob_patterns() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse(
+				"A=${VER//./_}\nB=(\"${A%%.tar*}\" \"${A:-${B^[a-z]}}\")\n\
+				 C=\"${A/#v/${B,,[A-Z]}}\"\nD=\"${A:1} $(echo ${A#a})\"\n",
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		let patterns = |value| {
+			glob_patterns(value)
+				.into_iter()
+				.map(|pattern| pattern.to_string())
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(patterns(&ast.0[0].value), ["."]);
+		assert_eq!(patterns(&ast.0[1].value), [".tar*", "[a-z]"]);
+		assert_eq!(patterns(&ast.0[2].value), ["v", "[A-Z]"]);
+		assert!(patterns(&ast.0[3].value).is_empty());
+		assert_eq!(
+			ast.glob_patterns()
+				.into_iter()
+				.map(|(name, pattern)| (name, pattern.to_string()))
+				.collect::<Vec<_>>(),
+			[
+				("A", ".".to_string()),
+				("B", ".tar*".to_string()),
+				("B", "[a-z]".to_string()),
+				("C", "v".to_string()),
+				("C", "[A-Z]".to_string()),
+			]
+		);
+	}
+
+	#[test]
+	fn test_quote_analysis() {
+		let kinds = |text: Text| {
+			quote_analysis(&text)
+				.reasons
+				.into_iter()
+				.map(|reason| reason.kind)
+				.collect::<Vec<_>>()
+		};
+		let var = || {
+			Word::Variable(VariableExpansion {
+				name: "A".into(),
+				modifier: None,
+			})
+		};
+		assert!(!quote_analysis(&"abc-1.0_+=,.:@%^/".into()).needs_quoting());
+		assert!(!quote_analysis(&"a#b~".into()).needs_quoting());
+		assert_eq!(kinds(Text(vec![])), vec![QuoteReasonKind::Empty]);
+		assert_eq!(kinds("".into()), vec![QuoteReasonKind::Empty]);
+		assert_eq!(kinds("a b\tc\n".into()), vec![
+			QuoteReasonKind::Whitespace(' '),
+			QuoteReasonKind::Whitespace('\t'),
+			QuoteReasonKind::Whitespace('\n'),
+		]);
+		assert_eq!(kinds("*.[ch]?".into()), vec![
+			QuoteReasonKind::Glob('*'),
+			QuoteReasonKind::Glob('['),
+			QuoteReasonKind::Glob('?'),
+		]);
+		assert_eq!(kinds("{a,b}".into()), vec![
+			QuoteReasonKind::Brace('{'),
+			QuoteReasonKind::Brace('}'),
+		]);
+		assert_eq!(kinds("a$".into()), vec![QuoteReasonKind::Dollar]);
+		assert_eq!(kinds("'\"`".into()), vec![
+			QuoteReasonKind::Quote('\''),
+			QuoteReasonKind::Quote('"'),
+			QuoteReasonKind::Quote('`'),
+		]);
+		assert_eq!(kinds("a\\b".into()), vec![QuoteReasonKind::Backslash]);
+		assert_eq!(kinds("a;b|c&d<e>f(g)".into()), vec![
+			QuoteReasonKind::Metachar(';'),
+			QuoteReasonKind::Metachar('|'),
+			QuoteReasonKind::Metachar('&'),
+			QuoteReasonKind::Metachar('<'),
+			QuoteReasonKind::Metachar('>'),
+			QuoteReasonKind::Metachar('('),
+			QuoteReasonKind::Metachar(')'),
+		]);
+		assert_eq!(kinds("#a".into()), vec![QuoteReasonKind::LeadingHash]);
+		assert_eq!(kinds("~/a".into()), vec![QuoteReasonKind::LeadingTilde]);
+		assert_eq!(kinds(Text(vec![var()])), vec![QuoteReasonKind::Expansion]);
+		assert_eq!(kinds(Text(vec![Word::Subcommand("$(a)".into())])), vec![
+			QuoteReasonKind::Subcommand
+		]);
+		assert_eq!(
+			quote_analysis(&Text(vec![var(), Word::Literal("#a b".into())]))
+				.reasons,
+			vec![
+				QuoteReason {
+					word: 0,
+					offset: 0,
+					kind: QuoteReasonKind::Expansion
+				},
+				QuoteReason {
+					word: 1,
+					offset: 2,
+					kind: QuoteReasonKind::Whitespace(' ')
+				},
+			]
+		);
+		assert_eq!(
+			kinds(Text(vec![
+				Word::Literal("".into()),
+				Word::Literal("#".into())
+			])),
+			vec![QuoteReasonKind::LeadingHash]
+		);
+	}
+
+	#[test]
+	fn test_variable_definition_ord() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse("c=1\nA=2\nb=3\na=(4)\n").unwrap(),
+		)
+		.unwrap();
+		let mut defs = ast.0;
+		defs.sort();
+		let names =
+			defs.iter().map(|def| def.name.as_ref()).collect::<Vec<_>>();
+		assert_eq!(names, vec!["A", "a", "b", "c"]);
+		let lst = lst::ApmlLst::parse("c=1\nA+=2\nb=3\n").unwrap();
+		let mut defs = lst
+			.0
+			.into_iter()
+			.filter_map(|token| match token {
+				lst::Token::Variable(def) => Some(def),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		defs.sort();
+		let names =
+			defs.iter().map(|def| def.name.as_ref()).collect::<Vec<_>>();
+		assert_eq!(names, vec!["A", "b", "c"]);
+	}
+
+	#[test]
+	fn test_declaration() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse(
+				"declare -x A=a\ndeclare -a B=b\ntypeset -a B+=c\n",
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		assert_eq!(
+			ast.to_string(),
+			"A=\"a\"\nB=(\"b\")\nB=(\"${B[@]}\" \"c\")"
+		);
+
+		let lst = lst::ApmlLst::parse("export A=1\n").unwrap();
+		let mut ast = ApmlAst::emit_from(&lst).unwrap();
+		ast.0[0].value = VariableValue::String("2".into());
+		assert_eq!(ast.to_string(), "A=\"2\"");
+		assert_eq!(lower_against(&ast, &lst).to_string(), "export A=\"2\"\n");
+	}
+
+	#[test]
+	fn test_display() {
+		let ast = ApmlAst::emit_from(
+			&lst::ApmlLst::parse("A=a # a\nB=(a 'b c' \"${A[@]}\")").unwrap(),
+		)
+		.unwrap();
+		assert_eq!(ast.to_string(), "A=\"a\"\nB=(\"a\" \"b c\" \"${A[@]}\")");
+		assert_eq!(format!("{}", ast.0[0]), "A=\"a\"");
+		assert_eq!(ast.0[1].value.to_string(), "(\"a\" \"b c\" \"${A[@]}\")");
+		assert_eq!(Text::from("$a").to_string(), "\"\\$a\"");
+	}
+
 	#[test]
 	fn test_apml_ast() {
 		let text_lst = Arc::new(lst::Text(vec![lst::TextUnit::SingleQuote(
@@ -747,6 +2074,7 @@ mod test {
 		)]));
 		let text_ast = Text(vec![Word::Literal("foo$\\".into())]);
 		let def_lst = lst::VariableDefinition {
+			declaration: None,
 			name: "test".into(),
 			op: lst::VariableOp::Assignment,
 			value: lst::VariableValue::String(text_lst.clone()),
@@ -785,6 +2113,44 @@ mod test {
 		]));
 	}
 
+	#[test]
+	fn test_emit_from_partial() {
+		let lst = lst::ApmlLst::parse(
+			"A=1\nB=${B:99999999999999999999} C=2\nD=(a b)",
+		)
+		.unwrap();
+		let (partial, errors) = ApmlAst::emit_from_partial(&lst);
+		assert_eq!(
+			partial.ast,
+			ApmlAst::emit_from(
+				&lst::ApmlLst::parse("A=1\nC=2\nD=(a b)").unwrap()
+			)
+			.unwrap()
+		);
+		assert_eq!(partial.token_indexes, vec![0, 4, 6]);
+		assert_eq!(errors.len(), 2);
+		assert!(matches!(errors[0].kind, EmitErrorKind::UnparsableInt(_)));
+		assert_eq!(errors[0].token_index, 2);
+		assert!(errors[0].description.ends_with(" in definition of B"));
+		assert!(matches!(
+			errors[1].kind,
+			EmitErrorKind::MissingRootElementDelimiter
+		));
+		assert_eq!(errors[1].token_index, 4);
+		assert_eq!(
+			errors[1].to_string(),
+			"Missing delimiters between root elements in definition of C \
+			 (token 4)"
+		);
+		let err = ApmlAst::emit_from(&lst).unwrap_err();
+		assert_eq!(err.token_index, 2);
+
+		let (partial, errors) =
+			ApmlAst::emit_from_partial(&lst::ApmlLst::parse("A=1").unwrap());
+		assert_eq!(partial.token_indexes, vec![0]);
+		assert!(errors.is_empty());
+	}
+
 	#[test]
 	fn test_variable_definition() {
 		let text_lst = Arc::new(lst::Text(vec![lst::TextUnit::SingleQuote(
@@ -793,6 +2159,7 @@ mod test {
 		let text_ast = Text(vec![Word::Literal("foo$\\".into())]);
 		assert_emit_lower(
 			lst::VariableDefinition {
+				declaration: None,
 				name: "test".into(),
 				op: lst::VariableOp::Assignment,
 				value: lst::VariableValue::String(text_lst.clone()),
@@ -805,6 +2172,7 @@ mod test {
 		);
 		assert_emit_lower(
 			lst::VariableDefinition {
+				declaration: None,
 				name: "test".into(),
 				op: lst::VariableOp::Append,
 				value: lst::VariableValue::String(text_lst.clone()),
@@ -823,6 +2191,7 @@ mod test {
 		);
 		assert_emit_lower(
 			lst::VariableDefinition {
+				declaration: None,
 				name: "test".into(),
 				op: lst::VariableOp::Assignment,
 				value: lst::VariableValue::Array(vec![
@@ -839,6 +2208,7 @@ mod test {
 		);
 		assert_emit_lower(
 			lst::VariableDefinition {
+				declaration: None,
 				name: "test".into(),
 				op: lst::VariableOp::Append,
 				value: lst::VariableValue::Array(vec![
@@ -895,6 +2265,31 @@ mod test {
 		]));
 	}
 
+	#[test]
+	fn test_array_round_trip() {
+		let src =
+			"A=(a \"$B\" ${C:-d} \"${E[@]}\" # f\n\t'g'\"${H}\" x${I#j}y)";
+		let lst = lst::ApmlLst::parse(src).unwrap();
+		let ast = ApmlAst::emit_from(&lst).unwrap();
+		let VariableValue::Array(elements) = &ast.0[0].value else {
+			panic!("not emitted as array: {:?}", ast.0[0].value)
+		};
+		assert_eq!(elements.len(), 6);
+		assert_eq!(elements[3], ArrayElement::ArrayInclusion("E".into()));
+		let lowered = ast.lower();
+		assert_eq!(
+			lowered.to_string(),
+			"A=(\"a\" \"${B}\" \"${C:-\"d\"}\" \"${E[@]}\" \"g${H}\" \
+			 \"x${I#j}y\")"
+		);
+		assert_eq!(ApmlAst::emit_from(&lowered).unwrap(), ast);
+		let lst = lst::ApmlLst::parse("A=()\nB+=(\"$A\")").unwrap();
+		let ast = ApmlAst::emit_from(&lst).unwrap();
+		assert_eq!(ast.0[0].value, VariableValue::Array(vec![]));
+		assert_eq!(ast.lower().to_string(), "A=()\nB=(\"${B[@]}\" \"${A}\")");
+		assert_eq!(ApmlAst::emit_from(&ast.lower()).unwrap(), ast);
+	}
+
 	#[test]
 	fn test_text() {
 		assert_emit_lower(
@@ -996,6 +2391,63 @@ mod test {
 			},
 			"#test",
 		);
+		assert_emit_lower(
+			lst::BracedExpansion {
+				name: "test".into(),
+				modifier: Some(lst::ExpansionModifier::Indirect),
+			},
+			VariableExpansion {
+				name: "test".into(),
+				modifier: Some(ExpansionModifier::Indirect),
+			},
+			"!test",
+		);
+		assert_emit_lower(
+			lst::BracedExpansion {
+				name: "test".into(),
+				modifier: Some(lst::ExpansionModifier::NamesWithPrefix),
+			},
+			VariableExpansion {
+				name: "test".into(),
+				modifier: Some(ExpansionModifier::NamesWithPrefix),
+			},
+			"!test@",
+		);
+		assert_emit_lower(
+			lst::BracedExpansion {
+				name: "test".into(),
+				modifier: Some(
+					lst::ExpansionModifier::SingleWordNamesWithPrefix,
+				),
+			},
+			VariableExpansion {
+				name: "test".into(),
+				modifier: Some(ExpansionModifier::SingleWordNamesWithPrefix),
+			},
+			"!test*",
+		);
+		assert_emit_lower(
+			lst::BracedExpansion {
+				name: "test".into(),
+				modifier: Some(lst::ExpansionModifier::ArrayLength),
+			},
+			VariableExpansion {
+				name: "test".into(),
+				modifier: Some(ExpansionModifier::ArrayLength),
+			},
+			"#test[@]",
+		);
+		assert_emit_lower(
+			lst::BracedExpansion {
+				name: "test".into(),
+				modifier: Some(lst::ExpansionModifier::SingleWordArrayLength),
+			},
+			VariableExpansion {
+				name: "test".into(),
+				modifier: Some(ExpansionModifier::SingleWordArrayLength),
+			},
+			"#test[*]",
+		);
 		assert_emit_lower(
 			lst::BracedExpansion {
 				name: "test".into(),
@@ -1053,10 +2505,21 @@ mod test {
 				length: None,
 			},
 			ExpansionModifier::Substring {
-				offset: 0,
+				offset: -1,
 				length: None,
 			},
-			":0",
+			": -1",
+		);
+		assert_emit_lower(
+			lst::ExpansionModifier::Substring {
+				offset: " -3".into(),
+				length: Some("2".into()),
+			},
+			ExpansionModifier::Substring {
+				offset: -3,
+				length: Some(2),
+			},
+			": -3:2",
 		);
 		assert_emit_lower(
 			lst::ExpansionModifier::Substring {
@@ -0,0 +1,264 @@
+//! Structured directives in comments.
+//!
+//! Directives are machine-readable hints stored in comments, for example
+//! `# PFU: upstream=github:owner/repo;nocheck`.
+//!
+//! A directive comment is made up of an optional leading text, a prefix
+//! ([`DEFAULT_PREFIX`] by default) and a list of entries delimited by
+//! semicolons. Each entry is a key, optionally followed by a `=` and a value.
+//!
+//! The leading text is kept as-is when a directive is written back,
+//! so unrelated comment text on the same line is preserved. So are the
+//! separators and spaces around entries which are not changed.
+//!
+//! A directive always extends to the end of the comment, so no other
+//! text can follow it. For example, `# PFU: a=b -- note` has a entry
+//! `a` with the value `b -- note`.
+
+use std::{
+	borrow::Cow,
+	fmt::{Display, Write},
+};
+
+/// The default prefix of directives.
+pub const DEFAULT_PREFIX: &str = "PFU:";
+
+/// A directive parsed from a comment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Directive<'a> {
+	/// Text before the prefix, including the spaces after `#`.
+	pub leading: Cow<'a, str>,
+	/// Prefix of the directive.
+	pub prefix: Cow<'a, str>,
+	/// Entries of the directive.
+	pub entries: Vec<DirectiveEntry<'a>>,
+	/// Text after the last entry, like a trailing `;`.
+	pub trailing: Cow<'a, str>,
+}
+
+/// A entry in a directive (`"<key>[=<value>]"`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DirectiveEntry<'a> {
+	/// Key of the entry.
+	pub key: Cow<'a, str>,
+	/// Value of the entry.
+	///
+	/// Flag-like entries have no value.
+	pub value: Option<Cow<'a, str>>,
+	/// Source text of the entry, including separators of empty entries
+	/// and spaces around it.
+	///
+	/// It is written back as-is if the key and value are not changed.
+	/// Otherwise, only the separators and spaces are kept.
+	pub source: Option<Cow<'a, str>>,
+}
+
+impl<'a> Directive<'a> {
+	/// Creates a directive with the default prefix and no entries.
+	pub fn new() -> Self {
+		Self {
+			leading: Cow::Borrowed(" "),
+			prefix: Cow::Borrowed(DEFAULT_PREFIX),
+			entries: Vec::new(),
+			trailing: Cow::Borrowed(""),
+		}
+	}
+
+	/// Parses a comment text (without the `#`) with the default prefix.
+	///
+	/// Returns [None] if the comment is not a directive.
+	pub fn parse(comment: &'a str) -> Option<Self> {
+		Self::parse_with_prefix(comment, DEFAULT_PREFIX)
+	}
+
+	/// Parses a comment text (without the `#`) with a custom prefix.
+	///
+	/// Returns [None] if the comment is not a directive.
+	pub fn parse_with_prefix(
+		comment: &'a str,
+		prefix: &'a str,
+	) -> Option<Self> {
+		let pos = comment.find(prefix)?;
+		let (leading, rest) = comment.split_at(pos);
+		let rest = &rest[prefix.len()..];
+		let mut entries = Vec::new();
+		// offset of the separator after the last entry
+		let mut end = 0;
+		let mut offset = 0;
+		for segment in rest.split(';') {
+			let segment_end = offset + segment.len();
+			if !segment.trim().is_empty() {
+				let start = if entries.is_empty() { 0 } else { end + 1 };
+				let (key, value) = parse_entry(segment);
+				entries.push(DirectiveEntry {
+					key: Cow::Borrowed(key),
+					value: value.map(Cow::Borrowed),
+					source: Some(Cow::Borrowed(&rest[start..segment_end])),
+				});
+				end = segment_end;
+			}
+			offset = segment_end + 1;
+		}
+		Some(Self {
+			leading: Cow::Borrowed(leading),
+			prefix: Cow::Borrowed(prefix),
+			entries,
+			trailing: Cow::Borrowed(&rest[end..]),
+		})
+	}
+
+	/// Gets a entry by key.
+	#[must_use]
+	pub fn get(&self, key: &str) -> Option<&DirectiveEntry<'a>> {
+		self.entries.iter().find(|entry| entry.key == key)
+	}
+
+	/// Gets the value of a entry by key.
+	///
+	/// Returns [None] if the entry is absent or has no value.
+	#[must_use]
+	pub fn value(&self, key: &str) -> Option<&str> {
+		self.get(key).and_then(|entry| entry.value.as_deref())
+	}
+
+	/// Returns if a entry with the given key exists.
+	#[must_use]
+	pub fn contains(&self, key: &str) -> bool {
+		self.get(key).is_some()
+	}
+
+	/// Adds or updates a entry.
+	pub fn set(&mut self, key: &str, value: Option<&str>) {
+		let value = value.map(|value| Cow::Owned(value.to_string()));
+		if let Some(entry) = self.entries.iter_mut().find(|e| e.key == key) {
+			entry.value = value;
+		} else {
+			self.entries.push(DirectiveEntry {
+				key: Cow::Owned(key.to_string()),
+				value,
+				source: None,
+			});
+		}
+	}
+
+	/// Removes a entry, returning if it has been removed.
+	pub fn remove(&mut self, key: &str) -> bool {
+		let len = self.entries.len();
+		self.entries.retain(|entry| entry.key != key);
+		len != self.entries.len()
+	}
+}
+
+impl Default for Directive<'_> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Display for Directive<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.leading)?;
+		f.write_str(&self.prefix)?;
+		for (idx, entry) in (1..).zip(&self.entries) {
+			if idx != 1 {
+				f.write_char(';')?;
+			}
+			let Some(source) = &entry.source else {
+				if idx == 1 {
+					f.write_char(' ')?;
+				}
+				Display::fmt(entry, f)?;
+				continue;
+			};
+			let text = source
+				.trim_start_matches(|ch: char| ch == ';' || ch.is_whitespace());
+			f.write_str(&source[..source.len() - text.len()])?;
+			let text = text.trim_end();
+			if parse_entry(text) == (&entry.key, entry.value.as_deref()) {
+				f.write_str(text)?;
+			} else {
+				Display::fmt(entry, f)?;
+			}
+			f.write_str(&source[source.trim_end().len()..])?;
+		}
+		f.write_str(&self.trailing)
+	}
+}
+
+impl Display for DirectiveEntry<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.key)?;
+		if let Some(value) = &self.value {
+			f.write_char('=')?;
+			f.write_str(value)?;
+		}
+		Ok(())
+	}
+}
+
+/// Parses the key and value of a entry.
+fn parse_entry(entry: &str) -> (&str, Option<&str>) {
+	match entry.split_once('=') {
+		Some((key, value)) => (key.trim(), Some(value.trim())),
+		None => (entry.trim(), None),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_directive_parse() {
+		let directive =
+			Directive::parse(" PFU: upstream=github:owner/repo;nocheck")
+				.unwrap();
+		assert_eq!(directive.leading, " ");
+		assert_eq!(directive.prefix, "PFU:");
+		assert_eq!(directive.entries.len(), 2);
+		assert_eq!(directive.value("upstream"), Some("github:owner/repo"));
+		assert!(directive.contains("nocheck"));
+		assert_eq!(directive.value("nocheck"), None);
+		assert!(!directive.contains("other"));
+		assert_eq!(
+			directive.to_string(),
+			" PFU: upstream=github:owner/repo;nocheck"
+		);
+		assert!(Directive::parse(" dummysrc for staging").is_none());
+		let directive =
+			Directive::parse_with_prefix(" dummysrc for staging", "dummysrc")
+				.unwrap();
+		assert_eq!(directive.entries.len(), 1);
+		assert!(directive.contains("for staging"));
+		let directive = Directive::parse(" PFU:").unwrap();
+		assert!(directive.entries.is_empty());
+	}
+
+	#[test]
+	fn test_directive_edit() {
+		let mut directive = Directive::parse(" keep this PFU: a=b; c").unwrap();
+		directive.set("a", Some("d"));
+		directive.set("e", None);
+		assert!(directive.remove("c"));
+		assert!(!directive.remove("c"));
+		assert_eq!(directive.to_string(), " keep this PFU: a=d;e");
+		let mut directive = Directive::new();
+		directive.set("a", Some("b"));
+		assert_eq!(directive.to_string(), " PFU: a=b");
+
+		let src = " PFU:  a = b ;; c;";
+		let mut directive = Directive::parse(src).unwrap();
+		assert_eq!(directive.value("a"), Some("b"));
+		assert_eq!(directive.to_string(), src);
+		directive.set("a", Some("b"));
+		assert_eq!(directive, Directive::parse(src).unwrap());
+		directive.set("c", Some("d"));
+		directive.set("e", None);
+		assert_eq!(directive.to_string(), " PFU:  a = b ;; c=d;e;");
+		let mut directive = Directive::parse(" PFU: a=b; c").unwrap();
+		directive.set("e", None);
+		assert_eq!(directive.to_string(), " PFU: a=b; c;e");
+		let directive = Directive::parse(" note PFU: a=b -- keep").unwrap();
+		assert_eq!(directive.value("a"), Some("b -- keep"));
+	}
+}
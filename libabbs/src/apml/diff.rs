@@ -0,0 +1,301 @@
+//! Line-based unified diffs.
+//!
+//! This is used to review edits made on APML sources, for example
+//! [`ApmlEditor::diff`][super::editor::ApmlEditor::diff].
+//! Lines are compared with the Myers algorithm after stripping the common
+//! prefix and suffix, which takes `O((N+M)D)` time for `D` changed lines.
+
+use std::fmt::Write;
+
+/// Options of [`unified_diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+	/// Number of unchanged lines shown around changes.
+	pub context: usize,
+	/// Name of the original file in the `---` header.
+	pub old_name: String,
+	/// Name of the modified file in the `+++` header.
+	pub new_name: String,
+}
+
+impl Default for DiffOptions {
+	fn default() -> Self {
+		Self {
+			context: 3,
+			old_name: "a".to_string(),
+			new_name: "b".to_string(),
+		}
+	}
+}
+
+impl DiffOptions {
+	/// Creates options with both header names being `a/<path>` and
+	/// `b/<path>`, like Git.
+	pub fn with_path(path: &str) -> Self {
+		Self {
+			old_name: format!("a/{path}"),
+			new_name: format!("b/{path}"),
+			..Default::default()
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+	Equal,
+	Delete,
+	Insert,
+}
+
+/// Produces a unified diff between two strings.
+///
+/// Returns an empty string if the strings are equal.
+/// Lines without a trailing newline are marked with
+/// `\ No newline at end of file`.
+pub fn unified_diff(old: &str, new: &str, options: &DiffOptions) -> String {
+	let old = old.split_inclusive('\n').collect::<Vec<_>>();
+	let new = new.split_inclusive('\n').collect::<Vec<_>>();
+	let script = diff_lines(&old, &new);
+	let changes = script
+		.iter()
+		.enumerate()
+		.filter(|(_, (op, ..))| *op != Op::Equal)
+		.map(|(index, _)| index)
+		.collect::<Vec<_>>();
+	let Some(first) = changes.first() else {
+		return String::new();
+	};
+
+	let mut result = String::new();
+	writeln!(result, "--- {}", options.old_name).unwrap();
+	writeln!(result, "+++ {}", options.new_name).unwrap();
+	let context = options.context;
+	let mut start = *first;
+	let mut end = *first;
+	for change in changes.iter().skip(1).chain([&usize::MAX]) {
+		if *change != usize::MAX && *change - end <= context * 2 + 1 {
+			end = *change;
+			continue;
+		}
+		let hunk = start.saturating_sub(context)
+			..(end + 1 + context).min(script.len());
+		write_hunk(&mut result, &script[hunk], &old, &new);
+		start = *change;
+		end = *change;
+	}
+	result
+}
+
+fn write_hunk(
+	result: &mut String,
+	script: &[(Op, usize, usize)],
+	old: &[&str],
+	new: &[&str],
+) {
+	let (_, old_start, new_start) = script[0];
+	let old_len = script.iter().filter(|(op, ..)| *op != Op::Insert).count();
+	let new_len = script.iter().filter(|(op, ..)| *op != Op::Delete).count();
+	let range = |start: usize, len: usize| match len {
+		0 => format!("{start},0"),
+		1 => format!("{}", start + 1),
+		_ => format!("{},{len}", start + 1),
+	};
+	writeln!(
+		result,
+		"@@ -{} +{} @@",
+		range(old_start, old_len),
+		range(new_start, new_len)
+	)
+	.unwrap();
+	for (op, old_index, new_index) in script {
+		let (prefix, line) = match op {
+			Op::Equal => (' ', old[*old_index]),
+			Op::Delete => ('-', old[*old_index]),
+			Op::Insert => ('+', new[*new_index]),
+		};
+		result.push(prefix);
+		result.push_str(line);
+		if !line.ends_with('\n') {
+			result.push_str("\n\\ No newline at end of file\n");
+		}
+	}
+}
+
+/// Computes a edit script between two lists of lines.
+///
+/// Each operation is returned with the line indexes in both lists
+/// where it happens.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<(Op, usize, usize)> {
+	let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+	let suffix = old[prefix..]
+		.iter()
+		.rev()
+		.zip(new[prefix..].iter().rev())
+		.take_while(|(a, b)| a == b)
+		.count();
+	let old_mid = &old[prefix..old.len() - suffix];
+	let new_mid = &new[prefix..new.len() - suffix];
+
+	let mut script = (0..prefix)
+		.map(|index| (Op::Equal, index, index))
+		.collect::<Vec<_>>();
+	script.extend(
+		myers(old_mid, new_mid)
+			.into_iter()
+			.map(|(op, i, j)| (op, prefix + i, prefix + j)),
+	);
+	let (old_end, new_end) = (prefix + old_mid.len(), prefix + new_mid.len());
+	script.extend(
+		(0..suffix).map(|index| (Op::Equal, old_end + index, new_end + index)),
+	);
+	script
+}
+
+/// Computes a shortest edit script with the Myers algorithm.
+///
+/// See "An O(ND) Difference Algorithm and Its Variations" by Eugene W.
+/// Myers.
+fn myers(old: &[&str], new: &[&str]) -> Vec<(Op, usize, usize)> {
+	let (n, m) = (old.len() as isize, new.len() as isize);
+	let offset = n + m + 1;
+	// v[k] is the furthest index in old reached on diagonal k
+	let mut v = vec![0isize; 2 * offset as usize + 1];
+	let mut trace = Vec::new();
+	'search: for d in 0..=n + m {
+		trace.push(v.clone());
+		for k in (-d..=d).step_by(2) {
+			let index = (k + offset) as usize;
+			let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+				v[index + 1]
+			} else {
+				v[index - 1] + 1
+			};
+			let mut y = x - k;
+			while x < n && y < m && old[x as usize] == new[y as usize] {
+				x += 1;
+				y += 1;
+			}
+			v[index] = x;
+			if x >= n && y >= m {
+				break 'search;
+			}
+		}
+	}
+
+	let mut script = Vec::new();
+	let (mut x, mut y) = (n, m);
+	for (d, v) in trace.iter().enumerate().rev() {
+		let d = d as isize;
+		let k = x - y;
+		let index = (k + offset) as usize;
+		let prev_k = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+			k + 1
+		} else {
+			k - 1
+		};
+		let prev_x = v[(prev_k + offset) as usize];
+		let prev_y = prev_x - prev_k;
+		while x > prev_x && y > prev_y {
+			x -= 1;
+			y -= 1;
+			script.push((Op::Equal, x as usize, y as usize));
+		}
+		if d > 0 {
+			if x == prev_x {
+				script.push((Op::Insert, x as usize, prev_y as usize));
+			} else {
+				script.push((Op::Delete, prev_x as usize, y as usize));
+			}
+		}
+		(x, y) = (prev_x, prev_y);
+	}
+	script.reverse();
+	script
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_unified_diff() {
+		let options = DiffOptions::default();
+		assert_eq!(unified_diff("A=1\n", "A=1\n", &options), "");
+		assert_eq!(
+			unified_diff("A=1\nB=2\nC=3\n", "A=1\nB=3\nC=3\nD=4\n", &options),
+			"--- a\n+++ b\n@@ -1,3 +1,4 @@\n A=1\n-B=2\n+B=3\n C=3\n+D=4\n"
+		);
+		assert_eq!(
+			unified_diff("A=1\nB=2", "A=1\nB=3", &DiffOptions {
+				context: 0,
+				..DiffOptions::with_path("spec")
+			}),
+			"--- a/spec\n+++ b/spec\n@@ -2 +2 @@\n-B=2\n\\ No newline at end \
+			 of file\n+B=3\n\\ No newline at end of file\n"
+		);
+		assert_eq!(
+			unified_diff("A=1\nB=2", "A=1\nB=2\n", &options),
+			"--- a\n+++ b\n@@ -1,2 +1,2 @@\n A=1\n-B=2\n\\ No newline at end \
+			 of file\n+B=2\n"
+		);
+		assert_eq!(
+			unified_diff("", "A=1\n", &options),
+			"--- a\n+++ b\n@@ -0,0 +1 @@\n+A=1\n"
+		);
+
+		let old = (1..=20).map(|i| format!("{i}\n")).collect::<String>();
+		let new = (1..=20)
+			.filter(|i| *i != 2)
+			.map(|i| {
+				if i == 18 {
+					"x\n".to_string()
+				} else {
+					format!("{i}\n")
+				}
+			})
+			.collect::<String>();
+		assert_eq!(
+			unified_diff(&old, &new, &DiffOptions {
+				context: 1,
+				..Default::default()
+			}),
+			"--- a\n+++ b\n@@ -1,3 +1,2 @@\n 1\n-2\n 3\n@@ -17,3 +16,3 @@\n 17\n\
+			 -18\n+x\n 19\n"
+		);
+	}
+
+	#[test]
+	fn test_myers() {
+		for (old, new, changes) in [
+			("abcabba", "cbabac", 5),
+			("", "abc", 3),
+			("abc", "", 3),
+			("abc", "abc", 0),
+			("ab", "ba", 2),
+		] {
+			let chars = |s: &'static str| {
+				(0..s.len()).map(|i| &s[i..=i]).collect::<Vec<_>>()
+			};
+			let (old, new) = (chars(old), chars(new));
+			let script = myers(&old, &new);
+			let (mut i, mut j) = (0, 0);
+			for (op, old_index, new_index) in &script {
+				assert_eq!((*old_index, *new_index), (i, j));
+				match op {
+					Op::Equal => {
+						assert_eq!(old[i], new[j]);
+						i += 1;
+						j += 1;
+					}
+					Op::Delete => i += 1,
+					Op::Insert => j += 1,
+				}
+			}
+			assert_eq!((i, j), (old.len(), new.len()));
+			assert_eq!(
+				script.iter().filter(|(op, ..)| *op != Op::Equal).count(),
+				changes
+			);
+		}
+	}
+}
@@ -9,47 +9,253 @@
 //! It basically just allows to add, rewrite and remove existing variable
 //! definitions.
 
+use std::{
+	borrow::Cow,
+	collections::{BTreeMap, HashMap, HashSet},
+	ops::Range,
+	sync::Arc,
+};
+
+use thiserror::Error;
+
 use super::{
+	ApmlContext,
 	ast::{self, AstNode},
+	comment::{DEFAULT_PREFIX, Directive},
+	diff::{DiffOptions, unified_diff},
 	lst::{self, ApmlLst},
+	pattern::BashPattern,
+	tokenizer::Span,
+	verify::{RoundTripError, assert_round_trip},
 };
 
+/// Runs the body of a mutating editor method and checks the invariants
+/// of the LST afterwards, see [`ApmlEditor::validate`].
+///
+/// The body is run in a closure, so early returns are supported.
+/// Invariants are only checked when the outermost checked method
+/// returns, so nested calls and loops do not check repeatedly.
+macro_rules! checked {
+	($self:ident, $method:literal, $body:block) => {{
+		$self.depth += 1;
+		#[allow(clippy::redundant_closure_call)]
+		let result = (|| $body)();
+		$self.depth -= 1;
+		if $self.depth == 0 {
+			$self.check_invariants($method);
+		}
+		result
+	}};
+}
+
 #[derive(Debug)]
-#[repr(transparent)]
-pub struct ApmlEditor<'a, 'b>(&'a mut ApmlLst<'b>);
+pub struct ApmlEditor<'a, 'b> {
+	lst: &'a mut ApmlLst<'b>,
+	/// Whether the LST may have been modified since the last save.
+	dirty: bool,
+	/// Tokens of the LST before the first mutation, for diffing.
+	///
+	/// This is captured lazily, so read-only editors copy nothing.
+	original: Option<Vec<lst::Token<'b>>>,
+	/// Nesting depth of running `checked!` methods.
+	depth: usize,
+}
 
 impl<'b> AsRef<ApmlLst<'b>> for ApmlEditor<'_, 'b> {
 	fn as_ref(&self) -> &ApmlLst<'b> {
-		self.0
+		self.lst
 	}
 }
 
 impl<'a, 'b> ApmlEditor<'a, 'b> {
 	/// Wraps the given LST with editing API.
 	pub fn wrap(lst: &'a mut ApmlLst<'b>) -> Self {
-		Self(lst)
+		Self {
+			lst,
+			dirty: false,
+			original: None,
+			depth: 0,
+		}
 	}
 
 	// Unwraps the LST from the editing API.
 	// pub fn unwrap(self) -> ApmlLst<'a> {
 	// 	self.0
 	// }
+
+	/// Returns if any mutating operation has been called since the
+	/// editor is created or [`mark_clean`][Self::mark_clean] is called.
+	pub fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	/// Clears the dirty flag, for example, after the LST is saved.
+	pub fn mark_clean(&mut self) {
+		self.dirty = false;
+	}
+
+	/// Produces a unified diff from the content when the editor is
+	/// created to the current content.
+	///
+	/// Returns an empty string if nothing is changed.
+	pub fn diff(&self) -> String {
+		self.diff_with(&DiffOptions::default())
+	}
+
+	/// Produces a unified diff with the given options.
+	/// See [`diff`][Self::diff].
+	pub fn diff_with(&self, options: &DiffOptions) -> String {
+		match &self.original {
+			Some(original) if *original != self.lst.0 => unified_diff(
+				&original.iter().map(ToString::to_string).collect::<String>(),
+				&self.lst.to_string(),
+				options,
+			),
+			_ => String::new(),
+		}
+	}
 }
 
 impl<'b> ApmlEditor<'_, 'b> {
+	/// Takes a snapshot of the LST, which can be restored later with
+	/// [`restore`][Self::restore].
+	///
+	/// All tokens are cloned. Texts of values are reference-counted and
+	/// shared with the LST, but token lists of arrays and owned strings,
+	/// like edited comments and names, are copied.
+	pub fn checkpoint(&self) -> Checkpoint<'b> {
+		Checkpoint(self.lst.0.clone())
+	}
+
+	/// Restores the LST to a snapshot.
+	///
+	/// The editor is marked as dirty only if the LST is changed.
+	pub fn restore(&mut self, checkpoint: Checkpoint<'b>) {
+		if self.lst.0 != checkpoint.0 {
+			*self.lst_tokens_mut() = checkpoint.0;
+		}
+	}
+
+	/// Runs edits in a transaction.
+	///
+	/// After the closure returns, the invariants of the result are checked
+	/// with [`validate`][Self::validate], and the serialized LST is
+	/// parsed again, see [`assert_round_trip`]. If the closure fails or
+	/// the result is invalid, the LST and the dirty flag are restored to
+	/// the state before the transaction.
+	///
+	/// Transactions can be nested, where a failed inner transaction only
+	/// rolls back its own edits.
+	pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+	where
+		F: FnOnce(&mut Self) -> Result<T, E>,
+		E: From<EditError>,
+	{
+		let checkpoint = self.checkpoint();
+		let dirty = self.dirty;
+		let result = f(self).and_then(|value| {
+			self.validate().map_err(EditError::Invariant)?;
+			assert_round_trip(&self.lst.to_string())
+				.map_err(|err| EditError::InvalidResult(err).into())
+				.map(|_| value)
+		});
+		if result.is_err() {
+			self.restore(checkpoint);
+			self.dirty = dirty;
+		}
+		result
+	}
+
+	/// Checks the invariants of the LST.
+	///
+	/// This checks that:
+	/// - Comments are terminated by newlines.
+	/// - Variable definitions are separated by spaces or newlines.
+	/// - The serialized LST is parsed into the same tokens, compared by
+	///   their serialized forms, since the inner structure of lowered
+	///   values may differ from parsed ones.
+	///   This is skipped if there are unparsable tokens.
+	///
+	/// Mutating methods check the invariants automatically in debug
+	/// builds or with the `paranoid` feature enabled, but modifications
+	/// made with [`lst_tokens_mut`][Self::lst_tokens_mut] are not checked.
+	pub fn validate(&self) -> Result<(), InvariantError> {
+		let tokens = &self.lst.0;
+		for (index, pair) in tokens.windows(2).enumerate() {
+			match pair {
+				[lst::Token::Comment(_), next]
+					if !matches!(next, lst::Token::Newline) =>
+				{
+					return Err(InvariantError::UnterminatedComment(index));
+				}
+				[lst::Token::Variable(_), lst::Token::Variable(_)] => {
+					return Err(InvariantError::AdjacentDefinitions(index + 1));
+				}
+				_ => {}
+			}
+		}
+		if tokens
+			.iter()
+			.any(|token| matches!(token, lst::Token::Unknown(_)))
+		{
+			return Ok(());
+		}
+		let src = self.lst.to_string();
+		match ApmlLst::parse(&src) {
+			Ok(reparsed) => {
+				let index = reparsed
+					.0
+					.iter()
+					.zip(tokens)
+					.position(|(a, b)| a.to_string() != b.to_string());
+				if let Some(index) = index {
+					return Err(InvariantError::Reparsed(index));
+				} else if reparsed.0.len() != tokens.len() {
+					return Err(InvariantError::Reparsed(
+						reparsed.0.len().min(tokens.len()),
+					));
+				}
+			}
+			Err(err) => {
+				let index = self.lst.token_at_offset(err.span.start);
+				return Err(InvariantError::Unparsable(
+					index.unwrap_or(tokens.len()),
+				));
+			}
+		}
+		Ok(())
+	}
+
+	/// Panics if the invariants are violated, in debug builds or with
+	/// the `paranoid` feature enabled.
+	fn check_invariants(&self, method: &str) {
+		if !cfg!(any(debug_assertions, feature = "paranoid")) {
+			return;
+		}
+		if let Err(err) = self.validate() {
+			panic!("invariant violated after ApmlEditor::{method}: {err}");
+		}
+	}
+
 	/// Returns a [Vec] including all LST tokens.
 	pub fn lst_tokens(&mut self) -> &Vec<lst::Token<'b>> {
-		&self.0.0
+		&self.lst.0
 	}
 
 	/// Iterates over all LST tokens.
 	pub fn lst_tokens_iter(&self) -> impl Iterator<Item = &lst::Token<'b>> {
-		self.0.0.iter()
+		self.lst.0.iter()
 	}
 
 	/// Returns a [Vec] including all LST tokens.
+	///
+	/// This marks the editor as dirty.
 	pub fn lst_tokens_mut(&mut self) -> &mut Vec<lst::Token<'b>> {
-		&mut self.0.0
+		self.dirty = true;
+		if self.original.is_none() {
+			self.original = Some(self.lst.0.clone());
+		}
+		&mut self.lst.0
 	}
 
 	/// Iterates over all variable definitions in LST form.
@@ -65,6 +271,21 @@ impl<'b> ApmlEditor<'_, 'b> {
 		})
 	}
 
+	/// Iterates over all variable definitions in LST form mutably.
+	///
+	/// This marks the editor as dirty.
+	pub fn lst_variables_mut(
+		&mut self,
+	) -> impl Iterator<Item = &mut lst::VariableDefinition<'b>> {
+		self.lst_tokens_mut().iter_mut().filter_map(|token| {
+			if let lst::Token::Variable(var) = token {
+				Some(var)
+			} else {
+				None
+			}
+		})
+	}
+
 	/// Iterates over all variables definitions in AST form.
 	pub fn ast_variables(
 		&self,
@@ -74,12 +295,104 @@ impl<'b> ApmlEditor<'_, 'b> {
 			.collect()
 	}
 
+	/// Returns glob patterns used in all variable definitions with names
+	/// of the variables. See [`ast::glob_patterns`].
+	pub fn glob_patterns(
+		&self,
+	) -> ast::EmitResult<Vec<(&str, Arc<BashPattern<'b>>)>> {
+		let mut result = Vec::new();
+		for def in self.lst_variables() {
+			let value = ast::VariableDefinition::emit_from(def)?.value;
+			let mut patterns = Vec::new();
+			ast::visit_glob_patterns(&value, &mut patterns);
+			result.extend(
+				patterns
+					.into_iter()
+					.map(|pattern| (def.name.as_ref(), pattern.clone())),
+			);
+		}
+		Ok(result)
+	}
+
+	/// Collects variables into a map sorted by names, in AST form.
+	///
+	/// Later definitions overwrite earlier ones. Note that appending
+	/// definitions are desugared, see [`ast::VariableDefinition`].
+	pub fn to_btreemap(
+		&self,
+	) -> ast::EmitResult<BTreeMap<String, ast::VariableValue<'b>>> {
+		let mut result = BTreeMap::new();
+		for def in self.lst_variables() {
+			let def = ast::VariableDefinition::emit_from(def)?;
+			result.insert(def.name.into_owned(), def.value);
+		}
+		Ok(result)
+	}
+
 	/// Iterates over all variable definition keys.
 	pub fn keys(&self) -> impl Iterator<Item = &str> {
 		self.lst_variables().map(|var| var.name.as_ref())
 	}
 
-	/// Finds a variable definition and its index.
+	/// Iterates over variable definitions with names starting with
+	/// the prefix, in order of occurrence.
+	pub fn vars_with_prefix<'s>(
+		&'s self,
+		prefix: &'s str,
+	) -> impl Iterator<Item = &'s lst::VariableDefinition<'b>> {
+		self.lst_variables()
+			.filter(move |var| var.name.starts_with(prefix))
+	}
+
+	/// Iterates over variable definition keys starting with the prefix.
+	/// See [`vars_with_prefix`][Self::vars_with_prefix].
+	pub fn keys_with_prefix<'s>(
+		&'s self,
+		prefix: &'s str,
+	) -> impl Iterator<Item = &'s str> {
+		self.vars_with_prefix(prefix).map(|var| var.name.as_ref())
+	}
+
+	/// Finds the index of the newline ending the line of a token,
+	/// or the length of tokens if it is the last line.
+	fn line_end(&self, index: usize) -> usize {
+		self.lst_tokens_iter()
+			.skip(index)
+			.position(|token| matches!(token, lst::Token::Newline))
+			.map(|pos| index + pos)
+			.unwrap_or(self.as_ref().0.len())
+	}
+
+	/// Inserts tokens after the line of a token.
+	///
+	/// The tokens should end with a newline.
+	fn insert_after_line(&mut self, index: usize, tokens: Vec<lst::Token<'b>>) {
+		if tokens.is_empty() {
+			return;
+		}
+		let end = self.line_end(index);
+		if end == self.as_ref().0.len() {
+			self.lst_tokens_mut().push(lst::Token::Newline);
+		}
+		self.lst_tokens_mut().splice(end + 1..end + 1, tokens);
+	}
+
+	/// Finds indexes of all definitions of a variable.
+	fn find_var_indexes(&self, name: &str) -> Vec<usize> {
+		self.lst_tokens_iter()
+			.enumerate()
+			.filter_map(|(idx, token)| match token {
+				lst::Token::Variable(var) if var.name == name => Some(idx),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Finds the first definition of a variable and its index.
+	///
+	/// See [`find_var_effective`][Self::find_var_effective] for the
+	/// definition effective in Bash.
+	#[must_use]
 	pub fn find_var<S: AsRef<str>>(
 		&self,
 		name: S,
@@ -97,7 +410,176 @@ impl<'b> ApmlEditor<'_, 'b> {
 		})
 	}
 
+	/// Finds the last definition of a variable, which is effective in
+	/// Bash, and its index.
+	#[must_use]
+	pub fn find_var_effective<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> Option<(usize, &lst::VariableDefinition<'b>)> {
+		self.find_vars(name).pop()
+	}
+
+	/// Finds all definitions of a variable and their indexes, in order
+	/// of occurrence.
+	#[must_use]
+	pub fn find_vars<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> Vec<(usize, &lst::VariableDefinition<'b>)> {
+		self.lst_tokens_iter()
+			.enumerate()
+			.filter_map(|(idx, token)| match token {
+				lst::Token::Variable(var) if var.name == name.as_ref() => {
+					Some((idx, var))
+				}
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Builds a map from variable names to indexes of all their
+	/// definitions, in order of occurrence.
+	///
+	/// This scans the LST once, so it is cheaper than calling
+	/// [`find_vars`][Self::find_vars] for many variables.
+	/// Indexes are invalidated after modifications.
+	#[must_use]
+	pub fn var_indexes(&self) -> HashMap<&str, Vec<usize>> {
+		let mut result = HashMap::<&str, Vec<usize>>::new();
+		for (idx, token) in self.lst_tokens_iter().enumerate() {
+			if let lst::Token::Variable(var) = token {
+				result.entry(var.name.as_ref()).or_default().push(idx);
+			}
+		}
+		result
+	}
+
+	/// Finds the definition of a variable for a architecture.
+	///
+	/// The architecture-specific definition (see [`arch_var_name`]) is
+	/// returned if present, or else the definition of the base variable.
+	/// The last definitions are used, see
+	/// [`find_var_effective`][Self::find_var_effective].
+	#[must_use]
+	pub fn get_var_for_arch(
+		&self,
+		base: &str,
+		arch: &str,
+	) -> Option<(usize, &lst::VariableDefinition<'b>)> {
+		self.find_var_effective(arch_var_name(base, arch))
+			.or_else(|| self.find_var_effective(base))
+	}
+
+	/// Lists architectures overriding a variable, in order of their
+	/// first definitions.
+	///
+	/// Suffixes are converted back to lowercase with underscores mapped
+	/// to hyphens, see [`arch_var_name`].
+	#[must_use]
+	pub fn arch_variants_of(&self, base: &str) -> Vec<String> {
+		let prefix = format!("{base}__");
+		let mut result = Vec::<String>::new();
+		for key in self.keys_with_prefix(&prefix) {
+			let arch =
+				key[prefix.len()..].to_ascii_lowercase().replace('_', "-");
+			if !arch.is_empty() && !result.contains(&arch) {
+				result.push(arch);
+			}
+		}
+		result
+	}
+
+	/// Gets the value of a variable in LST form.
+	///
+	/// If the variable is defined multiple times, the last definition is
+	/// used, like Bash. See [`get_all_var_lst`][Self::get_all_var_lst]
+	/// for all definitions.
+	#[must_use]
+	pub fn get_var_lst<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> Option<&lst::VariableValue<'b>> {
+		self.lst_variables()
+			.filter(|var| var.name == name.as_ref())
+			.last()
+			.map(|var| &var.value)
+	}
+
+	/// Gets the values of all definitions of a variable in LST form.
+	#[must_use]
+	pub fn get_all_var_lst<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> Vec<&lst::VariableValue<'b>> {
+		self.lst_variables()
+			.filter(|var| var.name == name.as_ref())
+			.map(|var| &var.value)
+			.collect()
+	}
+
+	/// Gets the value of a variable in AST form.
+	///
+	/// If the variable is defined multiple times, the last assignment is
+	/// used, like Bash, and following appending definitions are folded
+	/// into it. For example, `a=1` followed by `a+=2` gives `12`.
+	///
+	/// Appending definitions which cannot be folded are kept desugared,
+	/// see [`ast::VariableDefinition`]. This happens if there is no
+	/// assignment before them, if they reference the variable itself,
+	/// or if a string is appended to a array.
+	#[must_use]
+	pub fn get_var_ast<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> Option<ast::EmitResult<ast::VariableValue<'b>>> {
+		let name = name.as_ref();
+		let mut result = None;
+		for var in self.lst_variables().filter(|var| var.name == name) {
+			let value = match ast::VariableDefinition::emit_from(var) {
+				Ok(def) => def.value,
+				Err(err) => return Some(Err(err)),
+			};
+			result = Some(match (&var.op, result) {
+				(lst::VariableOp::Append, Some(prev)) => {
+					fold_append(name, prev, value)
+				}
+				_ => value,
+			});
+		}
+		result.map(Ok)
+	}
+
+	/// Gets the values of all definitions of a variable in AST form.
+	pub fn get_all_var_ast<S: AsRef<str>>(
+		&self,
+		name: S,
+	) -> ast::EmitResult<Vec<ast::VariableValue<'b>>> {
+		self.lst_variables()
+			.filter(|var| var.name == name.as_ref())
+			.map(|var| {
+				ast::VariableDefinition::emit_from(var).map(|def| def.value)
+			})
+			.collect()
+	}
+
+	/// Finds the first LST token matching the predicate for mutation.
+	///
+	/// See [`ApmlLst::find_token`]. The editor is marked as dirty only
+	/// if a token is found.
+	pub fn find_token_mut<F>(
+		&mut self,
+		predicate: F,
+	) -> Option<&mut lst::Token<'b>>
+	where
+		F: Fn(&lst::Token<'b>) -> bool,
+	{
+		let index = self.lst_tokens_iter().position(predicate)?;
+		Some(&mut self.lst_tokens_mut()[index])
+	}
+
 	/// Finds a variable definition's index.
+	#[must_use]
 	pub fn find_var_index<S: AsRef<str>>(&self, name: S) -> Option<usize> {
 		self.lst_tokens_iter().enumerate().find_map(|(idx, token)| {
 			if let lst::Token::Variable(var) = token {
@@ -113,6 +595,7 @@ impl<'b> ApmlEditor<'_, 'b> {
 	}
 
 	/// Finds a variable definition token and its index.
+	#[must_use]
 	pub fn find_var_token<S: AsRef<str>>(
 		&self,
 		name: S,
@@ -127,12 +610,52 @@ impl<'b> ApmlEditor<'_, 'b> {
 		})
 	}
 
+	/// Sets the architecture-specific definition of a variable.
+	///
+	/// The existing definition is replaced like
+	/// [`replace_var_ast`][Self::replace_var_ast]. New definitions are
+	/// placed after the line of the first definition of the base
+	/// variable, or at the end if the base variable is not defined.
+	pub fn set_var_for_arch(
+		&mut self,
+		base: &str,
+		arch: &str,
+		value: &ast::VariableValue<'b>,
+	) {
+		checked!(self, "set_var_for_arch", {
+			let name = arch_var_name(base, arch);
+			debug_assert_valid(&name, value);
+			match self.find_var_effective(&name) {
+				Some((index, def)) => {
+					let token = lst::Token::Variable(lst::VariableDefinition {
+						value: value.lower(),
+						..def.clone()
+					});
+					self.lst_tokens_mut()[index] = token;
+				}
+				None => {
+					let token = lst::Token::Variable(lst::VariableDefinition {
+						declaration: None,
+						name: name.into(),
+						op: lst::VariableOp::Assignment,
+						value: value.lower(),
+					});
+					self.insert_lines(vec![token], Some(base));
+				}
+			}
+		})
+	}
+
 	/// Ensures there is a newline after the text.
 	pub fn ensure_end_newline(&mut self) {
-		if !matches!(self.lst_tokens().last(), None | Some(lst::Token::Newline))
-		{
-			self.lst_tokens_mut().push(lst::Token::Newline);
-		}
+		checked!(self, "ensure_end_newline", {
+			if !matches!(
+				self.lst_tokens().last(),
+				None | Some(lst::Token::Newline)
+			) {
+				self.lst_tokens_mut().push(lst::Token::Newline);
+			}
+		})
 	}
 
 	/// Appends a new variable assignment definition.
@@ -142,12 +665,87 @@ impl<'b> ApmlEditor<'_, 'b> {
 		value: &ast::VariableValue<'b>,
 		after: Option<&str>,
 	) {
-		let definition = lst::VariableDefinition {
-			name: name.into(),
-			op: lst::VariableOp::Assignment,
-			value: value.lower(),
-		};
-		let token = lst::Token::Variable(definition);
+		checked!(self, "append_var_ast", {
+			debug_assert_valid(name, value);
+			let definition = lst::VariableDefinition {
+				declaration: None,
+				name: name.into(),
+				op: lst::VariableOp::Assignment,
+				value: value.lower(),
+			};
+			self.insert_lines(vec![lst::Token::Variable(definition)], after);
+		})
+	}
+
+	/// Appends a new variable assignment definition with a comment line
+	/// above it.
+	///
+	/// The comment is normalized to `# comment`. If the comment is empty,
+	/// no comment line is inserted.
+	/// See [`append_var_ast`][Self::append_var_ast].
+	pub fn add_var_with_comment(
+		&mut self,
+		name: &'b str,
+		value: &ast::VariableValue<'b>,
+		comment: &str,
+		after: Option<&str>,
+	) {
+		let comment = comment.trim();
+		self.append_var(AppendSpec {
+			comments: if comment.is_empty() {
+				vec![]
+			} else {
+				vec![comment]
+			},
+			after,
+			..AppendSpec::new(name, value.clone())
+		});
+	}
+
+	/// Appends a new variable definition described by a [`AppendSpec`].
+	///
+	/// The definition is placed in the same way as
+	/// [`append_var_ast`][Self::append_var_ast], with comment lines above
+	/// it and the inline comment following it.
+	/// Comments are normalized to `# comment`.
+	pub fn append_var(&mut self, spec: AppendSpec<'b, '_>) {
+		checked!(self, "append_var", {
+			debug_assert_valid(spec.name, &spec.value);
+			let comment = |text: &str| {
+				let text = text.trim();
+				debug_assert!(!text.contains('\n'));
+				lst::Token::Comment(if text.is_empty() {
+					Cow::Borrowed("")
+				} else {
+					Cow::Owned(format!(" {text}"))
+				})
+			};
+			let mut tokens = Vec::with_capacity(spec.comments.len() * 2 + 3);
+			for text in &spec.comments {
+				tokens.push(comment(text));
+				tokens.push(lst::Token::Newline);
+			}
+			tokens.push(lst::Token::Variable(lst::VariableDefinition {
+				declaration: None,
+				name: spec.name.into(),
+				op: spec.op,
+				value: spec.value.lower(),
+			}));
+			if let Some(text) = spec.inline_comment {
+				tokens.push(lst::Token::Spacy(' '));
+				tokens.push(comment(text));
+			}
+			self.insert_lines(tokens, spec.after);
+		})
+	}
+
+	/// Inserts tokens followed by a newline after the line of a variable,
+	/// or at the end if the variable is not found.
+	fn insert_lines(
+		&mut self,
+		tokens: Vec<lst::Token<'b>>,
+		after: Option<&str>,
+	) {
 		if let Some(after) = after {
 			if let Some((index, _)) = self.find_var(after) {
 				let after = self
@@ -157,185 +755,4637 @@ impl<'b> ApmlEditor<'_, 'b> {
 					.count();
 				let index = index + after + 1;
 				if index <= self.lst_tokens().len() {
-					self.lst_tokens_mut().insert(index, lst::Token::Newline);
-					self.lst_tokens_mut().insert(index, token);
+					self.lst_tokens_mut().splice(
+						index..index,
+						tokens.into_iter().chain([lst::Token::Newline]),
+					);
 					return;
 				}
 			}
 		}
 		self.ensure_end_newline();
-		self.lst_tokens_mut().push(token);
+		self.lst_tokens_mut().extend(tokens);
 		self.lst_tokens_mut().push(lst::Token::Newline);
 	}
 
 	/// Replace a variable definition.
+	///
+	/// If the variable is defined multiple times, the last definition is
+	/// replaced, see [`find_var_effective`][Self::find_var_effective].
+	/// The operator of the existing definition is kept, so a `+=`
+	/// definition stays appending. New definitions are assignments.
 	pub fn replace_var_ast(
 		&mut self,
 		name: &'b str,
 		value: &ast::VariableValue<'b>,
 	) {
+		debug_assert_valid(name, value);
 		self.replace_var_lst(name, value.lower())
 	}
 
+	/// Replace a variable definition with the given operator.
+	pub fn replace_var_with_op(
+		&mut self,
+		name: &'b str,
+		op: lst::VariableOp,
+		value: &ast::VariableValue<'b>,
+	) {
+		checked!(self, "replace_var_with_op", {
+			debug_assert_valid(name, value);
+			self.replace_var_lst_with_op(name, op, value.lower())
+		})
+	}
+
 	/// Replace a variable definition.
+	///
+	/// The operator of the existing definition is kept.
+	/// See [`replace_var_ast`][Self::replace_var_ast].
 	pub fn replace_var_lst(
 		&mut self,
 		name: &'b str,
 		value: lst::VariableValue<'b>,
 	) {
-		let definition = lst::VariableDefinition {
-			name: name.into(),
-			op: lst::VariableOp::Assignment,
-			value,
-		};
-		let token = lst::Token::Variable(definition);
-		if let Some((index, _)) = self.find_var(name) {
+		checked!(self, "replace_var_lst", {
+			let op = self
+				.find_var_effective(name)
+				.map(|(_, def)| def.op.clone())
+				.unwrap_or(lst::VariableOp::Assignment);
+			self.replace_var_lst_with_op(name, op, value)
+		})
+	}
+
+	/// Replace a variable definition, keeping the quoting style of the
+	/// existing value where possible.
+	///
+	/// If the existing value is a string made up of only unquoted,
+	/// only single-quoted or only double-quoted texts, a new literal
+	/// string value is lowered in the same style. Styles that cannot
+	/// represent the new value fall back to quoting, for example
+	/// unquoted values gain double quotes if the new value contains
+	/// spaces. See [`ast::LowerStyle`].
+	/// Line continuations in the existing value are not kept.
+	///
+	/// Other values are lowered like
+	/// [`replace_var_ast`][Self::replace_var_ast].
+	pub fn replace_var_preserving_style(
+		&mut self,
+		name: &'b str,
+		value: &ast::VariableValue<'b>,
+	) {
+		checked!(self, "replace_var_preserving_style", {
+			debug_assert_valid(name, value);
+			let style = self.find_var_effective(name).and_then(|(_, def)| {
+				match &def.value {
+					lst::VariableValue::String(text) => quoting_style(text),
+					lst::VariableValue::Array(_) => None,
+				}
+			});
+			let literal = match value {
+				ast::VariableValue::String(text) => text.literal_value(),
+				ast::VariableValue::Array(_) => None,
+			};
+			let value = match style.zip(literal) {
+				Some((style, literal)) => lst::VariableValue::String(Arc::new(
+					style.lower_str(&literal),
+				)),
+				None => value.lower(),
+			};
+			self.replace_var_lst(name, value)
+		})
+	}
+
+	fn replace_var_lst_with_op(
+		&mut self,
+		name: &'b str,
+		op: lst::VariableOp,
+		value: lst::VariableValue<'b>,
+	) {
+		// declaration keywords are kept, like `set_var_for_arch`
+		if let Some((index, def)) = self.find_var_effective(name) {
+			let token = lst::Token::Variable(lst::VariableDefinition {
+				op,
+				value,
+				..def.clone()
+			});
 			self.lst_tokens_mut()[index] = token;
 			return;
 		}
+		let token = lst::Token::Variable(lst::VariableDefinition {
+			declaration: None,
+			name: name.into(),
+			op,
+			value,
+		});
 		self.ensure_end_newline();
 		self.lst_tokens_mut().push(token);
 		self.lst_tokens_mut().push(lst::Token::Newline);
 	}
 
-	/// Removes a variable definition.
+	/// Replaces a variable definition with a literal string value,
+	/// or appends one if missing.
 	///
-	/// The given index must points to a variable definition token.
-	/// After a removal, all indexes are invalidated.
+	/// The value is left unquoted when possible, and double-quoted
+	/// otherwise. See [`ast::LowerStyle::Auto`].
+	pub fn set_var_string(&mut self, name: &'b str, value: &str) {
+		self.set_var_string_with(name, value, &ast::LowerStyle::Auto)
+	}
+
+	/// Replaces a variable definition with a literal string value in
+	/// the given quoting style, or appends one if missing.
+	pub fn set_var_string_with(
+		&mut self,
+		name: &'b str,
+		value: &str,
+		style: &ast::LowerStyle,
+	) {
+		self.replace_var_lst(
+			name,
+			lst::VariableValue::String(Arc::new(style.lower_str(value))),
+		)
+	}
+
+	/// Converts the quoting style of the effective definition of a
+	/// string variable, keeping its evaluated value unchanged.
 	///
-	/// Spaces and comments before the most near following newline will
-	/// be stripped. If there is comment before the line of variable definition
-	/// and the variable definition has been followed by two newlines,
-	/// All preceding comments will be stripped as well.
-	pub fn remove_var(&mut self, index: usize) {
-		// scan of following spaces and newline
-		let after = self
-			.lst_tokens_iter()
-			.skip(index)
-			.take_while(|token| !matches!(token, lst::Token::Newline))
-			.count();
-		let mut start = index;
-		let tokens = self.lst_tokens();
-		if matches!(tokens.get(index - 2), Some(lst::Token::Comment(_))) {
-			// scan for next line
-			if !tokens
-				.iter()
-				.skip(index)
-				.skip_while(|token| !matches!(token, lst::Token::Newline))
-				.skip(1)
-				.take_while(|token| !matches!(token, lst::Token::Newline))
-				.any(|token| matches!(token, lst::Token::Variable(_)))
+	/// The converted value is made up of a single text unit.
+	/// Values containing expansions cannot be single-quoted, and values
+	/// which need quoting (see [`ast::quote_analysis`]) for reasons other
+	/// than expansions cannot be unquoted.
+	pub fn requote_var(
+		&mut self,
+		name: &str,
+		style: QuoteStyle,
+	) -> Result<(), RequoteError> {
+		checked!(self, "requote_var", {
+			let Some((index, def)) = self.find_var_effective(name) else {
+				return Err(RequoteError::Undefined(name.to_string()));
+			};
+			let lst::VariableValue::String(text) = &def.value else {
+				return Err(RequoteError::NotString(name.to_string()));
+			};
+			let text = requote_text(name, &ast::Text::emit_from(text)?, style)?;
+			let lst::Token::Variable(def) = &self.as_ref().0[index] else {
+				unreachable!()
+			};
+			if !matches!(&def.value, lst::VariableValue::String(old) if **old == text)
 			{
-				// next line is empty, scan for removable comments
-				while matches!(tokens.get(start - 1), Some(lst::Token::Newline))
-					&& matches!(
-						tokens.get(start - 2),
-						Some(lst::Token::Comment(_))
-					) && matches!(
-					tokens.get(start - 3),
-					Some(lst::Token::Newline)
-				) {
-					start -= 2;
-				}
+				let lst::Token::Variable(def) =
+					&mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				def.value = lst::VariableValue::String(Arc::new(text));
 			}
-		}
-		self.lst_tokens_mut().drain(start..=(index + after));
+			Ok(())
+		})
 	}
 
-	/// Iterates over all comment lines.
-	pub fn comments(&self) -> impl Iterator<Item = &str> {
-		self.lst_tokens_iter().filter_map(|token| {
-			if let lst::Token::Comment(var) = token {
-				Some(var.as_ref())
-			} else {
-				None
+	/// Rewrites all string values and array elements to a canonical
+	/// quoting style, keeping their evaluated values unchanged.
+	///
+	/// Literals needing no quotes are unquoted, literals containing `$`
+	/// or `` ` `` but no `'` are single-quoted, and all other texts are
+	/// double-quoted. Expansions are written in the braced form.
+	/// Texts with line continuations are kept as-is, and so are array
+	/// elements with unquoted expansions or glob characters, since Bash
+	/// splits and expands them into multiple elements.
+	///
+	/// Returns the number of rewritten texts.
+	pub fn canonicalize_quoting(&mut self) -> usize {
+		checked!(self, "canonicalize_quoting", {
+			let canonical = |text: &lst::Text<'b>, element: bool| {
+				if has_line_continuation(text)
+					|| (element && has_unquoted_expansion(text))
+				{
+					return None;
+				}
+				let text_ast = ast::Text::emit_from(text).ok()?;
+				let style = canonical_quote_style(&text_ast);
+				let result = requote_text("", &text_ast, style).ok()?;
+				(result != *text).then_some(Arc::new(result))
+			};
+			let mut changes = Vec::new();
+			let mut count = 0;
+			for (index, token) in self.lst_tokens_iter().enumerate() {
+				let lst::Token::Variable(def) = token else {
+					continue;
+				};
+				let mut value = def.value.clone();
+				let texts = match &mut value {
+					lst::VariableValue::String(text) => vec![(text, false)],
+					lst::VariableValue::Array(tokens) => tokens
+						.iter_mut()
+						.filter_map(|token| match token {
+							lst::ArrayToken::Element(text) => {
+								Some((text, true))
+							}
+							_ => None,
+						})
+						.collect(),
+				};
+				let mut changed = false;
+				for (text, element) in texts {
+					let Some(result) = canonical(text, element) else {
+						continue;
+					};
+					*text = result;
+					changed = true;
+					count += 1;
+				}
+				if changed {
+					changes.push((index, value));
+				}
+			}
+			for (index, value) in changes {
+				let lst::Token::Variable(def) =
+					&mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				def.value = value;
 			}
+			count
 		})
 	}
+
+	/// Appends a word to the value of a string variable, separated
+	/// with a space.
+	///
+	/// The word is appended to the last definition, inside the closing
+	/// quote of the final text unit if it is quoted. Existing quoting,
+	/// expansions and line continuations are kept untouched.
+	/// The variable is created if it is not defined yet.
+	///
+	/// With `dedupe`, nothing is changed if the word is already present
+	/// in the evaluated value, split by whitespace. If the definitions up
+	/// to the last one cannot be evaluated, the word is only compared with
+	/// whole literal words of the last definition.
+	///
+	/// Returns if the word is appended. If the variable is an array,
+	/// an error is returned.
+	pub fn append_word(
+		&mut self,
+		name: &'b str,
+		word: &str,
+		dedupe: bool,
+	) -> Result<bool, EditError> {
+		checked!(self, "append_word", {
+			let Some(index) = self.find_var_indexes(name).last().copied()
+			else {
+				self.set_var_string(name, word);
+				return Ok(true);
+			};
+			let lst::Token::Variable(def) = &self.as_ref().0[index] else {
+				unreachable!()
+			};
+			let lst::VariableValue::String(text) = &def.value else {
+				return Err(EditError::NotString(name.to_string()));
+			};
+			if dedupe {
+				// later definitions cannot change the value
+				let prefix = ApmlLst(self.as_ref().0[..=index].to_vec());
+				let present = match ApmlContext::eval_lst(&prefix) {
+					Ok(context) => context.get(name).is_some_and(|value| {
+						value
+							.as_string()
+							.split_ascii_whitespace()
+							.any(|token| token == word)
+					}),
+					Err(_) => split_text_words(
+						&text.chars_with_origin().collect::<Vec<_>>(),
+					)
+					.iter()
+					.any(|text_word| {
+						text_word.literal.as_deref() == Some(word)
+					}),
+				};
+				if present {
+					return Ok(false);
+				}
+			}
+			let empty = def.op == lst::VariableOp::Assignment
+				&& text.literal_value().is_some_and(|value| value.is_empty());
+			let appended = if empty {
+				word.to_string()
+			} else {
+				format!(" {word}")
+			};
+
+			let lst::Token::Variable(def) = &mut self.lst_tokens_mut()[index]
+			else {
+				unreachable!()
+			};
+			let lst::VariableValue::String(text) = &mut def.value else {
+				unreachable!()
+			};
+			let text = Arc::make_mut(text);
+			match text.0.last_mut() {
+				Some(lst::TextUnit::DoubleQuote(words)) => words.push(
+					lst::Word::Literal(lst::LiteralPart::escape(appended)),
+				),
+				Some(lst::TextUnit::SingleQuote(string))
+					if !appended.contains('\'') =>
+				{
+					string.to_mut().push_str(&appended)
+				}
+				_ if empty => *text = ast::LowerStyle::Auto.lower_str(word),
+				_ => text.0.push(lst::TextUnit::DoubleQuote(vec![
+					lst::Word::Literal(lst::LiteralPart::escape(appended)),
+				])),
+			}
+			Ok(true)
+		})
+	}
+
+	/// Removes a word from the values of all definitions of a string
+	/// variable.
+	///
+	/// All occurrences of the word as a whole literal word are removed,
+	/// together with one adjacent separating whitespace.
+	/// Quotes are kept even if the unit becomes empty.
+	///
+	/// Returns if any word is removed. If any definition of the variable
+	/// is an array, an error is returned without any changes.
+	pub fn remove_word(
+		&mut self,
+		name: &str,
+		word: &str,
+	) -> Result<bool, EditError> {
+		checked!(self, "remove_word", {
+			let indexes = self.find_var_indexes(name);
+			let mut removals = Vec::new();
+			for index in indexes {
+				let lst::Token::Variable(def) = &self.as_ref().0[index] else {
+					unreachable!()
+				};
+				let lst::VariableValue::String(text) = &def.value else {
+					return Err(EditError::NotString(name.to_string()));
+				};
+				let chars = text.chars_with_origin().collect::<Vec<_>>();
+				let mut removed = HashSet::new();
+				for text_word in split_text_words(&chars) {
+					if text_word.literal.as_deref() != Some(word) {
+						continue;
+					}
+					let mut range = text_word.range;
+					if range.start > 0 && !removed.contains(&(range.start - 1))
+					{
+						range.start -= 1;
+					} else if range.end < chars.len() {
+						range.end += 1;
+					}
+					removed.extend(range);
+				}
+				if !removed.is_empty() {
+					removals.push((index, char_positions(&chars, &removed)));
+				}
+			}
+
+			let result = !removals.is_empty();
+			for (index, positions) in removals {
+				let lst::Token::Variable(def) =
+					&mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				let lst::VariableValue::String(text) = &mut def.value else {
+					unreachable!()
+				};
+				remove_text_chars(Arc::make_mut(text), &positions);
+			}
+			Ok(result)
+		})
+	}
+
+	/// Inserts a element into the value of a array variable.
+	///
+	/// The element is inserted into the last definition, following the
+	/// existing style of the array. Elements of single-line arrays are
+	/// separated with a space, while multi-line arrays get a new line
+	/// indented like other elements.
+	/// The variable is created if it is not defined yet.
+	///
+	/// If the variable is a string, an error is returned.
+	pub fn append_array_element(
+		&mut self,
+		name: &'b str,
+		element: &ast::Text<'b>,
+		position: ArrayPosition,
+	) -> Result<(), EditError> {
+		checked!(self, "append_array_element", {
+			let element =
+				lst::ArrayToken::Element(Arc::new(AstNode::lower(element)));
+			let Some(index) = self.find_var_indexes(name).last().copied()
+			else {
+				self.replace_var_lst(
+					name,
+					lst::VariableValue::Array(vec![element]),
+				);
+				return Ok(());
+			};
+			if !matches!(
+				&self.as_ref().0[index],
+				lst::Token::Variable(lst::VariableDefinition {
+					value: lst::VariableValue::Array(_),
+					..
+				})
+			) {
+				return Err(EditError::NotArray(name.to_string()));
+			}
+			let lst::Token::Variable(lst::VariableDefinition {
+				value: lst::VariableValue::Array(tokens),
+				..
+			}) = &mut self.lst_tokens_mut()[index]
+			else {
+				unreachable!()
+			};
+			insert_array_element(tokens, element, position);
+			Ok(())
+		})
+	}
+
+	/// Retains only the array elements matching the predicate, in all
+	/// definitions of a array variable.
+	///
+	/// Lines of removed elements are cleaned up, including indentation
+	/// and inline comments, while other elements are untouched.
+	/// Elements which cannot be emitted into AST are always retained.
+	/// Arrays without elements and comments are collapsed into `()`.
+	///
+	/// Returns the number of removed elements. If any definition of the
+	/// variable is a string, an error is returned without any changes.
+	pub fn retain_array_elements<F>(
+		&mut self,
+		name: &str,
+		mut f: F,
+	) -> Result<usize, EditError>
+	where
+		F: FnMut(&ast::Text) -> bool,
+	{
+		checked!(self, "retain_array_elements", {
+			let mut removals = Vec::new();
+			for index in self.find_var_indexes(name) {
+				let lst::Token::Variable(def) = &self.as_ref().0[index] else {
+					unreachable!()
+				};
+				let lst::VariableValue::Array(tokens) = &def.value else {
+					return Err(EditError::NotArray(name.to_string()));
+				};
+				let removed = tokens
+					.iter()
+					.enumerate()
+					.filter_map(|(idx, token)| match token {
+						lst::ArrayToken::Element(text) => Some((idx, text)),
+						_ => None,
+					})
+					.filter(|(_, text)| {
+						ast::Text::emit_from(text).is_ok_and(|text| !f(&text))
+					})
+					.map(|(idx, _)| idx)
+					.collect::<Vec<_>>();
+				if !removed.is_empty() {
+					removals.push((index, removed));
+				}
+			}
+
+			let mut result = 0;
+			for (index, removed) in removals {
+				let lst::Token::Variable(lst::VariableDefinition {
+					value: lst::VariableValue::Array(tokens),
+					..
+				}) = &mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				result += removed.len();
+				for idx in removed.into_iter().rev() {
+					remove_array_element(tokens, idx);
+				}
+				if tokens.iter().all(|token| {
+					matches!(
+						token,
+						lst::ArrayToken::Spacy(_) | lst::ArrayToken::Newline
+					)
+				}) {
+					tokens.clear();
+				}
+			}
+			Ok(result)
+		})
+	}
+
+	/// Replaces a element of a array variable.
+	///
+	/// The index counts elements of all definitions of the variable,
+	/// in order. Formatting of other elements is untouched.
+	///
+	/// If the index is out of range or any definition of the variable
+	/// is a string, an error is returned.
+	pub fn replace_array_element(
+		&mut self,
+		name: &str,
+		index: usize,
+		element: &ast::Text<'b>,
+	) -> Result<(), EditError> {
+		checked!(self, "replace_array_element", {
+			let mut target = None;
+			let mut count = 0;
+			for def_index in self.find_var_indexes(name) {
+				let lst::Token::Variable(def) = &self.as_ref().0[def_index]
+				else {
+					unreachable!()
+				};
+				let lst::VariableValue::Array(tokens) = &def.value else {
+					return Err(EditError::NotArray(name.to_string()));
+				};
+				for (idx, token) in tokens.iter().enumerate() {
+					if matches!(token, lst::ArrayToken::Element(_)) {
+						if count == index && target.is_none() {
+							target = Some((def_index, idx));
+						}
+						count += 1;
+					}
+				}
+			}
+			let Some((def_index, idx)) = target else {
+				return Err(EditError::IndexOutOfRange {
+					name: name.to_string(),
+					index,
+				});
+			};
+			let lst::Token::Variable(lst::VariableDefinition {
+				value: lst::VariableValue::Array(tokens),
+				..
+			}) = &mut self.lst_tokens_mut()[def_index]
+			else {
+				unreachable!()
+			};
+			tokens[idx] = lst::ArrayToken::Element(Arc::new(element.lower()));
+			Ok(())
+		})
+	}
+
+	/// Replaces or adds a set of variable definitions.
+	///
+	/// All names and values are validated before any modification, so
+	/// either all updates are applied, or none of them is applied.
+	/// If a name is given multiple times, the last value is used.
+	/// Missing variables are appended to the end, like
+	/// [`replace_var_ast`][Self::replace_var_ast].
+	pub fn batch_replace<I>(&mut self, updates: I) -> BatchReplaceResult<'b>
+	where
+		I: IntoIterator<Item = (&'b str, ast::VariableValue<'b>)>,
+	{
+		let mut deduped = Vec::<(&'b str, ast::VariableValue<'b>)>::new();
+		let mut indexes = HashMap::new();
+		for (name, value) in updates {
+			match indexes.get(name) {
+				Some(&index) => deduped[index] = (name, value),
+				None => {
+					indexes.insert(name, deduped.len());
+					deduped.push((name, value));
+				}
+			}
+		}
+		if let Some((name, _)) = deduped
+			.iter()
+			.find(|(name, _)| !ast::is_valid_var_name(name))
+		{
+			return Err(EditError::InvalidName(name.to_string()));
+		}
+		for (name, value) in &deduped {
+			ast::VariableDefinition {
+				name: Cow::Borrowed(name),
+				value: value.clone(),
+			}
+			.validate()
+			.map_err(|errors| EditError::InvalidValue(errors[0].clone()))?;
+		}
+		checked!(self, "batch_replace", {
+			let mut result = BatchReplacement::default();
+			for (name, value) in deduped {
+				if self.find_var_index(name).is_some() {
+					result.replaced.push(name);
+				} else {
+					result.inserted.push(name);
+				}
+				self.replace_var_ast(name, &value);
+			}
+			Ok(result)
+		})
+	}
+
+	/// Renames all definitions of a variable.
+	///
+	/// Only the names are rewritten, keeping values, operators, spaces
+	/// and comments untouched. References to the variable in values
+	/// are not renamed.
+	///
+	/// Returns if any definition is renamed. If the new name is invalid
+	/// or already defined, an error is returned without any changes.
+	pub fn rename_var(
+		&mut self,
+		old: &str,
+		new: &'b str,
+	) -> Result<bool, EditError> {
+		checked!(self, "rename_var", {
+			if !ast::is_valid_var_name(new) {
+				return Err(EditError::InvalidName(new.to_string()));
+			}
+			if old == new {
+				return Ok(self.find_var_index(old).is_some());
+			}
+			if self.find_var_index(new).is_some() {
+				return Err(EditError::DuplicateName(new.to_string()));
+			}
+			let indices = self
+				.lst_tokens_iter()
+				.enumerate()
+				.filter_map(|(idx, token)| match token {
+					lst::Token::Variable(var) if var.name == old => Some(idx),
+					_ => None,
+				})
+				.collect::<Vec<_>>();
+			for index in &indices {
+				if let lst::Token::Variable(var) =
+					&mut self.lst_tokens_mut()[*index]
+				{
+					var.name = Cow::Borrowed(new);
+				}
+			}
+			Ok(!indices.is_empty())
+		})
+	}
+
+	/// Substitutes `@key@` placeholders in all variable definitions.
+	///
+	/// See [`ast::substitute_placeholders`] for the syntax. Placeholders
+	/// are substituted in place, so that only their bytes are changed.
+	/// Values are escaped for the quoting context of each placeholder,
+	/// and values which cannot be written unquoted are double-quoted.
+	/// A placeholder must be contained in a single literal string.
+	///
+	/// Returns keys of unresolved placeholders, without duplicates.
+	pub fn instantiate_template(
+		&mut self,
+		values: &HashMap<&str, &str>,
+	) -> Vec<String> {
+		checked!(self, "instantiate_template", {
+			let mut unresolved = Vec::new();
+			let mut changes = Vec::new();
+			for (index, token) in self.lst_tokens_iter().enumerate() {
+				let lst::Token::Variable(def) = token else {
+					continue;
+				};
+				let mut value = def.value.clone();
+				let texts = match &mut value {
+					lst::VariableValue::String(text) => vec![text],
+					lst::VariableValue::Array(tokens) => tokens
+						.iter_mut()
+						.filter_map(|token| match token {
+							lst::ArrayToken::Element(text) => Some(text),
+							_ => None,
+						})
+						.collect(),
+				};
+				let mut changed = false;
+				for text in texts {
+					if let Some(result) = substitute_text_placeholders(
+						text,
+						values,
+						&mut unresolved,
+					) {
+						*text = Arc::new(result);
+						changed = true;
+					}
+				}
+				if changed {
+					changes.push((index, value));
+				}
+			}
+			for (index, value) in changes {
+				let lst::Token::Variable(def) =
+					&mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				def.value = value;
+			}
+			unresolved
+		})
+	}
+
+	/// Removes a variable definition.
+	///
+	/// The given index must points to a variable definition token.
+	/// After a removal, all indexes are invalidated.
+	///
+	/// Spaces and comments before the most near following newline will
+	/// be stripped, and so will the indentation of the definition.
+	/// If the variable definition has been followed by two
+	/// newlines, its leading comment block (see
+	/// [`leading_comments`][Self::leading_comments]) will be stripped
+	/// as well.
+	///
+	/// Returns the removed definition and tokens, which can be inserted
+	/// back with [`insert_removed`][Self::insert_removed].
+	pub fn remove_var(&mut self, index: usize) -> RemovedVar<'b> {
+		checked!(self, "remove_var", {
+			debug_assert!(
+				matches!(
+					self.as_ref().0.get(index),
+					Some(lst::Token::Variable(_))
+				),
+				"remove_var called with index {index} not pointing to a variable"
+			);
+			// scan of following spaces and newline
+			let after = self
+				.lst_tokens_iter()
+				.skip(index)
+				.take_while(|token| !matches!(token, lst::Token::Newline))
+				.count();
+			let tokens = self.lst_tokens();
+			let mut start = index;
+			while start > 0 && matches!(tokens[start - 1], lst::Token::Spacy(_))
+			{
+				start -= 1;
+			}
+			if start > 0 && !matches!(tokens[start - 1], lst::Token::Newline) {
+				// not indentation, keep spaces after other tokens
+				start = index;
+			}
+			if let Some(first) = leading_comment_indexes(tokens, index).first()
+			{
+				// scan for next line
+				if !tokens
+					.iter()
+					.skip(index)
+					.skip_while(|token| !matches!(token, lst::Token::Newline))
+					.skip(1)
+					.take_while(|token| !matches!(token, lst::Token::Newline))
+					.any(|token| matches!(token, lst::Token::Variable(_)))
+				{
+					// next line is empty, strip the leading comments
+					// together with the indentation before them
+					start = *first;
+					while start > 0
+						&& matches!(tokens[start - 1], lst::Token::Spacy(_))
+					{
+						start -= 1;
+					}
+				}
+			}
+			// the newline is absent at the end of file
+			let end = (index + after + 1).min(tokens.len());
+			let lst::Token::Variable(def) = &tokens[index] else {
+				unreachable!()
+			};
+			let def = def.clone();
+			let mut tokens =
+				self.lst_tokens_mut().drain(start..end).collect::<Vec<_>>();
+			if tokens.last() == Some(&lst::Token::Newline) {
+				tokens.pop();
+			}
+			RemovedVar { def, tokens }
+		})
+	}
+
+	/// Removes the last definition of a variable by name, which is
+	/// effective in Bash.
+	///
+	/// See [`remove_var`][Self::remove_var] for how comments are stripped.
+	///
+	/// Returns the removed definition, or [None] if the variable is
+	/// not defined.
+	#[must_use]
+	pub fn remove_var_by_name<S: AsRef<str>>(
+		&mut self,
+		name: S,
+	) -> Option<RemovedVar<'b>> {
+		let (index, _) = self.find_var_effective(name)?;
+		Some(self.remove_var(index))
+	}
+
+	/// Inserts tokens removed by [`remove_var`][Self::remove_var] verbatim
+	/// as lines after the line of a variable, or at the end if the
+	/// variable is not found.
+	pub fn insert_removed(
+		&mut self,
+		removed: RemovedVar<'b>,
+		after: Option<&str>,
+	) {
+		checked!(self, "insert_removed", {
+			self.insert_lines(removed.tokens, after)
+		})
+	}
+
+	/// Removes all definitions of a variable by name.
+	///
+	/// Returns the number of removed definitions.
+	pub fn remove_all_vars<S: AsRef<str>>(&mut self, name: S) -> usize {
+		self.remove_vars_matching(|var, _| var == name.as_ref())
+	}
+
+	/// Removes all variable definitions matching the predicate.
+	///
+	/// Each definition is removed with [`remove_var`][Self::remove_var],
+	/// from the last one to the first one, so the comments are stripped
+	/// in the same way.
+	///
+	/// Returns the number of removed definitions.
+	pub fn remove_vars_matching<F>(&mut self, predicate: F) -> usize
+	where
+		F: Fn(&str, &lst::VariableDefinition<'b>) -> bool,
+	{
+		checked!(self, "remove_vars_matching", {
+			let indices = self
+				.lst_tokens_iter()
+				.enumerate()
+				.filter_map(|(idx, token)| match token {
+					lst::Token::Variable(var) if predicate(&var.name, var) => {
+						Some(idx)
+					}
+					_ => None,
+				})
+				.collect::<Vec<_>>();
+			for index in indices.iter().rev() {
+				self.remove_var(*index);
+			}
+			indices.len()
+		})
+	}
+
+	/// Removes repeated assignments of the same variable.
+	///
+	/// Assignments (`=`) of a variable are duplicates if there is no
+	/// appending definition (`+=`) of the variable between them, so
+	/// appending definitions are never removed. The policy chooses which
+	/// one of the duplicates is kept. References from other variables
+	/// between the duplicates are not considered.
+	///
+	/// Definitions are removed with [`remove_var`][Self::remove_var],
+	/// so the comments are stripped in the same way.
+	pub fn dedupe_vars(&mut self, policy: DedupePolicy) -> DedupeReport<'b> {
+		fn flush(
+			group: &mut Vec<usize>,
+			policy: DedupePolicy,
+			out: &mut Vec<usize>,
+		) {
+			if group.len() > 1 {
+				match policy {
+					DedupePolicy::KeepFirst => out.extend(&group[1..]),
+					DedupePolicy::KeepLast => {
+						out.extend(&group[..group.len() - 1])
+					}
+				}
+			}
+			group.clear();
+		}
+
+		checked!(self, "dedupe_vars", {
+			let tokens = &self.as_ref().0;
+			let mut groups = HashMap::<&str, Vec<usize>>::new();
+			let mut removed = Vec::new();
+			for (index, token) in tokens.iter().enumerate() {
+				let lst::Token::Variable(def) = token else {
+					continue;
+				};
+				let group = groups.entry(def.name.as_ref()).or_default();
+				match def.op {
+					lst::VariableOp::Assignment => group.push(index),
+					lst::VariableOp::Append => {
+						flush(group, policy, &mut removed)
+					}
+				}
+			}
+			for group in groups.values_mut() {
+				flush(group, policy, &mut removed);
+			}
+			removed.sort_unstable();
+
+			let mut report = DedupeReport::default();
+			let mut line = 1;
+			let mut next = removed.iter().peekable();
+			for (index, token) in tokens.iter().enumerate() {
+				if next.next_if(|next| **next == index).is_some() {
+					let lst::Token::Variable(def) = token else {
+						unreachable!()
+					};
+					report.removed.push((line, def.clone()));
+				}
+				if matches!(token, lst::Token::Newline) {
+					line += 1;
+				}
+			}
+			for index in removed.into_iter().rev() {
+				self.remove_var(index);
+			}
+			report
+		})
+	}
+
+	/// Retains only the variable definitions matching the predicate.
+	///
+	/// This is the opposite of [`remove_vars_matching`][Self::remove_vars_matching].
+	///
+	/// Returns the number of removed definitions.
+	pub fn retain_vars<F>(&mut self, predicate: F) -> usize
+	where
+		F: Fn(&str, &lst::VariableDefinition<'b>) -> bool,
+	{
+		self.remove_vars_matching(|name, def| !predicate(name, def))
+	}
+
+	/// Moves the first definition of a variable after the line of
+	/// another variable, or to the top if `after` is [None].
+	///
+	/// The definition is moved together with its leading comment block
+	/// (see [`leading_comments`][Self::leading_comments]) and inline
+	/// comment, and is inserted in the same way as
+	/// [`append_var_ast`][Self::append_var_ast].
+	/// Nothing is changed if any of the variables is not found, the
+	/// variables are the same, or the definition shares its line with
+	/// other definitions.
+	///
+	/// Returns if the LST is changed.
+	pub fn move_var(&mut self, name: &str, after: Option<&str>) -> bool {
+		checked!(self, "move_var", {
+			if after == Some(name)
+				|| after.is_some_and(|after| self.find_var(after).is_none())
+			{
+				return false;
+			}
+			let Some(index) = self.find_var_index(name) else {
+				return false;
+			};
+			let tokens = &self.as_ref().0;
+			let lines = line_ranges(tokens);
+			let line_of = |index: usize| {
+				lines.iter().find(|line| line.contains(&index)).unwrap()
+			};
+			let line = line_of(index);
+			if tokens[line.clone()]
+				.iter()
+				.filter(|token| matches!(token, lst::Token::Variable(_)))
+				.count() > 1
+			{
+				return false;
+			}
+			let start = leading_comment_indexes(tokens, index)
+				.first()
+				.map_or(line.start, |first| line_of(*first).start);
+			let end = (line.end + 1).min(tokens.len());
+
+			let original = tokens.clone();
+			let dirty = self.dirty;
+			let mut block =
+				self.lst_tokens_mut().drain(start..end).collect::<Vec<_>>();
+			if matches!(block.last(), Some(lst::Token::Newline)) {
+				block.pop();
+			}
+			match after {
+				Some(_) => self.insert_lines(block, after),
+				None => {
+					self.lst_tokens_mut().splice(
+						0..0,
+						block.into_iter().chain([lst::Token::Newline]),
+					);
+				}
+			}
+			if self.lst.0 == original {
+				self.dirty = dirty;
+				return false;
+			}
+			true
+		})
+	}
+
+	/// Reorders variable definitions to match a canonical key order.
+	///
+	/// Each definition is moved together with its leading comment block
+	/// and inline comment. Definitions are placed into the positions
+	/// of the moved definitions, so blank lines, standalone comments and
+	/// lines with multiple definitions are left in place.
+	/// Variables not listed in the order are handled per the policy.
+	///
+	/// The sort is stable, keeping the relative order of definitions
+	/// of the same variable, and idempotent.
+	///
+	/// Returns if any definition is moved.
+	pub fn sort_vars(
+		&mut self,
+		order: &[&str],
+		unknown: UnknownKeyPolicy,
+	) -> bool {
+		checked!(self, "sort_vars", {
+			let tokens = &self.as_ref().0;
+			let lines = line_ranges(tokens);
+			let is_comment_line = |line: &Range<usize>| {
+				tokens[line.clone()].iter().all(|token| {
+					matches!(
+						token,
+						lst::Token::Spacy(_) | lst::Token::Comment(_)
+					)
+				}) && tokens[line.clone()]
+					.iter()
+					.any(|token| matches!(token, lst::Token::Comment(_)))
+			};
+			// units of movable lines and their ranks
+			let mut units = Vec::<(Range<usize>, Option<usize>)>::new();
+			for (index, line) in lines.iter().enumerate() {
+				let mut vars = tokens[line.clone()].iter().filter_map(
+					|token| match token {
+						lst::Token::Variable(def) => Some(def),
+						_ => None,
+					},
+				);
+				let (Some(def), None) = (vars.next(), vars.next()) else {
+					continue;
+				};
+				if tokens[line.clone()]
+					.iter()
+					.any(|token| matches!(token, lst::Token::Unknown(_)))
+				{
+					continue;
+				}
+				let mut start = index;
+				while start > 0 && is_comment_line(&lines[start - 1]) {
+					start -= 1;
+				}
+				let rank = order.iter().position(|key| *key == def.name);
+				units.push((start..index + 1, rank));
+			}
+
+			let mut slots = (0..units.len()).collect::<Vec<_>>();
+			if unknown == UnknownKeyPolicy::Keep {
+				slots.retain(|slot| units[*slot].1.is_some());
+			}
+			let mut sorted = slots.clone();
+			sorted.sort_by_key(|unit| units[*unit].1.unwrap_or(order.len()));
+			if sorted == slots {
+				return false;
+			}
+			let mut assignment = (0..units.len()).collect::<Vec<_>>();
+			for (slot, unit) in slots.into_iter().zip(sorted) {
+				assignment[slot] = unit;
+			}
+
+			let mut result = Vec::with_capacity(tokens.len());
+			let mut line = 0;
+			let mut slot = 0;
+			while line < lines.len() {
+				let range = if units
+					.get(slot)
+					.is_some_and(|(range, _)| range.start == line)
+				{
+					line = units[slot].0.end;
+					slot += 1;
+					units[assignment[slot - 1]].0.clone()
+				} else {
+					line += 1;
+					line - 1..line
+				};
+				for line in &lines[range] {
+					result.extend_from_slice(&tokens[line.clone()]);
+					result.push(lst::Token::Newline);
+				}
+			}
+			result.pop();
+			*self.lst_tokens_mut() = result;
+			true
+		})
+	}
+
+	/// Gets texts of the leading comment block of the first definition
+	/// of a variable, in file order.
+	///
+	/// The leading comment block is made up of the contiguous comment-only
+	/// lines immediately above the definition, stopping at a blank line
+	/// or a line with other tokens. Inline comments of the previous
+	/// definition are not included. This is the same block as stripped
+	/// by [`remove_var`][Self::remove_var].
+	///
+	/// Texts do not include the leading `#`.
+	pub fn leading_comments(&self, name: &str) -> Vec<&str> {
+		self.leading_comment_indexes(name)
+			.into_iter()
+			.map(|index| match &self.as_ref().0[index] {
+				lst::Token::Comment(text) => text.as_ref(),
+				_ => unreachable!(),
+			})
+			.collect()
+	}
+
+	/// Gets indexes of comment tokens in the leading comment block of the
+	/// first definition of a variable, in file order.
+	///
+	/// See [`leading_comments`][Self::leading_comments].
+	pub fn leading_comment_indexes(&self, name: &str) -> Vec<usize> {
+		match self.find_var_index(name) {
+			Some(index) => leading_comment_indexes(&self.as_ref().0, index),
+			None => Vec::new(),
+		}
+	}
+
+	/// Iterates over all comment lines.
+	pub fn comments(&self) -> impl Iterator<Item = &str> {
+		self.lst_tokens_iter().filter_map(|token| {
+			if let lst::Token::Comment(var) = token {
+				Some(var.as_ref())
+			} else {
+				None
+			}
+		})
+	}
+
+	/// Removes all comments.
+	///
+	/// Comment-only lines are removed entirely, and inline comments
+	/// are removed with spaces before them.
+	/// Comments in multi-line arrays are kept.
+	///
+	/// Returns the number of removed comments.
+	pub fn strip_comments(&mut self) -> usize {
+		checked!(self, "strip_comments", { self.strip_comments_impl(false) })
+	}
+
+	/// Removes comments following variable definitions on the same line,
+	/// together with spaces before them.
+	///
+	/// Returns the number of removed comments.
+	pub fn strip_inline_comments(&mut self) -> usize {
+		checked!(self, "strip_inline_comments", {
+			self.strip_comments_impl(true)
+		})
+	}
+
+	fn strip_comments_impl(&mut self, inline_only: bool) -> usize {
+		let tokens = &self.as_ref().0;
+		let mut result = Vec::with_capacity(tokens.len());
+		let mut count = 0;
+		for line in line_ranges(tokens) {
+			let line = &tokens[line];
+			let content = line.iter().position(|token| {
+				!matches!(token, lst::Token::Spacy(_) | lst::Token::Comment(_))
+			});
+			match line
+				.iter()
+				.position(|token| matches!(token, lst::Token::Comment(_)))
+			{
+				Some(comment) if content.is_some_and(|pos| pos < comment) => {
+					let end = line[..comment]
+						.iter()
+						.rposition(|token| {
+							!matches!(token, lst::Token::Spacy(_))
+						})
+						.map_or(0, |pos| pos + 1);
+					result.extend_from_slice(&line[..end]);
+					result.extend_from_slice(&line[comment + 1..]);
+					result.push(lst::Token::Newline);
+					count += 1;
+				}
+				Some(_) if !inline_only && content.is_none() => count += 1,
+				_ => {
+					result.extend_from_slice(line);
+					result.push(lst::Token::Newline);
+				}
+			}
+		}
+		result.pop();
+		if count != 0 {
+			*self.lst_tokens_mut() = result;
+		}
+		count
+	}
+
+	/// Finds spaces at the end of lines.
+	///
+	/// Spaces before newlines and the end of file are reported, including
+	/// those in multi-line arrays. Spaces in quoted texts are not space
+	/// tokens and are never reported.
+	///
+	/// Returns byte ranges in the serialized source.
+	pub fn trailing_whitespace(&self) -> Vec<Span> {
+		let tokens = &self.as_ref().0;
+		let mut result = Vec::new();
+		let mut offsets = Vec::with_capacity(tokens.len() + 1);
+		let mut offset = 0;
+		for token in tokens {
+			offsets.push(offset);
+			offset += token.source_len();
+		}
+		offsets.push(offset);
+
+		let root = trailing_space_runs(
+			tokens,
+			|token| matches!(token, lst::Token::Spacy(_)),
+			|token| matches!(token, lst::Token::Newline),
+			true,
+		);
+		let mut root = root.into_iter().peekable();
+		for (index, token) in tokens.iter().enumerate() {
+			if let Some(run) = root.next_if(|run| run.start == index) {
+				result.push(offsets[run.start]..offsets[run.end]);
+			}
+			let lst::Token::Variable(lst::VariableDefinition {
+				declaration,
+				name,
+				op,
+				value: lst::VariableValue::Array(elements),
+			}) = token
+			else {
+				continue;
+			};
+			let mut offset = offsets[index]
+				+ declaration_len(declaration)
+				+ name.len() + op.to_string().len()
+				+ 1;
+			let mut offsets = Vec::with_capacity(elements.len() + 1);
+			for element in elements {
+				offsets.push(offset);
+				offset += element.to_string().len();
+			}
+			offsets.push(offset);
+			for run in trailing_space_runs(
+				elements,
+				|token| matches!(token, lst::ArrayToken::Spacy(_)),
+				|token| matches!(token, lst::ArrayToken::Newline),
+				false,
+			) {
+				result.push(offsets[run.start]..offsets[run.end]);
+			}
+		}
+		result
+	}
+
+	/// Removes spaces at the end of lines.
+	///
+	/// See [`trailing_whitespace`][Self::trailing_whitespace] for which
+	/// spaces are removed.
+	///
+	/// Returns the number of lines stripped.
+	pub fn strip_trailing_whitespace(&mut self) -> usize {
+		checked!(self, "strip_trailing_whitespace", {
+			let mut count = 0;
+			let runs = trailing_space_runs(
+				&self.as_ref().0,
+				|token| matches!(token, lst::Token::Spacy(_)),
+				|token| matches!(token, lst::Token::Newline),
+				true,
+			);
+			if !runs.is_empty() {
+				count += runs.len();
+				let tokens = self.lst_tokens_mut();
+				for run in runs.into_iter().rev() {
+					tokens.drain(run);
+				}
+			}
+			let indexes = self
+				.lst_tokens_iter()
+				.enumerate()
+				.filter_map(|(index, token)| match token {
+					lst::Token::Variable(lst::VariableDefinition {
+						value: lst::VariableValue::Array(elements),
+						..
+					}) if elements.windows(2).any(|pair| {
+						matches!(pair, [
+							lst::ArrayToken::Spacy(_),
+							lst::ArrayToken::Newline
+						])
+					}) =>
+					{
+						Some(index)
+					}
+					_ => None,
+				})
+				.collect::<Vec<_>>();
+			for index in indexes {
+				let lst::Token::Variable(lst::VariableDefinition {
+					value: lst::VariableValue::Array(elements),
+					..
+				}) = &mut self.lst_tokens_mut()[index]
+				else {
+					unreachable!()
+				};
+				let runs = trailing_space_runs(
+					elements,
+					|token| matches!(token, lst::ArrayToken::Spacy(_)),
+					|token| matches!(token, lst::ArrayToken::Newline),
+					false,
+				);
+				count += runs.len();
+				for run in runs.into_iter().rev() {
+					elements.drain(run);
+				}
+			}
+			count
+		})
+	}
+
+	/// Normalizes whitespaces with all rules enabled.
+	///
+	/// See [`WhitespaceOptions`] for the rules.
+	pub fn normalize_whitespace(&mut self) {
+		self.normalize_whitespace_with(&WhitespaceOptions::default());
+	}
+
+	/// Normalizes whitespaces with the given rules.
+	///
+	/// Only space and newline tokens are added or removed, so the
+	/// semantics are unchanged.
+	pub fn normalize_whitespace_with(&mut self, options: &WhitespaceOptions) {
+		checked!(self, "normalize_whitespace_with", {
+			if options.strip_trailing {
+				self.strip_trailing_whitespace();
+			}
+			if options.collapse_blank_lines {
+				let tokens = &self.as_ref().0;
+				let removed = (2..tokens.len())
+					.filter(|index| {
+						tokens[index - 2..=*index]
+							.iter()
+							.all(|token| matches!(token, lst::Token::Newline))
+					})
+					.collect::<Vec<_>>();
+				if !removed.is_empty() {
+					let tokens = self.lst_tokens_mut();
+					for index in removed.into_iter().rev() {
+						tokens.remove(index);
+					}
+				}
+			}
+			if options.final_newline {
+				let tokens = &self.as_ref().0;
+				let newlines = tokens
+					.iter()
+					.rev()
+					.take_while(|token| matches!(token, lst::Token::Newline))
+					.count();
+				if newlines > 1 {
+					let len = tokens.len() - newlines + 1;
+					self.lst_tokens_mut().truncate(len);
+				}
+				self.ensure_end_newline();
+			}
+		})
+	}
+
+	/// Collapses runs of blank lines longer than `max_consecutive`.
+	///
+	/// A line is blank if it contains only spaces, so lines with only
+	/// comments are kept and end runs of blank lines. Newlines inside
+	/// multi-line values are not touched.
+	///
+	/// Returns the number of removed lines.
+	pub fn normalize_blank_lines(&mut self, max_consecutive: usize) -> usize {
+		checked!(self, "normalize_blank_lines", {
+			let tokens = &self.as_ref().0;
+			let mut removed = Vec::new();
+			let mut run = 0;
+			for line in line_ranges(tokens) {
+				if line.end == tokens.len()
+					|| !tokens[line.clone()]
+						.iter()
+						.all(|token| matches!(token, lst::Token::Spacy(_)))
+				{
+					run = 0;
+					continue;
+				}
+				run += 1;
+				if run > max_consecutive {
+					removed.push(line.start..line.end + 1);
+				}
+			}
+			if !removed.is_empty() {
+				let tokens = self.lst_tokens_mut();
+				for range in removed.iter().rev() {
+					tokens.drain(range.clone());
+				}
+			}
+			removed.len()
+		})
+	}
+
+	/// Runs all formatter passes configured by the options.
+	///
+	/// This wraps long values (see
+	/// [`wrap_long_values`][Self::wrap_long_values]) and collapses
+	/// blank lines per [`FormatOptions::max_consecutive_blanks`].
+	///
+	/// Returns the number of wrapped definitions and removed lines.
+	pub fn format(&mut self, options: &FormatOptions) -> usize {
+		checked!(self, "format", {
+			let mut count = self.wrap_long_values(options);
+			if let Some(max_consecutive) = options.max_consecutive_blanks {
+				count += self.normalize_blank_lines(max_consecutive);
+			}
+			count
+		})
+	}
+
+	/// Ensures there is a blank line above a variable definition and its
+	/// leading comment block.
+	///
+	/// Nothing is inserted at the start of file, or if the definition does
+	/// not start a line.
+	///
+	/// Returns [false] if the variable is not found.
+	pub fn ensure_blank_line_before(&mut self, name: &str) -> bool {
+		checked!(self, "ensure_blank_line_before", {
+			let Some(index) = self.find_var_index(name) else {
+				return false;
+			};
+			let tokens = &self.as_ref().0;
+			let mut line_start = leading_comment_indexes(tokens, index)
+				.first()
+				.copied()
+				.unwrap_or(index);
+			while line_start > 0
+				&& matches!(tokens[line_start - 1], lst::Token::Spacy(_))
+			{
+				line_start -= 1;
+			}
+			if line_start > 0
+				&& matches!(tokens[line_start - 1], lst::Token::Newline)
+				&& !tokens[..line_start - 1]
+					.iter()
+					.rev()
+					.take_while(|token| !matches!(token, lst::Token::Newline))
+					.all(|token| matches!(token, lst::Token::Spacy(_)))
+			{
+				self.lst_tokens_mut()
+					.insert(line_start, lst::Token::Newline);
+			}
+			true
+		})
+	}
+
+	/// Wraps long list-like string values with line continuations.
+	///
+	/// Double-quoted values made up of whitespace-separated elements are
+	/// wrapped when the line exceeds [`FormatOptions::max_line_width`].
+	/// Each line continuation is inserted before the spaces separating
+	/// two elements, so the spaces become the indentation of the next
+	/// line, and the value evaluates to the same string. No further
+	/// indentation is added, as it would change the value of prose
+	/// fields like `PKGDES`. Use [`reflow_var`][Self::reflow_var] for
+	/// lists whose elements may be re-spaced.
+	///
+	/// Values are only split at spaces in literal strings, so expansions
+	/// are never split. Values that already span multiple lines are
+	/// left alone.
+	///
+	/// Returns the number of wrapped definitions.
+	pub fn wrap_long_values(&mut self, options: &FormatOptions) -> usize {
+		checked!(self, "wrap_long_values", {
+			let mut wrapped = Vec::new();
+			let mut column = 0;
+			for (index, token) in self.lst_tokens_iter().enumerate() {
+				if let lst::Token::Variable(lst::VariableDefinition {
+					declaration,
+					name,
+					op,
+					value: lst::VariableValue::String(text),
+				}) = token
+				{
+					let prefix = declaration_len(declaration)
+						+ name.chars().count()
+						+ op.to_string().len();
+					if let Some(text) = wrap_list_text(
+						text,
+						column + prefix,
+						options.max_line_width,
+					) {
+						wrapped.push((index, text));
+					}
+				}
+				match token {
+					lst::Token::Newline => column = 0,
+					_ => column += token.to_string().chars().count(),
+				}
+			}
+			let count = wrapped.len();
+			for (index, text) in wrapped {
+				if let lst::Token::Variable(def) =
+					&mut self.lst_tokens_mut()[index]
+				{
+					def.value = lst::VariableValue::String(Arc::new(text));
+				}
+			}
+			count
+		})
+	}
+
+	/// Reflows the effective definition of a long list-like string
+	/// variable, such as `PKGDEP`, with line continuations.
+	///
+	/// Existing line continuations are removed first, so the result
+	/// only depends on the elements. Elements are separated by a single
+	/// space, and lines are wrapped before elements which would exceed
+	/// the width. Continuation lines are indented to align under the
+	/// first character of the value. A single element longer than the
+	/// width is never split.
+	///
+	/// Only double-quoted values can be reflowed, as line continuations
+	/// are literal in single quotes. Unquoted values are left alone.
+	///
+	/// Returns if the definition is changed.
+	pub fn reflow_var(
+		&mut self,
+		name: &str,
+		max_width: usize,
+	) -> Result<bool, ReflowError> {
+		checked!(self, "reflow_var", {
+			let Some((index, def)) = self.find_var_effective(name) else {
+				return Err(ReflowError::Undefined(name.to_string()));
+			};
+			let lst::VariableValue::String(text) = &def.value else {
+				return Err(ReflowError::NotString(name.to_string()));
+			};
+			let words = match text.0.as_slice() {
+				[lst::TextUnit::DoubleQuote(words)] => words,
+				[lst::TextUnit::Unquoted(_)] => return Ok(false),
+				[lst::TextUnit::SingleQuote(_)] => {
+					return Err(ReflowError::SingleQuoted(name.to_string()));
+				}
+				_ => return Err(ReflowError::NotList(name.to_string())),
+			};
+			let column = self.as_ref().0[..index]
+				.iter()
+				.rev()
+				.take_while(|token| !matches!(token, lst::Token::Newline))
+				.map(|token| token.to_string().chars().count())
+				.sum::<usize>()
+				+ declaration_len(&def.declaration)
+				+ def.name.chars().count()
+				+ def.op.to_string().len();
+			let Some(result) = reflow_list_text(words, column, max_width)
+			else {
+				return Err(ReflowError::NotList(name.to_string()));
+			};
+			if **text == result {
+				return Ok(false);
+			}
+			let lst::Token::Variable(def) = &mut self.lst_tokens_mut()[index]
+			else {
+				unreachable!()
+			};
+			def.value = lst::VariableValue::String(Arc::new(result));
+			Ok(true)
+		})
+	}
+
+	/// Sets or removes the inline comment on the line of the first
+	/// definition of a variable.
+	///
+	/// If a inline comment exists, its text is replaced while spaces
+	/// before it are preserved. Otherwise, ` # text` is appended to the
+	/// line. If [None] is given, the inline comment is removed together
+	/// with spaces before it.
+	///
+	/// Returns if the variable is defined.
+	pub fn set_inline_comment(
+		&mut self,
+		name: &str,
+		text: Option<&str>,
+	) -> bool {
+		checked!(self, "set_inline_comment", {
+			let Some(index) = self.find_var_index(name) else {
+				return false;
+			};
+			let tokens = &self.as_ref().0;
+			let line_end = tokens[index..]
+				.iter()
+				.position(|token| matches!(token, lst::Token::Newline))
+				.map_or(tokens.len(), |pos| index + pos);
+			let comment = tokens[index..line_end]
+				.iter()
+				.position(|token| matches!(token, lst::Token::Comment(_)))
+				.map(|pos| index + pos);
+			let spaces = tokens[..comment.unwrap_or(line_end)]
+				.iter()
+				.rposition(|token| !matches!(token, lst::Token::Spacy(_)))
+				.map_or(0, |pos| pos + 1);
+			match (text, comment) {
+				(Some(text), Some(comment)) => {
+					let text = Cow::Owned(format!(" {}", text.trim()));
+					if !matches!(&tokens[comment], lst::Token::Comment(old) if *old == text)
+					{
+						self.lst_tokens_mut()[comment] =
+							lst::Token::Comment(text);
+					}
+				}
+				(Some(text), None) => {
+					let text = Cow::Owned(format!(" {}", text.trim()));
+					let mut inserted = Vec::with_capacity(2);
+					if spaces == line_end {
+						inserted.push(lst::Token::Spacy(' '));
+					}
+					inserted.push(lst::Token::Comment(text));
+					self.lst_tokens_mut().splice(line_end..line_end, inserted);
+				}
+				(None, Some(comment)) => {
+					self.lst_tokens_mut().drain(spaces..=comment);
+				}
+				(None, None) => {}
+			}
+			true
+		})
+	}
+
+	/// Inserts a comment line above the first definition of a variable.
+	///
+	/// The comment is inserted above the leading comment block of the
+	/// definition, or directly above the definition if there is none,
+	/// with the same indentation. The text is normalized to `# text`.
+	///
+	/// Returns if the comment is inserted, which is false if the variable
+	/// is undefined or the identical comment exists in the leading
+	/// comment block.
+	pub fn insert_comment_before(&mut self, name: &str, text: &str) -> bool {
+		checked!(self, "insert_comment_before", {
+			let text = text.trim();
+			debug_assert!(!text.contains('\n'));
+			let Some(index) = self.find_var_index(name) else {
+				return false;
+			};
+			let tokens = &self.as_ref().0;
+			let block = leading_comment_indexes(tokens, index);
+			if block.iter().any(|comment| {
+				matches!(&tokens[*comment], lst::Token::Comment(existing) if existing.trim() == text)
+			}) {
+				return false;
+			}
+			let first = block.first().copied().unwrap_or(index);
+			let mut line_start = first;
+			while line_start > 0
+				&& matches!(tokens[line_start - 1], lst::Token::Spacy(_))
+			{
+				line_start -= 1;
+			}
+			let mut comment = tokens[line_start..first].to_vec();
+			comment.push(lst::Token::Comment(Cow::Owned(format!(" {text}"))));
+			comment.push(lst::Token::Newline);
+			self.lst_tokens_mut()
+				.splice(line_start..line_start, comment);
+			true
+		})
+	}
+
+	/// Appends a comment line to the end of file.
+	///
+	/// The text is normalized to `# text`.
+	pub fn insert_comment_at_end(&mut self, text: &str) {
+		checked!(self, "insert_comment_at_end", {
+			let text = text.trim();
+			debug_assert!(!text.contains('\n'));
+			self.ensure_end_newline();
+			let tokens = self.lst_tokens_mut();
+			tokens.push(lst::Token::Comment(Cow::Owned(format!(" {text}"))));
+			tokens.push(lst::Token::Newline);
+		})
+	}
+
+	/// Inserts a section header comment (`# === <title> ===`).
+	///
+	/// The header is separated from the surrounding lines with blank lines.
+	/// When inserting before a variable, the header is placed above
+	/// the leading comment block of the definition.
+	///
+	/// Returns [false] if the variable is not found.
+	pub fn insert_section_header(
+		&mut self,
+		title: &'b str,
+		position: SectionPosition,
+	) -> bool {
+		checked!(self, "insert_section_header", {
+			let pos = match position {
+				SectionPosition::Before(name) => {
+					let Some(index) = self.find_var_index(name) else {
+						return false;
+					};
+					let index =
+						leading_comment_indexes(&self.as_ref().0, index)
+							.first()
+							.copied()
+							.unwrap_or(index);
+					let tokens = self.lst_tokens();
+					let mut line_start = index;
+					while line_start > 0
+						&& matches!(
+							tokens[line_start - 1],
+							lst::Token::Spacy(_)
+						) {
+						line_start -= 1;
+					}
+					line_start
+				}
+				SectionPosition::After(name) => {
+					let Some(index) = self.find_var_index(name) else {
+						return false;
+					};
+					let after = self
+						.lst_tokens_iter()
+						.skip(index)
+						.take_while(|token| {
+							!matches!(token, lst::Token::Newline)
+						})
+						.count();
+					let newline = index + after;
+					if newline == self.lst_tokens().len() {
+						self.lst_tokens_mut().push(lst::Token::Newline);
+					}
+					newline + 1
+				}
+				SectionPosition::AtEnd => {
+					self.ensure_end_newline();
+					self.lst_tokens().len()
+				}
+			};
+			let tokens = self.lst_tokens();
+			let mut header = Vec::with_capacity(4);
+			if pos >= 2 && !matches!(tokens[pos - 2], lst::Token::Newline) {
+				header.push(lst::Token::Newline);
+			}
+			header.push(lst::Token::Comment(Cow::Owned(format!(
+				" {SECTION_HEADER_MARK} {title} {SECTION_HEADER_MARK}"
+			))));
+			header.push(lst::Token::Newline);
+			if pos < tokens.len() && !matches!(tokens[pos], lst::Token::Newline)
+			{
+				header.push(lst::Token::Newline);
+			}
+			self.lst_tokens_mut().splice(pos..pos, header);
+			true
+		})
+	}
+
+	/// Lists titles of all section header comments (`# === <title> ===`).
+	pub fn section_header_names(&self) -> Vec<&str> {
+		self.comments()
+			.filter_map(|comment| {
+				comment
+					.trim()
+					.strip_prefix(SECTION_HEADER_MARK)?
+					.strip_suffix(SECTION_HEADER_MARK)
+					.map(str::trim)
+			})
+			.collect()
+	}
+
+	/// Finds the variable a comment token is attached to.
+	///
+	/// Inline comments are attached to the definition on the same line.
+	/// Other comments are attached to the definition following its comment
+	/// block, if there is no blank lines between them.
+	fn comment_attached_var(&self, index: usize) -> Option<&str> {
+		let tokens = self.as_ref().0.as_slice();
+		let inline = tokens[..index]
+			.iter()
+			.rev()
+			.take_while(|token| !matches!(token, lst::Token::Newline))
+			.find_map(|token| match token {
+				lst::Token::Variable(def) => Some(def.name.as_ref()),
+				_ => None,
+			});
+		if inline.is_some() {
+			return inline;
+		}
+		let mut line_empty = false;
+		for token in &tokens[index + 1..] {
+			match token {
+				lst::Token::Spacy(_) => {}
+				lst::Token::Newline => {
+					if line_empty {
+						return None;
+					}
+					line_empty = true;
+				}
+				lst::Token::Comment(_) => line_empty = false,
+				lst::Token::Variable(def) => return Some(def.name.as_ref()),
+				lst::Token::Unknown(_) => return None,
+			}
+		}
+		None
+	}
+
+	/// Lists all directives with the [default prefix][DEFAULT_PREFIX].
+	///
+	/// See [`comment`][super::comment] for more about directives.
+	pub fn directives(&self) -> Vec<DirectiveRef<'_>> {
+		self.directives_with_prefix(DEFAULT_PREFIX)
+	}
+
+	/// Lists all directives with a custom prefix.
+	pub fn directives_with_prefix<'s>(
+		&'s self,
+		prefix: &'s str,
+	) -> Vec<DirectiveRef<'s>> {
+		self.lst_tokens_iter()
+			.enumerate()
+			.filter_map(|(index, token)| match token {
+				lst::Token::Comment(text) => Directive::parse_with_prefix(
+					text, prefix,
+				)
+				.map(|directive| DirectiveRef {
+					index,
+					directive,
+					variable: self.comment_attached_var(index),
+				}),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Finds the directive in the leading comment block of a variable.
+	pub fn var_directive<'s>(
+		&'s self,
+		name: &str,
+		prefix: &'s str,
+	) -> Option<DirectiveRef<'s>> {
+		let (index, var) = self.find_var(name)?;
+		leading_comment_indexes(&self.as_ref().0, index)
+			.into_iter()
+			.find_map(|index| match &self.as_ref().0[index] {
+				lst::Token::Comment(text) => Directive::parse_with_prefix(
+					text, prefix,
+				)
+				.map(|directive| DirectiveRef {
+					index,
+					directive,
+					variable: Some(var.name.as_ref()),
+				}),
+				_ => None,
+			})
+	}
+
+	/// Adds or updates a directive entry of a variable.
+	///
+	/// The directive is written into the leading comment block of the
+	/// variable definition. If there is no directive comment yet, a new
+	/// comment line is inserted above the definition.
+	///
+	/// Returns [false] if the variable is not found.
+	pub fn set_directive(
+		&mut self,
+		name: &str,
+		key: &str,
+		value: Option<&str>,
+	) -> bool {
+		self.edit_directive(name, DEFAULT_PREFIX, |directive| {
+			directive.set(key, value)
+		})
+	}
+
+	/// Removes a directive entry of a variable.
+	///
+	/// Returns if the entry has been removed.
+	/// The directive comment is kept even if it becomes empty.
+	pub fn remove_directive(&mut self, name: &str, key: &str) -> bool {
+		if !self
+			.var_directive(name, DEFAULT_PREFIX)
+			.is_some_and(|found| found.directive.contains(key))
+		{
+			return false;
+		}
+		self.edit_directive(name, DEFAULT_PREFIX, |directive| {
+			directive.remove(key);
+		})
+	}
+
+	/// Modifies or creates the directive of a variable.
+	///
+	/// The directive comment is only rewritten if the directive is changed,
+	/// and a new comment is only inserted if the directive has entries.
+	///
+	/// Returns [false] if the variable is not found.
+	pub fn edit_directive<F>(&mut self, name: &str, prefix: &str, f: F) -> bool
+	where
+		F: FnOnce(&mut Directive),
+	{
+		checked!(self, "edit_directive", {
+			let Some(index) = self.find_var_index(name) else {
+				return false;
+			};
+			if let Some(found) = self.var_directive(name, prefix) {
+				let comment_index = found.index;
+				let mut directive = found.directive.clone();
+				f(&mut directive);
+				if directive != found.directive {
+					let text = directive.to_string();
+					self.lst_tokens_mut()[comment_index] =
+						lst::Token::Comment(Cow::Owned(text));
+				}
+			} else {
+				let mut directive = Directive {
+					prefix: Cow::Borrowed(prefix),
+					..Directive::new()
+				};
+				f(&mut directive);
+				if directive.entries.is_empty() {
+					return true;
+				}
+				let text = directive.to_string();
+				let mut line_start = index;
+				while line_start > 0
+					&& matches!(
+						self.lst_tokens()[line_start - 1],
+						lst::Token::Spacy(_)
+					) {
+					line_start -= 1;
+				}
+				self.lst_tokens_mut()
+					.insert(line_start, lst::Token::Newline);
+				self.lst_tokens_mut()
+					.insert(line_start, lst::Token::Comment(Cow::Owned(text)));
+			}
+			true
+		})
+	}
+}
+
+/// A whitespace-separated word in a text.
+struct TextWord {
+	/// Range of the word in the characters of text.
+	range: Range<usize>,
+	/// Literal value of the word, or [None] if it contains expansions.
+	literal: Option<String>,
+}
+
+/// Splits characters of a text into whitespace-separated words.
+fn split_text_words(chars: &[lst::TextChar]) -> Vec<TextWord> {
+	let mut result = Vec::new();
+	let mut start = None;
+	let mut literal = Some(String::new());
+	for (idx, ch) in chars.iter().enumerate() {
+		match ch {
+			lst::TextChar::Char(' ' | '\t' | '\n', _) => {
+				if let Some(start) = start.take() {
+					result.push(TextWord {
+						range: start..idx,
+						literal: literal.replace(String::new()),
+					});
+				}
+				literal = Some(String::new());
+			}
+			lst::TextChar::Char(ch, _) => {
+				start.get_or_insert(idx);
+				if let Some(literal) = &mut literal {
+					literal.push(*ch);
+				}
+			}
+			lst::TextChar::Expansion(..) => {
+				start.get_or_insert(idx);
+				literal = None;
+			}
+		}
+	}
+	if let Some(start) = start {
+		result.push(TextWord {
+			range: start..chars.len(),
+			literal,
+		});
+	}
+	result
+}
+
+/// Position of a character in a text, made up of indexes of
+/// the text unit, the word, the literal part and the character in it.
+type CharPosition = (usize, Option<usize>, Option<usize>, usize);
+
+/// Converts indexes of characters of a text into their positions.
+fn char_positions(
+	chars: &[lst::TextChar],
+	indexes: &HashSet<usize>,
+) -> HashSet<CharPosition> {
+	let mut result = HashSet::new();
+	let mut last = None;
+	let mut offset = 0;
+	for (idx, ch) in chars.iter().enumerate() {
+		let origin = ch.origin();
+		let part = (origin.unit, origin.word, origin.part);
+		if last == Some(part) {
+			offset += 1;
+		} else {
+			last = Some(part);
+			offset = 0;
+		}
+		if indexes.contains(&idx) {
+			result.insert((part.0, part.1, part.2, offset));
+		}
+	}
+	result
+}
+
+/// Removes characters at the given positions from a text.
+///
+/// Literal parts and words which become empty are removed.
+fn remove_text_chars(text: &mut lst::Text, positions: &HashSet<CharPosition>) {
+	let filter = |string: &mut Cow<str>,
+	              pos: (usize, Option<usize>, Option<usize>)| {
+		if (0..string.chars().count())
+			.any(|idx| positions.contains(&(pos.0, pos.1, pos.2, idx)))
+		{
+			*string = Cow::Owned(
+				string
+					.chars()
+					.enumerate()
+					.filter(|(idx, _)| {
+						!positions.contains(&(pos.0, pos.1, pos.2, *idx))
+					})
+					.map(|(_, ch)| ch)
+					.collect(),
+			);
+		}
+	};
+	for (unit_idx, unit) in text.0.iter_mut().enumerate() {
+		let words = match unit {
+			lst::TextUnit::SingleQuote(string) => {
+				filter(string, (unit_idx, None, None));
+				continue;
+			}
+			lst::TextUnit::Unquoted(words)
+			| lst::TextUnit::DoubleQuote(words) => words,
+		};
+		for (word_idx, word) in words.iter_mut().enumerate() {
+			let lst::Word::Literal(parts) = word else {
+				continue;
+			};
+			let mut part_idx = 0;
+			parts.retain_mut(|part| {
+				let pos = (unit_idx, Some(word_idx), Some(part_idx));
+				part_idx += 1;
+				match part {
+					lst::LiteralPart::String(string) => {
+						filter(string, pos);
+						!string.is_empty()
+					}
+					lst::LiteralPart::Escaped(_) => {
+						!positions.contains(&(pos.0, pos.1, pos.2, 0))
+					}
+					lst::LiteralPart::LineContinuation => true,
+				}
+			});
+		}
+		words.retain(
+			|word| !matches!(word, lst::Word::Literal(parts) if parts.is_empty()),
+		);
+	}
+}
+
+/// Inserts a element into array tokens, following the existing style.
+fn insert_array_element<'a>(
+	tokens: &mut Vec<lst::ArrayToken<'a>>,
+	element: lst::ArrayToken<'a>,
+	position: ArrayPosition,
+) {
+	let elements = tokens
+		.iter()
+		.enumerate()
+		.filter(|(_, token)| matches!(token, lst::ArrayToken::Element(_)))
+		.map(|(idx, _)| idx)
+		.collect::<Vec<_>>();
+	let before = match position {
+		ArrayPosition::Start => elements.first(),
+		ArrayPosition::End => None,
+		ArrayPosition::Before(index) => elements.get(index),
+	}
+	.copied();
+	let line_start = |idx: usize| {
+		tokens[..idx]
+			.iter()
+			.rposition(|token| matches!(token, lst::ArrayToken::Newline))
+			.map(|pos| pos + 1)
+	};
+
+	if !tokens
+		.iter()
+		.any(|token| matches!(token, lst::ArrayToken::Newline))
+	{
+		// single-line array
+		match (before, elements.last()) {
+			(Some(idx), _) => {
+				tokens.splice(idx..idx, [element, lst::ArrayToken::Spacy(' ')]);
+			}
+			(None, Some(&idx)) => {
+				tokens.splice(idx + 1..idx + 1, [
+					lst::ArrayToken::Spacy(' '),
+					element,
+				]);
+			}
+			(None, None) => tokens.push(element),
+		}
+		return;
+	}
+
+	// multi-line array
+	let indent = elements
+		.iter()
+		.find_map(|&idx| {
+			let start = line_start(idx)?;
+			tokens[start..idx]
+				.iter()
+				.all(|token| matches!(token, lst::ArrayToken::Spacy(_)))
+				.then(|| tokens[start..idx].to_vec())
+		})
+		.unwrap_or_else(|| vec![lst::ArrayToken::Spacy('\t')]);
+	let mut line = indent.clone();
+	line.push(element);
+	if let Some(idx) = before {
+		match line_start(idx) {
+			Some(start) => {
+				line.push(lst::ArrayToken::Newline);
+				tokens.splice(start..start, line);
+			}
+			None => {
+				// the element is on the line of opening parenthesis
+				let mut line =
+					vec![line.pop().unwrap(), lst::ArrayToken::Newline];
+				line.extend(indent);
+				tokens.splice(idx..idx, line);
+			}
+		}
+		return;
+	}
+	let last = elements.last().copied().unwrap_or(0);
+	match tokens[last..]
+		.iter()
+		.position(|token| matches!(token, lst::ArrayToken::Newline))
+	{
+		Some(pos) => {
+			let pos = last + pos + 1;
+			line.push(lst::ArrayToken::Newline);
+			tokens.splice(pos..pos, line);
+		}
+		None => {
+			tokens.push(lst::ArrayToken::Newline);
+			tokens.append(&mut line);
+		}
+	}
+}
+
+/// Removes a element from array tokens, cleaning up its line.
+///
+/// If the element is alone on its line, the whole line is removed.
+/// Otherwise, spaces separating it from the neighbor are removed.
+fn remove_array_element(tokens: &mut Vec<lst::ArrayToken>, index: usize) {
+	let is_spacy =
+		|token: &lst::ArrayToken| matches!(token, lst::ArrayToken::Spacy(_));
+	let line_start = tokens[..index].iter().rposition(|token| !is_spacy(token));
+	let line_end = tokens[index + 1..]
+		.iter()
+		.position(|token| {
+			!is_spacy(token) && !matches!(token, lst::ArrayToken::Comment(_))
+		})
+		.map(|pos| index + 1 + pos);
+	let alone = line_start.zip(line_end).filter(|(start, end)| {
+		matches!(tokens[*start], lst::ArrayToken::Newline)
+			&& matches!(tokens[*end], lst::ArrayToken::Newline)
+	});
+	if let Some((start, end)) = alone {
+		tokens.drain(start + 1..=end);
+		return;
+	}
+	let mut end = index + 1;
+	while end < tokens.len() && is_spacy(&tokens[end]) {
+		end += 1;
+	}
+	if matches!(tokens.get(end), Some(lst::ArrayToken::Element(_))) {
+		tokens.drain(index..end);
+	} else {
+		let mut start = index;
+		while start > 0 && is_spacy(&tokens[start - 1]) {
+			start -= 1;
+		}
+		tokens.drain(start..=index);
+	}
+}
+
+/// Position to insert a array element at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayPosition {
+	/// Before the first element.
+	Start,
+	/// After the last element.
+	End,
+	/// Before the element at the given index.
+	///
+	/// If the index is out of bounds, the element is appended to the end.
+	Before(usize),
+}
+
+/// Options of formatter passes such as [`ApmlEditor::wrap_long_values`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FormatOptions {
+	/// Maximum width of lines in characters.
+	pub max_line_width: usize,
+	/// Maximum number of consecutive blank lines.
+	///
+	/// Longer runs of blank lines are collapsed, see
+	/// [`ApmlEditor::normalize_blank_lines`]. All blank lines are
+	/// preserved if this is [None].
+	pub max_consecutive_blanks: Option<usize>,
+}
+
+impl Default for FormatOptions {
+	fn default() -> Self {
+		Self {
+			max_line_width: 80,
+			max_consecutive_blanks: Some(1),
+		}
+	}
+}
+
+/// Rules of [`ApmlEditor::normalize_whitespace_with`].
+///
+/// All rules are enabled by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WhitespaceOptions {
+	/// Removes spaces at the end of lines.
+	///
+	/// See [`ApmlEditor::strip_trailing_whitespace`].
+	pub strip_trailing: bool,
+	/// Collapses three or more consecutive newlines into two.
+	pub collapse_blank_lines: bool,
+	/// Ensures non-empty files end with exactly one newline.
+	pub final_newline: bool,
+}
+
+impl Default for WhitespaceOptions {
+	fn default() -> Self {
+		Self {
+			strip_trailing: true,
+			collapse_blank_lines: true,
+			final_newline: true,
+		}
+	}
+}
+
+/// Description of a new variable definition for [`ApmlEditor::append_var`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendSpec<'a, 'c> {
+	/// Name of the variable.
+	pub name: &'a str,
+	/// Value of the variable.
+	pub value: ast::VariableValue<'a>,
+	/// Operator of the definition.
+	pub op: lst::VariableOp,
+	/// Comment following the definition on the same line.
+	pub inline_comment: Option<&'c str>,
+	/// Comment lines above the definition.
+	pub comments: Vec<&'c str>,
+	/// Variable to insert the definition after the line of.
+	///
+	/// The definition is appended to the end if this is [None] or
+	/// the variable is not found.
+	pub after: Option<&'c str>,
+}
+
+impl<'a> AppendSpec<'a, '_> {
+	/// Creates a spec of a assignment without comments, appended
+	/// to the end.
+	pub fn new(name: &'a str, value: ast::VariableValue<'a>) -> Self {
+		Self {
+			name,
+			value,
+			op: lst::VariableOp::Assignment,
+			inline_comment: None,
+			comments: Vec::new(),
+			after: None,
+		}
+	}
+}
+
+/// Conventional order of variables in `spec` files.
+pub const SPEC_KEY_ORDER: &[&str] = &[
+	"VER",
+	"REL",
+	"SRCS",
+	"CHKSUMS",
+	"CHKUPDATE",
+	"SUBDIR",
+	"DUMMYSRC",
+];
+
+/// Conventional order of variables in `defines` files.
+pub const DEFINES_KEY_ORDER: &[&str] = &[
+	"PKGNAME", "PKGSEC", "PKGDEP", "BUILDDEP", "PKGDES", "PKGRECOM", "PKGSUG",
+	"PKGPROV", "PKGREP", "PKGBREAK", "PKGCONFL", "PKGEPOCH",
+];
+
+/// Gets the name of the architecture-specific variable overriding
+/// a variable, like `VER__AMD64`.
+///
+/// Architecture names are uppercased with hyphens mapped to
+/// underscores.
+pub fn arch_var_name(base: &str, arch: &str) -> String {
+	format!("{base}__{}", arch.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Policies of [`ApmlEditor::sort_vars`] for variables not listed
+/// in the order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownKeyPolicy {
+	/// Moves unknown variables after all known variables.
+	End,
+	/// Keeps unknown variables at their positions.
+	Keep,
+}
+
+/// Policies of [`ApmlEditor::dedupe_vars`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DedupePolicy {
+	/// Keeps the first definition.
+	KeepFirst,
+	/// Keeps the last definition, which is the effective one in Bash.
+	#[default]
+	KeepLast,
+}
+
+/// Report of [`ApmlEditor::dedupe_vars`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DedupeReport<'a> {
+	/// Removed definitions with their line numbers, starting from 1,
+	/// in order of occurrence.
+	pub removed: Vec<(usize, lst::VariableDefinition<'a>)>,
+}
+
+/// Marks surrounding the title of section header comments.
+const SECTION_HEADER_MARK: &str = "===";
+
+/// Position to insert a section header at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionPosition<'a> {
+	/// Before the first definition of a variable.
+	Before(&'a str),
+	/// After the line of the first definition of a variable.
+	After(&'a str),
+	/// At the end of file.
+	AtEnd,
+}
+
+/// Merges default variable definitions into a LST.
+///
+/// Variables defined in `base` are kept as-is, and definitions of
+/// variables only defined in `defaults` are appended to the end,
+/// together with their inline comments.
+///
+/// See [`apply_defaults_with`] for more modes.
+pub fn apply_defaults<'a>(
+	base: &ApmlLst<'a>,
+	defaults: &ApmlLst<'a>,
+) -> ApmlLst<'a> {
+	apply_defaults_with(base, defaults, DefaultsMode::Merge)
+}
+
+/// Merges default variable definitions into a LST in the given mode.
+///
+/// See [`apply_defaults`].
+pub fn apply_defaults_with<'a>(
+	base: &ApmlLst<'a>,
+	defaults: &ApmlLst<'a>,
+	mode: DefaultsMode,
+) -> ApmlLst<'a> {
+	let mut result = match mode {
+		DefaultsMode::Merge => base.clone(),
+		DefaultsMode::MissingOnly => ApmlLst(Vec::new()),
+	};
+	let base = base
+		.0
+		.iter()
+		.filter_map(|token| match token {
+			lst::Token::Variable(def) => Some(def.name.as_ref()),
+			_ => None,
+		})
+		.collect::<HashSet<_>>();
+	let mut editor = ApmlEditor::wrap(&mut result);
+	for (idx, token) in defaults.0.iter().enumerate() {
+		let lst::Token::Variable(def) = token else {
+			continue;
+		};
+		if base.contains(def.name.as_ref()) {
+			continue;
+		}
+		editor.ensure_end_newline();
+		let line = definition_line(&defaults.0, idx);
+		editor.lst_tokens_mut().extend(line);
+	}
+	result
+}
+
+/// Strategies to resolve conflicting variables in [`ApmlLst::merge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergePolicy {
+	/// Keeps definitions in self, ignoring the other ones.
+	KeepSelf,
+	/// Replaces definitions in self with the other ones.
+	TakeOther,
+	/// Appends the other definitions after the ones in self.
+	///
+	/// This is useful for list-like variables. Assignments are converted
+	/// to appending (`+=`), and string values are prefixed with a space.
+	Append,
+}
+
+impl<'a> ApmlLst<'a> {
+	/// Merges definitions of another LST into this one.
+	///
+	/// Variables only defined in `other` are appended to the end, together
+	/// with their leading and inline comments. Conflicting variables are
+	/// resolved with the policy.
+	///
+	/// Appending definitions (`+=`) in `other` are deltas to the base
+	/// value, so they are always appended after the definitions in self
+	/// unless [`MergePolicy::KeepSelf`] is used. With
+	/// [`MergePolicy::TakeOther`], definitions in self are replaced only if
+	/// `other` assigns the variable, and appending definitions before the
+	/// last assignment in `other` are dropped.
+	///
+	/// See [`merge_with`][Self::merge_with] for per-variable policies.
+	pub fn merge(&mut self, other: &ApmlLst<'a>, policy: MergePolicy) {
+		self.merge_with(other, |_| policy)
+	}
+
+	/// Merges definitions of another LST into this one, choosing the
+	/// policy per conflicting variable.
+	///
+	/// See [`merge`][Self::merge].
+	pub fn merge_with<F>(&mut self, other: &ApmlLst<'a>, policy: F)
+	where
+		F: Fn(&str) -> MergePolicy,
+	{
+		let mut names = Vec::<&str>::new();
+		for token in &other.0 {
+			match token {
+				lst::Token::Variable(def)
+					if !names.contains(&def.name.as_ref()) =>
+				{
+					names.push(&def.name)
+				}
+				_ => {}
+			}
+		}
+		let mut editor = ApmlEditor::wrap(self);
+		checked!(editor, "merge_with", {
+			for name in names {
+				let defs = other
+					.0
+					.iter()
+					.enumerate()
+					.filter_map(|(idx, token)| match token {
+						lst::Token::Variable(def) if def.name == name => {
+							Some((idx, def))
+						}
+						_ => None,
+					})
+					.collect::<Vec<_>>();
+				let existing = editor.find_var_indexes(name);
+				let Some(&last) = existing.last() else {
+					editor.ensure_end_newline();
+					for (idx, _) in defs {
+						for comment in leading_comment_indexes(&other.0, idx) {
+							editor.lst_tokens_mut().extend([
+								other.0[comment].clone(),
+								lst::Token::Newline,
+							]);
+						}
+						let line = definition_line(&other.0, idx);
+						editor.lst_tokens_mut().extend(line);
+					}
+					continue;
+				};
+				let assignment = defs.iter().rposition(|(_, def)| {
+					def.op == lst::VariableOp::Assignment
+				});
+				match (policy(name), assignment) {
+					(MergePolicy::KeepSelf, _) => {}
+					(MergePolicy::TakeOther, Some(assignment)) => {
+						for &index in existing[1..].iter().rev() {
+							editor.remove_var(index);
+						}
+						let first = existing[0];
+						let (idx, def) = defs[assignment];
+						editor.lst_tokens_mut()[first] =
+							lst::Token::Variable(def.clone());
+						let mut line = definition_line(&other.0, idx);
+						// keep the inline comment in self if other has none
+						if line.len() > 2 {
+							let end = editor.line_end(first);
+							editor.lst_tokens_mut().drain(first + 1..end);
+							line.pop();
+							editor
+								.lst_tokens_mut()
+								.splice(first + 1..first + 1, line.drain(1..));
+						}
+						let lines = defs[assignment + 1..]
+							.iter()
+							.flat_map(|(idx, _)| {
+								definition_line(&other.0, *idx)
+							})
+							.collect::<Vec<_>>();
+						editor.insert_after_line(first, lines);
+					}
+					(policy, _) => {
+						let lines = defs
+							.iter()
+							.flat_map(|(idx, def)| {
+								let mut line = definition_line(&other.0, *idx);
+								if policy == MergePolicy::Append
+									&& def.op == lst::VariableOp::Assignment
+								{
+									line[0] =
+										lst::Token::Variable(to_append(def));
+								}
+								line
+							})
+							.collect::<Vec<_>>();
+						editor.insert_after_line(last, lines);
+					}
+				}
+			}
+		})
+	}
+}
+
+/// Gets tokens of the line of a variable definition.
+///
+/// The result includes the definition, the inline comment if any, and
+/// a trailing newline. Other definitions on the same line are excluded.
+fn definition_line<'a>(
+	tokens: &[lst::Token<'a>],
+	index: usize,
+) -> Vec<lst::Token<'a>> {
+	let mut result = vec![tokens[index].clone()];
+	let trailing = tokens[index + 1..]
+		.iter()
+		.take_while(|token| {
+			matches!(token, lst::Token::Spacy(_) | lst::Token::Comment(_))
+		})
+		.collect::<Vec<_>>();
+	// keep spaces only before inline comments
+	if matches!(trailing.last(), Some(lst::Token::Comment(_))) {
+		result.extend(trailing.into_iter().cloned());
+	}
+	result.push(lst::Token::Newline);
+	result
+}
+
+/// Splits tokens into ranges of lines, excluding newlines.
+///
+/// The last range is the line after the last newline, which may be empty.
+fn line_ranges(tokens: &[lst::Token]) -> Vec<Range<usize>> {
+	let mut lines = Vec::new();
+	let mut start = 0;
+	for (index, token) in tokens.iter().enumerate() {
+		if matches!(token, lst::Token::Newline) {
+			lines.push(start..index);
+			start = index + 1;
+		}
+	}
+	lines.push(start..tokens.len());
+	lines
+}
+
+/// Finds index ranges of space runs followed by newlines.
+///
+/// If `eof` is true, the space run at the end is included as well.
+fn trailing_space_runs<T>(
+	tokens: &[T],
+	is_spacy: impl Fn(&T) -> bool,
+	is_newline: impl Fn(&T) -> bool,
+	eof: bool,
+) -> Vec<Range<usize>> {
+	let mut result = Vec::new();
+	let mut start = None;
+	for (index, token) in tokens.iter().enumerate() {
+		if is_spacy(token) {
+			start.get_or_insert(index);
+		} else {
+			if let Some(start) = start.filter(|_| is_newline(token)) {
+				result.push(start..index);
+			}
+			start = None;
+		}
+	}
+	if let Some(start) = start.filter(|_| eof) {
+		result.push(start..tokens.len());
+	}
+	result
+}
+
+/// A piece of a list-like text, see [`wrap_list_text`].
+enum ListPiece<'a> {
+	/// Spaces separating elements.
+	Space(String),
+	/// A word in a element.
+	Word(lst::Word<'a>),
+}
+
+/// Wraps a double-quoted list-like text starting at the given column.
+///
+/// Returns [None] if the text is not list-like or needs no wrapping.
+fn wrap_list_text<'a>(
+	text: &lst::Text<'a>,
+	column: usize,
+	max_width: usize,
+) -> Option<lst::Text<'a>> {
+	let [lst::TextUnit::DoubleQuote(words)] = text.0.as_slice() else {
+		return None;
+	};
+	let pieces = list_pieces(words, false)?;
+
+	// group words into elements with the spaces before them
+	let mut elements = vec![(String::new(), Vec::new())];
+	for piece in pieces {
+		match piece {
+			ListPiece::Space(space) => elements.push((space, Vec::new())),
+			ListPiece::Word(word) => elements.last_mut().unwrap().1.push(word),
+		}
+	}
+
+	let mut result = Vec::<lst::Word>::new();
+	// opening quote
+	let mut column = column + 1;
+	let mut wrapped = false;
+	let last = elements.len() - 1;
+	for (index, (space, words)) in elements.into_iter().enumerate() {
+		let mut width = words
+			.iter()
+			.map(|word| word.to_string().chars().count())
+			.sum::<usize>();
+		if index == last {
+			// closing quote
+			width += 1;
+		}
+		let space_width = space.chars().count();
+		if index != 0
+			&& !words.is_empty()
+			&& column + space_width + width > max_width
+		{
+			push_literal_part(&mut result, lst::LiteralPart::LineContinuation);
+			column = 0;
+			wrapped = true;
+		}
+		column += space_width + width;
+		if !space.is_empty() {
+			push_literal_part(
+				&mut result,
+				lst::LiteralPart::String(space.into()),
+			);
+		}
+		push_words(&mut result, words);
+	}
+	wrapped.then(|| lst::Text(vec![lst::TextUnit::DoubleQuote(result)]))
+}
+
+/// Reflows a double-quoted list-like text starting at the given column.
+///
+/// Existing line continuations are removed first. Elements are then
+/// separated by a single space, or a line continuation followed by
+/// indentation aligned under the first character after the opening
+/// quote, whenever the line would exceed the width.
+///
+/// Returns [None] if the text is not list-like.
+fn reflow_list_text<'a>(
+	words: &[lst::Word<'a>],
+	column: usize,
+	max_width: usize,
+) -> Option<lst::Text<'a>> {
+	let mut elements = vec![(String::new(), Vec::new())];
+	for piece in list_pieces(words, true)? {
+		match piece {
+			ListPiece::Space(space) => elements.push((space, Vec::new())),
+			ListPiece::Word(word) => elements.last_mut().unwrap().1.push(word),
+		}
+	}
+
+	let mut result = Vec::<lst::Word>::new();
+	// opening quote
+	let indent = column + 1;
+	let mut column = indent;
+	let last = elements.len() - 1;
+	for (index, (mut space, words)) in elements.into_iter().enumerate() {
+		let mut width = words
+			.iter()
+			.map(|word| word.to_string().chars().count())
+			.sum::<usize>();
+		if index == last {
+			// closing quote
+			width += 1;
+		}
+		if index != 0 && !words.is_empty() {
+			if column > indent && column + 1 + width > max_width {
+				push_literal_part(
+					&mut result,
+					lst::LiteralPart::LineContinuation,
+				);
+				space = " ".repeat(indent);
+				column = 0;
+			} else {
+				space = " ".to_string();
+			}
+		}
+		column += space.chars().count() + width;
+		if !space.is_empty() {
+			push_literal_part(
+				&mut result,
+				lst::LiteralPart::String(space.into()),
+			);
+		}
+		push_words(&mut result, words);
+	}
+	Some(lst::Text(vec![lst::TextUnit::DoubleQuote(result)]))
+}
+
+/// Appends a literal part to words, merging into the last literal word
+/// and string.
+fn push_literal_part<'a>(
+	result: &mut Vec<lst::Word<'a>>,
+	part: lst::LiteralPart<'a>,
+) {
+	if let Some(lst::Word::Literal(parts)) = result.last_mut() {
+		if let (
+			Some(lst::LiteralPart::String(last)),
+			lst::LiteralPart::String(string),
+		) = (parts.last_mut(), &part)
+		{
+			last.to_mut().push_str(string);
+			return;
+		}
+		parts.push(part);
+	} else {
+		result.push(lst::Word::Literal(vec![part]));
+	}
+}
+
+/// Appends words, merging literal parts into the last literal word.
+fn push_words<'a>(result: &mut Vec<lst::Word<'a>>, words: Vec<lst::Word<'a>>) {
+	for word in words {
+		match word {
+			lst::Word::Literal(parts) => {
+				for part in parts {
+					push_literal_part(result, part);
+				}
+			}
+			_ => result.push(word),
+		}
+	}
+}
+
+/// Splits words of a list-like text into pieces.
+///
+/// With `unwrap`, line continuations are dropped and adjacent spaces
+/// are merged. Otherwise, [None] is returned if the text contains
+/// line continuations.
+/// Returns [None] if the text contains newlines between elements.
+fn list_pieces<'a>(
+	words: &[lst::Word<'a>],
+	unwrap: bool,
+) -> Option<Vec<ListPiece<'a>>> {
+	let mut pieces = Vec::new();
+	let push_space = |pieces: &mut Vec<ListPiece>, space: &str| {
+		if let Some(ListPiece::Space(last)) = pieces.last_mut() {
+			last.push_str(space);
+		} else {
+			pieces.push(ListPiece::Space(space.to_string()));
+		}
+	};
+	for word in words {
+		let lst::Word::Literal(parts) = word else {
+			pieces.push(ListPiece::Word(word.clone()));
+			continue;
+		};
+		for part in parts {
+			match part {
+				lst::LiteralPart::String(string) => {
+					let mut rest = string.as_ref();
+					while !rest.is_empty() {
+						let is_space = rest.starts_with(char::is_whitespace);
+						let len = rest
+							.find(|ch: char| ch.is_whitespace() != is_space)
+							.unwrap_or(rest.len());
+						let (run, next) = rest.split_at(len);
+						if !is_space {
+							pieces.push(ListPiece::Word(lst::Word::Literal(
+								vec![lst::LiteralPart::String(
+									run.to_string().into(),
+								)],
+							)));
+						} else if run.contains('\n') {
+							return None;
+						} else if unwrap {
+							push_space(&mut pieces, run);
+						} else {
+							pieces.push(ListPiece::Space(run.to_string()));
+						}
+						rest = next;
+					}
+				}
+				lst::LiteralPart::Escaped(_) => {
+					pieces.push(ListPiece::Word(lst::Word::Literal(vec![
+						part.clone(),
+					])))
+				}
+				lst::LiteralPart::LineContinuation if unwrap => {}
+				lst::LiteralPart::LineContinuation => return None,
+			}
+		}
+	}
+	Some(pieces)
+}
+
+/// Converts a assignment into appending.
+///
+/// String values are prefixed with a space to separate from the
+/// existing value.
+fn to_append<'a>(
+	def: &lst::VariableDefinition<'a>,
+) -> lst::VariableDefinition<'a> {
+	let mut def = def.clone();
+	def.op = lst::VariableOp::Append;
+	if let lst::VariableValue::String(text) = &mut def.value {
+		let text = Arc::make_mut(text);
+		match text.0.first_mut() {
+			Some(lst::TextUnit::DoubleQuote(words)) => words.insert(
+				0,
+				lst::Word::Literal(vec![lst::LiteralPart::String(" ".into())]),
+			),
+			Some(lst::TextUnit::SingleQuote(string)) => {
+				string.to_mut().insert(0, ' ')
+			}
+			_ => text.0.insert(
+				0,
+				lst::TextUnit::DoubleQuote(vec![lst::Word::Literal(vec![
+					lst::LiteralPart::String(" ".into()),
+				])]),
+			),
+		}
+	}
+	def
+}
+
+/// Modes of [`apply_defaults_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DefaultsMode {
+	/// Returns the base LST with missing definitions appended.
+	#[default]
+	Merge,
+	/// Returns only the missing definitions, which is useful for
+	/// previewing what would be added.
+	MissingOnly,
+}
+
+/// Returns indexes of comment tokens in the leading comment block
+/// of the token at the given index, in file order.
+///
+/// The leading comment block is made up of the contiguous comment-only
+/// lines immediately above the line of the token.
+fn leading_comment_indexes(tokens: &[lst::Token], index: usize) -> Vec<usize> {
+	let is_spacy = |pos: usize| matches!(tokens[pos], lst::Token::Spacy(_));
+	let mut result = Vec::new();
+	let mut pos = index;
+	while pos > 0 && is_spacy(pos - 1) {
+		pos -= 1;
+	}
+	while pos > 0 && matches!(tokens[pos - 1], lst::Token::Newline) {
+		let mut comment = pos - 1;
+		while comment > 0 && is_spacy(comment - 1) {
+			comment -= 1;
+		}
+		if comment == 0
+			|| !matches!(tokens[comment - 1], lst::Token::Comment(_))
+		{
+			break;
+		}
+		comment -= 1;
+		let mut line_start = comment;
+		while line_start > 0 && is_spacy(line_start - 1) {
+			line_start -= 1;
+		}
+		if line_start > 0
+			&& !matches!(tokens[line_start - 1], lst::Token::Newline)
+		{
+			// inline comment of the previous definition
+			break;
+		}
+		result.push(comment);
+		pos = line_start;
+	}
+	result.reverse();
+	result
+}
+
+/// Returns the source length of a optional declaration.
+fn declaration_len(declaration: &Option<lst::Declaration>) -> usize {
+	declaration
+		.as_ref()
+		.map_or(0, |declaration| declaration.to_string().len())
+}
+
+/// Detects the quoting style of a text made up of a single kind of units.
+fn quoting_style(text: &lst::Text) -> Option<ast::LowerStyle> {
+	let mut styles = text.0.iter().map(|unit| match unit {
+		lst::TextUnit::Unquoted(_) => ast::LowerStyle::Auto,
+		lst::TextUnit::SingleQuote(_) => ast::LowerStyle::SingleQuoted,
+		lst::TextUnit::DoubleQuote(_) => ast::LowerStyle::DoubleQuoted,
+	});
+	let first = styles.next()?;
+	styles.all(|style| style == first).then_some(first)
+}
+
+/// Lowers a AST text into a single text unit in the given style.
+///
+/// The name is only used in errors.
+fn requote_text<'a>(
+	name: &str,
+	text: &ast::Text<'a>,
+	style: QuoteStyle,
+) -> Result<lst::Text<'a>, RequoteError> {
+	let lst::TextUnit::DoubleQuote(words) = text.lower().0.pop().unwrap()
+	else {
+		unreachable!()
+	};
+	let unit = match style {
+		QuoteStyle::Unquoted => {
+			if ast::quote_analysis(text).reasons.iter().any(|reason| {
+				!matches!(
+					reason.kind,
+					ast::QuoteReasonKind::Expansion
+						| ast::QuoteReasonKind::Subcommand
+				)
+			}) {
+				return Err(RequoteError::NeedsQuoting(name.to_string()));
+			}
+			lst::TextUnit::Unquoted(words)
+		}
+		QuoteStyle::SingleQuoted => {
+			let Some(literal) = text.literal_value() else {
+				return Err(RequoteError::ContainsExpansion(name.to_string()));
+			};
+			if literal.contains('\'') {
+				return Err(RequoteError::ContainsSingleQuote(
+					name.to_string(),
+				));
+			}
+			lst::TextUnit::SingleQuote(literal.into_owned().into())
+		}
+		QuoteStyle::DoubleQuoted => lst::TextUnit::DoubleQuote(words),
+	};
+	Ok(lst::Text(vec![unit]))
+}
+
+/// Decides the quoting style used by
+/// [`ApmlEditor::canonicalize_quoting`].
+fn canonical_quote_style(text: &ast::Text) -> QuoteStyle {
+	match text.literal_value() {
+		Some(_) if !ast::quote_analysis(text).needs_quoting() => {
+			QuoteStyle::Unquoted
+		}
+		Some(literal)
+			if literal.contains(['$', '`']) && !literal.contains('\'') =>
+		{
+			QuoteStyle::SingleQuoted
+		}
+		_ => QuoteStyle::DoubleQuoted,
+	}
+}
+
+/// Returns if a text contains any line continuations.
+fn has_line_continuation(text: &lst::Text) -> bool {
+	text.0.iter().any(|unit| match unit {
+		lst::TextUnit::Unquoted(words) | lst::TextUnit::DoubleQuote(words) => {
+			words.iter().any(|word| {
+				matches!(word, lst::Word::Literal(parts)
+					if parts.contains(&lst::LiteralPart::LineContinuation))
+			})
+		}
+		lst::TextUnit::SingleQuote(_) => false,
+	})
+}
+
+/// Substitutes `@key@` placeholders in literal strings of a LST text,
+/// see [`ApmlEditor::instantiate_template`].
+///
+/// Returns the substituted text if any placeholder is substituted.
+fn substitute_text_placeholders<'a>(
+	text: &lst::Text<'a>,
+	values: &HashMap<&str, &str>,
+	unresolved: &mut Vec<String>,
+) -> Option<lst::Text<'a>> {
+	let mut changed = false;
+	let mut units = Vec::with_capacity(text.0.len());
+	for unit in &text.0 {
+		match unit {
+			lst::TextUnit::SingleQuote(string) => {
+				let pieces =
+					ast::split_placeholders(string, '@', values, unresolved);
+				if !has_placeholder_value(&pieces) {
+					units.push(unit.clone());
+					continue;
+				}
+				changed = true;
+				// single quotes in values are written as `'\''`
+				let string = pieces
+					.iter()
+					.map(ast::Placeholder::as_str)
+					.collect::<String>();
+				let mut parts = string.split('\'');
+				units.push(lst::TextUnit::SingleQuote(
+					parts.next().unwrap_or_default().to_string().into(),
+				));
+				for part in parts {
+					units.push(lst::TextUnit::Unquoted(vec![
+						lst::Word::Literal(vec![lst::LiteralPart::Escaped(
+							'\'',
+						)]),
+					]));
+					units.push(lst::TextUnit::SingleQuote(
+						part.to_string().into(),
+					));
+				}
+			}
+			lst::TextUnit::DoubleQuote(words) => {
+				let mut result = Vec::with_capacity(words.len());
+				changed |= substitute_words_placeholders(
+					words,
+					values,
+					unresolved,
+					&mut result,
+					None,
+				);
+				units.push(lst::TextUnit::DoubleQuote(result));
+			}
+			lst::TextUnit::Unquoted(words) => {
+				let mut result = Vec::with_capacity(words.len());
+				changed |= substitute_words_placeholders(
+					words,
+					values,
+					unresolved,
+					&mut result,
+					Some(&mut units),
+				);
+				if !result.is_empty() {
+					units.push(lst::TextUnit::Unquoted(result));
+				}
+			}
+		}
+	}
+	changed.then_some(lst::Text(units))
+}
+
+/// Substitutes `@key@` placeholders in literal strings of words.
+///
+/// For unquoted words, `units` is given and values which cannot be
+/// written unquoted are pushed to it as double-quoted units, after the
+/// preceding words in `result`.
+///
+/// Returns if any placeholder is substituted.
+fn substitute_words_placeholders<'a>(
+	words: &[lst::Word<'a>],
+	values: &HashMap<&str, &str>,
+	unresolved: &mut Vec<String>,
+	result: &mut Vec<lst::Word<'a>>,
+	mut units: Option<&mut Vec<lst::TextUnit<'a>>>,
+) -> bool {
+	let mut changed = false;
+	for word in words {
+		let lst::Word::Literal(parts) = word else {
+			result.push(word.clone());
+			continue;
+		};
+		for part in parts {
+			let lst::LiteralPart::String(string) = part else {
+				push_literal_part(result, part.clone());
+				continue;
+			};
+			let pieces =
+				ast::split_placeholders(string, '@', values, unresolved);
+			if !has_placeholder_value(&pieces) {
+				push_literal_part(result, part.clone());
+				continue;
+			}
+			changed = true;
+			for piece in pieces {
+				match (piece, &mut units) {
+					(ast::Placeholder::Text(text), _) => push_literal_part(
+						result,
+						lst::LiteralPart::String(text.to_string().into()),
+					),
+					(ast::Placeholder::Value(value), None) => {
+						for part in escape_double_quoted(value) {
+							push_literal_part(result, part);
+						}
+					}
+					(ast::Placeholder::Value(value), Some(units)) => {
+						if ast::quote_analysis(&value.to_string().into())
+							.reasons
+							.iter()
+							.all(|reason| {
+								reason.kind == ast::QuoteReasonKind::Empty
+							}) {
+							push_literal_part(
+								result,
+								lst::LiteralPart::String(
+									value.to_string().into(),
+								),
+							);
+						} else {
+							if !result.is_empty() {
+								units.push(lst::TextUnit::Unquoted(
+									std::mem::take(result),
+								));
+							}
+							units.push(lst::TextUnit::DoubleQuote(vec![
+								lst::Word::Literal(escape_double_quoted(value)),
+							]));
+						}
+					}
+				}
+			}
+		}
+	}
+	changed
+}
+
+/// Returns if any placeholder in pieces has a value.
+fn has_placeholder_value(pieces: &[ast::Placeholder]) -> bool {
+	pieces
+		.iter()
+		.any(|piece| matches!(piece, ast::Placeholder::Value(_)))
+}
+
+/// Escapes a string for double quotes.
+///
+/// Unlike [`lst::LiteralPart::escape`], backticks are escaped as well.
+fn escape_double_quoted(text: &str) -> Vec<lst::LiteralPart<'static>> {
+	let mut result = Vec::new();
+	for part in text.split_inclusive('`') {
+		match part.strip_suffix('`') {
+			Some(part) => {
+				result.extend(lst::LiteralPart::escape(part));
+				result.push(lst::LiteralPart::Escaped('`'));
+			}
+			None => result.extend(lst::LiteralPart::escape(part)),
+		}
+	}
+	result
+}
+
+/// Returns if a text contains unquoted expansions or glob characters,
+/// which are subject to word splitting and pathname expansion in arrays.
+fn has_unquoted_expansion(text: &lst::Text) -> bool {
+	text.0.iter().any(|unit| match unit {
+		lst::TextUnit::Unquoted(words) => words.iter().any(|word| match word {
+			lst::Word::Literal(parts) => parts.iter().any(|part| {
+				matches!(part, lst::LiteralPart::String(text)
+					if text.contains(['*', '?', '[', '{']))
+			}),
+			_ => true,
+		}),
+		lst::TextUnit::SingleQuote(_) | lst::TextUnit::DoubleQuote(_) => false,
+	})
+}
+
+/// Folds a desugared appending definition into the previous value.
+///
+/// See [`ApmlEditor::get_var_ast`].
+fn fold_append<'a>(
+	name: &str,
+	prev: ast::VariableValue<'a>,
+	value: ast::VariableValue<'a>,
+) -> ast::VariableValue<'a> {
+	let (mut prev, rest) = match (prev, value) {
+		(
+			ast::VariableValue::String(prev),
+			ast::VariableValue::String(mut text),
+		) => {
+			let rest =
+				ast::VariableValue::String(ast::Text(text.0.split_off(1)));
+			(ast::VariableValue::String(prev), rest)
+		}
+		(prev, ast::VariableValue::Array(mut elements)) => {
+			let rest = ast::VariableValue::Array(elements.split_off(1));
+			let prev = match prev {
+				ast::VariableValue::String(text) => ast::VariableValue::Array(
+					vec![ast::ArrayElement::Text(Arc::new(text))],
+				),
+				prev => prev,
+			};
+			(prev, rest)
+		}
+		(_, value) => return value,
+	};
+	if ast::referenced_vars(&rest).contains(&name) {
+		return match rest {
+			ast::VariableValue::String(mut text) => {
+				text.0.insert(
+					0,
+					ast::Word::Variable(ast::VariableExpansion {
+						name: Cow::Owned(name.to_string()),
+						modifier: None,
+					}),
+				);
+				ast::VariableValue::String(text)
+			}
+			ast::VariableValue::Array(mut elements) => {
+				elements.insert(
+					0,
+					ast::ArrayElement::ArrayInclusion(Cow::Owned(
+						name.to_string(),
+					)),
+				);
+				ast::VariableValue::Array(elements)
+			}
+		};
+	}
+	match (&mut prev, rest) {
+		(
+			ast::VariableValue::String(prev),
+			ast::VariableValue::String(text),
+		) => {
+			for word in text.0 {
+				match (prev.0.last_mut(), word) {
+					(
+						Some(ast::Word::Literal(last)),
+						ast::Word::Literal(literal),
+					) => last.to_mut().push_str(&literal),
+					(_, word) => prev.0.push(word),
+				}
+			}
+		}
+		(
+			ast::VariableValue::Array(prev),
+			ast::VariableValue::Array(elements),
+		) => prev.extend(elements),
+		_ => unreachable!(),
+	}
+	prev
+}
+
+/// Asserts that a variable definition is well-formed in debug builds.
+fn debug_assert_valid(name: &str, value: &ast::VariableValue) {
+	debug_assert_eq!(
+		ast::VariableDefinition {
+			name: name.into(),
+			value: value.clone(),
+		}
+		.validate(),
+		Ok(())
+	);
+}
+
+/// Errors produced by editors.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EditError {
+	#[error("Invalid variable name: {0:?}")]
+	InvalidName(String),
+	#[error("Invalid variable value: {0}")]
+	InvalidValue(ast::AstError),
+	#[error("Variable {0} is already defined")]
+	DuplicateName(String),
+	#[error("Variable {0} is not a string")]
+	NotString(String),
+	#[error("Variable {0} is not a array")]
+	NotArray(String),
+	#[error("Index {index} is out of range of {name}")]
+	IndexOutOfRange { name: String, index: usize },
+	#[error("Edited LST is invalid: {0}")]
+	InvalidResult(RoundTripError),
+	#[error("Edited LST violates invariants: {0}")]
+	Invariant(InvariantError),
+}
+
+/// A variable definition removed by [`ApmlEditor::remove_var`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedVar<'a> {
+	/// The removed definition.
+	pub def: lst::VariableDefinition<'a>,
+	/// All removed tokens, including the stripped leading comment lines
+	/// and inline comment, without the final newline.
+	pub tokens: Vec<lst::Token<'a>>,
 }
 
-#[cfg(test)]
-mod test {
-	use crate::apml::lst::ApmlLst;
+/// Target quoting styles of [`ApmlEditor::requote_var`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuoteStyle {
+	/// No quotes (`a`).
+	Unquoted,
+	/// Single quotes (`'a'`).
+	SingleQuoted,
+	/// Double quotes (`"a"`).
+	DoubleQuoted,
+}
+
+/// Errors produced by [`ApmlEditor::reflow_var`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReflowError {
+	#[error("Variable {0} is undefined")]
+	Undefined(String),
+	#[error("Variable {0} is not a string")]
+	NotString(String),
+	#[error("Value of {0} is single-quoted and cannot be reflowed")]
+	SingleQuoted(String),
+	#[error("Value of {0} is not a list of words")]
+	NotList(String),
+}
+
+/// Errors produced by [`ApmlEditor::requote_var`].
+#[derive(Debug, Error)]
+pub enum RequoteError {
+	#[error("Variable {0} is undefined")]
+	Undefined(String),
+	#[error("Variable {0} is not a string")]
+	NotString(String),
+	#[error("Value of {0} cannot be unquoted")]
+	NeedsQuoting(String),
+	#[error("Value of {0} contains expansions and cannot be single-quoted")]
+	ContainsExpansion(String),
+	#[error("Value of {0} contains single quotes and cannot be single-quoted")]
+	ContainsSingleQuote(String),
+	#[error(transparent)]
+	Emit(#[from] ast::EmitError),
+}
+
+/// A snapshot of a LST taken by [`ApmlEditor::checkpoint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint<'a>(Vec<lst::Token<'a>>);
+
+/// Result of [`ApmlEditor::batch_replace`].
+pub type BatchReplaceResult<'a> = Result<BatchReplacement<'a>, EditError>;
+
+/// Report of a successful [`ApmlEditor::batch_replace`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BatchReplacement<'a> {
+	/// Names of variables which are newly added.
+	pub inserted: Vec<&'a str>,
+	/// Names of variables which are replaced.
+	pub replaced: Vec<&'a str>,
+}
+
+/// Violations of LST invariants found by [`ApmlEditor::validate`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InvariantError {
+	#[error("comment at token {0} is not terminated by a newline")]
+	UnterminatedComment(usize),
+	#[error(
+		"variable definition at token {0} is not separated from the previous one"
+	)]
+	AdjacentDefinitions(usize),
+	#[error("serialized LST is parsed differently from token {0}")]
+	Reparsed(usize),
+	#[error("serialized LST is unparsable at token {0}")]
+	Unparsable(usize),
+}
+
+/// A directive found in a LST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveRef<'a> {
+	/// Index of the comment token.
+	pub index: usize,
+	/// The parsed directive.
+	pub directive: Directive<'a>,
+	/// Name of the variable which the directive is attached to.
+	pub variable: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod test {
+	use crate::apml::lst::ApmlLst;
+
+	use super::*;
+
+	#[test]
+	fn test() {
+		let mut lst = ApmlLst::parse("a=b\nb=c\nc=\"$1\"").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.lst_tokens_iter().count(), 5);
+		assert_eq!(editor.lst_tokens_mut().len(), 5);
+		assert_eq!(editor.ast_variables().unwrap().len(), 3);
+		assert_eq!(editor.keys().count(), 3);
+		assert_eq!(editor.find_var("a").unwrap().0, 0);
+		assert_eq!(editor.find_var("a").unwrap().1.name, "a");
+		assert_eq!(editor.find_var("b").unwrap().0, 2);
+		assert_eq!(editor.find_var_index("b").unwrap(), 2);
+		assert!(editor.find_var("A").is_none());
+		if let lst::Token::Variable(var) = editor.find_var_token("b").unwrap() {
+			assert_eq!(var.name, "b");
+		} else {
+			unreachable!();
+		}
+		assert!(editor.find_var_token("A").is_none());
+		assert!(editor.find_var_index("A").is_none());
+	}
+
+	#[test]
+	fn test_arch_vars() {
+		assert_eq!(arch_var_name("VER", "amd64"), "VER__AMD64");
+		assert_eq!(arch_var_name("PKGDEP", "mips64r6el"), "PKGDEP__MIPS64R6EL");
+		assert_eq!(arch_var_name("A", "loongson-3"), "A__LOONGSON_3");
+
+		let src = "VER=1\nVER__ARM64=2\nPKGDEP=a\nVER__LOONGSON_3=3\n\
+				   VER__ARM64=4\nVER_X=5\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let value = |base, arch| {
+			editor
+				.get_var_for_arch(base, arch)
+				.map(|(_, def)| def.value.to_string())
+		};
+		assert_eq!(value("VER", "arm64").as_deref(), Some("4"));
+		assert_eq!(value("VER", "loongson-3").as_deref(), Some("3"));
+		assert_eq!(value("VER", "amd64").as_deref(), Some("1"));
+		assert_eq!(value("PKGDEP", "arm64").as_deref(), Some("a"));
+		assert_eq!(value("SRCS", "arm64"), None);
+		assert_eq!(editor.arch_variants_of("VER"), ["arm64", "loongson-3"]);
+		assert!(editor.arch_variants_of("PKGDEP").is_empty());
+
+		let string =
+			|text: &'static str| ast::VariableValue::String(text.into());
+		editor.set_var_for_arch("VER", "arm64", &string("5"));
+		editor.set_var_for_arch("PKGDEP", "loongson-3", &string("b"));
+		editor.set_var_for_arch("SRCS", "amd64", &string("c"));
+		assert_eq!(
+			lst.to_string(),
+			"VER=1\nVER__ARM64=2\nPKGDEP=a\nPKGDEP__LOONGSON_3=\"b\"\n\
+			 VER__LOONGSON_3=3\nVER__ARM64=\"5\"\nVER_X=5\nSRCS__AMD64=\"c\"\n"
+		);
+	}
+
+	#[test]
+	fn test_vars_with_prefix() {
+		let mut lst = ApmlLst::parse(
+			"PKGNAME=a\nPKGDES=b\nVER=1\nPKGDEP=c\nPKGDEP+=\" d\"\nAPKG=e\n",
+		)
+		.unwrap();
+		let editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor
+				.vars_with_prefix("PKGDE")
+				.map(|var| var.value.to_string())
+				.collect::<Vec<_>>(),
+			["b", "c", "\" d\""]
+		);
+		assert_eq!(editor.keys_with_prefix("PKG").collect::<Vec<_>>(), [
+			"PKGNAME", "PKGDES", "PKGDEP", "PKGDEP"
+		]);
+		assert_eq!(editor.keys_with_prefix("").count(), 6);
+		assert_eq!(editor.keys_with_prefix("A").collect::<Vec<_>>(), ["APKG"]);
+		assert_eq!(editor.keys_with_prefix("X").count(), 0);
+	}
+
+	#[test]
+	fn test_ensure_end_newline() {
+		let mut lst = ApmlLst::parse("a=b").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.ensure_end_newline();
+		assert_eq!(lst.to_string(), "a=b\n");
+	}
+
+	#[test]
+	fn test_append_variable() {
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.append_var_ast(
+			"c",
+			&ast::VariableValue::String("a".into()),
+			None,
+		);
+		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.append_var_ast(
+			"c",
+			&ast::VariableValue::String("a".into()),
+			Some("a"),
+		);
+		assert_eq!(lst.to_string(), "a=b\nc=\"a\"\nb=c");
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.append_var_ast(
+			"c",
+			&ast::VariableValue::String("a".into()),
+			Some("b"),
+		);
+		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.append_var_ast(
+			"c",
+			&ast::VariableValue::String("a".into()),
+			Some("eee"),
+		);
+		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
+	}
+
+	#[test]
+	fn test_add_var_with_comment() {
+		let value = ast::VariableValue::String("1".into());
+		let mut lst = ApmlLst::parse("A=1").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.add_var_with_comment("B", &value, "  the b ", None);
+		assert_eq!(lst.0[1..], [
+			lst::Token::Newline,
+			lst::Token::Comment(" the b".into()),
+			lst::Token::Newline,
+			lst::Token::Variable(lst::VariableDefinition {
+				declaration: None,
+				name: "B".into(),
+				op: lst::VariableOp::Assignment,
+				value: value.lower(),
+			}),
+			lst::Token::Newline,
+		]);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.add_var_with_comment("C", &value, "c", Some("A"));
+		editor.add_var_with_comment("D", &value, "", Some("A"));
+		editor.add_var_with_comment("E", &value, "e", Some("F"));
+		assert_eq!(
+			lst.to_string(),
+			"A=1\nD=\"1\"\n# c\nC=\"1\"\n# the b\nB=\"1\"\n# e\nE=\"1\"\n"
+		);
+	}
+
+	#[test]
+	fn test_append_var() {
+		let mut lst = ApmlLst::parse("A=1\nB=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.append_var(AppendSpec {
+			op: lst::VariableOp::Append,
+			inline_comment: Some(" extra "),
+			comments: vec!["more deps", ""],
+			after: Some("A"),
+			..AppendSpec::new(
+				"PKGDEP",
+				ast::VariableValue::Array(vec![ast::ArrayElement::Text(
+					Arc::new(ast::Text(vec![ast::Word::Literal("a".into())])),
+				)]),
+			)
+		});
+		editor.append_var(AppendSpec {
+			inline_comment: Some("c"),
+			..AppendSpec::new("C", ast::VariableValue::String("3".into()))
+		});
+		assert_eq!(
+			lst.to_string(),
+			"A=1\n# more deps\n#\nPKGDEP+=(\"a\") # extra\nB=2\nC=\"3\" # c\n"
+		);
+		ApmlLst::parse(&lst.to_string()).unwrap();
+	}
+
+	#[test]
+	fn test_replace_variable() {
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_ast("c", &ast::VariableValue::String("a".into()));
+		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
+		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_ast("a", &ast::VariableValue::String("a".into()));
+		assert_eq!(lst.to_string(), "a=\"a\"\nb=c");
+
+		let src = "PKGDEP=\"a\"\nPKGDEP+=\" b\" # extra\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor
+			.replace_var_ast("PKGDEP", &ast::VariableValue::String("c".into()));
+		assert_eq!(lst.to_string(), "PKGDEP=\"a\"\nPKGDEP+=\"c\" # extra\n");
+		let mut lst = ApmlLst::parse("PKGDEP+=\" b\" # extra\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_ast(
+			"PKGDEP",
+			&ast::VariableValue::String(" c".into()),
+		);
+		assert_eq!(lst.to_string(), "PKGDEP+=\" c\" # extra\n");
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_with_op(
+			"PKGDEP",
+			lst::VariableOp::Assignment,
+			&ast::VariableValue::String("c".into()),
+		);
+		assert_eq!(lst.to_string(), "PKGDEP=\"c\" # extra\n");
+
+		let mut lst =
+			ApmlLst::parse("export A=1\ndeclare -a B=(x)\nC=1\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_ast("A", &ast::VariableValue::String("c".into()));
+		editor.replace_var_ast("B", &ast::VariableValue::String("c".into()));
+		editor.set_var_string("C", "c");
+		assert_eq!(
+			lst.to_string(),
+			"export A=\"c\"\ndeclare -a B=\"c\"\nC=c\n"
+		);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.set_var_string("A", "d");
+		assert_eq!(editor.get_var_lst("A").unwrap().to_string(), "d");
+		assert_eq!(lst.to_string(), "export A=d\ndeclare -a B=\"c\"\nC=c\n");
+	}
+
+	#[test]
+	fn test_replace_var_preserving_style() {
+		let mut lst = ApmlLst::parse(
+			"VER=1.2.3\nA='a'\nB=\"b\"\nC=c\"c\"\nD=d\\\nd\nE=(e)\n",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let string =
+			|text: &'static str| ast::VariableValue::String(text.into());
+		editor.replace_var_preserving_style("VER", &string("1.2.4"));
+		editor.replace_var_preserving_style("A", &string("a b"));
+		editor.replace_var_preserving_style("B", &string("b2"));
+		editor.replace_var_preserving_style("C", &string("c2"));
+		editor.replace_var_preserving_style("D", &string("d d"));
+		editor.replace_var_preserving_style("E", &string("e"));
+		editor.replace_var_preserving_style("F", &string("f"));
+		assert_eq!(
+			lst.to_string(),
+			"VER=1.2.4\nA='a b'\nB=\"b2\"\nC=\"c2\"\nD=\"d d\"\nE=\"e\"\n\
+			 F=\"f\"\n"
+		);
+
+		let mut lst = ApmlLst::parse("A=a\nB='b'\nC=c\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.replace_var_preserving_style("A", &string("a'"));
+		editor.replace_var_preserving_style("B", &string("it's"));
+		let expansion =
+			ast::VariableValue::String(ast::Text(vec![ast::Word::Variable(
+				ast::VariableExpansion {
+					name: "A".into(),
+					modifier: None,
+				},
+			)]));
+		editor.replace_var_preserving_style("C", &expansion);
+		assert_eq!(lst.to_string(), "A=\"a'\"\nB=\"it's\"\nC=\"${A}\"\n");
+	}
+
+	#[test]
+	fn test_set_var_string() {
+		let mut lst = ApmlLst::parse("REL=1\nPKGDES=a").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.set_var_string("REL", "2");
+		editor.set_var_string("PKGDES", "A tool to do $things");
+		editor.set_var_string("A", "it's \"ok\"");
+		editor.set_var_string("B", "");
+		editor.set_var_string_with("C", "a b", &ast::LowerStyle::SingleQuoted);
+		editor.set_var_string_with("D", "a'b", &ast::LowerStyle::SingleQuoted);
+		editor.set_var_string_with("E", "a", &ast::LowerStyle::DoubleQuoted);
+		assert_eq!(
+			lst.to_string(),
+			"REL=2\nPKGDES=\"A tool to do \\$things\"\nA=\"it's \\\"ok\\\"\"\n\
+			 B=\"\"\nC='a b'\nD=\"a'b\"\nE=\"a\"\n"
+		);
+		let ctx = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		assert_eq!(ctx["PKGDES"], "A tool to do $things");
+		assert_eq!(ctx["A"], "it's \"ok\"");
+		assert_eq!(ctx["D"], "a'b");
+	}
+
+	#[test]
+	fn test_section_header() {
+		let mut lst = ApmlLst::parse("A=1\n# b\nB=2\nC=3").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(
+			editor.insert_section_header("Head", SectionPosition::Before("A"))
+		);
+		assert!(
+			editor.insert_section_header("Bs", SectionPosition::Before("B"))
+		);
+		assert!(
+			editor.insert_section_header("Cs", SectionPosition::After("B"))
+		);
+		assert!(
+			editor.insert_section_header("End", SectionPosition::After("C"))
+		);
+		assert!(
+			!editor.insert_section_header("X", SectionPosition::Before("D"))
+		);
+		assert_eq!(editor.section_header_names(), vec![
+			"Head", "Bs", "Cs", "End"
+		]);
+		assert_eq!(
+			lst.to_string(),
+			"# === Head ===\n\nA=1\n\n# === Bs ===\n\n# b\nB=2\n\n\
+			 # === Cs ===\n\nC=3\n\n# === End ===\n"
+		);
+		let mut lst = ApmlLst::parse("A=1\n\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.insert_section_header("End", SectionPosition::AtEnd);
+		assert_eq!(lst.to_string(), "A=1\n\n# === End ===\n");
+		let mut lst = ApmlLst::parse("").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.insert_section_header("End", SectionPosition::AtEnd);
+		assert_eq!(lst.to_string(), "# === End ===\n");
+	}
+
+	#[test]
+	fn test_requote_var() {
+		let src = "A='1.2.3'\nB=\"foo bar\"\nC=\"no-expansions\"\nD=${A}x\nE=\"it's\"\nF=(a)\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let before = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.requote_var("A", QuoteStyle::Unquoted).unwrap();
+		editor.requote_var("C", QuoteStyle::Unquoted).unwrap();
+		editor.requote_var("D", QuoteStyle::DoubleQuoted).unwrap();
+		assert!(matches!(
+			editor.requote_var("B", QuoteStyle::Unquoted),
+			Err(RequoteError::NeedsQuoting(_))
+		));
+		assert!(matches!(
+			editor.requote_var("D", QuoteStyle::SingleQuoted),
+			Err(RequoteError::ContainsExpansion(_))
+		));
+		assert!(matches!(
+			editor.requote_var("E", QuoteStyle::SingleQuoted),
+			Err(RequoteError::ContainsSingleQuote(_))
+		));
+		assert!(matches!(
+			editor.requote_var("F", QuoteStyle::Unquoted),
+			Err(RequoteError::NotString(_))
+		));
+		assert!(matches!(
+			editor.requote_var("G", QuoteStyle::Unquoted),
+			Err(RequoteError::Undefined(_))
+		));
+		assert_eq!(
+			lst.to_string(),
+			"A=1.2.3\nB=\"foo bar\"\nC=no-expansions\nD=\"${A}x\"\nE=\"it's\"\nF=(a)\n"
+		);
+		assert_eq!(crate::apml::ApmlContext::eval_lst(&lst).unwrap(), before);
+
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.requote_var("D", QuoteStyle::Unquoted).unwrap();
+		editor.requote_var("B", QuoteStyle::SingleQuoted).unwrap();
+		editor.requote_var("A", QuoteStyle::DoubleQuoted).unwrap();
+		editor.requote_var("E", QuoteStyle::DoubleQuoted).unwrap();
+		assert_eq!(
+			lst.to_string(),
+			"A=\"1.2.3\"\nB='foo bar'\nC=no-expansions\nD=${A}x\nE=\"it's\"\nF=(a)\n"
+		);
+		assert_eq!(crate::apml::ApmlContext::eval_lst(&lst).unwrap(), before);
+	}
+
+	#[test]
+	fn test_canonicalize_quoting() {
+		let src = "A='1.2'\nB=\"a b\"\nC='a b'\nD='$HOME'\nE=\"\\$x'\"\nF=$A\nG=(\"a\" 'b c' $A)\nH=\"a \\\n b\"\nI=\"\"\nJ=x\"y\"\nK=(*.c 'x' a${A}b)\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let before = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.canonicalize_quoting(), 7);
+		assert_eq!(editor.canonicalize_quoting(), 0);
+		assert_eq!(
+			lst.to_string(),
+			"A=1.2\nB=\"a b\"\nC=\"a b\"\nD='$HOME'\nE=\"\\$x'\"\nF=\"${A}\"\nG=(a \"b c\" $A)\nH=\"a \\\n b\"\nI=\"\"\nJ=xy\nK=(*.c x a${A}b)\n"
+		);
+		assert_eq!(crate::apml::ApmlContext::eval_lst(&lst).unwrap(), before);
+	}
+
+	#[test]
+	fn test_reflow_var() {
+		let deps = (0..43).map(|i| format!("dep-{i:02}")).collect::<Vec<_>>();
+		let src =
+			format!("PKGDEP=\"{}\"\nA='a b'\nB=(a)\nC=a\n", deps.join(" "));
+		assert_eq!(src.lines().next().unwrap().len(), 309);
+		let mut lst = ApmlLst::parse(&src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.reflow_var("PKGDEP", 80), Ok(true));
+		assert_eq!(editor.reflow_var("PKGDEP", 80), Ok(false));
+		let reflowed = lst.to_string();
+		let lines = reflowed
+			.lines()
+			.take_while(|line| !line.starts_with("A="))
+			.collect::<Vec<_>>();
+		assert_eq!(lines.len(), 5);
+		assert!(lines.iter().all(|line| line.len() <= 80));
+		assert!(
+			lines[1..]
+				.iter()
+				.all(|line| line.starts_with("        dep-"))
+		);
+		let value = crate::apml::ApmlContext::eval_lst(&lst)
+			.unwrap()
+			.read("PKGDEP")
+			.into_string();
+		assert_eq!(value.split_whitespace().collect::<Vec<_>>(), deps);
+		assert_eq!(ApmlLst::parse(&reflowed).unwrap(), lst);
+
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.reflow_var("PKGDEP", 1000), Ok(true));
+		assert_eq!(lst.to_string(), src);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.reflow_var("PKGDEP", 80), Ok(true));
+		assert_eq!(lst.to_string(), reflowed);
+
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.reflow_var("A", 3),
+			Err(ReflowError::SingleQuoted("A".into()))
+		);
+		assert_eq!(
+			editor.reflow_var("B", 3),
+			Err(ReflowError::NotString("B".into()))
+		);
+		assert_eq!(editor.reflow_var("C", 3), Ok(false));
+		assert_eq!(
+			editor.reflow_var("D", 3),
+			Err(ReflowError::Undefined("D".into()))
+		);
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_append_word() {
+		let mut lst = ApmlLst::parse(
+			"A=\"a \\\n  $b\"\nB='a'\nC=a\nD=\nE=()\nA+=\"\"\nF=\"\"",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.append_word("A", "c$", false), Ok(true));
+		assert_eq!(editor.append_word("B", "b", false), Ok(true));
+		assert_eq!(editor.append_word("B", "a", true), Ok(false));
+		assert_eq!(editor.append_word("B", "b'", true), Ok(true));
+		assert_eq!(editor.append_word("C", "b", true), Ok(true));
+		assert_eq!(editor.append_word("D", "d", true), Ok(true));
+		assert_eq!(editor.append_word("F", "f", true), Ok(true));
+		assert_eq!(editor.append_word("G", "g", true), Ok(true));
+		assert_eq!(
+			editor.append_word("E", "e", false),
+			Err(EditError::NotString("E".into()))
+		);
+		assert_eq!(
+			lst.to_string(),
+			"A=\"a \\\n  $b\"\nB='a b'\" b'\"\nC=a\" b\"\nD=d\nE=()\n\
+			 A+=\" c\\$\"\nF=\"f\"\nG=g\n"
+		);
+
+		let mut lst = ApmlLst::parse("A=\"$b\"\nA+=\" $b c\"").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.append_word("A", "$b", true), Ok(true));
+		assert_eq!(editor.append_word("A", "c", true), Ok(false));
+
+		let mut lst = ApmlLst::parse(
+			"B=\"a b\"\nA=\"$B c\\ d\"\nC=x\\ y\nD=\"${E:?}\"\nF=${E:?}\n",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.append_word("A", "a", true), Ok(false));
+		assert_eq!(editor.append_word("A", "c\\", true), Ok(false));
+		assert_eq!(editor.append_word("A", "c", true), Ok(true));
+		assert_eq!(editor.append_word("C", "y", true), Ok(false));
+		assert_eq!(editor.append_word("D", "e", true), Ok(true));
+		assert_eq!(editor.append_word("D", "e", true), Ok(false));
+		assert_eq!(
+			lst.to_string(),
+			"B=\"a b\"\nA=\"$B c\\ d c\"\nC=x\\ y\nD=\"${E:?} e\"\n\
+			 F=${E:?}\n"
+		);
+	}
+
+	#[test]
+	fn test_remove_word() {
+		let mut lst = ApmlLst::parse(
+			"A=\"a b \\\n  c\"'d 'b\nB=\"b\"\nC=\"b$b b\"\nA+=\" b\"\nD=(b)",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.remove_word("A", "b"), Ok(true));
+		assert_eq!(editor.remove_word("B", "b"), Ok(true));
+		assert_eq!(editor.remove_word("C", "b"), Ok(true));
+		assert_eq!(editor.remove_word("A", "x"), Ok(false));
+		assert_eq!(
+			editor.remove_word("D", "b"),
+			Err(EditError::NotString("D".into()))
+		);
+		assert_eq!(
+			lst.to_string(),
+			"A=\"a \\\n  c\"'d'\nB=\"\"\nC=\"b$b\"\nA+=\"\"\nD=(b)"
+		);
+		let ctx = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		assert_eq!(ctx["A"], "a   cd");
+	}
+
+	#[test]
+	fn test_apply_defaults() {
+		let base = ApmlLst::parse("# base\nA=1\nB=2").unwrap();
+		let defaults =
+			ApmlLst::parse("# defaults\nA=3\nC=4 # c\nD=5 E=6\nC+=7\n")
+				.unwrap();
+		let merged = apply_defaults(&base, &defaults);
+		assert_eq!(
+			merged.to_string(),
+			"# base\nA=1\nB=2\nC=4 # c\nD=5\nE=6\nC+=7\n"
+		);
+		assert_eq!(ApmlLst::parse(&merged.to_string()).unwrap(), merged);
+		assert_eq!(
+			apply_defaults_with(&base, &defaults, DefaultsMode::MissingOnly)
+				.to_string(),
+			"C=4 # c\nD=5\nE=6\nC+=7\n"
+		);
+		assert_eq!(
+			apply_defaults(&defaults, &base).to_string(),
+			"# defaults\nA=3\nC=4 # c\nD=5 E=6\nC+=7\nB=2\n"
+		);
+	}
+
+	#[test]
+	fn test_append_array_element() {
+		let append = |src, position| {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			let result = editor.append_array_element(
+				"A",
+				&ast::Text(vec![ast::Word::Literal("x".into())]),
+				position,
+			);
+			result.map(|_| lst.to_string())
+		};
+		assert_eq!(append("A=()", ArrayPosition::End).unwrap(), "A=(\"x\")");
+		assert_eq!(append("", ArrayPosition::End).unwrap(), "A=(\"x\")\n");
+		assert_eq!(
+			append("A=(a b)", ArrayPosition::End).unwrap(),
+			"A=(a b \"x\")"
+		);
+		assert_eq!(
+			append("A=(a b)", ArrayPosition::Start).unwrap(),
+			"A=(\"x\" a b)"
+		);
+		assert_eq!(
+			append("A=(a b)", ArrayPosition::Before(1)).unwrap(),
+			"A=(a \"x\" b)"
+		);
+		assert_eq!(
+			append("A=(a b)", ArrayPosition::Before(5)).unwrap(),
+			"A=(a b \"x\")"
+		);
+		let src = "A=(\n  a # a\n  b # b\n)";
+		assert_eq!(
+			append(src, ArrayPosition::End).unwrap(),
+			"A=(\n  a # a\n  b # b\n  \"x\"\n)"
+		);
+		assert_eq!(
+			append(src, ArrayPosition::Before(1)).unwrap(),
+			"A=(\n  a # a\n  \"x\"\n  b # b\n)"
+		);
+		assert_eq!(
+			append("A=(a\n\tb)", ArrayPosition::End).unwrap(),
+			"A=(a\n\tb\n\t\"x\")"
+		);
+		assert_eq!(
+			append("A=(a\n\tb)", ArrayPosition::Start).unwrap(),
+			"A=(\"x\"\n\ta\n\tb)"
+		);
+		assert_eq!(
+			append("A=(\n)", ArrayPosition::Start).unwrap(),
+			"A=(\n\t\"x\"\n)"
+		);
+		assert_eq!(
+			append("A=a", ArrayPosition::End),
+			Err(EditError::NotArray("A".into()))
+		);
+	}
+
+	#[test]
+	fn test_merge() {
+		let base =
+			ApmlLst::parse("A=1 # a\nB=\"b\"\nB+=\" c\"\nC=(x)\nD=d").unwrap();
+		let other = ApmlLst::parse(
+			"# a new\nA=2\nB=\"e\" # e\nC=(y)\nD+=\" f\"\n\n# g\nG=g # g",
+		)
+		.unwrap();
+		let merge = |policy| {
+			let mut lst = base.clone();
+			lst.merge(&other, policy);
+			lst.to_string()
+		};
+		assert_eq!(
+			merge(MergePolicy::KeepSelf),
+			"A=1 # a\nB=\"b\"\nB+=\" c\"\nC=(x)\nD=d\n# g\nG=g # g\n"
+		);
+		assert_eq!(
+			merge(MergePolicy::TakeOther),
+			"A=2 # a\nB=\"e\" # e\nC=(y)\nD=d\nD+=\" f\"\n# g\nG=g # g\n"
+		);
+		assert_eq!(
+			merge(MergePolicy::Append),
+			"A=1 # a\nA+=\" \"2\nB=\"b\"\nB+=\" c\"\nB+=\" e\" # e\nC=(x)\n\
+			 C+=(y)\nD=d\nD+=\" f\"\n# g\nG=g # g\n"
+		);
+
+		let mut lst = base.clone();
+		lst.merge_with(&other, |name| {
+			if name == "C" {
+				MergePolicy::Append
+			} else {
+				MergePolicy::TakeOther
+			}
+		});
+		let ctx = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		assert_eq!(ctx["A"], "2");
+		assert_eq!(ctx["B"], "e");
+		assert_eq!(
+			ctx["C"],
+			crate::apml::VariableValue::Array(vec!["x".into(), "y".into()])
+		);
+		assert_eq!(ctx["D"], "d f");
+	}
+
+	#[test]
+	fn test_retain_array_elements() {
+		let retain = |src, keep: &[&str]| {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			let result = editor.retain_array_elements("A", |text| {
+				keep.iter()
+					.any(|word| text.0 == [ast::Word::Literal((*word).into())])
+			});
+			result.map(|count| (count, lst.to_string()))
+		};
+		assert_eq!(retain("A=(a b c)", &["a", "c"]), Ok((1, "A=(a c)".into())));
+		assert_eq!(retain("A=(a b c)", &["a", "b"]), Ok((1, "A=(a b)".into())));
+		assert_eq!(retain("A=(a b c)", &["b"]), Ok((2, "A=(b)".into())));
+		assert_eq!(retain("A=(a)", &[]), Ok((1, "A=()".into())));
+		assert_eq!(
+			retain("A=(\n\ta # a\n\tb # b\n\tc\n)", &["a", "c"]),
+			Ok((1, "A=(\n\ta # a\n\tc\n)".into()))
+		);
+		assert_eq!(
+			retain("A=(\n\ta # a\n\tb c\n)\nA+=(d)", &["a", "c"]),
+			Ok((2, "A=(\n\ta # a\n\tc\n)\nA+=()".into()))
+		);
+		assert_eq!(retain("A=(\n\ta # a\n)", &[]), Ok((1, "A=()".into())));
+		assert_eq!(
+			retain("A=(\n\t# x\n\ta\n)", &[]),
+			Ok((1, "A=(\n\t# x\n)".into()))
+		);
+		assert_eq!(retain("B=(a)", &[]), Ok((0, "B=(a)".into())));
+		assert_eq!(
+			retain("A=(a)\nA+=b", &[]),
+			Err(EditError::NotArray("A".into()))
+		);
+	}
+
+	#[test]
+	fn test_replace_array_element() {
+		let mut lst = ApmlLst::parse("A=(a  b # b\n)\nA+=(c)\nB=b").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let text = ast::Text(vec![ast::Word::Literal("x".into())]);
+		editor.replace_array_element("A", 1, &text).unwrap();
+		editor.replace_array_element("A", 2, &text).unwrap();
+		assert_eq!(lst.to_string(), "A=(a  \"x\" # b\n)\nA+=(\"x\")\nB=b");
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.replace_array_element("A", 3, &text),
+			Err(EditError::IndexOutOfRange {
+				name: "A".into(),
+				index: 3
+			})
+		);
+		assert_eq!(
+			editor.replace_array_element("B", 0, &text),
+			Err(EditError::NotArray("B".into()))
+		);
+	}
+
+	#[test]
+	fn test_move_var() {
+		let src = "# srcs\nSRCS=a\nVER=1\n\n# checksums\n# of srcs\n\
+				   CHKSUMS=b # sha\nREL=0";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(!editor.move_var("SRCS", Some("SRCS")));
+		assert!(!editor.move_var("SRCS", Some("A")));
+		assert!(!editor.move_var("A", Some("SRCS")));
+		assert!(!editor.move_var("SRCS", None));
+		assert!(!editor.move_var("VER", Some("SRCS")));
+		assert!(!editor.is_dirty());
+		assert!(editor.move_var("CHKSUMS", Some("SRCS")));
+		assert_eq!(
+			lst.to_string(),
+			"# srcs\nSRCS=a\n# checksums\n# of srcs\nCHKSUMS=b # sha\nVER=1\n\n\
+			 REL=0"
+		);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(editor.move_var("REL", None));
+		assert!(editor.move_var("SRCS", Some("VER")));
+		assert_eq!(
+			lst.to_string(),
+			"REL=0\n# checksums\n# of srcs\nCHKSUMS=b # sha\nVER=1\n# srcs\n\
+			 SRCS=a\n\n"
+		);
+
+		let mut lst = ApmlLst::parse("A=1 B=2\nC=3\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(!editor.move_var("A", Some("C")));
+		assert!(editor.move_var("C", None));
+		assert_eq!(lst.to_string(), "C=3\nA=1 B=2\n");
+	}
+
+	#[test]
+	fn test_sort_vars() {
+		let src = "# Package\n\
+			# description\n\
+			PKGDES=\"foo\"  # inline\n\
+			ABTYPE=cmake\n\
+			PKGDEP=\"a\"\n\
+			\n\
+			# standalone\n\
+			\n\
+			PKGNAME=foo\n\
+			PKGDEP+=\" b\"\n\
+			A=1 B=2\n\
+			PKGSEC=utils";
+		let sort = |policy| {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			assert!(editor.sort_vars(DEFINES_KEY_ORDER, policy));
+			let once = lst.to_string();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			assert!(!editor.sort_vars(DEFINES_KEY_ORDER, policy));
+			assert!(!editor.is_dirty());
+			assert_eq!(lst.to_string(), once);
+			once
+		};
+		assert_eq!(
+			sort(UnknownKeyPolicy::End),
+			"PKGNAME=foo\n\
+			PKGSEC=utils\n\
+			PKGDEP=\"a\"\n\
+			\n\
+			# standalone\n\
+			\n\
+			PKGDEP+=\" b\"\n\
+			# Package\n\
+			# description\n\
+			PKGDES=\"foo\"  # inline\n\
+			A=1 B=2\n\
+			ABTYPE=cmake"
+		);
+		assert_eq!(
+			sort(UnknownKeyPolicy::Keep),
+			"PKGNAME=foo\n\
+			ABTYPE=cmake\n\
+			PKGSEC=utils\n\
+			\n\
+			# standalone\n\
+			\n\
+			PKGDEP=\"a\"\n\
+			PKGDEP+=\" b\"\n\
+			A=1 B=2\n\
+			# Package\n\
+			# description\n\
+			PKGDES=\"foo\"  # inline"
+		);
 
-	use super::*;
+		let mut lst = ApmlLst::parse("VER=1\nREL=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(!editor.sort_vars(SPEC_KEY_ORDER, UnknownKeyPolicy::End));
+		assert_eq!(lst.to_string(), "VER=1\nREL=2\n");
+	}
 
 	#[test]
-	fn test() {
-		let mut lst = ApmlLst::parse("a=b\nb=c\nc=\"$1\"").unwrap();
+	fn test_strip_comments() {
+		let src = "# header\n\n  # indented\nA=1 # a\nB=(\n\t1 # 1\n)\t# b\nC=2\n# end";
+		let mut lst = ApmlLst::parse(src).unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		assert_eq!(editor.lst_tokens_iter().count(), 5);
-		assert_eq!(editor.lst_tokens_mut().len(), 5);
-		assert_eq!(editor.ast_variables().unwrap().len(), 3);
-		assert_eq!(editor.keys().count(), 3);
-		assert_eq!(editor.find_var("a").unwrap().0, 0);
-		assert_eq!(editor.find_var("a").unwrap().1.name, "a");
-		assert_eq!(editor.find_var("b").unwrap().0, 2);
-		assert_eq!(editor.find_var_index("b").unwrap(), 2);
-		assert!(editor.find_var("A").is_none());
-		if let lst::Token::Variable(var) = editor.find_var_token("b").unwrap() {
-			assert_eq!(var.name, "b");
-		} else {
-			unreachable!();
-		}
-		assert!(editor.find_var_token("A").is_none());
-		assert!(editor.find_var_index("A").is_none());
+		assert_eq!(editor.strip_inline_comments(), 2);
+		assert_eq!(
+			lst.to_string(),
+			"# header\n\n  # indented\nA=1\nB=(\n\t1 # 1\n)\nC=2\n# end"
+		);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.strip_inline_comments(), 0);
+		assert!(!editor.is_dirty());
+		assert_eq!(editor.strip_comments(), 3);
+		assert_eq!(lst.to_string(), "\nA=1\nB=(\n\t1 # 1\n)\nC=2");
+
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.strip_comments(), 5);
+		assert_eq!(lst.to_string(), "\nA=1\nB=(\n\t1 # 1\n)\nC=2");
+		let mut lst = ApmlLst::parse("A=1 # a\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.strip_comments(), 1);
+		assert_eq!(lst.to_string(), "A=1\n");
 	}
 
 	#[test]
-	fn test_ensure_end_newline() {
-		let mut lst = ApmlLst::parse("a=b").unwrap();
+	fn test_insert_comment() {
+		let mut lst = ApmlLst::parse(
+			"# header\n\n# about A\n#second\nA=1 # a\n  B=2\nC=3",
+		)
+		.unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.ensure_end_newline();
-		assert_eq!(lst.to_string(), "a=b\n");
+		assert!(editor.insert_comment_before("A", "  upstream moved  "));
+		assert!(!editor.insert_comment_before("A", "upstream moved"));
+		assert!(!editor.insert_comment_before("A", "second"));
+		assert!(editor.insert_comment_before("B", "indented"));
+		assert!(editor.insert_comment_before("C", "c"));
+		assert!(!editor.insert_comment_before("D", "d"));
+		editor.insert_comment_at_end("end");
+		let src = lst.to_string();
+		assert_eq!(
+			src,
+			"# header\n\n# upstream moved\n# about A\n#second\nA=1 # a\n  \
+			 # indented\n  B=2\n# c\nC=3\n# end\n"
+		);
+		assert_eq!(ApmlLst::parse(&src).unwrap().to_string(), src);
+
+		let mut lst = ApmlLst::parse("").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.insert_comment_at_end("note");
+		assert_eq!(lst.to_string(), "# note\n");
 	}
 
 	#[test]
-	fn test_append_variable() {
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+	fn test_checkpoint() {
+		let mut lst = ApmlLst::parse("A=1\nB=(a b)\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let checkpoint = editor.checkpoint();
+		editor.restore(checkpoint.clone());
+		assert!(!editor.is_dirty());
+		editor.set_var_string("A", "2");
+		assert!(editor.remove_var_by_name("B").is_some());
+		editor.mark_clean();
+		editor.restore(checkpoint);
+		assert!(editor.is_dirty());
+		assert_eq!(lst.to_string(), "A=1\nB=(a b)\n");
+	}
+
+	#[test]
+	fn test_diff() {
+		let mut lst = ApmlLst::parse("A=1\nB=2\nC=3").unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.diff(), "");
+		editor.set_var_string("C", "4");
+		assert!(editor.remove_var_by_name("A").is_some());
 		editor.append_var_ast(
-			"c",
-			&ast::VariableValue::String("a".into()),
+			"D",
+			&ast::VariableValue::String("5".into()),
 			None,
 		);
-		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		assert!(editor.is_dirty());
+		assert_eq!(
+			editor.diff_with(&DiffOptions::with_path("defines")),
+			"--- a/defines\n+++ b/defines\n@@ -1,3 +1,3 @@\n-A=1\n B=2\n-C=3\n\
+			 \\ No newline at end of file\n+C=4\n+D=\"5\"\n"
+		);
+		editor.mark_clean();
+		assert!(!editor.diff().is_empty());
+	}
+
+	#[test]
+	fn test_glob_patterns() {
+		let mut lst =
+			ApmlLst::parse("A=${VER//./_}\nB=\"${A%.*}\"\nC=$A\n").unwrap();
+		let editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor
+				.glob_patterns()
+				.unwrap()
+				.into_iter()
+				.map(|(name, pattern)| (name, pattern.to_string()))
+				.collect::<Vec<_>>(),
+			[("A", ".".to_string()), ("B", ".*".to_string())]
+		);
+	}
+
+	#[test]
+	fn test_validate() {
+		let mut lst = ApmlLst::parse("# a\nA=1 B=2\n").unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.append_var_ast(
-			"c",
-			&ast::VariableValue::String("a".into()),
-			Some("a"),
+		assert_eq!(editor.validate(), Ok(()));
+		editor.lst_tokens_mut().remove(1);
+		assert_eq!(
+			editor.validate(),
+			Err(InvariantError::UnterminatedComment(0))
 		);
-		assert_eq!(lst.to_string(), "a=b\nc=\"a\"\nb=c");
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		editor.lst_tokens_mut().remove(0);
+		editor.lst_tokens_mut().remove(1);
+		assert_eq!(
+			editor.validate(),
+			Err(InvariantError::AdjacentDefinitions(1))
+		);
+		editor.lst_tokens_mut().insert(1, lst::Token::Newline);
+		assert_eq!(editor.validate(), Ok(()));
+		editor.lst_tokens_mut().splice(2..2, [
+			lst::Token::Comment(" a\nC=3".into()),
+			lst::Token::Newline,
+		]);
+		assert_eq!(editor.validate(), Err(InvariantError::Reparsed(2)));
+		editor.lst_tokens_mut()[2] =
+			lst::Token::Variable(lst::VariableDefinition {
+				declaration: None,
+				name: "C".into(),
+				op: lst::VariableOp::Assignment,
+				value: lst::VariableValue::String(Arc::new(lst::Text(vec![
+					lst::TextUnit::Unquoted(vec![lst::Word::Literal(vec![
+						lst::LiteralPart::String("\"".into()),
+					])]),
+				]))),
+			});
+		assert_eq!(editor.validate(), Err(InvariantError::Unparsable(2)));
+	}
+
+	#[test]
+	#[should_panic(
+		expected = "invariant violated after ApmlEditor::ensure_end_newline"
+	)]
+	fn test_check_invariants() {
+		let mut lst = ApmlLst::parse("# a\nA=1").unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.append_var_ast(
-			"c",
-			&ast::VariableValue::String("a".into()),
-			Some("b"),
+		editor.lst_tokens_mut().remove(1);
+		editor.ensure_end_newline();
+	}
+
+	#[test]
+	fn test_transaction() {
+		let src = "VER=1\nREL=2\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let result = editor.transaction(|editor| {
+			editor.set_var_string("VER", "2");
+			editor.append_var_ast(
+				"CHKSUMS",
+				&ast::VariableValue::String("".into()),
+				None,
+			);
+			Err::<(), _>(EditError::NotArray("CHKSUMS".to_string()))
+		});
+		assert_eq!(result, Err(EditError::NotArray("CHKSUMS".to_string())));
+		assert!(!editor.is_dirty());
+		let result = editor.transaction(|editor| {
+			editor
+				.lst_tokens_mut()
+				.push(lst::Token::Unknown(")".into()));
+			Ok::<_, EditError>(())
+		});
+		assert!(matches!(result, Err(EditError::InvalidResult(_))));
+		assert!(!editor.is_dirty());
+		assert_eq!(editor.as_ref().to_string(), src);
+		let result = editor.transaction(|editor| {
+			let tokens = editor.lst_tokens_mut();
+			let def = tokens[2].clone();
+			tokens.truncate(1);
+			tokens.push(def);
+			Ok::<_, EditError>(())
+		});
+		assert_eq!(
+			result,
+			Err(EditError::Invariant(InvariantError::AdjacentDefinitions(1)))
 		);
-		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		assert!(!editor.is_dirty());
+		assert_eq!(editor.as_ref().to_string(), src);
+
+		let result = editor.transaction(|editor| {
+			editor.set_var_string("VER", "2");
+			let inner = editor.transaction(|editor| {
+				editor.set_var_string("REL", "0");
+				Err::<(), _>(EditError::NotString("REL".to_string()))
+			});
+			assert!(inner.is_err());
+			Ok::<_, EditError>(editor.find_var_index("VER"))
+		});
+		assert_eq!(result, Ok(Some(0)));
+		assert!(editor.is_dirty());
+		assert_eq!(lst.to_string(), "VER=2\nREL=2\n");
+	}
+
+	#[test]
+	fn test_trailing_whitespace() {
+		let src = "A=\"a  \" \t\n\nB=(\n\ta  \n\tb \n) # c\nC=1  ";
+		let mut lst = ApmlLst::parse(src).unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.append_var_ast(
-			"c",
-			&ast::VariableValue::String("a".into()),
-			Some("eee"),
+		let spans = editor.trailing_whitespace();
+		assert_eq!(spans, vec![7..9, 17..19, 22..23, 33..35]);
+		assert!(spans.iter().all(|span| src[span.clone()].trim().is_empty()));
+		assert!(!editor.is_dirty());
+		assert_eq!(editor.strip_trailing_whitespace(), 4);
+		assert!(editor.trailing_whitespace().is_empty());
+		assert_eq!(lst.to_string(), "A=\"a  \"\n\nB=(\n\ta\n\tb\n) # c\nC=1");
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.strip_trailing_whitespace(), 0);
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_set_inline_comment() {
+		let set = |src, text| {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			assert!(editor.set_inline_comment("A", text));
+			lst.to_string()
+		};
+		assert_eq!(set("A=1\nB=2", Some("a")), "A=1 # a\nB=2");
+		assert_eq!(set("A=1", Some(" a ")), "A=1 # a");
+		assert_eq!(set("A=1  \n", Some("a")), "A=1  # a\n");
+		assert_eq!(set("A=1   #old\n", Some("a")), "A=1   # a\n");
+		assert_eq!(set("A=1 B=2\n", Some("a")), "A=1 B=2 # a\n");
+		assert_eq!(set("A=1  # old\nB=2", None), "A=1\nB=2");
+		assert_eq!(set("A=1  \n", None), "A=1  \n");
+		assert_eq!(set("A=1 B=2 # old", None), "A=1 B=2");
+
+		let mut lst = ApmlLst::parse("A=1 # a\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(editor.set_inline_comment("A", Some("a")));
+		assert!(!editor.is_dirty());
+		assert!(!editor.set_inline_comment("B", Some("b")));
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_normalize_whitespace() {
+		let src = "# a \nA=1  \n\n\n\nB=(\n\ta \n)\n \n\n\nC=\"  \"\n\n\n";
+		let normalize = |options| {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			editor.normalize_whitespace_with(&options);
+			let result = lst.to_string();
+			let old = ApmlLst::parse(src).unwrap();
+			assert_eq!(
+				ast::ApmlAst::emit_from(&old).unwrap(),
+				ast::ApmlAst::emit_from(&lst).unwrap()
+			);
+			assert!(
+				ApmlEditor::wrap(&mut old.clone())
+					.comments()
+					.eq(ApmlEditor::wrap(&mut lst).comments())
+			);
+			result
+		};
+		assert_eq!(
+			normalize(WhitespaceOptions::default()),
+			"# a \nA=1\n\nB=(\n\ta\n)\n\nC=\"  \"\n"
 		);
-		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
+		let none = WhitespaceOptions {
+			strip_trailing: false,
+			collapse_blank_lines: false,
+			final_newline: false,
+		};
+		assert_eq!(normalize(none), src);
+		assert_eq!(
+			normalize(WhitespaceOptions {
+				strip_trailing: true,
+				..none
+			}),
+			"# a \nA=1\n\n\n\nB=(\n\ta\n)\n\n\n\nC=\"  \"\n\n\n"
+		);
+		assert_eq!(
+			normalize(WhitespaceOptions {
+				collapse_blank_lines: true,
+				..none
+			}),
+			"# a \nA=1  \n\nB=(\n\ta \n)\n \n\nC=\"  \"\n\n"
+		);
+		assert_eq!(
+			normalize(WhitespaceOptions {
+				final_newline: true,
+				..none
+			}),
+			"# a \nA=1  \n\n\n\nB=(\n\ta \n)\n \n\n\nC=\"  \"\n"
+		);
+
+		let mut lst = ApmlLst::parse("A=1").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.normalize_whitespace();
+		assert_eq!(lst.to_string(), "A=1\n");
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.normalize_whitespace();
+		assert!(!editor.is_dirty());
 	}
 
 	#[test]
-	fn test_replace_variable() {
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+	fn test_normalize_blank_lines() {
+		let mut lst =
+			ApmlLst::parse("VER=1\n\nSRCS=(\n\n\n\ta\n)\n\n# deps\nPKGDEP=a\n")
+				.unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.replace_var_ast("c", &ast::VariableValue::String("a".into()));
-		assert_eq!(lst.to_string(), "a=b\nb=c\nc=\"a\"\n");
-		let mut lst = ApmlLst::parse("a=b\nb=c").unwrap();
+		let value = ast::VariableValue::String("1".into());
+		editor.append_var_ast("REL", &value, Some("VER"));
+		assert!(editor.remove_var_by_name("PKGDEP").is_some());
+		editor.insert_comment_at_end("build");
+		editor.append_var_ast("ABTYPE", &value, None);
+		editor.lst_tokens_mut().extend([
+			lst::Token::Newline,
+			lst::Token::Spacy(' '),
+			lst::Token::Newline,
+			lst::Token::Newline,
+		]);
+		editor.append_var_ast("NOLTO", &value, None);
+		assert_eq!(
+			editor.as_ref().to_string(),
+			"VER=1\nREL=\"1\"\n\nSRCS=(\n\n\n\ta\n)\n\n# build\nABTYPE=\"1\"\n\n \n\n\
+			 NOLTO=\"1\"\n"
+		);
+		assert_eq!(editor.normalize_blank_lines(1), 2);
+		assert!(editor.ensure_blank_line_before("SRCS"));
+		assert!(editor.ensure_blank_line_before("ABTYPE"));
+		assert!(editor.ensure_blank_line_before("REL"));
+		assert!(!editor.ensure_blank_line_before("PKGDEP"));
+		let expected = "VER=1\n\nREL=\"1\"\n\nSRCS=(\n\n\n\ta\n)\n\n# build\n\
+						ABTYPE=\"1\"\n\nNOLTO=\"1\"\n";
+		assert_eq!(editor.as_ref().to_string(), expected);
+		assert_eq!(editor.normalize_blank_lines(1), 0);
+		assert!(editor.ensure_blank_line_before("REL"));
+		assert!(editor.ensure_blank_line_before("VER"));
+		assert_eq!(lst.to_string(), expected);
+
+		let mut lst = ApmlLst::parse("\n\n\nA=1\n\n# a\n\n\nB=1\n\n").unwrap();
 		let mut editor = ApmlEditor::wrap(&mut lst);
-		editor.replace_var_ast("a", &ast::VariableValue::String("a".into()));
-		assert_eq!(lst.to_string(), "a=\"a\"\nb=c");
+		assert_eq!(editor.normalize_blank_lines(0), 7);
+		assert_eq!(lst.to_string(), "A=1\n# a\nB=1\n");
+	}
+
+	#[test]
+	fn test_wrap_long_values() {
+		let src = "PKGDEP=\"glibc gcc-runtime ${PYTHON:-python-3} perl \\$x zlib\" # deps\n\
+			A=\"a b\"\nB='aaaaaa bbbbbbb cccccccc'\nC=\"a\\\nb\"\n\
+			D=\"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\"";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.wrap_long_values(&FormatOptions {
+				max_line_width: 20,
+				..Default::default()
+			}),
+			1
+		);
+		let result = lst.to_string();
+		assert_eq!(
+			result,
+			"PKGDEP=\"glibc\\\n gcc-runtime\\\n ${PYTHON:-python-3}\\\n perl \\$x zlib\" # deps\n\
+			A=\"a b\"\nB='aaaaaa bbbbbbb cccccccc'\nC=\"a\\\nb\"\n\
+			D=\"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\""
+		);
+		assert_eq!(
+			crate::apml::ApmlContext::eval_source(src).unwrap(),
+			crate::apml::ApmlContext::eval_source(&result).unwrap()
+		);
+		assert_eq!(ApmlLst::parse(&result).unwrap().to_string(), result);
+
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.wrap_long_values(&FormatOptions::default()), 0);
+		assert!(!editor.is_dirty());
+
+		let src = "PKGDES=\"A library for parsing and emitting package \
+			definitions, used by the build system\"\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.format(&FormatOptions::default());
+		let result = lst.to_string();
+		assert_ne!(result, src);
+		assert_eq!(
+			crate::apml::ApmlContext::eval_source(src).unwrap(),
+			crate::apml::ApmlContext::eval_source(&result).unwrap()
+		);
+	}
+
+	#[test]
+	fn test_format() {
+		let src = "A=1\n\n\n\nB=\"aaaaaa bbbbbb\"\n\n\nC=1\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.format(&FormatOptions::default()), 3);
+		assert_eq!(lst.to_string(), "A=1\n\nB=\"aaaaaa bbbbbb\"\n\nC=1\n");
+
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.format(&FormatOptions {
+				max_line_width: 10,
+				max_consecutive_blanks: None,
+			}),
+			1
+		);
+		assert_eq!(
+			lst.to_string(),
+			"A=1\n\n\n\nB=\"aaaaaa\\\n bbbbbb\"\n\n\nC=1\n"
+		);
+	}
+
+	#[test]
+	fn test_leading_comments() {
+		let src = "# header\n\n# a1\n  #a2\nA=1 # inline\n# b1\n\n# b2\nB=2 # b\n\nC=3\n# d\nE=4 D=5\n  F=6";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.leading_comments("A"), vec![" a1", "a2"]);
+		assert_eq!(editor.leading_comment_indexes("A"), vec![3, 7]);
+		assert_eq!(editor.leading_comments("B"), vec![" b2"]);
+		assert!(editor.leading_comments("C").is_empty());
+		assert_eq!(editor.leading_comments("E"), vec![" d"]);
+		assert!(editor.leading_comments("D").is_empty());
+		assert!(editor.leading_comments("F").is_empty());
+		assert!(editor.leading_comments("G").is_empty());
+
+		// remove_var strips the same block
+		let index = editor.find_var_index("B").unwrap();
+		editor.remove_var(index);
+		assert_eq!(
+			lst.to_string(),
+			"# header\n\n# a1\n  #a2\nA=1 # inline\n# b1\n\n\nC=3\n# d\nE=4 D=5\n  F=6"
+		);
+		let mut lst = ApmlLst::parse("# a\nA=1\n\nB=2").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		editor.remove_var(2);
+		assert_eq!(lst.to_string(), "\nB=2");
 	}
 
 	#[test]
@@ -349,6 +5399,452 @@ mod test {
 		let mut editor = ApmlEditor::wrap(&mut lst);
 		editor.remove_var(editor.find_var("b").unwrap().0);
 		assert_eq!(lst.to_string(), "a=b # a\n\n# a\nc=\"$1\"");
+
+		// definitions at the start of file
+		for (src, index, expected) in [
+			("a=b", 0, ""),
+			("a=b\n", 0, ""),
+			("a=b # a\nb=c", 0, "b=c"),
+			("b=c\na=b # a", 2, "b=c\n"),
+			(" a=b\nb=c", 1, "b=c"),
+			("a=b\n\t a=b\nb=c", 4, "a=b\nb=c"),
+			("a=b c=d\nb=c", 2, "a=b b=c"),
+			("# a\na=b\n\nb=c", 2, "\nb=c"),
+			("# a\na=b\nb=c", 2, "# a\nb=c"),
+			("\na=b\nb=c", 1, "\nb=c"),
+			("  # a\n a=b\n\nb=c", 5, "\nb=c"),
+			("# a \n\ta=b # b\n\nb=c", 3, "\nb=c"),
+			("# a\n  # b\n  a=b\n\nb=c", 8, "\nb=c"),
+		] {
+			let mut lst = ApmlLst::parse(src).unwrap();
+			let mut editor = ApmlEditor::wrap(&mut lst);
+			editor.remove_var(index);
+			assert_eq!(lst.to_string(), expected, "{src:?}");
+		}
+	}
+
+	#[test]
+	fn test_rename_var() {
+		let src =
+			"# srcs\nSRCTBL=\"a\"  # b\nB=$SRCTBL\nSRCTBL+=(\n c # d\n)\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.rename_var("SRCTBL", "SRCS"), Ok(true));
+		assert_eq!(
+			lst.to_string(),
+			src.replace("SRCTBL=", "SRCS=")
+				.replace("SRCTBL+=", "SRCS+=")
+		);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.rename_var("SRCTBL", "A"), Ok(false));
+		assert!(!editor.is_dirty());
+		assert_eq!(
+			editor.rename_var("SRCS", "B"),
+			Err(EditError::DuplicateName("B".into()))
+		);
+		assert_eq!(
+			editor.rename_var("SRCS", "C D"),
+			Err(EditError::InvalidName("C D".into()))
+		);
+		assert_eq!(editor.rename_var("B", "B"), Ok(true));
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_lst_variables_mut() {
+		let mut lst = ApmlLst::parse("a=b # a\nb+=c\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		for var in editor.lst_variables_mut() {
+			var.op = lst::VariableOp::Assignment;
+			if var.name == "a" {
+				var.name = "c".into();
+			}
+		}
+		assert!(editor.is_dirty());
+		assert_eq!(lst.to_string(), "c=b # a\nb=c\n");
+	}
+
+	#[test]
+	fn test_get_var() {
+		let mut lst = ApmlLst::parse("a=1\nb=(2)\na+=3\n").unwrap();
+		let editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.get_var_lst("a").unwrap().to_string(), "3");
+		assert_eq!(editor.get_var_lst("b").unwrap().to_string(), "(2)");
+		assert!(editor.get_var_lst("c").is_none());
+		assert_eq!(
+			editor
+				.get_all_var_lst("a")
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>(),
+			vec!["1", "3"]
+		);
+		assert_eq!(
+			editor
+				.get_var_ast("a")
+				.unwrap()
+				.unwrap()
+				.lower()
+				.to_string(),
+			"\"13\""
+		);
+		assert!(editor.get_var_ast("c").is_none());
+		assert_eq!(editor.get_all_var_ast("a").unwrap().len(), 2);
+		assert!(editor.get_all_var_ast("c").unwrap().is_empty());
+
+		let mut lst = ApmlLst::parse(
+			"a+=0\na=1\na+=\"$b\"\nb=(1)\nb+=(2 \"$a\")\nc=1\nc+=(2)\nd=(1)\n\
+			 d+=2\ne=1\ne+=\"${e}2\"\nf+=1\n",
+		)
+		.unwrap();
+		let editor = ApmlEditor::wrap(&mut lst);
+		let get = |name| {
+			editor
+				.get_var_ast(name)
+				.unwrap()
+				.unwrap()
+				.lower()
+				.to_string()
+		};
+		assert_eq!(get("a"), "\"1${b}\"");
+		assert_eq!(get("b"), "(\"1\" \"2\" \"${a}\")");
+		assert_eq!(get("c"), "(\"1\" \"2\")");
+		assert_eq!(get("d"), "\"${d}2\"");
+		assert_eq!(get("e"), "\"${e}${e}2\"");
+		assert_eq!(get("f"), "\"${f}1\"");
+	}
+
+	#[test]
+	fn test_dirty() {
+		let mut lst = ApmlLst::parse("a=b\nb=c\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(!editor.is_dirty());
+		assert!(editor.find_var("a").is_some());
+		editor.ensure_end_newline();
+		assert!(editor.remove_var_by_name("c").is_none());
+		assert!(!editor.is_dirty());
+		editor.replace_var_ast("a", &ast::VariableValue::String("c".into()));
+		assert!(editor.is_dirty());
+		editor.mark_clean();
+		assert!(!editor.is_dirty());
+		assert!(editor.remove_var_by_name("b").is_some());
+		assert!(editor.is_dirty());
+	}
+
+	#[test]
+	fn test_to_btreemap() {
+		let mut lst = ApmlLst::parse("b=1\na=2\nc=(3)\nb=4\n").unwrap();
+		let editor = ApmlEditor::wrap(&mut lst);
+		let map = editor.to_btreemap().unwrap();
+		assert_eq!(map.keys().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+		assert_eq!(map["b"], ast::VariableValue::String("4".into()));
+	}
+
+	#[test]
+	fn test_remove_var_by_name() {
+		let mut lst = ApmlLst::parse("a=b\nb=c\n\nc=\"$1\"").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(editor.remove_var_by_name("b").is_some());
+		assert!(editor.remove_var_by_name("b").is_none());
+		assert!(editor.remove_var_by_name("d").is_none());
+		assert_eq!(lst.to_string(), "a=b\n\nc=\"$1\"");
+		let mut lst =
+			ApmlLst::parse("a=b # a\n# b\n# c\nb=c\n\n# a\nc=\"$1\"").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(editor.remove_var_by_name("b").is_some());
+		assert_eq!(lst.to_string(), "a=b # a\n\n# a\nc=\"$1\"");
+
+		let mut lst =
+			ApmlLst::parse("a=b\nb=c\n# b\nb+=d\nc=\"$1\"\nb=e\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.remove_all_vars("b"), 3);
+		assert_eq!(editor.remove_all_vars("b"), 0);
+		assert_eq!(lst.to_string(), "a=b\n# b\nc=\"$1\"\n");
+
+		// move a definition between files
+		let mut spec = ApmlLst::parse(
+			"VER=1\n# a\n# b\nSRCS=\"git::commit=tags/v$VER::a \\\n\tb\" # c\n\nCHKSUMS=SKIP\n",
+		)
+		.unwrap();
+		let mut defines = ApmlLst::parse("PKGNAME=a\nPKGDES=b\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut spec);
+		let removed = editor.remove_var_by_name("SRCS").unwrap();
+		assert_eq!(removed.def.name, "SRCS");
+		assert_eq!(removed.tokens.len(), 7);
+		assert_eq!(spec.to_string(), "VER=1\n\nCHKSUMS=SKIP\n");
+		let mut editor = ApmlEditor::wrap(&mut defines);
+		editor.insert_removed(removed.clone(), Some("PKGNAME"));
+		assert_eq!(
+			defines.to_string(),
+			"PKGNAME=a\n# a\n# b\nSRCS=\"git::commit=tags/v$VER::a \\\n\tb\" # c\nPKGDES=b\n"
+		);
+		assert_eq!(
+			ApmlEditor::wrap(&mut defines).find_var("SRCS").unwrap().1,
+			&removed.def
+		);
+	}
+
+	#[test]
+	fn test_find_token_mut() {
+		let mut lst = ApmlLst::parse("A=1 # a\nB=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(
+			editor
+				.find_token_mut(|token| matches!(
+					token,
+					lst::Token::Spacy('\t')
+				))
+				.is_none()
+		);
+		assert!(!editor.is_dirty());
+		let Some(lst::Token::Comment(comment)) = editor
+			.find_token_mut(|token| matches!(token, lst::Token::Comment(_)))
+		else {
+			unreachable!()
+		};
+		*comment = " b".into();
+		assert!(editor.is_dirty());
+		assert_eq!(lst.to_string(), "A=1 # b\nB=2\n");
+	}
+
+	#[test]
+	fn test_find_vars() {
+		let src = "VER=1\n# a\nREL=0\nVER=2\nSRCS=a\n\nVER+=.1 # b\nREL=1\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let indexes = |vars: Vec<(usize, &lst::VariableDefinition)>| {
+			vars.into_iter().map(|(index, _)| index).collect::<Vec<_>>()
+		};
+		assert_eq!(indexes(editor.find_vars("VER")), [0, 6, 11]);
+		assert_eq!(indexes(editor.find_vars("REL")), [4, 15]);
+		assert!(editor.find_vars("A").is_empty());
+		assert_eq!(editor.find_var("VER").unwrap().0, 0);
+		let (index, def) = editor.find_var_effective("VER").unwrap();
+		assert_eq!(index, 11);
+		assert_eq!(def.op, lst::VariableOp::Append);
+		assert!(editor.find_var_effective("A").is_none());
+		let map = editor.var_indexes();
+		assert_eq!(map.len(), 3);
+		assert_eq!(map["VER"], [0, 6, 11]);
+		assert_eq!(map["SRCS"], [8]);
+
+		editor.replace_var_ast("REL", &ast::VariableValue::String("2".into()));
+		assert!(editor.remove_var_by_name("VER").is_some());
+		assert_eq!(
+			lst.to_string(),
+			"VER=1\n# a\nREL=0\nVER=2\nSRCS=a\n\nREL=\"2\"\n"
+		);
+	}
+
+	#[test]
+	fn test_batch_replace() {
+		let mut lst = ApmlLst::parse("PKGVER=1\nPKGREL=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let result = editor
+			.batch_replace([
+				("PKGVER", ast::VariableValue::String("2".into())),
+				("PKGREL", ast::VariableValue::String("0".into())),
+				("PKGEPOCH", ast::VariableValue::String("1".into())),
+				("PKGEPOCH", ast::VariableValue::String("2".into())),
+			])
+			.unwrap();
+		assert_eq!(result.inserted, vec!["PKGEPOCH"]);
+		assert_eq!(result.replaced, vec!["PKGVER", "PKGREL"]);
+		assert_eq!(
+			lst.to_string(),
+			"PKGVER=\"2\"\nPKGREL=\"0\"\nPKGEPOCH=\"2\"\n"
+		);
+		let mut lst = ApmlLst::parse("PKGVER=1\nPKGREL=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.batch_replace([
+				("PKGVER", ast::VariableValue::String("2".into())),
+				("PKG-REL", ast::VariableValue::String("0".into())),
+			]),
+			Err(EditError::InvalidName("PKG-REL".into()))
+		);
+		assert_eq!(
+			editor
+				.batch_replace([("1A", ast::VariableValue::String("".into()))]),
+			Err(EditError::InvalidName("1A".into()))
+		);
+		assert_eq!(
+			editor.batch_replace([
+				("PKGVER", ast::VariableValue::String("2".into())),
+				(
+					"PKGREL",
+					ast::VariableValue::Array(vec![
+						ast::ArrayElement::ArrayInclusion("A-B".into())
+					])
+				),
+			]),
+			Err(EditError::InvalidValue(
+				ast::AstError::InvalidInclusionName {
+					variable: "PKGREL".into(),
+					name: "A-B".into(),
+				}
+			))
+		);
+		assert_eq!(lst.to_string(), "PKGVER=1\nPKGREL=2\n");
+	}
+
+	#[test]
+	fn test_instantiate_template() {
+		let mut lst = ApmlLst::parse(
+			"VER=@VER@\nREL=0\nSRCS=(\"git::@URL@\" \"$A\" v@VER@/@DESC@)\n\
+			 DESC='@DESC@ @X@' # @VER@\nB=\"@DESC@ $B\"@VER@\n",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let values = HashMap::from([
+			("VER", "1.0"),
+			("URL", "https://example.com"),
+			("DESC", "\"$HOME\" isn't `here`"),
+		]);
+		assert_eq!(editor.instantiate_template(&values), vec!["X"]);
+		assert_eq!(
+			lst.to_string(),
+			"VER=1.0\nREL=0\nSRCS=(\"git::https://example.com\" \"$A\" \
+			 v1.0/\"\\\"\\$HOME\\\" isn't \\`here\\`\")\nDESC='\"$HOME\" \
+			 isn'\\''t `here` @X@' # @VER@\nB=\"\\\"\\$HOME\\\" isn't \\`here\\` \
+			 $B\"1.0\n"
+		);
+		let ctx = crate::apml::ApmlContext::eval_lst(&lst).unwrap();
+		assert_eq!(ctx["DESC"], "\"$HOME\" isn't `here` @X@");
+		assert_eq!(
+			ctx.read("SRCS").as_array()[2],
+			"v1.0/\"$HOME\" isn't `here`"
+		);
+		assert_eq!(ctx["B"], "\"$HOME\" isn't `here` 1.0");
+
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.instantiate_template(&HashMap::new()), vec!["X"]);
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_dedupe_vars() {
+		let src = "# version\nVER=1\nREL=0\n\n# newer version\nVER=2 # b\n\n\
+				   PKGDEP=a\nPKGDEP+=b\n# newest\nVER=3\nPKGDEP=c\nPKGDEP=d\n";
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let report = editor.dedupe_vars(DedupePolicy::default());
+		assert_eq!(
+			report
+				.removed
+				.iter()
+				.map(|(line, def)| (
+					*line,
+					def.name.as_ref(),
+					def.value.to_string()
+				))
+				.collect::<Vec<_>>(),
+			[
+				(2, "VER", "1".into()),
+				(6, "VER", "2".into()),
+				(12, "PKGDEP", "c".into())
+			]
+		);
+		assert_eq!(
+			lst.to_string(),
+			"# version\nREL=0\n\n\nPKGDEP=a\nPKGDEP+=b\n# newest\nVER=3\n\
+			 PKGDEP=d\n"
+		);
+
+		let mut lst = ApmlLst::parse(src).unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let report = editor.dedupe_vars(DedupePolicy::KeepFirst);
+		assert_eq!(report.removed.len(), 3);
+		assert_eq!(
+			lst.to_string(),
+			"# version\nVER=1\nREL=0\n\n\nPKGDEP=a\nPKGDEP+=b\n# newest\n\
+			 PKGDEP=c\n"
+		);
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(
+			editor
+				.dedupe_vars(DedupePolicy::KeepFirst)
+				.removed
+				.is_empty()
+		);
+		assert!(!editor.is_dirty());
+	}
+
+	#[test]
+	fn test_remove_vars_matching() {
+		let mut lst = ApmlLst::parse("a=b\nb=c\n# b\nc=\"$1\"\nd=e\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor.remove_vars_matching(|name, _| name == "b" || name == "d"),
+			2
+		);
+		assert_eq!(editor.depth, 0);
+		assert_eq!(lst.to_string(), "a=b\n# b\nc=\"$1\"\n");
+		let mut lst = ApmlLst::parse("a=b\nb=c\nc=\"$1\"\nd+=e\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(
+			editor
+				.retain_vars(|name, def| name == "a"
+					|| def.op == lst::VariableOp::Append),
+			2
+		);
+		assert_eq!(lst.to_string(), "a=b\nd+=e\n");
+		let mut lst = ApmlLst::parse("a=b\nb=c\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert_eq!(editor.remove_vars_matching(|_, _| false), 0);
+		assert_eq!(lst.to_string(), "a=b\nb=c\n");
+	}
+
+	#[test]
+	fn test_directives() {
+		let mut lst = ApmlLst::parse(
+			"# PFU: upstream=github:a/b\nVER=1 # PFU: nocheck\n\n# PFU: \
+			 orphan\n\n# note\n# dummysrc PFU: a=b\nSRCS=a\n",
+		)
+		.unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		let directives = editor.directives();
+		assert_eq!(directives.len(), 4);
+		assert_eq!(directives[0].index, 0);
+		assert_eq!(directives[0].variable, Some("VER"));
+		assert_eq!(
+			directives[0].directive.value("upstream"),
+			Some("github:a/b")
+		);
+		assert_eq!(directives[1].variable, Some("VER"));
+		assert!(directives[1].directive.contains("nocheck"));
+		assert_eq!(directives[2].variable, None);
+		assert_eq!(directives[3].variable, Some("SRCS"));
+		assert_eq!(
+			editor.var_directive("SRCS", "PFU:").unwrap().index,
+			directives[3].index
+		);
+		assert!(editor.var_directive("VER", "dummysrc").is_none());
+		assert!(editor.set_directive("SRCS", "a", Some("c")));
+		assert!(editor.set_directive("SRCS", "d", None));
+		assert!(editor.set_directive("VER", "e", Some("f")));
+		assert!(!editor.set_directive("NONEXISTENT", "e", Some("f")));
+		assert_eq!(
+			lst.to_string(),
+			"# PFU: upstream=github:a/b;e=f\nVER=1 # PFU: nocheck\n\n# \
+			 PFU: orphan\n\n# note\n# dummysrc PFU: a=c;d\nSRCS=a\n"
+		);
+		let mut lst = ApmlLst::parse("A=1\n# note\nB=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(editor.set_directive("B", "a", Some("b")));
+		assert!(editor.set_directive("A", "a", Some("b")));
+		assert!(editor.remove_directive("B", "a"));
+		assert!(!editor.remove_directive("B", "a"));
+		assert_eq!(lst.to_string(), "# PFU: a=b\nA=1\n# note\n# PFU:\nB=2\n");
+		let mut lst = ApmlLst::parse("A=1\n#PFU:a=b ;c\nB=2\n").unwrap();
+		let mut editor = ApmlEditor::wrap(&mut lst);
+		assert!(!editor.remove_directive("A", "a"));
+		assert!(!editor.remove_directive("B", "d"));
+		assert!(!editor.remove_directive("C", "a"));
+		assert!(editor.set_directive("B", "a", Some("b")));
+		assert!(editor.edit_directive("A", DEFAULT_PREFIX, |_| {}));
+		assert!(!editor.is_dirty());
+		assert_eq!(lst.to_string(), "A=1\n#PFU:a=b ;c\nB=2\n");
 	}
 
 	#[test]
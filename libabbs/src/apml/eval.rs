@@ -1,17 +1,38 @@
 //! APML expression evaluator.
 
-use std::cmp::min;
+use std::{cmp::min, collections::HashMap, sync::Arc};
 
 use thiserror::Error;
 
 use super::{ApmlContext, VariableValue, ast};
 
+/// Errors produced while evaluating APML.
 #[derive(Error, Debug)]
 pub enum EvalError {
-	#[error("Glob-as-regex error: {0}")]
-	RegexError(#[from] regex::Error),
-	#[error("Required variable is unset: {0}")]
-	Unset(String),
+	/// A pattern in the expansion of a variable cannot be converted
+	/// into regex.
+	#[error("Glob-as-regex error in expansion of {name}: {source}")]
+	RegexError {
+		name: String,
+		#[source]
+		source: regex::Error,
+	},
+	/// A `${name:?message}` expansion is applied to a unset or null variable.
+	#[error("Required variable {name} is unset: {message}")]
+	UserError { name: String, message: String },
+	/// A substring expansion is applied with a out-of-bound range.
+	#[error(
+		"Bad substring expansion of {name}: offset {offset}{}",
+		len.map(|len| format!(", length {len}")).unwrap_or_default()
+	)]
+	BadSubstring {
+		name: String,
+		offset: isize,
+		len: Option<isize>,
+	},
+	/// The index of a array element expansion is not a integer.
+	#[error("Bad index of {name}: {index}")]
+	BadIndex { name: String, index: String },
 }
 
 type Result<T> = std::result::Result<T, EvalError>;
@@ -24,6 +45,27 @@ pub fn eval_ast(apml: &mut ApmlContext, tree: &ast::ApmlAst) -> Result<()> {
 	Ok(())
 }
 
+/// Evaluates all variables in a AST against a base environment,
+/// returning the resulting environment.
+///
+/// Variables in the base environment are visible to definitions, and
+/// definitions are evaluated in order, so later definitions can reference
+/// earlier ones. Array values are joined with spaces.
+pub fn to_env_pairs(
+	ast: &ast::ApmlAst,
+	env: &HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+	let mut apml = ApmlContext::new();
+	for (name, value) in env {
+		apml.insert(name.clone(), VariableValue::String(value.clone()));
+	}
+	eval_ast(&mut apml, ast)?;
+	Ok(apml
+		.iter()
+		.map(|(name, value)| (name.clone(), value.as_string()))
+		.collect())
+}
+
 #[inline]
 fn eval_variable_def(
 	apml: &mut ApmlContext,
@@ -36,7 +78,7 @@ fn eval_variable_def(
 }
 
 #[inline]
-fn eval_variable_value(
+pub(crate) fn eval_variable_value(
 	apml: &ApmlContext,
 	value: &ast::VariableValue,
 ) -> Result<VariableValue> {
@@ -64,16 +106,22 @@ fn eval_array_element(
 		ast::ArrayElement::ArrayInclusion(name) => {
 			// expand array elements
 			values.append(
-				&mut apml
-					.variables
-					.get(name.as_ref())
-					.cloned()
-					.unwrap_or_default()
-					.into_array(),
+				&mut apml.lookup(name).unwrap_or_default().into_array(),
 			);
 			Ok(())
 		}
 		ast::ArrayElement::Text(text) => {
+			if let [
+				ast::Word::Variable(ast::VariableExpansion {
+					name,
+					modifier: Some(ast::ExpansionModifier::NamesWithPrefix),
+				}),
+			] = text.0.as_slice()
+			{
+				// expand names as elements
+				values.append(&mut names_with_prefix(apml, name));
+				return Ok(());
+			}
 			values.push(eval_text(apml, text)?);
 			Ok(())
 		}
@@ -96,22 +144,40 @@ fn eval_word(apml: &ApmlContext, word: &ast::Word) -> Result<String> {
 			Ok(text.to_string())
 		}
 		ast::Word::Variable(expansion) => {
-			let val = apml
-				.variables
-				.get(expansion.name.as_ref())
-				.cloned()
-				.unwrap_or_default();
-			if let Some(modifier) = &expansion.modifier {
-				apply_expansion_modifier(apml, modifier, val)
-			} else {
-				Ok(val.into_string())
+			let val = apml.lookup(&expansion.name);
+			match &expansion.modifier {
+				// unset variables have no elements, unlike empty strings
+				Some(
+					ast::ExpansionModifier::ArrayLength
+					| ast::ExpansionModifier::SingleWordArrayLength,
+				) if val.is_none() => Ok("0".to_string()),
+				Some(modifier) => apply_expansion_modifier(
+					apml,
+					&expansion.name,
+					modifier,
+					val.unwrap_or_default(),
+				),
+				None => Ok(val.unwrap_or_default().into_string()),
 			}
 		}
 	}
 }
 
+/// Lists names of variables starting with a prefix, in sorted order.
+fn names_with_prefix(apml: &ApmlContext, prefix: &str) -> Vec<String> {
+	let mut names = apml
+		.variables
+		.keys()
+		.filter(|name| name.starts_with(prefix))
+		.cloned()
+		.collect::<Vec<_>>();
+	names.sort();
+	names
+}
+
 fn apply_expansion_modifier(
 	apml: &ApmlContext,
+	name: &str,
 	modifier: &ast::ExpansionModifier,
 	value: VariableValue,
 ) -> Result<String> {
@@ -148,77 +214,111 @@ fn apply_expansion_modifier(
 		}
 	}
 
+	let regex_error = |source| EvalError::RegexError {
+		name: name.to_string(),
+		source,
+	};
+
 	match modifier {
 		ast::ExpansionModifier::Substring { offset, length } => {
 			let value = value.into_string();
-			if let Some(length) = length {
-				if *length > 0 {
-					Ok(value
-						[*offset..min(*offset + *length as usize, value.len())]
-						.to_string())
-				} else {
-					Ok(value[*offset..(value.len() - (-*length) as usize)]
-						.to_string())
+			let start = if *offset < 0 {
+				match value.len().checked_sub(offset.unsigned_abs()) {
+					Some(start) => start,
+					None => return Ok(String::new()),
 				}
 			} else {
-				Ok(value[*offset..].to_string())
+				*offset as usize
+			};
+			if start > value.len() {
+				return Ok(String::new());
+			}
+			let end = match length {
+				Some(length) if *length >= 0 => {
+					Some(min(start + *length as usize, value.len()))
+				}
+				Some(length) => value.len().checked_sub(length.unsigned_abs()),
+				None => Some(value.len()),
+			};
+			match end.and_then(|end| value.get(start..end)) {
+				Some(result) => Ok(result.to_string()),
+				None => Err(EvalError::BadSubstring {
+					name: name.to_string(),
+					offset: *offset,
+					len: *length,
+				}),
 			}
 		}
 		ast::ExpansionModifier::StripShortestPrefix(pattern) => Ok(pattern
-			.to_regex("^(?:", ")?(.*)$", false)?
+			.to_regex("^(?:", ")?(.*)$", false)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), MatchReplacer(1))
 			.to_string()),
 		ast::ExpansionModifier::StripLongestPrefix(pattern) => Ok(pattern
-			.to_regex("^(?:", ")?(.*?)$", true)?
+			.to_regex("^(?:", ")?(.*?)$", true)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), MatchReplacer(1))
 			.to_string()),
 		ast::ExpansionModifier::StripShortestSuffix(pattern) => Ok(pattern
-			.to_regex("^(.*)(?:", ")$", false)?
+			.to_regex("^(.*)(?:", ")$", false)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), MatchReplacer(1))
 			.to_string()),
 		ast::ExpansionModifier::StripLongestSuffix(pattern) => Ok(pattern
-			.to_regex("^(.*?)(?:", ")$", true)?
+			.to_regex("^(.*?)(?:", ")$", true)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), MatchReplacer(1))
 			.to_string()),
 		ast::ExpansionModifier::ReplaceOnce { pattern, string } => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), &eval_text(apml, string)?)
 			.to_string()),
 		ast::ExpansionModifier::ReplaceAll { pattern, string } => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace_all(&value.into_string(), &eval_text(apml, string)?)
 			.to_string()),
 		ast::ExpansionModifier::ReplacePrefix { pattern, string } => {
 			Ok(pattern
-				.to_regex("^", "", true)?
+				.to_regex("^", "", true)
+				.map_err(regex_error)?
 				.replace_all(&value.into_string(), &eval_text(apml, string)?)
 				.to_string())
 		}
 		ast::ExpansionModifier::ReplaceSuffix { pattern, string } => {
 			Ok(pattern
-				.to_regex("", "$", true)?
+				.to_regex("", "$", true)
+				.map_err(regex_error)?
 				.replace_all(&value.into_string(), &eval_text(apml, string)?)
 				.to_string())
 		}
 		ast::ExpansionModifier::UpperOnce(pattern) => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), UppercaseReplacer)
 			.to_string()),
 		ast::ExpansionModifier::UpperAll(pattern) => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace_all(&value.into_string(), UppercaseReplacer)
 			.to_string()),
 		ast::ExpansionModifier::LowerOnce(pattern) => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace(&value.into_string(), LowercaseReplacer)
 			.to_string()),
 		ast::ExpansionModifier::LowerAll(pattern) => Ok(pattern
-			.to_regex("", "", true)?
+			.to_regex("", "", true)
+			.map_err(regex_error)?
 			.replace_all(&value.into_string(), LowercaseReplacer)
 			.to_string()),
 		ast::ExpansionModifier::ErrorOnUnset(text) => {
 			if value.is_empty() {
-				Err(EvalError::Unset(eval_text(apml, text)?))
+				Err(EvalError::UserError {
+					name: name.to_string(),
+					message: eval_text(apml, text)?,
+				})
 			} else {
 				Ok(value.into_string())
 			}
@@ -238,17 +338,197 @@ fn apply_expansion_modifier(
 				Ok(value.into_string())
 			}
 		}
+		ast::ExpansionModifier::Indirect => Ok(apml
+			.lookup(&value.into_string())
+			.unwrap_or_default()
+			.into_string()),
+		ast::ExpansionModifier::NamesWithPrefix
+		| ast::ExpansionModifier::SingleWordNamesWithPrefix => {
+			Ok(names_with_prefix(apml, name).join(" "))
+		}
+		ast::ExpansionModifier::Index(index) => {
+			let elements = subscript_elements(value);
+			let index =
+				eval_index(apml, index).ok_or_else(|| EvalError::BadIndex {
+					name: name.to_string(),
+					index: index.to_string(),
+				})?;
+			let index = if index < 0 {
+				elements.len().checked_sub(index.unsigned_abs())
+			} else {
+				Some(index as usize)
+			};
+			Ok(index
+				.and_then(|index| elements.into_iter().nth(index))
+				.unwrap_or_default())
+		}
+		ast::ExpansionModifier::ArrayLength
+		| ast::ExpansionModifier::SingleWordArrayLength => {
+			Ok(subscript_elements(value).len().to_string())
+		}
+	}
+}
+
+/// Gets the elements of a value for subscripts.
+///
+/// Unlike [`VariableValue::into_array`], string values are not split, but
+/// form a single element like Bash.
+fn subscript_elements(value: VariableValue) -> Vec<String> {
+	match value {
+		VariableValue::String(text) => vec![text],
+		VariableValue::Array(elements) => elements,
+	}
+}
+
+/// Evaluates a array index, which is either a integer or a variable name.
+fn eval_index(apml: &ApmlContext, index: &str) -> Option<isize> {
+	let index = index.trim();
+	if let Ok(index) = index.parse() {
+		return Some(index);
+	}
+	let index = index.strip_prefix('$').unwrap_or(index);
+	if !ast::is_valid_var_name(index) {
+		return None;
+	}
+	let value = apml.lookup(index).unwrap_or_default();
+	let value = value.into_string();
+	if value.trim().is_empty() {
+		Some(0)
+	} else {
+		value.trim().parse().ok()
+	}
+}
+
+/// Folds `${name:-text}` and `${name:+text}` expansions whose condition
+/// is known in the context, replacing them with the resolved branches.
+///
+/// Unlike evaluation, the result is kept as AST, so that it can be
+/// written back as APML. Variables are looked up with
+/// [`ApmlContext::lookup`], so the fallback resolver is consulted.
+/// Expansions of undefined variables are kept, while texts in their
+/// modifiers are still folded.
+pub fn fold_defaults<'a>(
+	value: &ast::VariableValue<'a>,
+	apml: &ApmlContext,
+) -> ast::VariableValue<'a> {
+	match value {
+		ast::VariableValue::String(text) => {
+			ast::VariableValue::String(fold_text(text, apml))
+		}
+		ast::VariableValue::Array(elements) => ast::VariableValue::Array(
+			elements
+				.iter()
+				.map(|element| match element {
+					ast::ArrayElement::ArrayInclusion(_) => element.clone(),
+					ast::ArrayElement::Text(text) => {
+						ast::ArrayElement::Text(Arc::new(fold_text(text, apml)))
+					}
+				})
+				.collect(),
+		),
+	}
+}
+
+fn fold_text<'a>(text: &ast::Text<'a>, apml: &ApmlContext) -> ast::Text<'a> {
+	let mut result = Vec::<ast::Word>::with_capacity(text.0.len());
+	let mut push = |word: ast::Word<'a>| {
+		if let (Some(ast::Word::Literal(last)), ast::Word::Literal(literal)) =
+			(result.last_mut(), &word)
+		{
+			last.to_mut().push_str(literal);
+		} else {
+			result.push(word);
+		}
+	};
+	for word in &text.0 {
+		let ast::Word::Variable(expansion) = word else {
+			push(word.clone());
+			continue;
+		};
+		let value = apml.lookup(&expansion.name);
+		let modifier = match (&expansion.modifier, value) {
+			(Some(ast::ExpansionModifier::WhenUnset(text)), Some(value)) => {
+				if value.is_empty() {
+					fold_text(text, apml).0.into_iter().for_each(&mut push);
+				} else {
+					push(ast::Word::Variable(ast::VariableExpansion {
+						name: expansion.name.clone(),
+						modifier: None,
+					}));
+				}
+				continue;
+			}
+			(Some(ast::ExpansionModifier::WhenSet(text)), Some(value)) => {
+				if !value.is_empty() {
+					fold_text(text, apml).0.into_iter().for_each(&mut push);
+				}
+				continue;
+			}
+			(Some(modifier), _) => Some(fold_modifier(modifier, apml)),
+			(None, _) => None,
+		};
+		push(ast::Word::Variable(ast::VariableExpansion {
+			name: expansion.name.clone(),
+			modifier,
+		}));
+	}
+	ast::Text(result)
+}
+
+fn fold_modifier<'a>(
+	modifier: &ast::ExpansionModifier<'a>,
+	apml: &ApmlContext,
+) -> ast::ExpansionModifier<'a> {
+	let fold = |text: &ast::Text<'a>| Arc::new(fold_text(text, apml));
+	match modifier {
+		ast::ExpansionModifier::ReplaceOnce { pattern, string } => {
+			ast::ExpansionModifier::ReplaceOnce {
+				pattern: pattern.clone(),
+				string: fold(string),
+			}
+		}
+		ast::ExpansionModifier::ReplaceAll { pattern, string } => {
+			ast::ExpansionModifier::ReplaceAll {
+				pattern: pattern.clone(),
+				string: fold(string),
+			}
+		}
+		ast::ExpansionModifier::ReplacePrefix { pattern, string } => {
+			ast::ExpansionModifier::ReplacePrefix {
+				pattern: pattern.clone(),
+				string: fold(string),
+			}
+		}
+		ast::ExpansionModifier::ReplaceSuffix { pattern, string } => {
+			ast::ExpansionModifier::ReplaceSuffix {
+				pattern: pattern.clone(),
+				string: fold(string),
+			}
+		}
+		ast::ExpansionModifier::ErrorOnUnset(text) => {
+			ast::ExpansionModifier::ErrorOnUnset(fold(text))
+		}
+		ast::ExpansionModifier::WhenUnset(text) => {
+			ast::ExpansionModifier::WhenUnset(fold(text))
+		}
+		ast::ExpansionModifier::WhenSet(text) => {
+			ast::ExpansionModifier::WhenSet(fold(text))
+		}
+		_ => modifier.clone(),
 	}
 }
 
 #[cfg(test)]
 mod test {
-	use std::sync::Arc;
+	use std::{collections::HashMap, sync::Arc};
 
 	use crate::apml::{
-		ApmlContext,
-		ast::{ExpansionModifier, Text, Word},
-		eval::apply_expansion_modifier,
+		ApmlContext, VariableValue,
+		ast::{self, AstNode, ExpansionModifier, Text, Word},
+		eval::{
+			EvalError, apply_expansion_modifier, fold_defaults, to_env_pairs,
+		},
+		lst,
 		pattern::{BashPattern, GlobPart},
 	};
 
@@ -260,6 +540,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::Substring {
 					offset: 0,
 					length: Some(10)
@@ -272,6 +553,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::Substring {
 					offset: 0,
 					length: Some(-1)
@@ -284,6 +566,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::Substring {
 					offset: 1,
 					length: None
@@ -305,6 +588,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripShortestPrefix(pattern1.clone()),
 				"123".into()
 			)
@@ -314,6 +598,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripShortestPrefix(pattern1.clone()),
 				"a123".into()
 			)
@@ -323,6 +608,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripShortestPrefix(pattern1.clone()),
 				"123".into()
 			)
@@ -332,6 +618,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripShortestSuffix(pattern1.clone()),
 				"a123a123".into()
 			)
@@ -341,6 +628,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripLongestPrefix(pattern1.clone()),
 				"123".into()
 			)
@@ -350,6 +638,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripLongestPrefix(pattern1.clone()),
 				"a123".into()
 			)
@@ -359,6 +648,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripLongestSuffix(pattern1.clone()),
 				"123".into()
 			)
@@ -368,6 +658,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::StripLongestSuffix(pattern1.clone()),
 				"a123a123".into()
 			)
@@ -377,6 +668,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceOnce {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -389,6 +681,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceOnce {
 					pattern: pattern2.clone(),
 					string: text1.clone()
@@ -401,6 +694,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceAll {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -413,6 +707,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceAll {
 					pattern: pattern2.clone(),
 					string: text1.clone()
@@ -425,6 +720,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplacePrefix {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -437,6 +733,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplacePrefix {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -449,6 +746,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceSuffix {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -461,6 +759,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ReplaceSuffix {
 					pattern: pattern1.clone(),
 					string: text1.clone()
@@ -473,6 +772,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::UpperOnce(pattern1.clone()),
 				"aa123abc123".into()
 			)
@@ -482,6 +782,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::UpperOnce(pattern2.clone()),
 				"aa123abc123".into()
 			)
@@ -491,6 +792,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::UpperAll(pattern1.clone()),
 				"aa123abc123".into()
 			)
@@ -500,6 +802,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::UpperAll(pattern2.clone()),
 				"aa123abc123".into()
 			)
@@ -509,6 +812,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::LowerOnce(pattern1.clone()),
 				"aA123aBC123".into()
 			)
@@ -518,6 +822,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::LowerOnce(pattern2.clone()),
 				"aA123aBC123".into()
 			)
@@ -527,6 +832,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::LowerAll(pattern1.clone()),
 				"aA123aBC123".into()
 			)
@@ -536,6 +842,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::LowerAll(pattern2.clone()),
 				"aA123aBc123".into()
 			)
@@ -545,6 +852,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::ErrorOnUnset(text1.clone()),
 				"test".into()
 			)
@@ -553,6 +861,7 @@ mod test {
 		);
 		apply_expansion_modifier(
 			&ctx,
+			"A",
 			&ExpansionModifier::ErrorOnUnset(text1.clone()),
 			"".into(),
 		)
@@ -560,6 +869,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::Length,
 				"test".into()
 			)
@@ -569,6 +879,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::WhenUnset(text1.clone()),
 				"aaa".into()
 			)
@@ -578,6 +889,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::WhenUnset(text1.clone()),
 				"".into()
 			)
@@ -587,6 +899,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::WhenSet(text1.clone()),
 				"aaa".into()
 			)
@@ -596,6 +909,7 @@ mod test {
 		assert_eq!(
 			apply_expansion_modifier(
 				&ctx,
+				"A",
 				&ExpansionModifier::WhenSet(text1.clone()),
 				"".into()
 			)
@@ -603,4 +917,239 @@ mod test {
 			""
 		);
 	}
+
+	#[test]
+	fn test_eval_error() {
+		let ctx = ApmlContext::new();
+		let err = apply_expansion_modifier(
+			&ctx,
+			"A",
+			&ExpansionModifier::ErrorOnUnset(Arc::new(Text(vec![
+				Word::Literal("required".into()),
+			]))),
+			"".into(),
+		)
+		.unwrap_err();
+		assert!(matches!(&err, EvalError::UserError { name, message }
+			if name == "A" && message == "required"));
+		assert_eq!(err.to_string(), "Required variable A is unset: required");
+		let err = apply_expansion_modifier(
+			&ctx,
+			"A",
+			&ExpansionModifier::Substring {
+				offset: 1,
+				length: Some(-5),
+			},
+			"123".into(),
+		)
+		.unwrap_err();
+		assert!(matches!(&err, EvalError::BadSubstring {
+			name,
+			offset: 1,
+			len: Some(-5)
+		} if name == "A"));
+		assert_eq!(
+			err.to_string(),
+			"Bad substring expansion of A: offset 1, length -5"
+		);
+		assert_eq!(
+			apply_expansion_modifier(
+				&ctx,
+				"A",
+				&ExpansionModifier::Substring {
+					offset: 10,
+					length: None,
+				},
+				"123".into(),
+			)
+			.unwrap(),
+			""
+		);
+		assert_eq!(
+			EvalError::BadSubstring {
+				name: "A".into(),
+				offset: -5,
+				len: None,
+			}
+			.to_string(),
+			"Bad substring expansion of A: offset -5"
+		);
+		ApmlContext::eval_source("A=\"${B:?B is required}\"").unwrap_err();
+	}
+	#[test]
+	fn test_indirect_expansion() {
+		let ctx = ApmlContext::eval_source(
+			"A=test\nB=A\nC=\"${!B}\"\nD=\"${!E}\"\nF=UNSET\nG=\"${!F}\"",
+		)
+		.unwrap();
+		assert_eq!(ctx["C"], "test");
+		assert_eq!(ctx["D"], "");
+		assert_eq!(ctx["G"], "");
+	}
+
+	#[test]
+	fn test_names_with_prefix_expansion() {
+		let ctx = ApmlContext::eval_source(
+			"PKGVER=1\nPKGNAME=a\nPKG=b\nA=\"${!PKG*}\"\nB=(\"${!PKG@}\" c)\nD=\"${!NONE@}\"",
+		)
+		.unwrap();
+		assert_eq!(
+			ctx["A"],
+			VariableValue::String("PKG PKGNAME PKGVER".into())
+		);
+		assert_eq!(
+			ctx["B"],
+			VariableValue::Array(vec![
+				"PKG".into(),
+				"PKGNAME".into(),
+				"PKGVER".into(),
+				"c".into()
+			])
+		);
+		assert_eq!(ctx["D"], "");
+	}
+
+	#[test]
+	fn test_array_subscript_expansion() {
+		let ctx = ApmlContext::eval_source(
+			"SRCS=(a b c)\nI=1\nA=\"${#SRCS[@]}\"\nB=\"${#SRCS[*]}\"\n\
+			 C=\"${SRCS[0]}\"\nD=\"${SRCS[-1]}\"\nE=\"${SRCS[I]}\"\n\
+			 F=\"${SRCS[$I]}\"\nG=\"${SRCS[5]}\"\nH=\"${#NONE[@]}\"\n\
+			 S=\"a b c\"\nJ=\"${#S[@]}\"\nK=\"${S[1]}\"\nL=\"${S[0]}\"\nM=\n\
+			 N=\"${#M[*]}\"",
+		)
+		.unwrap();
+		assert_eq!(ctx["A"], "3");
+		assert_eq!(ctx["B"], "3");
+		assert_eq!(ctx["C"], "a");
+		assert_eq!(ctx["D"], "c");
+		assert_eq!(ctx["E"], "b");
+		assert_eq!(ctx["F"], "b");
+		assert_eq!(ctx["G"], "");
+		assert_eq!(ctx["H"], "0");
+		assert_eq!(ctx["J"], "1");
+		assert_eq!(ctx["K"], "");
+		assert_eq!(ctx["L"], "a b c");
+		assert_eq!(ctx["N"], "1");
+		assert!(matches!(
+			ApmlContext::eval_source("A=(a)\nB=\"${A[i+1]}\"").unwrap_err(),
+			crate::apml::ApmlError::Eval(EvalError::BadIndex { .. })
+		));
+	}
+
+	#[test]
+	fn test_negative_substring_offset() {
+		let ctx = ApmlContext::eval_source(
+			"A=abcdef\nB=\"${A: -3}\"\nC=\"${A: -3:2}\"\nD=\"${A: -4:-1}\"\n\
+			 E=\"${A: -10}\"\nF=\"${A:-3}\"\nG=\"${NONE:-3}\"",
+		)
+		.unwrap();
+		assert_eq!(ctx["B"], "def");
+		assert_eq!(ctx["C"], "de");
+		assert_eq!(ctx["D"], "cde");
+		assert_eq!(ctx["E"], "");
+		assert_eq!(ctx["F"], "abcdef");
+		assert_eq!(ctx["G"], "3");
+		assert!(matches!(
+			ApmlContext::eval_source("A=abc\nB=\"${A: -1:-2}\"").unwrap_err(),
+			crate::apml::ApmlError::Eval(EvalError::BadSubstring {
+				offset: -1,
+				len: Some(-2),
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn test_fallback() {
+		let mut ctx = ApmlContext::with_fallback(|name| match name {
+			"A" | "B" => Some(format!("fallback-{name}")),
+			"IDX" => Some("1".into()),
+			_ => None,
+		});
+		let src = "A=spec\nC=\"$A $B ${D:-unset}\"\nE=(a b)\nF=${E[IDX]}";
+		let ast = ast::ApmlAst::emit_from(&lst::ApmlLst::parse(src).unwrap())
+			.unwrap();
+		ctx.eval_ast_in(&ast).unwrap();
+		assert_eq!(ctx["C"], "spec fallback-B unset");
+		assert_eq!(ctx["F"], "b");
+		assert_eq!(ctx.get("B"), None);
+		assert_eq!(ctx.lookup("B"), Some("fallback-B".into()));
+		assert_eq!(ctx.lookup("D"), None);
+
+		let ctx = ApmlContext::with_env();
+		assert_eq!(
+			ctx.lookup("PATH"),
+			std::env::var("PATH").ok().map(VariableValue::String)
+		);
+	}
+
+	#[test]
+	fn test_to_env_pairs() {
+		let env = HashMap::from([
+			("HOME".to_string(), "/root".to_string()),
+			("A".to_string(), "env".to_string()),
+		]);
+		let ast = |src| {
+			ast::ApmlAst::emit_from(&lst::ApmlLst::parse(src).unwrap()).unwrap()
+		};
+		let result =
+			to_env_pairs(&ast("B=\"$HOME/$A\"\nA=spec\nC=($A $B)"), &env)
+				.unwrap();
+		assert_eq!(
+			result,
+			HashMap::from([
+				("HOME".to_string(), "/root".to_string()),
+				("A".to_string(), "spec".to_string()),
+				("B".to_string(), "/root/env".to_string()),
+				("C".to_string(), "spec /root/env".to_string()),
+			])
+		);
+		assert!(matches!(
+			to_env_pairs(&ast("A=${A:?}\nB=${D:?unset}"), &env),
+			Err(EvalError::UserError { name, .. }) if name == "D"
+		));
+	}
+
+	#[test]
+	fn test_fold_defaults() {
+		let ctx = ApmlContext::eval_source("SET=1\nEMPTY=").unwrap();
+		let fold = |src: &str| {
+			let ast =
+				ast::ApmlAst::emit_from(&lst::ApmlLst::parse(src).unwrap())
+					.unwrap();
+			fold_defaults(&ast.0[0].value, &ctx).lower().to_string()
+		};
+		assert_eq!(fold("A=a${SET:-b}c"), "\"a${SET}c\"");
+		assert_eq!(fold("A=a${EMPTY:-b}c"), "\"abc\"");
+		assert_eq!(fold("A=a${SET:+b}c"), "\"abc\"");
+		assert_eq!(fold("A=a${EMPTY:+b}c"), "\"ac\"");
+		assert_eq!(
+			fold("A=${NONE:-b}${NONE:+c}"),
+			"\"${NONE:-\"b\"}${NONE:+\"c\"}\""
+		);
+		assert_eq!(fold("A=${EMPTY:-${SET:+x${EMPTY:-y}}}"), "\"xy\"");
+		assert_eq!(
+			fold("A=${NONE:-${SET:-z}}${NONE/a/${EMPTY:-b}}${SET:1}"),
+			"\"${NONE:-\"${SET}\"}${NONE/a/\"b\"}${SET:1}\""
+		);
+		assert_eq!(
+			fold("A=(\"${B[@]}\" ${EMPTY:-a} \"${NONE:+b}\")"),
+			"(\"${B[@]}\" \"a\" \"${NONE:+\"b\"}\")"
+		);
+
+		let ctx = ApmlContext::with_fallback(|name| match name {
+			"FALLBACK" => Some("1".into()),
+			_ => None,
+		});
+		let ast = ast::ApmlAst::emit_from(
+			&lst::ApmlLst::parse("A=${FALLBACK:-a}${FALLBACK:+b}${NONE:+c}")
+				.unwrap(),
+		)
+		.unwrap();
+		assert_eq!(
+			fold_defaults(&ast.0[0].value, &ctx).lower().to_string(),
+			"\"${FALLBACK}b${NONE:+\"c\"}\""
+		);
+	}
 }
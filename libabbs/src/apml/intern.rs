@@ -0,0 +1,242 @@
+//! Interning of repeated AST nodes.
+//!
+//! When emitting a large number of APML files, texts like `"$SRCDIR"`
+//! and patterns like `*.tar.*` repeat constantly. An [`Interner`] keeps
+//! one shared allocation for each structurally identical node.
+//!
+//! Only nodes that are already reference-counted in AST are interned,
+//! which includes array elements, texts in expansion modifiers and
+//! patterns.
+
+use std::{collections::HashSet, hash::Hash, mem::size_of, sync::Arc};
+
+use super::{
+	ast::{
+		ApmlAst, ArrayElement, AstNode, EmitResult, ExpansionModifier, Text,
+		VariableExpansion, VariableValue, Word,
+	},
+	lst,
+	pattern::{BashPattern, GlobPart},
+};
+
+/// A pool of shared AST nodes.
+#[derive(Debug, Default)]
+pub struct Interner<'a> {
+	texts: HashSet<Arc<Text<'a>>>,
+	patterns: HashSet<Arc<BashPattern<'a>>>,
+	stats: InternerStats,
+}
+
+/// Statistics of a [`Interner`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InternerStats {
+	/// Number of nodes looked up.
+	pub lookups: usize,
+	/// Number of nodes replaced with a existing shared node.
+	pub hits: usize,
+	/// Estimated number of bytes saved by sharing.
+	pub bytes_saved: usize,
+}
+
+impl InternerStats {
+	/// Returns the ratio of hits in all lookups.
+	pub fn hit_rate(&self) -> f64 {
+		if self.lookups == 0 {
+			0.0
+		} else {
+			self.hits as f64 / self.lookups as f64
+		}
+	}
+}
+
+impl<'a> Interner<'a> {
+	/// Creates a empty interner.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns the statistics.
+	pub fn stats(&self) -> InternerStats {
+		self.stats
+	}
+
+	/// Returns the number of distinct nodes in the pool.
+	pub fn len(&self) -> usize {
+		self.texts.len() + self.patterns.len()
+	}
+
+	/// Returns if the pool is empty.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Replaces shared nodes in a AST with interned ones.
+	pub fn intern_ast(&mut self, ast: &mut ApmlAst<'a>) {
+		for def in &mut ast.0 {
+			match &mut def.value {
+				VariableValue::String(text) => self.intern_words(text),
+				VariableValue::Array(elements) => {
+					for element in elements {
+						if let ArrayElement::Text(text) = element {
+							self.intern_text(text);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	/// Replaces a shared text with the interned one.
+	pub fn intern_text(&mut self, text: &mut Arc<Text<'a>>) {
+		if let Some(text) = Arc::get_mut(text) {
+			self.intern_words(text);
+		}
+		Self::intern(&mut self.texts, &mut self.stats, text, text_size);
+	}
+
+	/// Replaces a shared pattern with the interned one.
+	pub fn intern_pattern(&mut self, pattern: &mut Arc<BashPattern<'a>>) {
+		Self::intern(&mut self.patterns, &mut self.stats, pattern, |pattern| {
+			size_of::<BashPattern>() + pattern.0.len() * size_of::<GlobPart>()
+		});
+	}
+
+	fn intern_words(&mut self, text: &mut Text<'a>) {
+		for word in &mut text.0 {
+			if let Word::Variable(VariableExpansion {
+				modifier: Some(modifier),
+				..
+			}) = word
+			{
+				self.intern_modifier(modifier);
+			}
+		}
+	}
+
+	fn intern_modifier(&mut self, modifier: &mut ExpansionModifier<'a>) {
+		match modifier {
+			ExpansionModifier::StripShortestPrefix(pattern)
+			| ExpansionModifier::StripLongestPrefix(pattern)
+			| ExpansionModifier::StripShortestSuffix(pattern)
+			| ExpansionModifier::StripLongestSuffix(pattern)
+			| ExpansionModifier::UpperOnce(pattern)
+			| ExpansionModifier::UpperAll(pattern)
+			| ExpansionModifier::LowerOnce(pattern)
+			| ExpansionModifier::LowerAll(pattern) => self.intern_pattern(pattern),
+			ExpansionModifier::ReplaceOnce { pattern, string }
+			| ExpansionModifier::ReplaceAll { pattern, string }
+			| ExpansionModifier::ReplacePrefix { pattern, string }
+			| ExpansionModifier::ReplaceSuffix { pattern, string } => {
+				self.intern_pattern(pattern);
+				self.intern_text(string);
+			}
+			ExpansionModifier::ErrorOnUnset(text)
+			| ExpansionModifier::WhenUnset(text)
+			| ExpansionModifier::WhenSet(text) => self.intern_text(text),
+			ExpansionModifier::Substring { .. }
+			| ExpansionModifier::Length
+			| ExpansionModifier::Indirect
+			| ExpansionModifier::NamesWithPrefix
+			| ExpansionModifier::SingleWordNamesWithPrefix
+			| ExpansionModifier::Index(_)
+			| ExpansionModifier::ArrayLength
+			| ExpansionModifier::SingleWordArrayLength => {}
+		}
+	}
+
+	fn intern<T: Eq + Hash>(
+		pool: &mut HashSet<Arc<T>>,
+		stats: &mut InternerStats,
+		node: &mut Arc<T>,
+		size: impl Fn(&T) -> usize,
+	) {
+		stats.lookups += 1;
+		match pool.get(node) {
+			Some(shared) => {
+				if !Arc::ptr_eq(shared, node) {
+					stats.hits += 1;
+					stats.bytes_saved += size(node);
+					*node = shared.clone();
+				}
+			}
+			None => {
+				pool.insert(node.clone());
+			}
+		}
+	}
+}
+
+/// Estimates the size of a text in bytes.
+fn text_size(text: &Text) -> usize {
+	size_of::<Text>()
+		+ text
+			.0
+			.iter()
+			.map(|word| {
+				size_of::<Word>()
+					+ match word {
+						Word::Literal(text) | Word::Subcommand(text) => {
+							text.len()
+						}
+						Word::Variable(expansion) => expansion.name.len(),
+					}
+			})
+			.sum::<usize>()
+}
+
+impl<'a> ApmlAst<'a> {
+	/// Emits a LST into AST, sharing repeated nodes with the interner.
+	pub fn emit_from_interned(
+		lst: &lst::ApmlLst<'a>,
+		interner: &mut Interner<'a>,
+	) -> EmitResult<Self> {
+		let mut ast = Self::emit_from(lst)?;
+		interner.intern_ast(&mut ast);
+		Ok(ast)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_interner() {
+		let mut interner = Interner::new();
+		assert!(interner.is_empty());
+		let lst1 = lst::ApmlLst::parse(
+			"A=(\"$SRCDIR\" \"$SRCDIR\" b)\nB=${A%%*.tar.*}\nC=${A:-\"$SRCDIR\"}",
+		)
+		.unwrap();
+		let lst2 =
+			lst::ApmlLst::parse("A=(\"$SRCDIR\")\nB=${B%%*.tar.*}").unwrap();
+		let ast1 = ApmlAst::emit_from_interned(&lst1, &mut interner).unwrap();
+		let ast2 = ApmlAst::emit_from_interned(&lst2, &mut interner).unwrap();
+		assert_eq!(ast1, ApmlAst::emit_from(&lst1).unwrap());
+		assert_eq!(ast2, ApmlAst::emit_from(&lst2).unwrap());
+
+		let (VariableValue::Array(elements1), VariableValue::Array(elements2)) =
+			(&ast1.0[0].value, &ast2.0[0].value)
+		else {
+			unreachable!()
+		};
+		let (
+			ArrayElement::Text(a),
+			ArrayElement::Text(b),
+			ArrayElement::Text(c),
+		) = (&elements1[0], &elements1[1], &elements2[0])
+		else {
+			unreachable!()
+		};
+		assert!(Arc::ptr_eq(a, b));
+		assert!(Arc::ptr_eq(a, c));
+
+		let stats = interner.stats();
+		assert_eq!(stats.lookups, 7);
+		assert_eq!(stats.hits, 4);
+		assert!(stats.bytes_saved > 0);
+		assert!((stats.hit_rate() - 4.0 / 7.0).abs() < f64::EPSILON);
+		assert_eq!(interner.len(), 3);
+		assert_eq!(InternerStats::default().hit_rate(), 0.0);
+	}
+}
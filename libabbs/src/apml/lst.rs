@@ -19,12 +19,17 @@
 
 use std::{
 	borrow::Cow,
+	collections::HashMap,
 	fmt::{Debug, Display, Write},
+	ops::{Add, Deref, Range},
 	sync::Arc,
 };
 
+use thiserror::Error;
+
 use super::{
-	parser::{ParseError, apml_lst},
+	ast::{LowerStyle, is_valid_var_name},
+	parser::{ParseError, ParseErrorKind, apml_lst, variable_value},
 	pattern::BashPattern,
 };
 
@@ -44,18 +49,331 @@ impl Display for ApmlLst<'_> {
 impl<'a> ApmlLst<'a> {
 	/// Parses a APML source string into a lossless syntax tree.
 	///
-	/// This is a wrapper calling [`apml_lst`] parser combinator,
-	/// while errors produced by the parser are converted into [`ParseError`],
-	/// and [`ParseError::UnexpectedSource`] is produced when
-	/// there are some unparsable texts in the input.
+	/// This is a wrapper calling [`apml_lst`] parser combinator.
+	/// When there are some unparsable texts in the input, a [`ParseError`]
+	/// is produced, diagnosing why parsing gave up.
 	pub fn parse(src: &'a str) -> Result<Self, ParseError> {
-		let (out, tree) = apml_lst(src)?;
-		if !out.is_empty() {
-			return Err(ParseError::UnexpectedSource {
-				pos: nom::Offset::offset(src, out) + 1,
-			});
+		match Self::parse_prefix(src) {
+			(tree, None) => Ok(tree),
+			(_, Some(err)) => Err(err),
+		}
+	}
+
+	/// Parses the longest parsable prefix of a APML source string into LST.
+	///
+	/// Returns the LST of the prefix and, if the source is not fully
+	/// parsed, a [`ParseError`] diagnosing why parsing gave up.
+	pub(crate) fn parse_prefix(src: &'a str) -> (Self, Option<ParseError>) {
+		match apml_lst(src) {
+			Ok(("", tree)) => (tree, None),
+			Ok((out, tree)) => {
+				let err = tree.diagnose(src, nom::Offset::offset(src, out));
+				(tree, Some(err))
+			}
+			Err(err) => {
+				(Self(Vec::new()), Some(ParseError::from_nom(src, err)))
+			}
+		}
+	}
+
+	/// Parses a UTF-8 encoded APML source into LST.
+	///
+	/// A leading UTF-8 BOM is skipped. Invalid UTF-8 sequences are
+	/// rejected with [`ParseErrorKind::InvalidEncoding`] errors.
+	/// Spans of errors are byte offsets in the given bytes,
+	/// while the LST does not include the BOM.
+	pub fn parse_bytes(bytes: &'a [u8]) -> Result<Self, ParseError> {
+		const BOM: &[u8] = b"\xEF\xBB\xBF";
+		let (bom, src) = match bytes.strip_prefix(BOM) {
+			Some(src) => (BOM.len(), src),
+			None => (0, bytes),
+		};
+		let src = std::str::from_utf8(src).map_err(|err| {
+			let start = bom + err.valid_up_to();
+			let end = start + err.error_len().unwrap_or(bytes.len() - start);
+			ParseError {
+				span: start..end,
+				kind: ParseErrorKind::InvalidEncoding,
+				message: "Invalid UTF-8 sequence".to_string(),
+			}
+		})?;
+		Self::parse(src).map_err(|mut err| {
+			err.span = err.span.start + bom..err.span.end + bom;
+			err
+		})
+	}
+
+	/// Parses a ISO-8859-1 (Latin-1) encoded APML source into LST.
+	///
+	/// As LSTs borrow from the source, the decoded source is stored
+	/// into `buf`. Spans of errors are byte offsets in the decoded source.
+	pub fn parse_latin1(
+		bytes: &[u8],
+		buf: &'a mut String,
+	) -> Result<Self, ParseError> {
+		*buf = bytes.iter().map(|byte| *byte as char).collect();
+		Self::parse(buf)
+	}
+
+	/// Parses a APML source string into LST, recovering from errors.
+	///
+	/// Unparsable regions, from where the parser gives up to the end of
+	/// the line, are kept as [`Token::Unknown`] tokens, and parsing is
+	/// resumed at the next line. The produced LST is always lossless.
+	///
+	/// Returns the best-effort LST and errors of all unparsable regions.
+	/// There is exactly one error for each [`Token::Unknown`], and its
+	/// span covers the span of the token.
+	pub fn parse_recover(src: &'a str) -> (Self, Vec<ParseError>) {
+		let mut tree = ApmlLst(Vec::new());
+		let mut errors = Vec::new();
+		let mut offset = 0;
+		while offset < src.len() {
+			let (rest, error) = match apml_lst(&src[offset..]) {
+				Ok((rest, mut part)) => {
+					tree.0.append(&mut part.0);
+					(rest, None)
+				}
+				Err(err) => {
+					(&src[offset..], Some(ParseError::from_nom(src, err)))
+				}
+			};
+			if rest.is_empty() {
+				break;
+			}
+			offset = nom::Offset::offset(src, rest);
+			let end = rest.find('\n').map_or(src.len(), |len| offset + len);
+			let mut error = error.unwrap_or_else(|| tree.diagnose(src, offset));
+			error.span = error.span.start.min(offset)..error.span.end.max(end);
+			errors.push(error);
+			tree.0
+				.push(Token::Unknown(Cow::Borrowed(&src[offset..end])));
+			offset = end;
+		}
+		(tree, errors)
+	}
+
+	/// Diagnoses why parsing stopped at the given offset,
+	/// with the LST parsed before the offset.
+	fn diagnose(&self, src: &str, offset: usize) -> ParseError {
+		let last_def = self
+			.0
+			.iter()
+			.rev()
+			.take_while(|token| !matches!(token, Token::Unknown(_)))
+			.position(|token| matches!(token, Token::Variable(_)))
+			.and_then(|index| self.token_span(self.0.len() - index - 1));
+		ParseError::at(src, offset, last_def)
+	}
+
+	/// Iterates over lines of the LST, as slices of tokens separated by
+	/// [`Token::Newline`].
+	///
+	/// Newlines inside multi-line values do not split lines.
+	/// Like [`str::lines`], the empty line after a final newline is
+	/// not produced.
+	pub fn iter_lines(&self) -> impl Iterator<Item = &[Token<'a>]> {
+		self.0
+			.split_inclusive(|token| matches!(token, Token::Newline))
+			.map(|line| line.strip_suffix(&[Token::Newline]).unwrap_or(line))
+	}
+
+	/// Gets the byte range of the token at the given index in the
+	/// serialized source.
+	///
+	/// As LSTs are lossless, for a LST parsed from a source string,
+	/// this is exactly where the token comes from.
+	pub fn token_span(&self, index: usize) -> Option<Range<usize>> {
+		let token = self.0.get(index)?;
+		let start = self.0[..index].iter().map(Token::source_len).sum();
+		Some(start..start + token.source_len())
+	}
+
+	/// Finds the index of the token covering the given byte offset
+	/// of the serialized source.
+	pub fn token_at_offset(&self, offset: usize) -> Option<usize> {
+		let mut end = 0;
+		self.0.iter().position(|token| {
+			end += token.source_len();
+			end > offset
+		})
+	}
+
+	/// Extracts tokens whose spans in the serialized source fall entirely
+	/// within `[byte_start, byte_end)` into a new LST.
+	///
+	/// Tokens partially covered by the range are not included.
+	pub fn extract_range(&self, byte_start: usize, byte_end: usize) -> Self {
+		let mut start = 0;
+		let mut result = Vec::new();
+		for token in &self.0 {
+			let end = start + token.source_len();
+			if start >= byte_start && end <= byte_end {
+				result.push(token.clone());
+			} else if start >= byte_end {
+				break;
+			}
+			start = end;
 		}
-		Ok(tree)
+		Self(result)
+	}
+
+	/// Finds the index of a token in this LST.
+	///
+	/// Tokens are compared by identity, so the token must be borrowed
+	/// from this LST.
+	pub fn token_index(&self, token: &Token) -> Option<usize> {
+		self.0.iter().position(|item| std::ptr::eq(item, token))
+	}
+
+	/// Finds the first token matching the predicate.
+	///
+	/// Use [`token_index`][Self::token_index] to locate the token.
+	pub fn find_token<F>(&self, predicate: F) -> Option<&Token<'a>>
+	where
+		F: Fn(&Token<'a>) -> bool,
+	{
+		self.0.iter().find(|token| predicate(token))
+	}
+
+	/// Finds all tokens matching the predicate, in order of occurrence.
+	pub fn find_all_tokens<F>(&self, predicate: F) -> Vec<&Token<'a>>
+	where
+		F: Fn(&Token<'a>) -> bool,
+	{
+		self.0.iter().filter(|token| predicate(token)).collect()
+	}
+
+	/// Gets the source text of a token in this LST.
+	///
+	/// The given source must be the one this LST is parsed from.
+	/// Returns [None] if the token is not borrowed from this LST or
+	/// the source is too short.
+	pub fn source_text<'s>(
+		&self,
+		src: &'s str,
+		token: &Token,
+	) -> Option<&'s str> {
+		src.get(self.token_span(self.token_index(token)?)?)
+	}
+}
+
+impl TryFrom<Vec<(String, String)>> for ApmlLst<'static> {
+	type Error = InvalidVarName;
+
+	/// Builds a LST defining variables with the given names and values.
+	///
+	/// Each variable is defined on its own line, followed by a newline.
+	/// Values are double-quoted if needed.
+	///
+	/// Returns a error if any name is not a valid variable name.
+	fn try_from(vars: Vec<(String, String)>) -> Result<Self, Self::Error> {
+		if let Some((name, _)) =
+			vars.iter().find(|(name, _)| !is_valid_var_name(name))
+		{
+			return Err(InvalidVarName(name.clone()));
+		}
+		Ok(Self::define_vars(vars))
+	}
+}
+
+impl TryFrom<HashMap<String, String>> for ApmlLst<'static> {
+	type Error = InvalidVarName;
+
+	/// Builds a LST defining variables with the given names and values.
+	///
+	/// Variables are sorted by names.
+	fn try_from(vars: HashMap<String, String>) -> Result<Self, Self::Error> {
+		let mut vars = vars.into_iter().collect::<Vec<_>>();
+		vars.sort();
+		vars.try_into()
+	}
+}
+
+/// Error of building a LST with a invalid variable name.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid variable name: {0:?}")]
+pub struct InvalidVarName(pub String);
+
+impl ApmlLst<'static> {
+	/// Builds a LST defining variables with valid names.
+	fn define_vars(vars: Vec<(String, String)>) -> Self {
+		let mut tokens = Vec::with_capacity(vars.len() * 2);
+		for (name, value) in vars {
+			let value = LowerStyle::Auto.lower_str(&value);
+			tokens.push(Token::Variable(VariableDefinition {
+				declaration: None,
+				name: name.into(),
+				op: VariableOp::Assignment,
+				value: VariableValue::String(Arc::new(value)),
+			}));
+			tokens.push(Token::Newline);
+		}
+		Self(tokens)
+	}
+
+	/// Builds a LST defining variables of the current process environment.
+	///
+	/// Variables are sorted by names and values are quoted if needed.
+	/// Variables with names that are not valid APML variable names, or
+	/// with names or values that are not valid Unicode, are skipped.
+	pub fn from_env() -> Self {
+		let mut vars = std::env::vars_os()
+			.filter_map(|(name, value)| {
+				Some((name.into_string().ok()?, value.into_string().ok()?))
+			})
+			.filter(|(name, _)| is_valid_var_name(name))
+			.collect::<Vec<_>>();
+		vars.sort();
+		Self::define_vars(vars)
+	}
+}
+
+impl<'a> ApmlLst<'a> {
+	/// Converts this LST into a read-only one.
+	pub fn freeze(self) -> ApmlFrozenLst<'a> {
+		ApmlFrozenLst(self)
+	}
+}
+
+/// A read-only APML parse-tree.
+///
+/// Only immutable methods of [`ApmlLst`] are accessible through [`Deref`],
+/// so it cannot be edited with [`ApmlEditor`][super::editor::ApmlEditor]
+/// unless it is converted back with [`thaw`][Self::thaw].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApmlFrozenLst<'a>(ApmlLst<'a>);
+
+impl<'a> ApmlFrozenLst<'a> {
+	/// Converts this LST back into a mutable one.
+	pub fn thaw(self) -> ApmlLst<'a> {
+		self.0
+	}
+}
+
+impl<'a> Deref for ApmlFrozenLst<'a> {
+	type Target = ApmlLst<'a>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl<'a> AsRef<ApmlLst<'a>> for ApmlFrozenLst<'a> {
+	fn as_ref(&self) -> &ApmlLst<'a> {
+		&self.0
+	}
+}
+
+impl<'a> From<ApmlLst<'a>> for ApmlFrozenLst<'a> {
+	fn from(lst: ApmlLst<'a>) -> Self {
+		lst.freeze()
+	}
+}
+
+impl Display for ApmlFrozenLst<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.0, f)
 	}
 }
 
@@ -74,6 +392,10 @@ pub enum Token<'a> {
 	Comment(Cow<'a, str>),
 	/// A variable definition.
 	Variable(VariableDefinition<'a>),
+	/// A unparsable source text (`"<text>"`).
+	///
+	/// This is only produced by [`ApmlLst::parse_recover`].
+	Unknown(Cow<'a, str>),
 }
 
 impl Token<'_> {
@@ -81,6 +403,20 @@ impl Token<'_> {
 	pub fn is_empty(&self) -> bool {
 		matches!(&self, Token::Newline | Token::Spacy(_))
 	}
+
+	/// Returns the length of the token in bytes when serialized.
+	pub fn source_len(&self) -> usize {
+		struct Counter(usize);
+		impl Write for Counter {
+			fn write_str(&mut self, s: &str) -> std::fmt::Result {
+				self.0 += s.len();
+				Ok(())
+			}
+		}
+		let mut counter = Counter(0);
+		let _ = write!(counter, "{}", self);
+		counter.0
+	}
 }
 
 impl Display for Token<'_> {
@@ -90,6 +426,7 @@ impl Display for Token<'_> {
 			Token::Newline => f.write_char('\n'),
 			Token::Comment(text) => f.write_fmt(format_args!("#{}", text)),
 			Token::Variable(def) => Display::fmt(def, f),
+			Token::Unknown(text) => f.write_str(text),
 		}
 	}
 }
@@ -97,6 +434,8 @@ impl Display for Token<'_> {
 /// A variable definition (`"<name>=<value>"`).
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct VariableDefinition<'a> {
+	/// Declaration command before the name, like `declare -x `.
+	pub declaration: Option<Declaration<'a>>,
 	/// Name of the variable.
 	pub name: Cow<'a, str>,
 	/// Binary operator.
@@ -105,8 +444,27 @@ pub struct VariableDefinition<'a> {
 	pub value: VariableValue<'a>,
 }
 
+/// Variable definitions are ordered by their names.
+///
+/// Note that two definitions with the same name but different values are
+/// considered equivalent in ordering while they are not equal.
+impl PartialOrd for VariableDefinition<'_> {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for VariableDefinition<'_> {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.name.cmp(&other.name)
+	}
+}
+
 impl Display for VariableDefinition<'_> {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if let Some(declaration) = &self.declaration {
+			Display::fmt(declaration, f)?;
+		}
 		f.write_str(&self.name)?;
 		Display::fmt(&self.op, f)?;
 		Display::fmt(&self.value, f)?;
@@ -114,6 +472,83 @@ impl Display for VariableDefinition<'_> {
 	}
 }
 
+/// A declaration command prefixing a variable definition
+/// (`"<keyword> <flags> "`), like `declare -x ` and `typeset -a `.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Declaration<'a> {
+	/// The declaration keyword.
+	pub keyword: DeclarationKeyword,
+	/// Spaces and flags after the keyword, including the spaces before
+	/// the variable name.
+	pub tokens: Vec<DeclarationToken<'a>>,
+}
+
+impl Declaration<'_> {
+	/// Iterates over all flag characters, for example `x` and `r` for
+	/// `declare -x -r `.
+	pub fn flags(&self) -> impl Iterator<Item = char> + '_ {
+		self.tokens.iter().flat_map(|token| match token {
+			DeclarationToken::Flag(flag) => flag[1..].chars(),
+			DeclarationToken::Spacy(_) => "".chars(),
+		})
+	}
+
+	/// Returns if the given flag is set.
+	pub fn has_flag(&self, flag: char) -> bool {
+		self.flags().any(|ch| ch == flag)
+	}
+
+	/// Returns if the variable is declared as a indexed array (`-a`).
+	pub fn is_array(&self) -> bool {
+		self.has_flag('a')
+	}
+}
+
+impl Display for Declaration<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		Display::fmt(&self.keyword, f)?;
+		for token in &self.tokens {
+			match token {
+				DeclarationToken::Spacy(ch) => f.write_char(*ch)?,
+				DeclarationToken::Flag(flag) => f.write_str(flag)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A declaration keyword.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeclarationKeyword {
+	/// `declare`.
+	Declare,
+	/// `typeset`.
+	Typeset,
+	/// `export`.
+	Export,
+}
+
+impl Display for DeclarationKeyword {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			DeclarationKeyword::Declare => "declare",
+			DeclarationKeyword::Typeset => "typeset",
+			DeclarationKeyword::Export => "export",
+		})
+	}
+}
+
+/// A token in a [`Declaration`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeclarationToken<'a> {
+	/// A space-like character (`'<char>'`).
+	///
+	/// See [Token::Spacy] for more.
+	Spacy(char),
+	/// A flag argument, including the leading `-` (`"-<flags>"`).
+	Flag(Cow<'a, str>),
+}
+
 /// A variable operator.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum VariableOp {
@@ -141,6 +576,64 @@ pub enum VariableValue<'a> {
 	Array(Vec<ArrayToken<'a>>),
 }
 
+impl<'a> VariableValue<'a> {
+	/// Parses a bare value fragment, such as the right hand side of
+	/// a variable definition.
+	///
+	/// The whole source must be a single value, so unquoted spaces
+	/// and newlines outside of arrays are rejected.
+	pub fn parse(src: &'a str) -> Result<Self, ParseError> {
+		let (out, value) = variable_value(src)
+			.map_err(|err| ParseError::from_nom(src, err))?;
+		if !out.is_empty() {
+			return Err(ParseError::unexpected_char(
+				src,
+				nom::Offset::offset(src, out),
+			));
+		}
+		Ok(value)
+	}
+
+	/// Creates a string value with the minimal quoting style.
+	///
+	/// Values consisting of only `[A-Za-z0-9._/-]` are left unquoted,
+	/// values without `'` are single-quoted, and other values are
+	/// double-quoted with special characters escaped.
+	pub fn new_string(value: &'a str) -> Self {
+		let unit = if !value.is_empty()
+			&& value.chars().all(|ch| {
+				ch.is_ascii_alphanumeric()
+					|| matches!(ch, '.' | '_' | '/' | '-')
+			}) {
+			TextUnit::Unquoted(vec![Word::Literal(vec![LiteralPart::String(
+				Cow::Borrowed(value),
+			)])])
+		} else if !value.contains('\'') {
+			TextUnit::SingleQuote(Cow::Borrowed(value))
+		} else {
+			TextUnit::DoubleQuote(vec![Word::Literal(LiteralPart::escape(
+				value,
+			))])
+		};
+		Self::String(Arc::new(Text(vec![unit])))
+	}
+}
+
+impl VariableValue<'_> {
+	/// Returns if the value is fully determined by its source text,
+	/// containing no variable or sub-command expansions.
+	///
+	/// See [`Text::is_constant`].
+	pub fn is_constant(&self) -> bool {
+		match self {
+			VariableValue::String(text) => text.is_constant(),
+			VariableValue::Array(tokens) => tokens.iter().all(
+				|token| !matches!(token, ArrayToken::Element(text) if !text.is_constant()),
+			),
+		}
+	}
+}
+
 impl Display for VariableValue<'_> {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
@@ -157,6 +650,85 @@ impl Display for VariableValue<'_> {
 	}
 }
 
+/// Compares two values by their literal contents, ignoring quoting styles.
+///
+/// For example, `"abc"`, `'abc'` and `abc` are equal. For arrays,
+/// elements are compared in order, ignoring spaces and comments.
+///
+/// This only applies to constant values, see [`VariableValue::is_constant`].
+/// Values containing expansions cannot be compared without a context,
+/// so [false] is returned if either side is not constant.
+pub fn values_equal_unquoted(a: &VariableValue, b: &VariableValue) -> bool {
+	fn elements<'s>(tokens: &'s [ArrayToken]) -> Option<Vec<Cow<'s, str>>> {
+		tokens
+			.iter()
+			.filter_map(|token| match token {
+				ArrayToken::Element(text) => Some(text.literal_value()),
+				_ => None,
+			})
+			.collect()
+	}
+	match (a, b) {
+		(VariableValue::String(a), VariableValue::String(b)) => {
+			match (a.literal_value(), b.literal_value()) {
+				(Some(a), Some(b)) => a == b,
+				_ => false,
+			}
+		}
+		(VariableValue::Array(a), VariableValue::Array(b)) => {
+			match (elements(a), elements(b)) {
+				(Some(a), Some(b)) => a == b,
+				_ => false,
+			}
+		}
+		_ => false,
+	}
+}
+
+/// Kinds of quoting used by a value, see [`quoting_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotingKind {
+	/// A empty string value (`A=`).
+	Empty,
+	/// A string value made up of only unquoted units.
+	Unquoted,
+	/// A string value made up of only single-quoted units.
+	SingleQuoted,
+	/// A string value made up of only double-quoted units.
+	DoubleQuoted,
+	/// A string value made up of different kinds of units.
+	Mixed,
+	/// A array value.
+	Array,
+}
+
+/// Gets the kind of quoting used by a value.
+pub fn quoting_kind(value: &VariableValue) -> QuotingKind {
+	let VariableValue::String(text) = value else {
+		return QuotingKind::Array;
+	};
+	let mut kinds = text.0.iter().map(|unit| match unit {
+		TextUnit::Unquoted(_) => QuotingKind::Unquoted,
+		TextUnit::SingleQuote(_) => QuotingKind::SingleQuoted,
+		TextUnit::DoubleQuote(_) => QuotingKind::DoubleQuoted,
+	});
+	let Some(first) = kinds.next() else {
+		return QuotingKind::Empty;
+	};
+	if kinds.all(|kind| kind == first) {
+		first
+	} else {
+		QuotingKind::Mixed
+	}
+}
+
+/// Returns if a value is exactly one single-quoted unit, which is
+/// taken literally without any expansions.
+pub fn is_single_quoted_literal(value: &VariableValue) -> bool {
+	matches!(value, VariableValue::String(text)
+		if matches!(text.0.as_slice(), [TextUnit::SingleQuote(_)]))
+}
+
 /// A section of text.
 ///
 /// Text is made up of several text units.
@@ -175,6 +747,199 @@ impl Display for Text<'_> {
 	}
 }
 
+impl<'a> Text<'a> {
+	/// Returns if the text is fully determined by its source text,
+	/// containing no variable or sub-command expansions.
+	pub fn is_constant(&self) -> bool {
+		self.0.iter().all(|unit| match unit {
+			TextUnit::SingleQuote(_) => true,
+			TextUnit::Unquoted(words) | TextUnit::DoubleQuote(words) => {
+				words.iter().all(|word| matches!(word, Word::Literal(_)))
+			}
+		})
+	}
+
+	/// Gets the literal value of a constant text without evaluation.
+	///
+	/// Quotes and line continuations are removed and escaped characters
+	/// are unescaped. Returns [`None`] if the text is not constant,
+	/// see [`Text::is_constant`].
+	pub fn literal_value(&self) -> Option<Cow<'_, str>> {
+		fn push<'s>(result: &mut Cow<'s, str>, text: &'s str) {
+			if result.is_empty() {
+				*result = Cow::Borrowed(text);
+			} else {
+				result.to_mut().push_str(text);
+			}
+		}
+		let mut result = Cow::Borrowed("");
+		for unit in &self.0 {
+			match unit {
+				TextUnit::SingleQuote(text) => push(&mut result, text),
+				TextUnit::Unquoted(words) | TextUnit::DoubleQuote(words) => {
+					for word in words {
+						let Word::Literal(literal) = word else {
+							return None;
+						};
+						for part in literal {
+							match part {
+								LiteralPart::String(text) => {
+									push(&mut result, text)
+								}
+								LiteralPart::Escaped(ch) => {
+									result.to_mut().push(*ch)
+								}
+								LiteralPart::LineContinuation => {}
+							}
+						}
+					}
+				}
+			}
+		}
+		Some(result)
+	}
+
+	/// Concatenates two texts.
+	///
+	/// The serialized form of the result is the concatenation of both
+	/// serialized forms. Adjacent unquoted units are collapsed into one.
+	/// Note that this may change the meaning of texts, for example,
+	/// `$A` followed by `B` becomes `$AB`.
+	pub fn concat(mut self, rhs: Text<'a>) -> Text<'a> {
+		let mut units = rhs.0.into_iter();
+		if let (Some(TextUnit::Unquoted(lhs)), Some(TextUnit::Unquoted(_))) =
+			(self.0.last_mut(), units.as_slice().first())
+		{
+			let Some(TextUnit::Unquoted(rhs)) = units.next() else {
+				unreachable!()
+			};
+			lhs.extend(rhs);
+		}
+		self.0.extend(units);
+		self
+	}
+
+	/// Iterates over literal characters of the text with their origins.
+	///
+	/// Quotes and line continuations produce no items. Escaped characters
+	/// are produced without the backslash. Variable and sub-command
+	/// expansions produce a [`TextChar::Expansion`] marker each.
+	pub fn chars_with_origin(&self) -> impl Iterator<Item = TextChar<'_, 'a>> {
+		let mut result = Vec::new();
+		let mut pos = 0;
+		for (unit_idx, unit) in self.0.iter().enumerate() {
+			let words = match unit {
+				TextUnit::SingleQuote(text) => {
+					pos += 1;
+					for ch in text.chars() {
+						let span = pos..pos + ch.len_utf8();
+						pos = span.end;
+						result.push(TextChar::Char(ch, CharOrigin {
+							unit: unit_idx,
+							word: None,
+							part: None,
+							span,
+						}));
+					}
+					pos += 1;
+					continue;
+				}
+				TextUnit::Unquoted(words) => words,
+				TextUnit::DoubleQuote(words) => {
+					pos += 1;
+					words
+				}
+			};
+			for (word_idx, word) in words.iter().enumerate() {
+				let Word::Literal(parts) = word else {
+					let span = pos..pos + word.to_string().len();
+					pos = span.end;
+					result.push(TextChar::Expansion(word, CharOrigin {
+						unit: unit_idx,
+						word: Some(word_idx),
+						part: None,
+						span,
+					}));
+					continue;
+				};
+				for (part_idx, part) in parts.iter().enumerate() {
+					let origin = |span| CharOrigin {
+						unit: unit_idx,
+						word: Some(word_idx),
+						part: Some(part_idx),
+						span,
+					};
+					match part {
+						LiteralPart::String(text) => {
+							for ch in text.chars() {
+								let span = pos..pos + ch.len_utf8();
+								pos = span.end;
+								result.push(TextChar::Char(ch, origin(span)));
+							}
+						}
+						LiteralPart::Escaped(ch) => {
+							let span = pos..pos + 1 + ch.len_utf8();
+							pos = span.end;
+							result.push(TextChar::Char(*ch, origin(span)));
+						}
+						LiteralPart::LineContinuation => pos += 2,
+					}
+				}
+			}
+			if matches!(unit, TextUnit::DoubleQuote(_)) {
+				pos += 1;
+			}
+		}
+		result.into_iter()
+	}
+}
+
+impl<'a> Add for Text<'a> {
+	type Output = Text<'a>;
+
+	fn add(self, rhs: Text<'a>) -> Self::Output {
+		self.concat(rhs)
+	}
+}
+
+/// A item produced by [`Text::chars_with_origin`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextChar<'t, 'a> {
+	/// A literal character.
+	Char(char, CharOrigin),
+	/// A variable or sub-command expansion.
+	Expansion(&'t Word<'a>, CharOrigin),
+}
+
+impl TextChar<'_, '_> {
+	/// Gets the origin of the item.
+	pub fn origin(&self) -> &CharOrigin {
+		match self {
+			TextChar::Char(_, origin) | TextChar::Expansion(_, origin) => {
+				origin
+			}
+		}
+	}
+}
+
+/// Origin of a item in [`Text::chars_with_origin`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CharOrigin {
+	/// Index of the [`TextUnit`] in the text.
+	pub unit: usize,
+	/// Index of the [`Word`] in the text unit.
+	///
+	/// This is [None] for single-quoted units.
+	pub word: Option<usize>,
+	/// Index of the [`LiteralPart`] in the word.
+	///
+	/// This is [None] for single-quoted units and expansions.
+	pub part: Option<usize>,
+	/// Byte range in the serialized text, including the escaping
+	/// backslash if any.
+	pub span: Range<usize>,
+}
+
 /// A unit of text.
 ///
 /// See [Text] and [Word] for more documentation.
@@ -225,6 +990,21 @@ pub enum Word<'a> {
 	Subcommand(Vec<ArrayToken<'a>>),
 }
 
+impl Word<'_> {
+	/// Gets the variable name if the word is a plain variable reference,
+	/// like `$NAME` or `${NAME}`, without any modifiers.
+	pub fn is_pure_variable_ref(&self) -> Option<&str> {
+		match self {
+			Word::UnbracedVariable(name)
+			| Word::BracedVariable(BracedExpansion {
+				name,
+				modifier: None,
+			}) => Some(name),
+			_ => None,
+		}
+	}
+}
+
 impl Display for Word<'_> {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
@@ -307,7 +1087,14 @@ impl LiteralPart<'_> {
 
 /// A braced variable expansion (`"<name>[modifier]"`).
 ///
-/// Note that for [ExpansionModifier::Length], the format is `"#<name>"`.
+/// Note that for [ExpansionModifier::Length], the format is `"#<name>"`,
+/// and for [ExpansionModifier::Indirect], the format is `"!<name>"`.
+/// For [ExpansionModifier::NamesWithPrefix] and
+/// [ExpansionModifier::SingleWordNamesWithPrefix], the formats are
+/// `"!<name>@"` and `"!<name>*"`.
+/// For [ExpansionModifier::ArrayLength] and
+/// [ExpansionModifier::SingleWordArrayLength], the formats are
+/// `"#<name>[@]"` and `"#<name>[*]"`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BracedExpansion<'a> {
 	/// Name of the variable.
@@ -322,6 +1109,21 @@ impl Display for BracedExpansion<'_> {
 			Some(ExpansionModifier::Length) => {
 				f.write_fmt(format_args!("#{}", self.name))
 			}
+			Some(ExpansionModifier::Indirect) => {
+				f.write_fmt(format_args!("!{}", self.name))
+			}
+			Some(ExpansionModifier::NamesWithPrefix) => {
+				f.write_fmt(format_args!("!{}@", self.name))
+			}
+			Some(ExpansionModifier::SingleWordNamesWithPrefix) => {
+				f.write_fmt(format_args!("!{}*", self.name))
+			}
+			Some(ExpansionModifier::ArrayLength) => {
+				f.write_fmt(format_args!("#{}[@]", self.name))
+			}
+			Some(ExpansionModifier::SingleWordArrayLength) => {
+				f.write_fmt(format_args!("#{}[*]", self.name))
+			}
 			None => f.write_str(&self.name),
 			Some(modifier) => {
 				f.write_fmt(format_args!("{}{}", self.name, modifier))
@@ -336,6 +1138,8 @@ pub enum ExpansionModifier<'a> {
 	/// Reference to a substring (`":offset"` or `":offset:length"`).
 	///
 	/// The range is [offset, (offset+length)) (indexing from zero).
+	/// Negative offsets must be separated from the colon with a space
+	/// (`": -offset"`), or else it is a `":-"` modifier.
 	Substring {
 		/// Offset.
 		offset: Cow<'a, str>,
@@ -392,6 +1196,9 @@ pub enum ExpansionModifier<'a> {
 	/// Returning the length of the variable.
 	///
 	/// Note that this modifier uses a special format, see [BracedExpansion].
+	/// When displayed alone, it is written as `"#"`, which is only
+	/// meaningful as a part of [BracedExpansion]. Appending it after a name
+	/// would produce a prefix-stripping modifier instead.
 	Length,
 	/// Returning a text when the variable is unset or null (`":-<text>"`).
 	WhenUnset(Arc<Text<'a>>),
@@ -401,6 +1208,33 @@ pub enum ExpansionModifier<'a> {
 	ArrayElements,
 	/// Expands to a string of array elements concatenated with space (`"[*]"`).
 	SingleWordElements,
+	/// Expands to a single element of array (`"[<index>]"`).
+	///
+	/// The index is a arithmetic expression and is kept as written.
+	Index(Cow<'a, str>),
+	/// Returning the number of array elements.
+	///
+	/// Note that this modifier uses a special format, see [BracedExpansion].
+	ArrayLength,
+	/// Returning the number of array elements, with the subscript written
+	/// as `[*]`.
+	///
+	/// Note that this modifier uses a special format, see [BracedExpansion].
+	SingleWordArrayLength,
+	/// Expands to the value of the variable whose name is the value of
+	/// the variable.
+	///
+	/// Note that this modifier uses a special format, see [BracedExpansion].
+	Indirect,
+	/// Expands to names of variables starting with the name, as elements.
+	///
+	/// Note that this modifier uses a special format, see [BracedExpansion].
+	NamesWithPrefix,
+	/// Expands to names of variables starting with the name, concatenated
+	/// with space.
+	///
+	/// Note that this modifier uses a special format, see [BracedExpansion].
+	SingleWordNamesWithPrefix,
 }
 
 impl Display for ExpansionModifier<'_> {
@@ -478,6 +1312,14 @@ impl Display for ExpansionModifier<'_> {
 			}
 			ExpansionModifier::ArrayElements => f.write_str("[@]"),
 			ExpansionModifier::SingleWordElements => f.write_str("[*]"),
+			ExpansionModifier::Index(index) => {
+				f.write_fmt(format_args!("[{}]", index))
+			}
+			ExpansionModifier::ArrayLength => f.write_str("#[@]"),
+			ExpansionModifier::SingleWordArrayLength => f.write_str("#[*]"),
+			ExpansionModifier::Indirect => f.write_char('!'),
+			ExpansionModifier::NamesWithPrefix => f.write_str("!@"),
+			ExpansionModifier::SingleWordNamesWithPrefix => f.write_str("!*"),
 		}
 	}
 }
@@ -523,6 +1365,434 @@ mod test {
 		dbg!(&tree);
 	}
 
+	#[test]
+	fn test_parse_recover() {
+		let src = "A=1\nB=\"abc\nC=2 )x\n\nD=(a b";
+		let (tree, errors) = ApmlLst::parse_recover(src);
+		assert_eq!(tree.to_string(), src);
+		assert_eq!(
+			errors.iter().map(|err| err.kind).collect::<Vec<_>>(),
+			vec![
+				ParseErrorKind::UnterminatedQuote,
+				ParseErrorKind::UnexpectedChar,
+				ParseErrorKind::UnterminatedArray,
+			]
+		);
+		assert_eq!(errors[0].span, 6..10);
+		assert_eq!(errors[1].span, 15..17);
+		let unknown = tree
+			.0
+			.iter()
+			.filter_map(|token| match token {
+				Token::Unknown(text) => Some(text.as_ref()),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(unknown, vec!["\"abc", ")x", "b"]);
+		let names = tree
+			.0
+			.iter()
+			.filter_map(|token| match token {
+				Token::Variable(def) => Some(def.name.as_ref()),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(names, vec!["A", "B", "C", "D"]);
+		let spans = (0..tree.0.len())
+			.filter(|index| matches!(tree.0[*index], Token::Unknown(_)))
+			.map(|index| tree.token_span(index).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(spans.len(), errors.len());
+		for (span, err) in spans.iter().zip(&errors) {
+			assert!(err.span.start <= span.start && err.span.end >= span.end);
+		}
+
+		let src = "A=1 B\nC=2";
+		let (tree, errors) = ApmlLst::parse_recover(src);
+		assert_eq!(tree.0[2], Token::Unknown(Cow::Borrowed("B")));
+		assert_eq!(tree.token_span(2), Some(4..5));
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].span, 4..5);
+		assert_eq!(errors[0].message, "Invalid definition of variable B");
+
+		let src = "A=1\n# a\n";
+		let (tree, errors) = ApmlLst::parse_recover(src);
+		assert!(errors.is_empty());
+		assert_eq!(tree, ApmlLst::parse(src).unwrap());
+		assert_eq!(ApmlLst::parse_recover("").0, ApmlLst(vec![]));
+	}
+
+	#[test]
+	fn test_parse_bytes() {
+		let tree =
+			ApmlLst::parse_bytes(b"\xEF\xBB\xBFA=\"\xE6\xB5\x8B\"\n").unwrap();
+		assert_eq!(tree.to_string(), "A=\"测\"\n");
+		assert_eq!(ApmlLst::parse_bytes(b"A=1").unwrap().to_string(), "A=1");
+		let err = ApmlLst::parse_bytes(b"A=1\nB=\xFF\n").unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::InvalidEncoding);
+		assert_eq!(err.span, 6..7);
+		let err = ApmlLst::parse_bytes(b"\xEF\xBB\xBFA=\xE6\xB5").unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::InvalidEncoding);
+		assert_eq!(err.span, 5..7);
+		let err = ApmlLst::parse_bytes(b"\xEF\xBB\xBFA=1\n)").unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedChar);
+		assert_eq!(err.span, 7..8);
+
+		let mut buf = String::new();
+		let tree = ApmlLst::parse_latin1(b"A=\"caf\xE9\"\n", &mut buf).unwrap();
+		assert_eq!(tree.to_string(), "A=\"café\"\n");
+	}
+
+	#[test]
+	fn test_from_pairs() {
+		let lst = ApmlLst::try_from(vec![
+			("B".to_string(), "1".to_string()),
+			("A".to_string(), "a b".to_string()),
+			("C".to_string(), String::new()),
+			("D".to_string(), "$\"".to_string()),
+		])
+		.unwrap();
+		assert_eq!(lst.to_string(), "B=1\nA=\"a b\"\nC=\"\"\nD=\"\\$\\\"\"\n");
+		let lst = ApmlLst::try_from(HashMap::from([
+			("B".to_string(), "1".to_string()),
+			("A".to_string(), "a b".to_string()),
+		]))
+		.unwrap();
+		assert_eq!(lst.to_string(), "A=\"a b\"\nB=1\n");
+		assert_eq!(ApmlLst::try_from(Vec::new()).unwrap().to_string(), "");
+		assert_eq!(
+			ApmlLst::try_from(vec![
+				("A".to_string(), "1".to_string()),
+				("B=1;C".to_string(), String::new()),
+			]),
+			Err(InvalidVarName("B=1;C".into()))
+		);
+		assert_eq!(
+			ApmlLst::try_from(HashMap::from([(String::new(), String::new())])),
+			Err(InvalidVarName(String::new()))
+		);
+	}
+
+	#[test]
+	fn test_from_env() {
+		let lst = ApmlLst::from_env();
+		let src = lst.to_string();
+		let ctx = crate::apml::ApmlContext::eval_source(&src).unwrap();
+		let mut count = 0;
+		for (name, value) in std::env::vars() {
+			if is_valid_var_name(&name) {
+				assert_eq!(ctx[name.as_str()].as_string(), value);
+				count += 1;
+			}
+		}
+		assert_eq!(ctx.keys().count(), count);
+	}
+
+	#[test]
+	fn test_freeze() {
+		let src = "# a\nA=1\nB=2\n";
+		let lst = ApmlLst::parse(src).unwrap();
+		let frozen = lst.clone().freeze();
+		assert_eq!(frozen.to_string(), src);
+		assert_eq!(frozen.iter_lines().count(), 3);
+		assert_eq!(frozen.token_span(2), Some(4..7));
+		assert_eq!(frozen.as_ref(), &lst);
+		assert_eq!(ApmlFrozenLst::from(lst.clone()), frozen);
+		assert_eq!(frozen.thaw(), lst);
+	}
+
+	#[test]
+	fn test_token_span() {
+		let src = "# 测试\nA=1  # a\nB=( a\n b )\n";
+		let tree = ApmlLst::parse(src).unwrap();
+		assert_eq!(tree.0[0].source_len(), 8);
+		assert_eq!(tree.token_span(0), Some(0..8));
+		assert_eq!(tree.token_span(2), Some(9..12));
+		assert_eq!(tree.token_span(100), None);
+		assert_eq!(tree.token_at_offset(0), Some(0));
+		assert_eq!(tree.token_at_offset(9), Some(2));
+		assert_eq!(tree.token_at_offset(11), Some(2));
+		assert_eq!(tree.token_at_offset(12), Some(3));
+		assert_eq!(tree.token_at_offset(src.len()), None);
+		let token = &tree.0[7];
+		assert_eq!(tree.token_index(token), Some(7));
+		assert_eq!(tree.source_text(src, token), Some("B=( a\n b )"));
+		assert_eq!(tree.source_text(src, &tree.0[5]), Some("# a"));
+		assert_eq!(tree.source_text(src, &Token::Newline), None);
+
+		let token = tree
+			.find_token(|token| matches!(token, Token::Comment(_)))
+			.unwrap();
+		assert_eq!(tree.token_index(token), Some(0));
+		assert!(
+			tree.find_token(|token| matches!(token, Token::Spacy('\t')))
+				.is_none()
+		);
+		let comments =
+			tree.find_all_tokens(|token| matches!(token, Token::Comment(_)));
+		assert_eq!(comments, [
+			&Token::Comment(" 测试".into()),
+			&Token::Comment(" a".into())
+		]);
+		assert_eq!(
+			tree.find_all_tokens(|token| matches!(token, Token::Newline))
+				.len(),
+			3
+		);
+	}
+
+	#[test]
+	fn test_iter_lines() {
+		let tree = ApmlLst::parse("# a\nA=1 B=2\n\nC=( a\n b )\n").unwrap();
+		let lines = tree.iter_lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 4);
+		assert_eq!(lines[0], &[Token::Comment(" a".into())]);
+		assert_eq!(
+			lines[1]
+				.iter()
+				.filter(|token| matches!(token, Token::Variable(_)))
+				.count(),
+			2
+		);
+		assert!(lines[2].is_empty());
+		assert!(matches!(lines[3], [Token::Variable(_)]));
+		let tree = ApmlLst::parse("A=1\n\nB=2").unwrap();
+		assert_eq!(tree.iter_lines().map(<[_]>::len).collect::<Vec<_>>(), [
+			1, 0, 1
+		]);
+		assert_eq!(ApmlLst::parse("").unwrap().iter_lines().count(), 0);
+	}
+
+	#[test]
+	fn test_extract_range() {
+		let src = "# a\nA=1  # b\nB=( a\n b )\n";
+		let tree = ApmlLst::parse(src).unwrap();
+		assert_eq!(tree.extract_range(0, src.len()), tree);
+		assert_eq!(tree.extract_range(4, 7).to_string(), "A=1");
+		assert_eq!(tree.extract_range(4, 8).to_string(), "A=1 ");
+		assert_eq!(tree.extract_range(5, 12).to_string(), "  # b");
+		assert_eq!(tree.extract_range(12, 23).to_string(), "\nB=( a\n b )");
+		assert_eq!(tree.extract_range(15, 24).to_string(), "\n");
+		assert_eq!(tree.extract_range(3, 3), ApmlLst(vec![]));
+		assert_eq!(tree.extract_range(100, 200), ApmlLst(vec![]));
+	}
+
+	#[test]
+	fn test_is_constant() {
+		let value = |src| {
+			let Token::Variable(def) = ApmlLst::parse(src).unwrap().0.remove(0)
+			else {
+				unreachable!()
+			};
+			def.value
+		};
+		assert!(value("A=").is_constant());
+		assert!(value("A=a'$b'\"c\\$\"\\\nd").is_constant());
+		assert!(value("A=(a 'b' # $c\n \"d\")").is_constant());
+		assert!(!value("A=$a").is_constant());
+		assert!(!value("A=\"a${b}\"").is_constant());
+		assert!(!value("A=\"$(b)\"").is_constant());
+		assert!(!value("A=(a \"${b[@]}\")").is_constant());
+		let VariableValue::String(text) = value("A='a'\"$b\"") else {
+			unreachable!()
+		};
+		assert!(!text.is_constant());
+		assert!(Text(vec![]).is_constant());
+	}
+
+	#[test]
+	fn test_text_concat() {
+		let text = |src: &'static str| {
+			let Token::Variable(def) = ApmlLst::parse(src).unwrap().0.remove(0)
+			else {
+				unreachable!()
+			};
+			let VariableValue::String(text) = def.value else {
+				unreachable!()
+			};
+			Arc::unwrap_or_clone(text)
+		};
+		for (lhs, rhs, units) in [
+			("A=a", "A=b", 1),
+			("A=a'b'", "A=\"c\"d", 4),
+			("A=$a", "A=\"b\"", 2),
+			("A='a'", "A=b", 2),
+			("A=", "A=a", 1),
+			("A=a", "A=", 1),
+		] {
+			let result = text(lhs).concat(text(rhs));
+			assert_eq!(
+				result.to_string(),
+				format!("{}{}", &lhs[2..], &rhs[2..])
+			);
+			assert_eq!(result.0.len(), units);
+		}
+		let result = text("A=a$b") + text("A=c");
+		assert_eq!(result.to_string(), "a$bc");
+		let TextUnit::Unquoted(words) = &result.0[0] else {
+			unreachable!()
+		};
+		assert_eq!(words.len(), 3);
+	}
+
+	#[test]
+	fn test_literal_value() {
+		let value = |src| {
+			let Token::Variable(def) = ApmlLst::parse(src).unwrap().0.remove(0)
+			else {
+				unreachable!()
+			};
+			let VariableValue::String(text) = def.value else {
+				unreachable!()
+			};
+			text.literal_value().map(|value| value.into_owned())
+		};
+		assert_eq!(value("A=").as_deref(), Some(""));
+		assert_eq!(value("A=abc").as_deref(), Some("abc"));
+		assert_eq!(value("A=a'$b'\"c\\$\"\\\nd").as_deref(), Some("a$bc$d"));
+		assert_eq!(value("A=\\$\"\"").as_deref(), Some("$"));
+		assert_eq!(value("A=a$b"), None);
+		assert_eq!(value("A=\"a$(b)\""), None);
+		assert!(matches!(
+			Text(vec![TextUnit::SingleQuote("a".into())]).literal_value(),
+			Some(Cow::Borrowed("a"))
+		));
+	}
+
+	#[test]
+	fn test_variable_value_new_string() {
+		for (value, expected) in [
+			("1.0-x/y_z", "1.0-x/y_z"),
+			("", "''"),
+			("a b$c", "'a b$c'"),
+			("it's \"$HOME\"\\", r#""it's \"\$HOME\"\\""#),
+		] {
+			let lst = VariableValue::new_string(value);
+			assert_eq!(lst.to_string(), expected);
+			let src = format!("A={lst}");
+			let parsed = ApmlLst::parse(&src).unwrap();
+			let Some(Token::Variable(def)) = parsed.0.first() else {
+				unreachable!()
+			};
+			assert!(values_equal_unquoted(&def.value, &lst));
+		}
+	}
+
+	#[test]
+	fn test_values_equal_unquoted() {
+		let equal = |a, b| {
+			let value = |src| {
+				let Token::Variable(def) =
+					ApmlLst::parse(src).unwrap().0.remove(0)
+				else {
+					unreachable!()
+				};
+				def.value
+			};
+			values_equal_unquoted(&value(a), &value(b))
+		};
+		assert!(equal("A=abc", "A=\"abc\""));
+		assert!(equal("A='abc'", "A=a\"b\"'c'"));
+		assert!(equal("A=\"a b\"", "A=a\\ b"));
+		assert!(equal("A=(a 'b' # c\n)", "A=(\"a\" b)"));
+		assert!(!equal("A=abc", "A=abd"));
+		assert!(!equal("A=abc", "A=(abc)"));
+		assert!(!equal("A=(a b)", "A=(a)"));
+		assert!(!equal("A=$a", "A=$a"));
+		assert!(!equal("A=(a \"$b\")", "A=(a \"$b\")"));
+	}
+
+	#[test]
+	fn test_quoting_kind() {
+		for (src, kind, literal) in [
+			("A=", QuotingKind::Empty, false),
+			("A=a$b", QuotingKind::Unquoted, false),
+			("A='$b'", QuotingKind::SingleQuoted, true),
+			("A='a''b'", QuotingKind::SingleQuoted, false),
+			("A=\"$b\"", QuotingKind::DoubleQuoted, false),
+			("A=a'b'", QuotingKind::Mixed, false),
+			("A=('a')", QuotingKind::Array, false),
+		] {
+			let Token::Variable(def) = ApmlLst::parse(src).unwrap().0.remove(0)
+			else {
+				unreachable!()
+			};
+			assert_eq!(quoting_kind(&def.value), kind, "{src}");
+			assert_eq!(is_single_quoted_literal(&def.value), literal, "{src}");
+		}
+	}
+
+	#[test]
+	fn test_is_pure_variable_ref() {
+		let words = |src| {
+			let Token::Variable(def) = ApmlLst::parse(src).unwrap().0.remove(0)
+			else {
+				unreachable!()
+			};
+			let VariableValue::String(text) = def.value else {
+				unreachable!()
+			};
+			let [TextUnit::Unquoted(words) | TextUnit::DoubleQuote(words)] =
+				&text.0[..]
+			else {
+				unreachable!()
+			};
+			words
+				.iter()
+				.map(|word| word.is_pure_variable_ref().map(str::to_string))
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(words("A=$B"), vec![Some("B".to_string())]);
+		assert_eq!(words("A=${B}"), vec![Some("B".to_string())]);
+		assert_eq!(words("A=\"${B}c$(d)\""), vec![
+			Some("B".to_string()),
+			None,
+			None
+		]);
+		assert_eq!(words("A=${B:-c}${#B}${B[@]}"), vec![None, None, None]);
+	}
+
+	#[test]
+	fn test_chars_with_origin() {
+		let src = "A='a'b\\$\"c\\\n$D${E}\"$(f)";
+		let tree = ApmlLst::parse(src).unwrap();
+		let Token::Variable(def) = &tree.0[0] else {
+			unreachable!()
+		};
+		let VariableValue::String(text) = &def.value else {
+			unreachable!()
+		};
+		let text_src = text.to_string();
+		let items = text.chars_with_origin().collect::<Vec<_>>();
+		let chars = items
+			.iter()
+			.map(|item| match item {
+				TextChar::Char(ch, _) => *ch,
+				TextChar::Expansion(..) => '*',
+			})
+			.collect::<String>();
+		assert_eq!(chars, "ab$c***");
+		let slices = items
+			.iter()
+			.map(|item| &text_src[item.origin().span.clone()])
+			.collect::<Vec<_>>();
+		assert_eq!(slices, vec!["a", "b", "\\$", "c", "$D", "${E}", "$(f)"]);
+		assert_eq!(items[0].origin(), &CharOrigin {
+			unit: 0,
+			word: None,
+			part: None,
+			span: 1..2,
+		});
+		assert_eq!(items[2].origin().unit, 1);
+		assert_eq!(items[2].origin().part, Some(1));
+		assert_eq!(items[3].origin().unit, 2);
+		assert_eq!(items[5].origin().word, Some(2));
+		assert!(matches!(
+			items[6],
+			TextChar::Expansion(Word::Subcommand(_), _)
+		));
+	}
+
 	#[test]
 	fn test_token() {
 		assert!(Token::Newline.is_empty());
@@ -531,6 +1801,7 @@ mod test {
 		assert!(!Token::Comment(Cow::Borrowed("Test")).is_empty());
 		assert!(
 			!Token::Variable(VariableDefinition {
+				declaration: None,
 				name: Cow::Borrowed("Test"),
 				op: VariableOp::Assignment,
 				value: VariableValue::String(Arc::new(Text(vec![])))
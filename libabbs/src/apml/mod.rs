@@ -2,34 +2,108 @@
 
 use std::{
 	collections::HashMap,
-	fmt::{Display, Write},
+	fmt::{Debug, Display, Write},
 	ops::{Add, AddAssign, Index},
+	sync::Arc,
 };
 
 use ast::{ApmlAst, AstNode};
 use lst::ApmlLst;
 use thiserror::Error;
+pub use verify::{
+	LosslessStage, LosslessViolation, RoundTripError, assert_round_trip,
+	verify_lossless,
+};
 
 pub mod ast;
+pub mod comment;
+pub mod diff;
 pub mod editor;
 pub mod eval;
+pub mod intern;
 pub mod lst;
 pub mod parser;
 pub mod pattern;
+pub mod schema;
+pub mod shell;
+pub mod tokenizer;
+pub mod util;
 pub mod value;
+pub mod verify;
 
 /// A evaluated APML context.
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct ApmlContext {
 	variables: HashMap<String, VariableValue>,
+	fallback: Option<VariableResolver>,
+}
+
+/// A resolver of variables undefined in APML.
+///
+/// See [`ApmlContext::with_fallback`].
+#[derive(Clone)]
+pub struct VariableResolver(Arc<ResolverFn>);
+
+type ResolverFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+impl Debug for VariableResolver {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("VariableResolver")
+	}
 }
 
+impl PartialEq for VariableResolver {
+	fn eq(&self, other: &Self) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
+}
+
+impl Eq for VariableResolver {}
+
 impl ApmlContext {
 	/// Creates a empty APML context.
 	pub fn new() -> Self {
 		Default::default()
 	}
 
+	/// Creates a empty APML context with a fallback resolver.
+	///
+	/// When evaluating, variables defined in APML always take precedence.
+	/// The resolver is consulted only for variables undefined in APML,
+	/// and the variable is treated as unset if it returns [None].
+	/// Resolved values are not stored into the context.
+	///
+	/// As the resolver cannot enumerate variables, it is not consulted
+	/// by `${!prefix@}` expansions.
+	pub fn with_fallback<F>(resolver: F) -> Self
+	where
+		F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+	{
+		Self {
+			variables: HashMap::new(),
+			fallback: Some(VariableResolver(Arc::new(resolver))),
+		}
+	}
+
+	/// Creates a empty APML context falling back to the process
+	/// environment for undefined variables.
+	///
+	/// See [`ApmlContext::with_fallback`] for the precedence.
+	pub fn with_env() -> Self {
+		Self::with_fallback(|name| std::env::var(name).ok())
+	}
+
+	/// Evaluates a APML AST in this context, expanding variables.
+	///
+	/// Variables defined by the AST are added to this context.
+	pub fn eval_ast_in(
+		&mut self,
+		ast: &ApmlAst,
+	) -> std::result::Result<(), ApmlError> {
+		eval::eval_ast(self, ast)?;
+		Ok(())
+	}
+
 	/// Evaluates a APML AST, expanding variables.
 	pub fn eval_ast(ast: &ApmlAst) -> std::result::Result<Self, ApmlError> {
 		let mut apml = ApmlContext::default();
@@ -47,12 +121,40 @@ impl ApmlContext {
 		Self::eval_lst(&ApmlLst::parse(src)?)
 	}
 
+	/// Parses and evaluates a bare value fragment in this context,
+	/// for example `${VER//./_}`.
+	///
+	/// Array values are joined with spaces.
+	/// See [`lst::VariableValue::parse`] for accepted fragments.
+	pub fn eval_expr(
+		&self,
+		expr: &str,
+	) -> std::result::Result<String, ApmlError> {
+		let value =
+			ast::VariableValue::emit_from(&lst::VariableValue::parse(expr)?)?;
+		Ok(eval::eval_variable_value(self, &value)?.into_string())
+	}
+
 	/// Gets a variable value.
 	#[must_use]
 	pub fn get(&self, name: &str) -> Option<&VariableValue> {
 		self.variables.get(name)
 	}
 
+	/// Gets a variable value, consulting the fallback resolver
+	/// if the variable is undefined.
+	#[must_use]
+	pub fn lookup(&self, name: &str) -> Option<VariableValue> {
+		match self.variables.get(name) {
+			Some(value) => Some(value.clone()),
+			None => self
+				.fallback
+				.as_ref()
+				.and_then(|resolver| (resolver.0)(name))
+				.map(VariableValue::String),
+		}
+	}
+
 	/// Gets a variable value or returns a default value if not found.
 	#[must_use]
 	pub fn read(&self, name: &str) -> VariableValue {
@@ -86,6 +188,7 @@ impl ApmlContext {
 	}
 
 	/// Returns if a variable is defined.
+	#[must_use]
 	pub fn contains_var<S: AsRef<str>>(&self, key: S) -> bool {
 		self.variables.contains_key(key.as_ref())
 	}
@@ -470,6 +573,12 @@ B="${VAR1[*]}"
 		assert_eq!(apml.get("A"), None);
 		apml.insert("A".to_string(), "test".into());
 		assert_eq!(apml["A"], "test");
+		assert_eq!(apml.eval_expr("${A//t/T}-\"$A\"").unwrap(), "TesT-test");
+		assert_eq!(apml.eval_expr("(a \"$A\")").unwrap(), "a test");
+		assert_eq!(apml.eval_expr("").unwrap(), "");
+		assert!(matches!(apml.eval_expr("a b"), Err(ApmlError::Parse(_))));
+		assert!(apml.eval_expr("${A:?x}").is_ok());
+		assert!(matches!(apml.eval_expr("${C:?x}"), Err(ApmlError::Eval(_))));
 		{
 			let mut keys = apml.keys().collect::<Vec<_>>();
 			keys.sort();
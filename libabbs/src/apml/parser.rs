@@ -1,13 +1,13 @@
 //! Parser combinators to parse APML source code to [LST][super::lst].
 
-use std::{borrow::Cow, sync::Arc};
+use std::{borrow::Cow, ops::Range, sync::Arc};
 
 use nom::{
 	IResult,
 	branch::alt,
 	bytes::complete::{tag, take, take_till, take_while, take_while1},
 	character::complete::{anychar, char, newline, one_of},
-	combinator::{map, opt, recognize, value},
+	combinator::{map, opt, recognize, value, verify},
 	multi::{many0, many1},
 	sequence::{delimited, pair, preceded, tuple},
 };
@@ -18,17 +18,267 @@ use crate::apml::pattern::{BashPattern, bash_pattern};
 use super::lst::*;
 
 /// Errors produced while parsing the input source.
-#[derive(Debug, Error)]
-pub enum ParseError {
-	#[error("Syntax error: {0}")]
-	SyntaxError(String),
-	#[error("Unexpected source at char {pos}")]
-	UnexpectedSource { pos: usize },
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message} at byte {}", span.start)]
+pub struct ParseError {
+	/// Byte range of the offending source.
+	///
+	/// The start of the span is where parsing gave up.
+	pub span: Range<usize>,
+	/// Kind of the error.
+	pub kind: ParseErrorKind,
+	/// Human-readable description.
+	pub message: String,
 }
 
-impl From<nom::Err<nom::error::Error<&str>>> for ParseError {
-	fn from(value: nom::Err<nom::error::Error<&str>>) -> Self {
-		Self::SyntaxError(value.to_string())
+/// Kinds of [`ParseError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParseErrorKind {
+	/// A single or double quote is not closed.
+	UnterminatedQuote,
+	/// A braced expansion (`${`) is not closed.
+	UnterminatedExpansion,
+	/// A subcommand (`$(`) is not closed.
+	UnterminatedSubcommand,
+	/// A array value is not closed.
+	UnterminatedArray,
+	/// A character is not allowed at the position.
+	UnexpectedChar,
+	/// Other syntax errors.
+	Syntax,
+	/// The source is not encoded properly.
+	InvalidEncoding,
+	/// A here-document (`<<`) is not supported.
+	HereDocument,
+	/// A here-string (`<<<`) is not supported.
+	HereString,
+}
+
+impl ParseError {
+	/// Diagnoses the reason why parsing stopped at the given offset.
+	///
+	/// The offset must be at the start of a token. As parsing may give up
+	/// in the middle of the preceding variable definition, the span of
+	/// it should be provided as well, if any.
+	pub(crate) fn at(
+		src: &str,
+		offset: usize,
+		last_def: Option<Range<usize>>,
+	) -> Self {
+		if let Some(def) = last_def {
+			let (start, name, _) = Self::definition_head(src, def.start);
+			if let Some(err) = Self::unterminated(src, start) {
+				return err;
+			}
+			if def.end == offset {
+				return Self::invalid_definition(src, def.start, name);
+			}
+		}
+		let (start, name, unexpected) = Self::definition_head(src, offset);
+		if let Some(err) = Self::unterminated(src, start) {
+			return err;
+		}
+		// a definition cut by the end of line is reported as a whole
+		if let Some(unexpected) =
+			unexpected.filter(|pos| !src[*pos..].starts_with('\n'))
+		{
+			return Self::unexpected_char(src, unexpected);
+		}
+		Self::invalid_definition(src, offset, name)
+	}
+
+	/// Creates a error of invalid definition starting at the given offset.
+	fn invalid_definition(src: &str, offset: usize, name: &str) -> Self {
+		let end = src[offset..]
+			.find('\n')
+			.map(|len| offset + len)
+			.unwrap_or(src.len());
+		Self {
+			span: offset..end,
+			kind: ParseErrorKind::Syntax,
+			message: format!("Invalid definition of variable {name}"),
+		}
+	}
+
+	/// Parses the name and operator of a variable definition.
+	///
+	/// Returns the offset of value, the name, and the offset of the
+	/// unexpected character if the name or operator is broken.
+	fn definition_head(
+		src: &str,
+		offset: usize,
+	) -> (usize, &str, Option<usize>) {
+		let rest = &src[offset..];
+		match variable_name(rest) {
+			Ok((value, name)) => match variable_op(value) {
+				Ok((value, _)) => (src.len() - value.len(), name, None),
+				Err(_) => (offset, name, Some(offset + name.len())),
+			},
+			Err(_) => (offset, "", Some(offset)),
+		}
+	}
+
+	/// Finds the innermost unterminated quote or bracket in a value.
+	///
+	/// Unquoted here-documents and here-strings are reported as well.
+	fn unterminated(src: &str, start: usize) -> Option<Self> {
+		#[derive(Clone, Copy, PartialEq, Eq)]
+		enum Frame {
+			SingleQuote,
+			DoubleQuote,
+			Expansion,
+			Subcommand,
+			Arithmetic,
+			Array,
+		}
+		let mut stack = Vec::<(Frame, usize)>::new();
+		let mut chars = src[start..].char_indices().peekable();
+		while let Some((idx, ch)) = chars.next() {
+			let pos = start + idx;
+			let top = stack.last().map(|(frame, _)| *frame);
+			match (top, ch) {
+				(Some(Frame::SingleQuote), '\'') => {
+					stack.pop();
+				}
+				(Some(Frame::SingleQuote), _) => {}
+				(_, '\\') => {
+					chars.next();
+				}
+				(Some(Frame::DoubleQuote), '"') => {
+					stack.pop();
+				}
+				(Some(Frame::Expansion), '}') => {
+					stack.pop();
+				}
+				(
+					Some(Frame::Subcommand | Frame::Arithmetic | Frame::Array),
+					')',
+				) => {
+					stack.pop();
+				}
+				(Some(Frame::Arithmetic), '(') => {
+					stack.push((Frame::Arithmetic, pos));
+				}
+				(Some(Frame::Subcommand | Frame::Array), '#') => {
+					while chars.next_if(|(_, ch)| *ch != '\n').is_some() {}
+				}
+				(_, '$') => match chars.peek() {
+					Some((_, '{')) => {
+						chars.next();
+						stack.push((Frame::Expansion, pos));
+					}
+					Some((_, '(')) => {
+						chars.next();
+						stack.push((Frame::Subcommand, pos));
+						if chars.next_if(|(_, ch)| *ch == '(').is_some() {
+							stack.push((Frame::Arithmetic, pos));
+						}
+					}
+					_ => {}
+				},
+				(Some(Frame::DoubleQuote), _) => {}
+				(_, '"') => stack.push((Frame::DoubleQuote, pos)),
+				(_, '\'') => stack.push((Frame::SingleQuote, pos)),
+				(None | Some(Frame::Array | Frame::Subcommand), '<')
+					if src[pos..].starts_with("<<")
+						&& !stack.iter().any(|(frame, _)| {
+							matches!(
+								frame,
+								Frame::DoubleQuote | Frame::Arithmetic
+							)
+						}) =>
+				{
+					let end = src[pos..]
+						.find('\n')
+						.map(|len| pos + len)
+						.unwrap_or(src.len());
+					return Some(if src[pos..].starts_with("<<<") {
+						Self {
+							span: pos..end,
+							kind: ParseErrorKind::HereString,
+							message: "Here-strings are not supported"
+								.to_string(),
+						}
+					} else {
+						Self {
+							span: pos..end,
+							kind: ParseErrorKind::HereDocument,
+							message: "Here-documents are not supported"
+								.to_string(),
+						}
+					});
+				}
+				(None, '(') if idx == 0 => stack.push((Frame::Array, pos)),
+				(None, ' ' | '\t' | '\n' | '#') => break,
+				_ => {}
+			}
+			if stack.is_empty() && top == Some(Frame::Array) {
+				break;
+			}
+		}
+
+		let (frame, pos) = stack.pop()?;
+		let end = src[pos..]
+			.find('\n')
+			.map(|len| pos + len)
+			.unwrap_or(src.len());
+		let (kind, message) = match frame {
+			Frame::SingleQuote => {
+				(ParseErrorKind::UnterminatedQuote, "single quote")
+			}
+			Frame::DoubleQuote => {
+				(ParseErrorKind::UnterminatedQuote, "double quote")
+			}
+			Frame::Expansion => {
+				(ParseErrorKind::UnterminatedExpansion, "braced expansion")
+			}
+			Frame::Subcommand | Frame::Arithmetic => {
+				(ParseErrorKind::UnterminatedSubcommand, "subcommand")
+			}
+			Frame::Array => (ParseErrorKind::UnterminatedArray, "array"),
+		};
+		Some(Self {
+			span: pos..end,
+			kind,
+			message: format!("Unterminated {message}"),
+		})
+	}
+
+	/// Converts a error produced by parser combinators.
+	pub(crate) fn from_nom(
+		src: &str,
+		err: nom::Err<nom::error::Error<&str>>,
+	) -> Self {
+		let (offset, message) = match err {
+			nom::Err::Incomplete(_) => {
+				(src.len(), "Incomplete input".to_string())
+			}
+			nom::Err::Error(err) | nom::Err::Failure(err) => (
+				nom::Offset::offset(src, err.input),
+				format!("Syntax error ({:?})", err.code),
+			),
+		};
+		Self {
+			span: offset..offset,
+			kind: ParseErrorKind::Syntax,
+			message,
+		}
+	}
+
+	/// Creates a error of unexpected character at the given offset.
+	pub(crate) fn unexpected_char(src: &str, offset: usize) -> Self {
+		match src[offset..].chars().next() {
+			Some(ch) => Self {
+				span: offset..offset + ch.len_utf8(),
+				kind: ParseErrorKind::UnexpectedChar,
+				message: format!("Unexpected character {ch:?}"),
+			},
+			None => Self {
+				span: offset..offset,
+				kind: ParseErrorKind::UnexpectedChar,
+				message: "Unexpected end of input".to_string(),
+			},
+		}
 	}
 }
 
@@ -66,8 +316,9 @@ fn comment_token(i: &str) -> IResult<&str, Token> {
 #[inline]
 fn variable_def(i: &str) -> IResult<&str, VariableDefinition> {
 	map(
-		tuple((variable_name, variable_op, variable_value)),
-		|(name, op, value)| VariableDefinition {
+		tuple((opt(declaration), variable_name, variable_op, variable_value)),
+		|(declaration, name, op, value)| VariableDefinition {
+			declaration,
 			name: Cow::Borrowed(name),
 			op,
 			value,
@@ -75,6 +326,44 @@ fn variable_def(i: &str) -> IResult<&str, VariableDefinition> {
 	)(i)
 }
 
+#[inline]
+fn declaration(i: &str) -> IResult<&str, Declaration<'_>> {
+	map(
+		tuple((
+			declaration_keyword,
+			map(spacy_char, DeclarationToken::Spacy),
+			many0(declaration_token),
+		)),
+		|(keyword, first, mut tokens)| {
+			tokens.insert(0, first);
+			Declaration { keyword, tokens }
+		},
+	)(i)
+}
+
+#[inline]
+fn declaration_keyword(i: &str) -> IResult<&str, DeclarationKeyword> {
+	alt((
+		value(DeclarationKeyword::Declare, tag("declare")),
+		value(DeclarationKeyword::Typeset, tag("typeset")),
+		value(DeclarationKeyword::Export, tag("export")),
+	))(i)
+}
+
+#[inline]
+fn declaration_token(i: &str) -> IResult<&str, DeclarationToken<'_>> {
+	alt((
+		map(spacy_char, DeclarationToken::Spacy),
+		map(
+			recognize(pair(
+				char('-'),
+				take_while1(|ch: char| ch.is_ascii_alphabetic()),
+			)),
+			|flag| DeclarationToken::Flag(Cow::Borrowed(flag)),
+		),
+	))(i)
+}
+
 #[inline]
 fn variable_op(i: &str) -> IResult<&str, VariableOp> {
 	alt((
@@ -88,20 +377,27 @@ fn variable_name(i: &str) -> IResult<&str, &str> {
 	take_while1(|ch: char| ch.is_alphanumeric() || ch == '_')(i)
 }
 
+/// Parses a variable value, for example `"a"` or `(a b)`.
 #[inline]
-fn variable_value(i: &str) -> IResult<&str, VariableValue> {
-	alt((
-		// array
-		map(
-			delimited(char('('), many0(array_token), char(')')),
-			VariableValue::Array,
-		),
-		// string
-		map(
-			|s| text_or_null(s, &|ch| ch != ' ' && ch != '#'),
-			|text| VariableValue::String(Arc::new(text)),
-		),
-	))(i)
+pub fn variable_value(i: &str) -> IResult<&str, VariableValue<'_>> {
+	verify(
+		alt((
+			// array, where newlines are trivia instead of terminators
+			map(
+				delimited(char('('), many0(array_token), char(')')),
+				VariableValue::Array,
+			),
+			// string
+			map(
+				|s| text_or_null(s, &|ch| ch != ' ' && ch != '#'),
+				|text| VariableValue::String(Arc::new(text)),
+			),
+		)),
+		|value| match value {
+			VariableValue::String(text) => !has_here_document(text),
+			VariableValue::Array(tokens) => !tokens_have_here_document(tokens),
+		},
+	)(i)
 }
 
 #[inline]
@@ -175,6 +471,38 @@ where
 	))(i)
 }
 
+/// Checks if a text contains unquoted here-document or here-string
+/// operators, which are rejected as they are not supported.
+///
+/// Quoted text and arithmetic expansions (`$((1 << 2))`) are skipped,
+/// as `<<` is not a redirection there.
+fn has_here_document(text: &Text) -> bool {
+	text.0.iter().any(|unit| {
+		let TextUnit::Unquoted(words) = unit else {
+			return false;
+		};
+		words.iter().any(|word| match word {
+			Word::Literal(parts) => parts.iter().any(
+				|part| matches!(part, LiteralPart::String(text) if text.contains("<<")),
+			),
+			Word::Subcommand(tokens) => {
+				!matches!(tokens.first(), Some(ArrayToken::Element(text))
+					if text.to_string().starts_with('('))
+					&& tokens_have_here_document(tokens)
+			}
+			_ => false,
+		})
+	})
+}
+
+/// Checks if elements of a array or subcommand contain unquoted
+/// here-document or here-string operators.
+fn tokens_have_here_document(tokens: &[ArrayToken]) -> bool {
+	tokens.iter().any(
+		|token| matches!(token, ArrayToken::Element(text) if has_here_document(text)),
+	)
+}
+
 #[inline]
 fn word<'a, Cond, EscCond>(
 	i: &'a str,
@@ -231,14 +559,55 @@ where
 	))(i)
 }
 
+/// Parses the content of a braced expansion.
+///
+/// A `#` immediately after `{` is always the length operator, and a `#`
+/// after the name is always a prefix-stripping modifier.
+/// Length expansions take no further modifiers, so `${#FOO#bar}` is
+/// rejected as it is a bad substitution in Bash.
+/// Array subscripts are parsed as modifiers, so they cannot be combined
+/// with other modifiers, like `${FOO[0]:-bar}`.
 #[inline]
 fn braced_expansion(i: &str) -> IResult<&str, BracedExpansion> {
 	alt((
+		// number of array elements
+		map(
+			delimited(
+				char('#'),
+				pair(variable_name, preceded(char('['), one_of("@*"))),
+				char(']'),
+			),
+			|(name, kind)| BracedExpansion {
+				name: Cow::Borrowed(name),
+				modifier: Some(if kind == '@' {
+					ExpansionModifier::ArrayLength
+				} else {
+					ExpansionModifier::SingleWordArrayLength
+				}),
+			},
+		),
 		// length of
 		map(preceded(char('#'), variable_name), |name| BracedExpansion {
 			name: Cow::Borrowed(name),
 			modifier: Some(ExpansionModifier::Length),
 		}),
+		// names with prefix
+		map(
+			preceded(char('!'), pair(variable_name, one_of("@*"))),
+			|(name, kind)| BracedExpansion {
+				name: Cow::Borrowed(name),
+				modifier: Some(if kind == '@' {
+					ExpansionModifier::NamesWithPrefix
+				} else {
+					ExpansionModifier::SingleWordNamesWithPrefix
+				}),
+			},
+		),
+		// indirect
+		map(preceded(char('!'), variable_name), |name| BracedExpansion {
+			name: Cow::Borrowed(name),
+			modifier: Some(ExpansionModifier::Indirect),
+		}),
 		// other
 		map(
 			pair(variable_name, opt(expansion_modifier)),
@@ -368,6 +737,14 @@ fn expansion_modifier(i: &str) -> IResult<&str, ExpansionModifier> {
 		substring_expansion_modifier,
 		value(ExpansionModifier::ArrayElements, tag("[@]")),
 		value(ExpansionModifier::SingleWordElements, tag("[*]")),
+		map(
+			delimited(
+				char('['),
+				take_while1(|ch| ch != ']' && ch != '}'),
+				char(']'),
+			),
+			|index| ExpansionModifier::Index(Cow::Borrowed(index)),
+		),
 	))(i)
 }
 
@@ -394,10 +771,78 @@ fn substring_expansion_modifier(i: &str) -> IResult<&str, ExpansionModifier> {
 #[cfg(test)]
 mod test {
 	use crate::apml::{
+		lst::ApmlLst,
 		parser::*,
 		pattern::{BashPattern, GlobPart},
 	};
 
+	#[test]
+	fn test_parse_error() {
+		let err = |src| ApmlLst::parse(src).unwrap_err();
+		assert_eq!(err("A=1\n)"), ParseError {
+			span: 4..5,
+			kind: ParseErrorKind::UnexpectedChar,
+			message: "Unexpected character ')'".to_string()
+		});
+		assert_eq!(err("A=1\nB").span, 5..5);
+		assert_eq!(err("A=1\nB").message, "Unexpected end of input");
+		assert_eq!(err("A=1\nB-").span, 5..6);
+		assert_eq!(err("A=1\nB\n"), ParseError {
+			span: 4..5,
+			kind: ParseErrorKind::Syntax,
+			message: "Invalid definition of variable B".to_string()
+		});
+		assert_eq!(err("A=1\n→").span, 4..7);
+		assert_eq!(err("A=\"abc\nB=1"), ParseError {
+			span: 2..6,
+			kind: ParseErrorKind::UnterminatedQuote,
+			message: "Unterminated double quote".to_string()
+		});
+		assert_eq!(err("A=a'b").span, 3..5);
+		assert_eq!(err("A=a'b").kind, ParseErrorKind::UnterminatedQuote);
+		let e = err("A=\"$(a \")\"");
+		assert_eq!(e.kind, ParseErrorKind::UnterminatedSubcommand);
+		assert_eq!(e.span, 3..10);
+		let e = err("B=1\nA=${B:-\"x}\"");
+		assert_eq!(e.kind, ParseErrorKind::UnterminatedExpansion);
+		assert_eq!(e.span, 6..15);
+		assert_eq!(
+			err("A=$(echo \"a\"").kind,
+			ParseErrorKind::UnterminatedSubcommand
+		);
+		let e = err("A=(a # it's\n \"b\"\n");
+		assert_eq!(e.kind, ParseErrorKind::UnterminatedArray);
+		assert_eq!(e.span, 2..11);
+		let e = err("A=${#FOO#bar}\nB=1");
+		assert_eq!(e.kind, ParseErrorKind::Syntax);
+		assert_eq!(e.span, 0..13);
+		assert_eq!(e.to_string(), "Invalid definition of variable A at byte 0");
+
+		assert_eq!(err("A=<<EOF\nfoo\nEOF\n"), ParseError {
+			span: 2..7,
+			kind: ParseErrorKind::HereDocument,
+			message: "Here-documents are not supported".to_string()
+		});
+		let e = err("B=1\nA=a<<<foo\n");
+		assert_eq!(e.kind, ParseErrorKind::HereString);
+		assert_eq!(e.span, 7..13);
+		assert_eq!(e.to_string(), "Here-strings are not supported at byte 7");
+		assert_eq!(err("A=1 <<EOF\n").kind, ParseErrorKind::HereDocument);
+		assert_eq!(err("A=(a <<EOF)\n").kind, ParseErrorKind::HereDocument);
+		assert_eq!(err("A=$(cat <<EOF)\n").span, 8..14);
+		assert!(apml_lst("A=\"<<\" B='<<<' C=\\<<D E=a<b\n").is_ok());
+		assert!(apml_lst("A=$((1<<2))\n").is_ok());
+		assert!(apml_lst("A=$(echo $((1<<2)))\n").is_ok());
+		assert!(apml_lst("A=\"$((1 << 2))\"\nB=($(( (1) << 2 )))\n").is_ok());
+		assert_eq!(
+			err("A=$((1<<2))\nB=$(cat <<EOF)\n").kind,
+			ParseErrorKind::HereDocument
+		);
+		let e = err("A=\"$((1 << 2))\" B='x");
+		assert_eq!(e.kind, ParseErrorKind::UnterminatedQuote);
+		assert_eq!(e.span, 18..20);
+	}
+
 	#[test]
 	fn test_ast() {
 		let src = r##"# Test APML
@@ -422,6 +867,7 @@ b+=("-a" \
 					Token::Newline,
 					Token::Newline,
 					Token::Variable(VariableDefinition {
+						declaration: None,
 						name: Cow::Borrowed("a"),
 						op: VariableOp::Assignment,
 						value: VariableValue::String(Arc::new(Text(vec![
@@ -435,6 +881,7 @@ b+=("-a" \
 					Token::Comment(Cow::Borrowed(" Inline comment")),
 					Token::Newline,
 					Token::Variable(VariableDefinition {
+						declaration: None,
 						name: Cow::Borrowed("K"),
 						op: VariableOp::Assignment,
 						value: VariableValue::String(Arc::new(Text(vec![
@@ -790,6 +1237,7 @@ b+=("-a" \
 					}),
 					Token::Newline,
 					Token::Variable(VariableDefinition {
+						declaration: None,
 						name: Cow::Borrowed("b"),
 						op: VariableOp::Append,
 						value: VariableValue::Array(vec![
@@ -883,6 +1331,7 @@ MESON_AFTER__AMD64=" \
 			(
 				"\n",
 				Token::Variable(VariableDefinition {
+					declaration: None,
 					name: Cow::Borrowed("a"),
 					op: VariableOp::Assignment,
 					value: VariableValue::String(Arc::new(Text(vec![])))
@@ -906,6 +1355,7 @@ MESON_AFTER__AMD64=" \
 		assert_eq!(
 			variable_def("a=\n").unwrap(),
 			("\n", VariableDefinition {
+				declaration: None,
 				name: Cow::Borrowed("a"),
 				op: VariableOp::Assignment,
 				value: VariableValue::String(Arc::new(Text(vec![])))
@@ -914,6 +1364,7 @@ MESON_AFTER__AMD64=" \
 		assert_eq!(
 			variable_def("a=b$0\n").unwrap(),
 			("\n", VariableDefinition {
+				declaration: None,
 				name: Cow::Borrowed("a"),
 				op: VariableOp::Assignment,
 				value: VariableValue::String(Arc::new(Text(vec![
@@ -929,6 +1380,7 @@ MESON_AFTER__AMD64=" \
 		assert_eq!(
 			variable_def("a+=b$0\n").unwrap(),
 			("\n", VariableDefinition {
+				declaration: None,
 				name: Cow::Borrowed("a"),
 				op: VariableOp::Append,
 				value: VariableValue::String(Arc::new(Text(vec![
@@ -943,6 +1395,52 @@ MESON_AFTER__AMD64=" \
 		);
 	}
 
+	#[test]
+	fn test_declaration() {
+		let src = "declare -x  -r A=1\ntypeset -a\tB=(a b)\nexport C+=c\n\
+				   declare=1\nexported=1\n";
+		let lst = ApmlLst::parse(src).unwrap();
+		assert_eq!(lst.to_string(), src);
+		let defs = lst
+			.0
+			.iter()
+			.filter_map(|token| match token {
+				Token::Variable(def) => Some(def),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			defs.iter().map(|def| def.name.as_ref()).collect::<Vec<_>>(),
+			["A", "B", "C", "declare", "exported"]
+		);
+		assert_eq!(
+			defs[0].declaration,
+			Some(Declaration {
+				keyword: DeclarationKeyword::Declare,
+				tokens: vec![
+					DeclarationToken::Spacy(' '),
+					DeclarationToken::Flag(Cow::Borrowed("-x")),
+					DeclarationToken::Spacy(' '),
+					DeclarationToken::Spacy(' '),
+					DeclarationToken::Flag(Cow::Borrowed("-r")),
+					DeclarationToken::Spacy(' '),
+				]
+			})
+		);
+		let declaration = defs[0].declaration.as_ref().unwrap();
+		assert_eq!(declaration.flags().collect::<String>(), "xr");
+		assert!(!declaration.is_array());
+		assert!(defs[1].declaration.as_ref().unwrap().is_array());
+		assert_eq!(
+			defs[2].declaration.as_ref().unwrap().keyword,
+			DeclarationKeyword::Export
+		);
+		assert_eq!(defs[2].op, VariableOp::Append);
+		assert_eq!(defs[3].declaration, None);
+		ApmlLst::parse("declare -1 A=1").unwrap_err();
+		ApmlLst::parse("declare -x").unwrap_err();
+	}
+
 	#[test]
 	fn test_variable_op() {
 		assert_eq!(
@@ -1092,6 +1590,56 @@ MESON_AFTER__AMD64=" \
 		);
 	}
 
+	#[test]
+	fn test_multiline_array() {
+		let src = "SRCS=( # sources\n\ta # first\n\t# between\n\t\"b c\" 'd'\n)\nA=1\n";
+		let lst = ApmlLst::parse(src).unwrap();
+		assert_eq!(lst.to_string(), src);
+		assert_eq!(lst.0.len(), 4);
+		let Token::Variable(def) = &lst.0[0] else {
+			unreachable!()
+		};
+		let VariableValue::Array(tokens) = &def.value else {
+			unreachable!()
+		};
+		assert_eq!(
+			tokens
+				.iter()
+				.filter(|token| matches!(token, ArrayToken::Newline))
+				.count(),
+			4
+		);
+		assert_eq!(
+			tokens
+				.iter()
+				.filter_map(|token| match token {
+					ArrayToken::Comment(comment) => Some(comment.as_ref()),
+					_ => None,
+				})
+				.collect::<Vec<_>>(),
+			vec![" sources", " first", " between"]
+		);
+		assert_eq!(
+			tokens
+				.iter()
+				.filter(|token| matches!(token, ArrayToken::Element(_)))
+				.count(),
+			3
+		);
+		// a bare newline still terminates scalar values
+		assert_eq!(
+			variable_value("a\nb").unwrap(),
+			(
+				"\nb",
+				VariableValue::String(Arc::new(Text(vec![
+					TextUnit::Unquoted(vec![Word::Literal(vec![
+						LiteralPart::String(Cow::Borrowed("a"))
+					])])
+				])))
+			)
+		);
+	}
+
 	#[test]
 	fn test_text() {
 		text("", &|_| true).unwrap_err();
@@ -1372,6 +1920,93 @@ MESON_AFTER__AMD64=" \
 				modifier: Some(ExpansionModifier::Length)
 			})
 		);
+		assert_eq!(
+			braced_expansion("!a_1").unwrap(),
+			("", BracedExpansion {
+				name: Cow::Borrowed("a_1"),
+				modifier: Some(ExpansionModifier::Indirect)
+			})
+		);
+		assert_eq!(
+			braced_expansion("!PKG@").unwrap(),
+			("", BracedExpansion {
+				name: Cow::Borrowed("PKG"),
+				modifier: Some(ExpansionModifier::NamesWithPrefix)
+			})
+		);
+		assert_eq!(
+			braced_expansion("!PKG*").unwrap(),
+			("", BracedExpansion {
+				name: Cow::Borrowed("PKG"),
+				modifier: Some(ExpansionModifier::SingleWordNamesWithPrefix)
+			})
+		);
+	}
+
+	#[test]
+	fn test_array_subscript() {
+		assert_eq!(
+			braced_expansion("#SRCS[@]}").unwrap(),
+			("}", BracedExpansion {
+				name: Cow::Borrowed("SRCS"),
+				modifier: Some(ExpansionModifier::ArrayLength)
+			})
+		);
+		assert_eq!(
+			braced_expansion("#SRCS[*]}").unwrap(),
+			("}", BracedExpansion {
+				name: Cow::Borrowed("SRCS"),
+				modifier: Some(ExpansionModifier::SingleWordArrayLength)
+			})
+		);
+		assert_eq!(
+			braced_expansion("SRCS[i+1]}").unwrap(),
+			("}", BracedExpansion {
+				name: Cow::Borrowed("SRCS"),
+				modifier: Some(ExpansionModifier::Index(Cow::Borrowed("i+1")))
+			})
+		);
+		for src in [
+			"A=${#SRCS[@]}",
+			"A=\"${#SRCS[*]}\"",
+			"A=${SRCS[0]}",
+			"A=(\"${SRCS[-1]}\" ${SRCS[@]} ${SRCS[*]})",
+		] {
+			assert_eq!(ApmlLst::parse(src).unwrap().to_string(), src);
+		}
+		ApmlLst::parse("A=${SRCS[]}").unwrap_err();
+		ApmlLst::parse("A=${SRCS[0}").unwrap_err();
+	}
+
+	#[test]
+	fn test_length_strip_prefix_ambiguity() {
+		assert_eq!(
+			braced_expansion("#FOO}").unwrap(),
+			("}", BracedExpansion {
+				name: Cow::Borrowed("FOO"),
+				modifier: Some(ExpansionModifier::Length)
+			})
+		);
+		assert_eq!(
+			braced_expansion("FOO#bar}").unwrap(),
+			("}", BracedExpansion {
+				name: Cow::Borrowed("FOO"),
+				modifier: Some(ExpansionModifier::StripShortestPrefix(
+					Arc::new(BashPattern(vec![GlobPart::String(
+						Cow::Borrowed("bar")
+					)]))
+				))
+			})
+		);
+		for src in ["A=${#FOO}", "A=${FOO#bar}", "A=${FOO##bar}", "A=${#FOO}#"]
+		{
+			assert_eq!(ApmlLst::parse(src).unwrap().to_string(), src);
+		}
+		ApmlLst::parse("A=${#FOO#bar}").unwrap_err();
+		ApmlLst::parse("A=\"${#FOO#bar}\"").unwrap_err();
+		ApmlLst::parse("A=${#FOO:1}").unwrap_err();
+		ApmlLst::parse("A=${#}").unwrap_err();
+		ApmlLst::parse("A=${##}").unwrap_err();
 	}
 
 	#[test]
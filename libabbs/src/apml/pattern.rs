@@ -16,6 +16,8 @@ use nom::{
 };
 use regex::{Regex, RegexBuilder};
 
+use super::parser::ParseError;
+
 /// A pattern, consisting of one or more [`GlobPart`]s.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BashPattern<'a>(pub Vec<GlobPart<'a>>);
@@ -97,6 +99,28 @@ impl Display for PatternList<'_> {
 	}
 }
 
+impl<'a> BashPattern<'a> {
+	/// Parses a standalone glob pattern, which is not embedded in a
+	/// expansion, so `}` and `/` are literal characters.
+	///
+	/// The whole string must be a pattern, and a empty string is parsed
+	/// as a empty pattern. Spans of errors are byte offsets in the string.
+	pub fn from_glob_str(pattern: &'a str) -> Result<Self, ParseError> {
+		if pattern.is_empty() {
+			return Ok(Self(Vec::new()));
+		}
+		let (out, result) = bash_pattern(pattern, "")
+			.map_err(|err| ParseError::from_nom(pattern, err))?;
+		if !out.is_empty() {
+			return Err(ParseError::unexpected_char(
+				pattern,
+				nom::Offset::offset(pattern, out),
+			));
+		}
+		Ok(result)
+	}
+}
+
 impl BashPattern<'_> {
 	/// Converts a pattern into regex string.
 	pub fn build_regex(&self, result: &mut String, greedy: bool) {
@@ -289,6 +313,29 @@ mod test {
 		assert_eq!(result, "abc.*.?aa(a|b)?(a|b)*(a|b)+(a|b)(?!(a|b)).*");
 	}
 
+	#[test]
+	fn test_from_glob_str() {
+		let pattern = BashPattern::from_glob_str("@(gz|xz)*.tar}/").unwrap();
+		assert_eq!(pattern.0, vec![
+			GlobPart::OneOf(PatternList(vec![
+				BashPattern(vec![GlobPart::String(Cow::Borrowed("gz"))]),
+				BashPattern(vec![GlobPart::String(Cow::Borrowed("xz"))]),
+			])),
+			GlobPart::AnyString,
+			GlobPart::String(Cow::Borrowed(".tar}/")),
+		]);
+		assert_eq!(pattern.to_string(), "@(gz|xz)*.tar}/");
+		assert!(
+			pattern
+				.to_regex("^", "$", true)
+				.unwrap()
+				.is_match("xz-1.tar}/")
+		);
+		assert_eq!(BashPattern::from_glob_str("").unwrap().0, vec![]);
+		let err = BashPattern::from_glob_str("a[bc").unwrap_err();
+		assert_eq!(err.span, 1..2);
+	}
+
 	#[test]
 	fn test_pattern_part() {
 		assert_eq!(
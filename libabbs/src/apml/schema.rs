@@ -0,0 +1,285 @@
+//! Schema validation of APML variables.
+//!
+//! A [`Schema`] describes which variables are allowed in a APML file,
+//! which of them are required, and constraints on their values.
+//! Use [`validate_against_schema`] to check a AST against a schema,
+//! and [`lint_variable_order`] to check the order of definitions.
+//!
+//! Only literal values are checked against patterns. Values containing
+//! expansions or sub-commands depend on the evaluation context and
+//! are skipped.
+
+use regex::Regex;
+use thiserror::Error;
+
+use super::ast::{ApmlAst, ArrayElement, Text, VariableValue};
+
+/// A set of constraints on variables.
+#[derive(Debug, Clone, Default)]
+pub struct Schema<'a> {
+	/// Known variables.
+	pub entries: Vec<SchemaEntry<'a>>,
+	/// Names of variables that must not be defined.
+	pub forbidden: Vec<&'a str>,
+	/// Whether variables without a entry are rejected.
+	pub deny_unknown: bool,
+}
+
+impl<'a> Schema<'a> {
+	/// Creates a empty schema.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Gets the entry of a variable.
+	#[must_use]
+	pub fn get(&self, name: &str) -> Option<&SchemaEntry<'a>> {
+		self.entries.iter().find(|entry| entry.name == name)
+	}
+}
+
+/// Constraints on a variable.
+#[derive(Debug, Clone)]
+pub struct SchemaEntry<'a> {
+	/// Name of the variable.
+	pub name: &'a str,
+	/// Whether the variable must be defined.
+	pub required: bool,
+	/// Pattern that the value must match.
+	///
+	/// For arrays, each element must match the pattern.
+	/// Note that the pattern is not anchored implicitly.
+	pub pattern: Option<Regex>,
+	/// Human-readable description of the variable.
+	pub description: &'a str,
+}
+
+impl<'a> SchemaEntry<'a> {
+	/// Creates a optional entry without any constraints.
+	pub fn new(name: &'a str) -> Self {
+		Self {
+			name,
+			required: false,
+			pattern: None,
+			description: "",
+		}
+	}
+
+	/// Marks the variable as required.
+	pub fn required(mut self) -> Self {
+		self.required = true;
+		self
+	}
+
+	/// Sets the pattern of values.
+	pub fn pattern(mut self, pattern: Regex) -> Self {
+		self.pattern = Some(pattern);
+		self
+	}
+
+	/// Sets the description.
+	pub fn description(mut self, description: &'a str) -> Self {
+		self.description = description;
+		self
+	}
+}
+
+/// A violation of schema.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SchemaViolation {
+	#[error("Required variable {0} is missing")]
+	Missing(String),
+	#[error("Variable {0} is forbidden")]
+	Forbidden(String),
+	#[error("Variable {0} is unknown")]
+	Unknown(String),
+	#[error("Value of {name} does not match the pattern: {value:?}")]
+	PatternMismatch { name: String, value: String },
+}
+
+/// Validates a AST against a schema, returning all violations.
+///
+/// Violations are reported in the order of definitions in the AST,
+/// followed by missing variables in the order of schema entries.
+pub fn validate_against_schema(
+	ast: &ApmlAst,
+	schema: &Schema,
+) -> Vec<SchemaViolation> {
+	let mut result = Vec::new();
+	for def in &ast.0 {
+		let name = def.name.as_ref();
+		if schema.forbidden.contains(&name) {
+			result.push(SchemaViolation::Forbidden(name.to_string()));
+			continue;
+		}
+		let Some(entry) = schema.get(name) else {
+			if schema.deny_unknown {
+				result.push(SchemaViolation::Unknown(name.to_string()));
+			}
+			continue;
+		};
+		let Some(pattern) = &entry.pattern else {
+			continue;
+		};
+		let values = match &def.value {
+			VariableValue::String(text) => vec![text],
+			VariableValue::Array(elements) => elements
+				.iter()
+				.filter_map(|element| match element {
+					ArrayElement::Text(text) => Some(text.as_ref()),
+					ArrayElement::ArrayInclusion(_) => None,
+				})
+				.collect(),
+		};
+		for value in values.into_iter().filter_map(Text::literal_value) {
+			if !pattern.is_match(&value) {
+				result.push(SchemaViolation::PatternMismatch {
+					name: name.to_string(),
+					value: value.into_owned(),
+				});
+			}
+		}
+	}
+	for entry in &schema.entries {
+		if entry.required && !ast.0.iter().any(|def| def.name == entry.name) {
+			result.push(SchemaViolation::Missing(entry.name.to_string()));
+		}
+	}
+	result
+}
+
+/// A variable defined before another variable which precedes it in the
+/// expected order.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Variable {name} should be defined after {expected_after}")]
+pub struct OrderViolation {
+	/// Name of the variable defined too early.
+	pub name: String,
+	/// Index of the definition of the variable in the AST.
+	pub index: usize,
+	/// Name of the variable defined later, which should precede it.
+	pub expected_after: String,
+}
+
+/// Checks that variables are defined in the order of the given names.
+///
+/// Variables not listed in the order may appear anywhere.
+/// For each definition placed after a variable which should follow it,
+/// a violation is reported against the latest-ordered variable defined
+/// so far. Repeated definitions of the same variable never violate.
+pub fn lint_variable_order(
+	ast: &ApmlAst,
+	schema: &[&str],
+) -> Vec<OrderViolation> {
+	let mut result = Vec::new();
+	// rank, name and index of the latest-ordered definition so far
+	let mut latest: Option<(usize, &str, usize)> = None;
+	for (index, def) in ast.0.iter().enumerate() {
+		let name = def.name.as_ref();
+		let Some(rank) = schema.iter().position(|key| *key == name) else {
+			continue;
+		};
+		match latest {
+			Some((latest_rank, latest_name, latest_index))
+				if latest_rank > rank =>
+			{
+				result.push(OrderViolation {
+					name: latest_name.to_string(),
+					index: latest_index,
+					expected_after: name.to_string(),
+				});
+			}
+			_ => latest = Some((rank, name, index)),
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::apml::{ast::AstNode, lst::ApmlLst};
+
+	#[test]
+	fn test_validate_against_schema() {
+		let schema = Schema {
+			entries: vec![
+				SchemaEntry::new("VER")
+					.required()
+					.pattern(Regex::new(r"^\d+\.\d+$").unwrap())
+					.description("Version"),
+				SchemaEntry::new("REL").required(),
+				SchemaEntry::new("ARCH")
+					.pattern(Regex::new("^(amd64|arm64)$").unwrap()),
+				SchemaEntry::new("SRCS"),
+			],
+			forbidden: vec!["PKGEPOCH"],
+			deny_unknown: true,
+		};
+		let check = |src: &str| {
+			let ast =
+				ApmlAst::emit_from(&ApmlLst::parse(src).unwrap()).unwrap();
+			validate_against_schema(&ast, &schema)
+		};
+		assert!(check("VER=1.0\nREL=1\nARCH=(amd64 arm64)\n").is_empty());
+		assert!(check("VER=\"${A}\"\nREL=1\nSRCS=\"$VER\"\n").is_empty());
+		assert_eq!(
+			check("VER=1\nARCH=(amd64 riscv)\nPKGEPOCH=1\nFOO=1\n"),
+			vec![
+				SchemaViolation::PatternMismatch {
+					name: "VER".into(),
+					value: "1".into()
+				},
+				SchemaViolation::PatternMismatch {
+					name: "ARCH".into(),
+					value: "riscv".into()
+				},
+				SchemaViolation::Forbidden("PKGEPOCH".into()),
+				SchemaViolation::Unknown("FOO".into()),
+				SchemaViolation::Missing("REL".into()),
+			]
+		);
+		assert_eq!(schema.get("VER").unwrap().description, "Version");
+		assert!(schema.get("FOO").is_none());
+	}
+
+	#[test]
+	fn test_lint_variable_order() {
+		let schema = ["PKGNAME", "PKGVER", "PKGREL", "PKGDES"];
+		let check = |src: &str| {
+			let ast =
+				ApmlAst::emit_from(&ApmlLst::parse(src).unwrap()).unwrap();
+			lint_variable_order(&ast, &schema)
+		};
+		assert!(check("PKGNAME=a\nA=1\nPKGVER=1\nPKGDES=a\n").is_empty());
+		assert!(check("B=1\nPKGREL=1\nPKGREL=2\nA=1\nPKGDES=a\n").is_empty());
+		assert_eq!(
+			check("PKGDES=a\nA=1\nPKGNAME=a\nPKGREL=1\nPKGVER=1\n"),
+			vec![
+				OrderViolation {
+					name: "PKGDES".into(),
+					index: 0,
+					expected_after: "PKGNAME".into(),
+				},
+				OrderViolation {
+					name: "PKGDES".into(),
+					index: 0,
+					expected_after: "PKGREL".into(),
+				},
+				OrderViolation {
+					name: "PKGDES".into(),
+					index: 0,
+					expected_after: "PKGVER".into(),
+				},
+			]
+		);
+		let violations = check("PKGNAME=a\nPKGREL=1\nPKGVER=1\n");
+		assert_eq!(
+			violations
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>(),
+			["Variable PKGREL should be defined after PKGVER"]
+		);
+	}
+}
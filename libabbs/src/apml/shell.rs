@@ -0,0 +1,192 @@
+//! Generation of shell scripts setting APML variables.
+//!
+//! The generated script sets evaluated values, so it does not depend on
+//! the environment it is sourced in.
+
+use std::fmt::Write;
+
+use super::{
+	ApmlContext, ApmlError, VariableValue,
+	lst::{ApmlLst, DeclarationKeyword, Token},
+};
+
+/// Options of [`ApmlLst::to_shell_script`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ShellOptions {
+	/// Target shell.
+	pub shell: Shell,
+	/// Whether to export all variables.
+	pub export: bool,
+	/// Line ending of the script.
+	pub line_ending: LineEnding,
+}
+
+/// Target shells of [`ApmlLst::to_shell_script`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Shell {
+	/// POSIX sh, where arrays are joined with spaces.
+	Sh,
+	/// Bash, where arrays are declared with `declare -a`.
+	#[default]
+	Bash,
+}
+
+/// Line endings of generated scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LineEnding {
+	/// `\n`.
+	#[default]
+	Lf,
+	/// `\r\n`.
+	CrLf,
+}
+
+impl LineEnding {
+	/// Gets the string of the line ending.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			LineEnding::Lf => "\n",
+			LineEnding::CrLf => "\r\n",
+		}
+	}
+}
+
+impl ApmlLst<'_> {
+	/// Evaluates the LST and generates a shell script fragment which sets
+	/// all defined variables when sourced.
+	///
+	/// Variables are set once with their final values, in the order of
+	/// their first definitions. Variables declared with `-r` are made
+	/// readonly, and variables declared with `export` or `-x` are always
+	/// exported. Note that Bash does not pass arrays to child processes
+	/// even if they are exported.
+	pub fn to_shell_script(
+		&self,
+		options: &ShellOptions,
+	) -> Result<String, ApmlError> {
+		let context = ApmlContext::eval_lst(self)?;
+		let mut names = Vec::<&str>::new();
+		let mut readonly = Vec::new();
+		let mut exported = Vec::new();
+		for def in self.0.iter().filter_map(|token| match token {
+			Token::Variable(def) => Some(def),
+			_ => None,
+		}) {
+			if !names.contains(&def.name.as_ref()) {
+				names.push(&def.name);
+			}
+			if let Some(declaration) = &def.declaration {
+				if declaration.has_flag('r') {
+					readonly.push(def.name.as_ref());
+				}
+				if declaration.has_flag('x')
+					|| declaration.keyword == DeclarationKeyword::Export
+				{
+					exported.push(def.name.as_ref());
+				}
+			}
+		}
+
+		let newline = options.line_ending.as_str();
+		let mut result = String::new();
+		for name in names {
+			let Some(value) = context.get(name) else {
+				continue;
+			};
+			let readonly = readonly.contains(&name);
+			let export = options.export || exported.contains(&name);
+			match (value, options.shell) {
+				(VariableValue::Array(elements), Shell::Bash) => {
+					let mut flags = String::from("a");
+					if readonly {
+						flags.push('r');
+					}
+					if export {
+						flags.push('x');
+					}
+					let elements = elements
+						.iter()
+						.map(|element| quote(element))
+						.collect::<Vec<_>>()
+						.join(" ");
+					write!(result, "declare -{flags} {name}=({elements})")
+						.unwrap();
+				}
+				_ => {
+					let value = quote(&value.as_string());
+					match (readonly, export) {
+						(true, true) => write!(
+							result,
+							"readonly {name}={value}{newline}export {name}"
+						),
+						(true, false) => {
+							write!(result, "readonly {name}={value}")
+						}
+						(false, true) => {
+							write!(result, "export {name}={value}")
+						}
+						(false, false) => write!(result, "{name}={value}"),
+					}
+					.unwrap();
+				}
+			}
+			result.push_str(newline);
+		}
+		Ok(result)
+	}
+}
+
+/// Quotes a string as a shell word.
+///
+/// Strings made up of only safe characters are left unquoted, and
+/// others are single-quoted.
+fn quote(value: &str) -> String {
+	if !value.is_empty()
+		&& value.chars().all(|ch| {
+			ch.is_ascii_alphanumeric()
+				|| matches!(
+					ch,
+					'.' | '_' | '/' | '-' | '+' | ':' | ',' | '@' | '%'
+				)
+		}) {
+		value.to_string()
+	} else {
+		format!("'{}'", value.replace('\'', r"'\''"))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_to_shell_script() {
+		let lst = ApmlLst::parse(
+			"VER=1.0\nSRCS=(\"a b\" \"it's\")\ndeclare -r REL=\"$VER-$1\"\nexport \
+			 A='$x \"y\"'\nB=\nB+=\"$A\"\n",
+		)
+		.unwrap();
+		assert_eq!(
+			lst.to_shell_script(&ShellOptions::default()).unwrap(),
+			"VER=1.0\ndeclare -a SRCS=('a b' 'it'\\''s')\nreadonly REL=1.0-\nexport \
+			 A='$x \"y\"'\nB='$x \"y\"'\n"
+		);
+		assert_eq!(
+			lst.to_shell_script(&ShellOptions {
+				shell: Shell::Sh,
+				export: true,
+				line_ending: LineEnding::CrLf,
+			})
+			.unwrap(),
+			"export VER=1.0\r\nexport SRCS='a b it'\\''s'\r\nreadonly \
+			 REL=1.0-\r\nexport REL\r\nexport A='$x \"y\"'\r\nexport B='$x \
+			 \"y\"'\r\n"
+		);
+		assert!(
+			ApmlLst::parse("A=${B:?}")
+				.unwrap()
+				.to_shell_script(&ShellOptions::default())
+				.is_err()
+		);
+	}
+}
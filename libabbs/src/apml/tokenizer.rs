@@ -0,0 +1,591 @@
+//! Low-level lexical tokenizer of APML.
+//!
+//! [`tokens`] splits a APML source into [`RawToken`]s with their byte
+//! spans, which is more granular than [LST tokens][super::lst::Token].
+//! It is suitable for consumers like syntax highlighters that do not
+//! need to walk the LST themselves.
+//!
+//! Tokens are derived from the LST produced by the [parser][super::parser],
+//! so both accept the same sources and report the same errors.
+//!
+//! Glob patterns in expansion modifiers are produced as literal and
+//! escape tokens, so glob characters are not distinguished from literals.
+//! Texts in expansion modifiers are lexed like values.
+
+use std::ops::Range;
+
+use super::{
+	lst::{
+		ApmlLst, ArrayToken, BracedExpansion, DeclarationToken,
+		ExpansionModifier, LiteralPart, Text, TextUnit, Token, VariableValue,
+		Word,
+	},
+	parser::ParseError,
+	pattern::{BashPattern, GlobPart, PatternList},
+};
+
+/// Byte range of a token in the source.
+pub type Span = Range<usize>;
+
+/// A lexical token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RawToken {
+	/// A run of spaces and tabs.
+	Space,
+	/// A newline character.
+	Newline,
+	/// A comment, including the leading `#`.
+	Comment,
+	/// Keyword of a declaration command, like `declare`.
+	Keyword,
+	/// Flag of a declaration command, like `-x`.
+	Flag,
+	/// Name of a variable definition.
+	VariableName,
+	/// Operator of a variable definition (`=` or `+=`).
+	Operator,
+	/// Opening parenthesis of a array value.
+	ArrayStart,
+	/// Closing parenthesis of a array value.
+	ArrayEnd,
+	/// Opening single or double quote.
+	QuoteOpen,
+	/// Closing single or double quote.
+	QuoteClose,
+	/// A run of literal characters.
+	Literal,
+	/// A escaped character (`\<char>`).
+	Escape,
+	/// A line continuation (`\<newline>`).
+	LineContinuation,
+	/// A unbraced variable expansion (`$<name>`).
+	Variable,
+	/// Start of a braced expansion (`${`).
+	ExpansionStart,
+	/// Name of the variable in a braced expansion.
+	ExpansionName,
+	/// Operator in a braced expansion, like `#` before the name and
+	/// `:-` of modifiers.
+	ExpansionOperator,
+	/// End of a braced expansion (`}`).
+	ExpansionEnd,
+	/// Start of a sub-command (`$(`).
+	SubcommandStart,
+	/// End of a sub-command (`)`).
+	SubcommandEnd,
+}
+
+/// Splits a APML source into lexical tokens.
+///
+/// Tokens are produced in order and their spans cover the whole source.
+/// If the source cannot be fully parsed, tokens of the parsed part are
+/// followed by a error, and the iterator ends.
+pub fn tokens(
+	src: &str,
+) -> impl Iterator<Item = Result<(Span, RawToken), ParseError>> + '_ {
+	let (tree, error) = ApmlLst::parse_prefix(src);
+	let mut lexer = Lexer {
+		pos: 0,
+		tokens: Vec::new(),
+	};
+	for token in &tree.0 {
+		lexer.token(token);
+	}
+	lexer.tokens.into_iter().map(Ok).chain(error.map(Err))
+}
+
+/// State of [`tokens`].
+struct Lexer {
+	pos: usize,
+	tokens: Vec<(Span, RawToken)>,
+}
+
+impl Lexer {
+	/// Emits a token of the given length, merging adjacent spaces.
+	fn emit(&mut self, len: usize, token: RawToken) {
+		let start = self.pos;
+		self.pos += len;
+		if let (RawToken::Space, Some((span, RawToken::Space))) =
+			(token, self.tokens.last_mut())
+		{
+			span.end = self.pos;
+			return;
+		}
+		self.tokens.push((start..self.pos, token));
+	}
+
+	fn token(&mut self, token: &Token) {
+		match token {
+			Token::Spacy(ch) => self.emit(ch.len_utf8(), RawToken::Space),
+			Token::Newline => self.emit(1, RawToken::Newline),
+			Token::Comment(text) => {
+				self.emit(1 + text.len(), RawToken::Comment)
+			}
+			Token::Variable(def) => {
+				if let Some(declaration) = &def.declaration {
+					self.emit(
+						declaration.keyword.to_string().len(),
+						RawToken::Keyword,
+					);
+					for token in &declaration.tokens {
+						match token {
+							DeclarationToken::Spacy(ch) => {
+								self.emit(ch.len_utf8(), RawToken::Space)
+							}
+							DeclarationToken::Flag(flag) => {
+								self.emit(flag.len(), RawToken::Flag)
+							}
+						}
+					}
+				}
+				self.emit(def.name.len(), RawToken::VariableName);
+				self.emit(def.op.to_string().len(), RawToken::Operator);
+				match &def.value {
+					VariableValue::String(text) => self.text(text),
+					VariableValue::Array(tokens) => {
+						self.emit(1, RawToken::ArrayStart);
+						self.array_tokens(tokens);
+						self.emit(1, RawToken::ArrayEnd);
+					}
+				}
+			}
+			// not produced by the parser
+			Token::Unknown(text) => self.emit(text.len(), RawToken::Literal),
+		}
+	}
+
+	/// Lexes tokens of a array or a sub-command.
+	fn array_tokens(&mut self, tokens: &[ArrayToken]) {
+		for token in tokens {
+			match token {
+				ArrayToken::Spacy(ch) => {
+					self.emit(ch.len_utf8(), RawToken::Space)
+				}
+				ArrayToken::Newline => self.emit(1, RawToken::Newline),
+				ArrayToken::Comment(text) => {
+					self.emit(1 + text.len(), RawToken::Comment)
+				}
+				ArrayToken::Element(text) => self.text(text),
+			}
+		}
+	}
+
+	fn text(&mut self, text: &Text) {
+		for unit in &text.0 {
+			match unit {
+				TextUnit::Unquoted(words) => self.words(words, true),
+				TextUnit::SingleQuote(text) => {
+					self.emit(1, RawToken::QuoteOpen);
+					if !text.is_empty() {
+						self.emit(text.len(), RawToken::Literal);
+					}
+					self.emit(1, RawToken::QuoteClose);
+				}
+				TextUnit::DoubleQuote(words) => {
+					self.emit(1, RawToken::QuoteOpen);
+					self.words(words, false);
+					self.emit(1, RawToken::QuoteClose);
+				}
+			}
+		}
+	}
+
+	fn words(&mut self, words: &[Word], unquoted: bool) {
+		for word in words {
+			match word {
+				Word::Literal(parts) => {
+					for part in parts {
+						match part {
+							LiteralPart::String(text) => {
+								self.emit(text.len(), RawToken::Literal)
+							}
+							// in double quotes, other backslashes are literal
+							LiteralPart::Escaped(ch)
+								if unquoted || "$`\"\\".contains(*ch) =>
+							{
+								self.emit(1 + ch.len_utf8(), RawToken::Escape)
+							}
+							LiteralPart::Escaped(ch) => {
+								self.emit(1 + ch.len_utf8(), RawToken::Literal)
+							}
+							LiteralPart::LineContinuation => {
+								self.emit(2, RawToken::LineContinuation)
+							}
+						}
+					}
+				}
+				Word::UnbracedVariable(name) => {
+					self.emit(1 + name.len(), RawToken::Variable)
+				}
+				Word::BracedVariable(expansion) => {
+					self.braced_expansion(expansion)
+				}
+				Word::Subcommand(tokens) => {
+					self.emit(2, RawToken::SubcommandStart);
+					self.array_tokens(tokens);
+					self.emit(1, RawToken::SubcommandEnd);
+				}
+			}
+		}
+	}
+
+	fn braced_expansion(&mut self, expansion: &BracedExpansion) {
+		self.emit(2, RawToken::ExpansionStart);
+		let name = expansion.name.len();
+		match &expansion.modifier {
+			None => self.emit(name, RawToken::ExpansionName),
+			Some(ExpansionModifier::Length) => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.emit(name, RawToken::ExpansionName);
+			}
+			Some(
+				ExpansionModifier::ArrayLength
+				| ExpansionModifier::SingleWordArrayLength,
+			) => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.emit(name, RawToken::ExpansionName);
+				self.emit(3, RawToken::ExpansionOperator);
+			}
+			Some(ExpansionModifier::Indirect) => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.emit(name, RawToken::ExpansionName);
+			}
+			Some(
+				ExpansionModifier::NamesWithPrefix
+				| ExpansionModifier::SingleWordNamesWithPrefix,
+			) => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.emit(name, RawToken::ExpansionName);
+				self.emit(1, RawToken::ExpansionOperator);
+			}
+			Some(modifier) => {
+				self.emit(name, RawToken::ExpansionName);
+				self.expansion_modifier(modifier);
+			}
+		}
+		self.emit(1, RawToken::ExpansionEnd);
+	}
+
+	fn expansion_modifier(&mut self, modifier: &ExpansionModifier) {
+		match modifier {
+			ExpansionModifier::Substring { offset, length } => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.emit(offset.len(), RawToken::Literal);
+				if let Some(length) = length {
+					self.emit(1, RawToken::ExpansionOperator);
+					self.emit(length.len(), RawToken::Literal);
+				}
+			}
+			ExpansionModifier::StripShortestPrefix(pattern)
+			| ExpansionModifier::StripShortestSuffix(pattern)
+			| ExpansionModifier::UpperOnce(pattern)
+			| ExpansionModifier::LowerOnce(pattern) => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.pattern(pattern);
+			}
+			ExpansionModifier::StripLongestPrefix(pattern)
+			| ExpansionModifier::StripLongestSuffix(pattern)
+			| ExpansionModifier::UpperAll(pattern)
+			| ExpansionModifier::LowerAll(pattern) => {
+				self.emit(2, RawToken::ExpansionOperator);
+				self.pattern(pattern);
+			}
+			ExpansionModifier::ReplaceOnce { pattern, string } => {
+				self.emit(1, RawToken::ExpansionOperator);
+				self.replacement(pattern, string.as_deref());
+			}
+			ExpansionModifier::ReplaceAll { pattern, string }
+			| ExpansionModifier::ReplacePrefix { pattern, string }
+			| ExpansionModifier::ReplaceSuffix { pattern, string } => {
+				self.emit(2, RawToken::ExpansionOperator);
+				self.replacement(pattern, string.as_deref());
+			}
+			ExpansionModifier::ErrorOnUnset(text)
+			| ExpansionModifier::WhenUnset(text)
+			| ExpansionModifier::WhenSet(text) => {
+				self.emit(2, RawToken::ExpansionOperator);
+				self.text(text);
+			}
+			modifier => self
+				.emit(modifier.to_string().len(), RawToken::ExpansionOperator),
+		}
+	}
+
+	fn replacement(&mut self, pattern: &BashPattern, string: Option<&Text>) {
+		self.pattern(pattern);
+		if let Some(string) = string {
+			self.emit(1, RawToken::ExpansionOperator);
+			self.text(string);
+		}
+	}
+
+	fn pattern(&mut self, pattern: &BashPattern) {
+		for part in &pattern.0 {
+			match part {
+				GlobPart::Escaped(ch) => {
+					self.emit(1 + ch.len_utf8(), RawToken::Escape)
+				}
+				GlobPart::ZeroOrOneOf(list)
+				| GlobPart::ZeroOrMoreOf(list)
+				| GlobPart::OneOrMoreOf(list)
+				| GlobPart::OneOf(list)
+				| GlobPart::Not(list) => self.pattern_list(list),
+				part => self.emit(part.to_string().len(), RawToken::Literal),
+			}
+		}
+	}
+
+	/// Lexes a pattern list with its delimiters, like `@(a|b)`.
+	fn pattern_list(&mut self, list: &PatternList) {
+		self.emit(2, RawToken::Literal);
+		for (idx, pattern) in list.0.iter().enumerate() {
+			if idx != 0 {
+				self.emit(1, RawToken::Literal);
+			}
+			self.pattern(pattern);
+		}
+		self.emit(1, RawToken::Literal);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{fs, path::Path};
+
+	use super::*;
+	use crate::apml::{
+		lst::{self, ApmlLst},
+		parser::ParseErrorKind,
+	};
+
+	/// Sources used in tests of the parser, and fragments of them.
+	const PARSER_SOURCES: &[&str] = &[
+		"# Test APML\n\na=b'c' # Inline comment\nK=a\"${#a} $ab b\\ \\\\#l \\\nc\"\n",
+		"PKGVER=8.2\nPKGDEP=\"x11-lib libdrm \\\n        libva\"\nMESON_AFTER__AMD64=\" \\\n 		 ${MESON_AFTER} \\\n             -Dlibunwind=true\"",
+		"declare -x  -r A=1\ntypeset -a\tB=(a b)\nexport C+=c\ndeclare=1\nexported=1\n",
+		"declare -1 A=1",
+		"SRCS=( # sources\n\ta # first\n\t# between\n\t\"b c\" 'd'\n)\nA=1\n",
+		"A=(\"${SRCS[-1]}\" ${SRCS[@]} ${SRCS[*]} ${#SRCS[@]} \"${#SRCS[*]}\")",
+		"A=${SRCS[0]}${SRCS[i+1]}${SRCS[]}${SRCS[0}",
+		"A=${#FOO}${FOO#bar}${FOO##bar}${#FOO}#${#FOO#bar}${#FOO:1}${#}${##}",
+		"A=${abc#test?}${abc:1:2}${abc: -10:-1}${abc:10}${!PKG*}${!PKG@}${!a_1}",
+		"A=${a##a*}${a%a*}${a%%a*}${a^a*}${a^^a*}${a,a*}${a,,a*}",
+		"A=${a/a*/$b}${a//a*}${a/#a*/$b}${a/%a*}${a:+a${#a}}${a:-a${a}}${a:?a$a}",
+		"A=${a#@(b|c)}${a#*(b|c)}${a#[}]}${a#\\}}${a#\"}\"}${a#x?(y}",
+		"A=${B,}${B^}${B#}${B:=x}${B:}${B[@]:-x}",
+		"A=(a#b) B=(a #b\n) C=(a\tb) D=a\tb",
+		"A=\"$(a \")\"",
+		"A=$(echo \"a\"",
+		"A=$(123 ) B=$123 C=\\\na D=asdf\\134\\\n a",
+		"A=\"1\\\na$a${#b}安同'\" a",
+		"A=\"<<\" B='<<<' C=\\<<D E=a<b\n",
+		"A=<<EOF\nfoo\nEOF\n",
+		"B=1\nA=a<<<foo\n",
+		"A=1 <<EOF\nB=(a <<EOF)\nC=$(cat <<EOF)\n",
+		"A=$((1<<2)) B=$(echo $((1<<2))) C=\"$((1 << 2))\"\n",
+		"A=1\n)\nB-\n→",
+		"A=\"abc\nB=1\nC=a'b",
+		"B=1\nA=${B:-\"x}\"",
+		"A=(a # it's\n \"b\"\n",
+		"a=b$0\na+=b$0\n安安本来是只兔子\n",
+	];
+
+	/// Checks that the tokenizer accepts the same source as the parser,
+	/// with the same variable definitions or the same error.
+	fn assert_same_as_parser(src: &str) {
+		let result = tokens(src).collect::<Result<Vec<_>, _>>();
+		let lst = match ApmlLst::parse(src) {
+			Ok(lst) => lst,
+			Err(err) => {
+				assert_eq!(result, Err(err), "{src:?}");
+				return;
+			}
+		};
+		let tokens = result.unwrap_or_else(|err| panic!("{src:?}: {err}"));
+		let names = tokens
+			.into_iter()
+			.filter(|(_, token)| *token == RawToken::VariableName)
+			.map(|(span, _)| span)
+			.collect::<Vec<_>>();
+		let expected = lst
+			.0
+			.iter()
+			.enumerate()
+			.filter_map(|(index, token)| match token {
+				lst::Token::Variable(def) => {
+					let start = lst.token_span(index).unwrap().start
+						+ def.declaration.as_ref().map_or(0, |declaration| {
+							declaration.to_string().len()
+						});
+					Some(start..start + def.name.len())
+				}
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(names, expected, "{src:?}");
+	}
+
+	fn lex(src: &str) -> Vec<(&str, RawToken)> {
+		tokens(src)
+			.map(|token| {
+				let (span, token) = token.unwrap();
+				(&src[span], token)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_tokens() {
+		use RawToken::*;
+		assert_eq!(lex(""), vec![]);
+		assert_eq!(
+			lex("# a\nA=b'c'\"$d\\$\"  \nB+=(x ${Y:-\"z\"} # c\n)"),
+			vec![
+				("# a", Comment),
+				("\n", Newline),
+				("A", VariableName),
+				("=", Operator),
+				("b", Literal),
+				("'", QuoteOpen),
+				("c", Literal),
+				("'", QuoteClose),
+				("\"", QuoteOpen),
+				("$d", Variable),
+				("\\$", Escape),
+				("\"", QuoteClose),
+				("  ", Space),
+				("\n", Newline),
+				("B", VariableName),
+				("+=", Operator),
+				("(", ArrayStart),
+				("x", Literal),
+				(" ", Space),
+				("${", ExpansionStart),
+				("Y", ExpansionName),
+				(":-", ExpansionOperator),
+				("\"", QuoteOpen),
+				("z", Literal),
+				("\"", QuoteClose),
+				("}", ExpansionEnd),
+				(" ", Space),
+				("# c", Comment),
+				("\n", Newline),
+				(")", ArrayEnd),
+			]
+		);
+		assert_eq!(
+			lex("A=${#B[@]}${!C@}${D//a/b}$(e \"f\")\\\n\"\\g\""),
+			vec![
+				("A", VariableName),
+				("=", Operator),
+				("${", ExpansionStart),
+				("#", ExpansionOperator),
+				("B", ExpansionName),
+				("[@]", ExpansionOperator),
+				("}", ExpansionEnd),
+				("${", ExpansionStart),
+				("!", ExpansionOperator),
+				("C", ExpansionName),
+				("@", ExpansionOperator),
+				("}", ExpansionEnd),
+				("${", ExpansionStart),
+				("D", ExpansionName),
+				("//", ExpansionOperator),
+				("a", Literal),
+				("/", ExpansionOperator),
+				("b", Literal),
+				("}", ExpansionEnd),
+				("$(", SubcommandStart),
+				("e", Literal),
+				(" ", Space),
+				("\"", QuoteOpen),
+				("f", Literal),
+				("\"", QuoteClose),
+				(")", SubcommandEnd),
+				("\\\n", LineContinuation),
+				("\"", QuoteOpen),
+				("\\g", Literal),
+				("\"", QuoteClose),
+			]
+		);
+		assert_eq!(lex("declare -x  -r\tA=1"), vec![
+			("declare", Keyword),
+			(" ", Space),
+			("-x", Flag),
+			("  ", Space),
+			("-r", Flag),
+			("\t", Space),
+			("A", VariableName),
+			("=", Operator),
+			("1", Literal),
+		]);
+		assert_eq!(lex("export=1"), vec![
+			("export", VariableName),
+			("=", Operator),
+			("1", Literal),
+		]);
+	}
+
+	#[test]
+	fn test_tokens_error() {
+		let result = tokens("A=1\nB=\"abc\nC=2").collect::<Vec<_>>();
+		assert_eq!(result.len(), 7);
+		assert!(result[..6].iter().all(Result::is_ok));
+		assert_eq!(
+			result[6].as_ref().unwrap_err().kind,
+			ParseErrorKind::UnterminatedQuote
+		);
+		let err = tokens("A=1\n)").last().unwrap().unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedChar);
+		assert_eq!(err.span, 4..5);
+		tokens("A=$").last().unwrap().unwrap_err();
+		tokens("A=${B,}").last().unwrap().unwrap_err();
+		tokens("A=${B:=x}").last().unwrap().unwrap_err();
+		// like the parser, unterminated arrays are strings
+		assert_eq!(lex("A=(a#b)"), vec![
+			("A", RawToken::VariableName),
+			("=", RawToken::Operator),
+			("(a", RawToken::Literal),
+			("#b)", RawToken::Comment),
+		]);
+	}
+
+	#[test]
+	fn test_tokens_same_as_parser() {
+		for src in PARSER_SOURCES {
+			for (end, _) in src.char_indices().chain([(src.len(), ' ')]) {
+				assert_same_as_parser(&src[..end]);
+			}
+		}
+	}
+
+	#[test]
+	fn test_tokens_corpus() {
+		fn check(path: &Path) {
+			for entry in path.read_dir().unwrap() {
+				let path = entry.unwrap().path();
+				if path.is_dir() {
+					check(&path);
+					continue;
+				}
+				let Ok(src) = fs::read_to_string(&path) else {
+					continue;
+				};
+				assert_same_as_parser(&src);
+				if ApmlLst::parse(&src).is_err() {
+					continue;
+				}
+				let mut end = 0;
+				for token in tokens(&src) {
+					let (span, _) = token.unwrap_or_else(|err| {
+						panic!("{}: {err}", path.display())
+					});
+					assert_eq!(span.start, end, "{}", path.display());
+					end = span.end;
+				}
+				assert_eq!(end, src.len(), "{}", path.display());
+			}
+		}
+		check(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testrepo")));
+	}
+}
@@ -0,0 +1,86 @@
+//! Utilities for APML-like sources beyond the core grammar.
+
+use super::{lst::ApmlLst, parser::ParseError};
+
+/// Splits a source bundling multiple APML files into sections and parses
+/// each section independently.
+///
+/// A section starts at a line beginning with the marker, for example
+/// `# === subpackage:` for lines like `# === subpackage: foo ===`.
+/// The name of a section is the rest of the marker line with spaces and
+/// trailing `=` trimmed. The marker line itself is excluded from the
+/// section. Text before the first marker forms a section with empty name,
+/// which is omitted if it is empty.
+///
+/// Each LST borrows from its own section, so token positions are relative
+/// to the section. Spans of returned [`ParseError`]s are byte offsets in
+/// the whole source.
+pub fn split_sections<'a>(
+	src: &'a str,
+	marker: &str,
+) -> Result<Vec<(&'a str, ApmlLst<'a>)>, ParseError> {
+	let mut sections = Vec::new();
+	let mut name = "";
+	let mut start = 0;
+	let mut offset = 0;
+	for line in src.split_inclusive('\n') {
+		let line_start = offset;
+		offset += line.len();
+		let Some(rest) = line.strip_prefix(marker) else {
+			continue;
+		};
+		if line_start != 0 || !name.is_empty() {
+			sections.push((name, start..line_start));
+		}
+		name = rest.trim().trim_end_matches('=').trim_end();
+		start = offset;
+	}
+	if start != 0 || !src.is_empty() {
+		sections.push((name, start..src.len()));
+	}
+
+	sections
+		.into_iter()
+		.map(|(name, range)| {
+			let base = range.start;
+			ApmlLst::parse(&src[range]).map(|lst| (name, lst)).map_err(
+				|mut err| {
+					err.span = err.span.start + base..err.span.end + base;
+					err
+				},
+			)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_split_sections() {
+		let marker = "# === subpackage:";
+		let src = "A=1\n# === subpackage: foo ===\nB=2\n\n# === subpackage: \
+		           bar\nC=\"a\n";
+		let err = split_sections(src, marker).unwrap_err();
+		assert!(err.span.start >= src.find("C=").unwrap());
+
+		let src = &src[..src.len() - 3];
+		let sections = split_sections(src, marker).unwrap();
+		let sections = sections
+			.iter()
+			.map(|(name, lst)| (*name, lst.to_string()))
+			.collect::<Vec<_>>();
+		assert_eq!(sections, [
+			("", "A=1\n".to_string()),
+			("foo", "B=2\n\n".to_string()),
+			("bar", "C=".to_string()),
+		]);
+
+		assert!(split_sections("", marker).unwrap().is_empty());
+		let sections = split_sections("# === subpackage: a\n", marker).unwrap();
+		assert_eq!(sections.len(), 1);
+		assert_eq!(sections[0].0, "a");
+		assert_eq!(sections[0].1.to_string(), "");
+	}
+}
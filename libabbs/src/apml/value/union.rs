@@ -87,11 +87,13 @@ impl TryFrom<&str> for Union {
 				),
 			)),
 			opt(preceded(tag("::"), take_while1(|ch: char| ch.is_ascii()))),
-		))(src)?;
+		))(src)
+		.map_err(|err| ParseError::from_nom(value, err))?;
 		if !i.is_empty() {
-			return Err(ParseError::UnexpectedSource {
-				pos: nom::Offset::offset(src, i) + 1,
-			});
+			return Err(ParseError::unexpected_char(
+				value,
+				nom::Offset::offset(value, i),
+			));
 		}
 		let mut props = HashMap::new();
 		if let Some(properties) = properties {
@@ -0,0 +1,239 @@
+//! Round-trip verification of APML sources.
+//!
+//! [`verify_lossless`] checks that a source can be converted to LST and back
+//! byte by byte, and that lowering the emitted AST keeps its semantics.
+//! [`assert_round_trip`] only checks the byte-for-byte part, which is
+//! cheap enough to be used as a fuzzing oracle.
+
+use thiserror::Error;
+
+use super::{
+	ast::{ApmlAst, AstNode},
+	lst::{self, ApmlLst},
+	parser::ParseError,
+};
+
+/// A stage of round-trip verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LosslessStage {
+	/// Parsing the source into LST.
+	Parse,
+	/// Serializing the LST back to source.
+	Serialize,
+	/// Emitting AST from the LST.
+	Emit,
+	/// Lowering the AST into LST and emitting it again.
+	Lower,
+}
+
+/// A violation of the round-trip guarantee.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("lossless violation at {stage:?} stage: {message}")]
+pub struct LosslessViolation {
+	/// The stage where the violation occurs.
+	pub stage: LosslessStage,
+	/// Byte offset in the source of the first difference, if known.
+	pub offset: Option<usize>,
+	/// Index of the LST token where the violation occurs, if known.
+	pub token: Option<usize>,
+	/// Human-readable description.
+	pub message: String,
+}
+
+/// A failure of [`assert_round_trip`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RoundTripError {
+	/// The source cannot be parsed.
+	#[error(transparent)]
+	Parse(#[from] ParseError),
+	/// The serialized LST differs from the source.
+	#[error(
+		"serialized source diverges at byte {offset} (source length \
+		 {source_len}, serialized length {serialized_len})"
+	)]
+	Diverged {
+		/// Byte offset of the first difference.
+		offset: usize,
+		/// Index of the LST token covering the offset, if any.
+		token: Option<usize>,
+		/// Length of the source.
+		source_len: usize,
+		/// Length of the serialized LST.
+		serialized_len: usize,
+	},
+}
+
+/// Checks that a APML source can be parsed into LST and serialized back
+/// to the same bytes.
+///
+/// On difference, the first diverging byte offset is reported.
+pub fn assert_round_trip(src: &str) -> Result<(), RoundTripError> {
+	round_trip(src).map(|_| ())
+}
+
+fn round_trip(src: &str) -> Result<ApmlLst<'_>, RoundTripError> {
+	let lst = ApmlLst::parse(src)?;
+	let serialized = lst.to_string();
+	if serialized != src {
+		let offset = src
+			.bytes()
+			.zip(serialized.bytes())
+			.position(|(a, b)| a != b)
+			.unwrap_or(src.len().min(serialized.len()));
+		return Err(RoundTripError::Diverged {
+			offset,
+			token: lst.token_at_offset(offset),
+			source_len: src.len(),
+			serialized_len: serialized.len(),
+		});
+	}
+	Ok(lst)
+}
+
+/// Verifies the round-trip guarantee on a APML source.
+///
+/// The source is parsed into LST and serialized again, which must
+/// produce the same bytes. Then the LST is emitted into AST, lowered back
+/// into LST and emitted again, which must produce a equal AST.
+pub fn verify_lossless(src: &str) -> Result<(), LosslessViolation> {
+	let lst = round_trip(src).map_err(|err| match err {
+		RoundTripError::Parse(err) => LosslessViolation {
+			stage: LosslessStage::Parse,
+			offset: Some(err.span.start),
+			token: ApmlLst::parse_recover(src)
+				.0
+				.token_at_offset(err.span.start),
+			message: err.to_string(),
+		},
+		RoundTripError::Diverged { offset, token, .. } => LosslessViolation {
+			stage: LosslessStage::Serialize,
+			offset: Some(offset),
+			token,
+			message: err.to_string(),
+		},
+	})?;
+
+	let ast = ApmlAst::emit_from(&lst).map_err(|err| LosslessViolation {
+		stage: LosslessStage::Emit,
+		offset: None,
+		token: None,
+		message: err.to_string(),
+	})?;
+
+	let lowered = ast.lower();
+	let lowered_ast =
+		ApmlAst::emit_from(&lowered).map_err(|err| LosslessViolation {
+			stage: LosslessStage::Lower,
+			offset: None,
+			token: None,
+			message: err.to_string(),
+		})?;
+	if lowered_ast != ast {
+		let def = ast
+			.0
+			.iter()
+			.zip(&lowered_ast.0)
+			.position(|(a, b)| a != b)
+			.unwrap_or(ast.0.len().min(lowered_ast.0.len()));
+		let token = variable_token_index(&lst, def);
+		return Err(LosslessViolation {
+			stage: LosslessStage::Lower,
+			offset: token
+				.and_then(|token| lst.token_span(token))
+				.map(|span| span.start),
+			token,
+			message: format!(
+				"lowered AST differs at definition {}",
+				ast.0
+					.get(def)
+					.map(|def| def.name.as_ref())
+					.unwrap_or("<end>")
+			),
+		});
+	}
+	Ok(())
+}
+
+/// Finds the token index of the n-th variable definition.
+fn variable_token_index(lst: &ApmlLst, n: usize) -> Option<usize> {
+	lst.0
+		.iter()
+		.enumerate()
+		.filter(|(_, token)| matches!(token, lst::Token::Variable(_)))
+		.nth(n)
+		.map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod test {
+	use std::{env, fs, path::Path};
+
+	use super::*;
+
+	fn collect_apml(path: &Path, result: &mut Vec<String>) {
+		for entry in path.read_dir().unwrap() {
+			let entry = entry.unwrap();
+			if entry.file_name() == "spec"
+				|| entry
+					.file_name()
+					.to_str()
+					.unwrap_or_default()
+					.starts_with("defines")
+			{
+				result.push(fs::read_to_string(entry.path()).unwrap());
+			} else if entry.file_type().unwrap().is_dir() {
+				collect_apml(&entry.path(), result);
+			}
+		}
+	}
+
+	#[test]
+	fn test_assert_round_trip() {
+		assert_round_trip("").unwrap();
+		assert_round_trip("# a\nA=(\n\ta # b\n)\nB=\"$A\" \n").unwrap();
+		assert!(matches!(
+			assert_round_trip("A=1\n)"),
+			Err(RoundTripError::Parse(_))
+		));
+		let err = RoundTripError::Diverged {
+			offset: 3,
+			token: Some(1),
+			source_len: 5,
+			serialized_len: 4,
+		};
+		assert_eq!(
+			err.to_string(),
+			"serialized source diverges at byte 3 (source length 5, serialized \
+			 length 4)"
+		);
+	}
+
+	#[test]
+	fn test_verify_lossless() {
+		verify_lossless("").unwrap();
+		verify_lossless("# test\nA=1 # a\nB+=(1 2 \"$A\")\n\n").unwrap();
+		verify_lossless("A='a'\"b\"c\nB=${A:1:2}\n").unwrap();
+		let err = verify_lossless("A=1\n)").unwrap_err();
+		assert_eq!(err.stage, LosslessStage::Parse);
+		assert_eq!(err.offset, Some(4));
+		assert_eq!(err.token, Some(2));
+		let err = verify_lossless("A=1 B=2").unwrap_err();
+		assert_eq!(err.stage, LosslessStage::Emit);
+	}
+
+	/// Verifies all APML files in the tree given by `TREE` env var,
+	/// or the test repository if unset.
+	#[test]
+	fn test_verify_lossless_corpus() {
+		let tree = env::var("TREE").unwrap_or_else(|_| {
+			concat!(env!("CARGO_MANIFEST_DIR"), "/testrepo").to_string()
+		});
+		let mut srcs = Vec::new();
+		collect_apml(Path::new(&tree), &mut srcs);
+		for src in &srcs {
+			if let Err(err) = verify_lossless(src) {
+				panic!("{err:?}\n{src}");
+			}
+		}
+	}
+}
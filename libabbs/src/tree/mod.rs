@@ -10,7 +10,7 @@ use std::{
 use kstring::KString;
 use thiserror::Error;
 
-use crate::apml::{ApmlContext, ApmlError};
+use crate::apml::{ApmlContext, ApmlError, eval::EvalError};
 
 #[derive(Debug, Clone)]
 pub struct AbbsTree(PathBuf);
@@ -161,6 +161,12 @@ pub enum AbbsError {
 	ApmlError(#[from] ApmlError),
 }
 
+impl From<EvalError> for AbbsError {
+	fn from(err: EvalError) -> Self {
+		Self::ApmlError(err.into())
+	}
+}
+
 pub type AbbsResult<T> = Result<T, AbbsError>;
 
 /// Name of a package section or a category, e.g. `app-admin` and `app-devel`.
@@ -486,6 +492,19 @@ mod test {
 		);
 	}
 
+	#[test]
+	fn test_error() {
+		let err = AbbsError::from(EvalError::BadIndex {
+			name: "A".into(),
+			index: "x".into(),
+		});
+		assert!(matches!(
+			err,
+			AbbsError::ApmlError(ApmlError::Eval(EvalError::BadIndex { .. }))
+		));
+		assert_eq!(err.to_string(), "Bad index of A: x");
+	}
+
 	#[test]
 	fn test_section_name() {
 		let sec = SectionName::from_ref("app");
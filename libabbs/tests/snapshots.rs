@@ -0,0 +1,59 @@
+//! Snapshot tests of parsing and emitting.
+//!
+//! Each `tests/snapshots/<name>.apml` file is parsed and emitted into AST,
+//! and the pretty-printed [`Debug`] output of the AST is compared to
+//! `tests/snapshots/<name>.snap`.
+//!
+//! Run with `UPDATE_SNAPSHOTS=1` to regenerate snapshot files.
+
+use std::{env, fs, path::Path};
+
+use libabbs::apml::{
+	ast::{ApmlAst, AstNode},
+	lst::ApmlLst,
+};
+
+#[test]
+fn test_snapshots() {
+	let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+	let update = env::var("UPDATE_SNAPSHOTS").is_ok_and(|val| val == "1");
+	let mut entries = dir
+		.read_dir()
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| path.extension().is_some_and(|ext| ext == "apml"))
+		.collect::<Vec<_>>();
+	entries.sort();
+	assert!(!entries.is_empty(), "no snapshot inputs found");
+
+	let mut mismatches = Vec::new();
+	for path in entries {
+		let src = fs::read_to_string(&path).unwrap();
+		let lst = ApmlLst::parse(&src)
+			.unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+		assert_eq!(lst.to_string(), src, "{}: not lossless", path.display());
+		let ast = ApmlAst::emit_from(&lst)
+			.unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+		let actual = format!("{ast:#?}\n");
+
+		let snap = path.with_extension("snap");
+		if update {
+			fs::write(&snap, actual).unwrap();
+			continue;
+		}
+		let expected = fs::read_to_string(&snap).unwrap_or_else(|_| {
+			panic!(
+				"{}: snapshot missing, run with UPDATE_SNAPSHOTS=1",
+				snap.display()
+			)
+		});
+		if expected != actual {
+			mismatches.push(snap.display().to_string());
+		}
+	}
+	assert!(
+		mismatches.is_empty(),
+		"snapshots mismatched, run with UPDATE_SNAPSHOTS=1 to update: {}",
+		mismatches.join(", ")
+	);
+}
@@ -143,7 +143,7 @@ impl Linter for Pep517Linter {
 								apml.with_editor(|apml| {
 									apml.remove_var(
 										apml.find_var_index("ABTYPE").unwrap(),
-									)
+									);
 								})
 							}
 						})
@@ -79,9 +79,9 @@ impl Snippet {
 		});
 		let source = match token {
 			lst::Token::Spacy(_) | lst::Token::Newline => None,
-			lst::Token::Comment(_) | lst::Token::Variable(_) => {
-				Some(token.to_string())
-			}
+			lst::Token::Comment(_)
+			| lst::Token::Variable(_)
+			| lst::Token::Unknown(_) => Some(token.to_string()),
 		};
 		Self { path, line, source }
 	}
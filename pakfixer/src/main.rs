@@ -1,10 +1,10 @@
-use std::{path::PathBuf, time::SystemTime};
+use std::{path::PathBuf, process::ExitCode, time::SystemTime};
 
 use anyhow::{Context, Result, bail};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use console::style;
 use libabbs::tree::AbbsTree;
-use libpfu::{Session, walk_apml};
+use libpfu::{Linter, Session, walk_apml};
 use log::{debug, error, info};
 use logger::LintReporter;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
@@ -14,13 +14,18 @@ use selector::LinterSelector;
 pub mod linters;
 pub mod logger;
 pub mod selector;
+pub mod watch;
 
 #[derive(Parser, Debug)]
 #[command(
 	version,
-	about = "PackFixerUpper: bring up AOSC OS packages magically"
+	about = "PackFixerUpper: bring up AOSC OS packages magically",
+	args_conflicts_with_subcommands = true,
+	subcommand_negates_reqs = true
 )]
 struct Args {
+	#[command(subcommand)]
+	command: Option<Command>,
 	/// Path of ABBS tree.
 	#[arg(short = 'C', env = "ABBS_TREE")]
 	tree: Option<PathBuf>,
@@ -54,14 +59,24 @@ struct Args {
 	quiet: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Watch APML files and re-validate them on changes.
+	Watch(watch::WatchArgs),
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
 	let args = Args::parse();
 	#[cfg(debug_assertions)]
 	logger::init(args.debug)?;
 	#[cfg(not(debug_assertions))]
 	logger::init(false)?;
 
+	if let Some(Command::Watch(args)) = args.command {
+		return watch::run(args).await;
+	}
+
 	let abbs = AbbsTree::new(
 		args.tree
 			.unwrap_or_else(|| std::env::current_dir().unwrap()),
@@ -84,16 +99,7 @@ async fn main() -> Result<()> {
 		bail!("Package name must be specified")
 	};
 
-	let mut linters = LinterSelector::default();
-	for directive in args.directives {
-		linters.apply(&directive);
-	}
-	let (linters, disabled_lints) = linters.select();
-	let reporter = LintReporter { disabled_lints };
-	let linters = linters
-		.iter()
-		.map(|linter| (linter.ident, linter.create()))
-		.collect::<Vec<_>>();
+	let (linters, reporter) = select_linters(&args.directives);
 
 	let total_packages = packages.len();
 	let total_linters = linters.len();
@@ -126,41 +132,8 @@ async fn main() -> Result<()> {
 		};
 		sess.dry = args.dry;
 		sess.offline = args.offline;
-		for (ident, linter) in &linters {
-			match linter.apply(&sess).await {
-				Ok(_) => {
-					debug!("{} finished on {:?}", ident, &package);
-				}
-				Err(err) => {
-					error!("{} failed on {:?}: {:#?}", ident, &package, err);
-					continue;
-				}
-			};
-			let messages = sess.take_messages();
-			if messages.is_empty() {
-				continue;
-			}
-			let mut stdout = std::io::stdout().lock();
-			for message in messages {
-				reporter.report(message, &mut stdout)?;
-			}
-		}
-		if !sess.dry {
-			debug!("Saving APML files for {:?}", &package);
-			for mut apml in walk_apml(&sess) {
-				if apml.is_dirty() {
-					apml.with_upgraded(|apml| apml.save())
-						.with_context(|| format!("saving {:?}", apml))?;
-				}
-			}
-		} else {
-			debug!("Checking APML files sync states for {:?}", &package);
-			for apml in walk_apml(&sess) {
-				if apml.is_dirty() {
-					bail!("APML file is desynced in dry-run session");
-				}
-			}
-		}
+		apply_linters(&sess, &linters, &reporter).await?;
+		save_session(&sess)?;
 	}
 
 	let elapsed = start_time.elapsed()?;
@@ -172,5 +145,84 @@ async fn main() -> Result<()> {
 		elapsed.as_secs(),
 	);
 
+	Ok(ExitCode::SUCCESS)
+}
+
+/// A created linter with its identifier.
+pub(crate) type SelectedLinter = (&'static str, Box<dyn Linter>);
+
+/// Selects and creates linters with selector directives.
+///
+/// Returns the linters and the reporter of their messages.
+pub(crate) fn select_linters(
+	directives: &[String],
+) -> (Vec<SelectedLinter>, LintReporter) {
+	let mut linters = LinterSelector::default();
+	for directive in directives {
+		linters.apply(directive);
+	}
+	let (linters, disabled_lints) = linters.select();
+	let reporter = LintReporter { disabled_lints };
+	let linters = linters
+		.iter()
+		.map(|linter| (linter.ident, linter.create()))
+		.collect::<Vec<_>>();
+	(linters, reporter)
+}
+
+/// Applies linters on a session, printing lint messages to stdout.
+///
+/// Returns the number of reported messages.
+pub(crate) async fn apply_linters(
+	sess: &Session,
+	linters: &[SelectedLinter],
+	reporter: &LintReporter,
+) -> Result<usize> {
+	let mut reported = 0;
+	for (ident, linter) in linters {
+		match linter.apply(sess).await {
+			Ok(_) => {
+				debug!("{} finished on {:?}", ident, &sess.package);
+			}
+			Err(err) => {
+				error!("{} failed on {:?}: {:#?}", ident, &sess.package, err);
+				continue;
+			}
+		};
+		let messages = sess.take_messages();
+		if messages.is_empty() {
+			continue;
+		}
+		let mut stdout = std::io::stdout().lock();
+		for message in messages {
+			if !reporter.disabled_lints.contains(message.lint.ident) {
+				reported += 1;
+			}
+			reporter.report(message, &mut stdout)?;
+		}
+	}
+	Ok(reported)
+}
+
+/// Saves changed APML files of a session.
+///
+/// For dry-run sessions, fails if any file is changed instead.
+pub(crate) fn save_session(sess: &Session) -> Result<()> {
+	if !sess.dry {
+		debug!("Saving APML files for {:?}", &sess.package);
+		for mut apml in walk_apml(sess) {
+			if apml.is_dirty() {
+				apml.with_upgraded(|apml| apml.save())
+					.with_context(|| format!("saving {:?}", apml))?;
+			}
+		}
+	} else {
+		debug!("Checking APML files sync states for {:?}", &sess.package);
+		for apml in walk_apml(sess) {
+			if apml.is_dirty() {
+				bail!("APML file is desynced in dry-run session");
+			}
+		}
+	}
 	Ok(())
 }
@@ -0,0 +1,317 @@
+//! `watch` subcommand, re-validating APML files on changes.
+//!
+//! Changed files are parsed, emitted and evaluated first. Packages
+//! containing valid changed files are then checked with the selected
+//! linters, like the main command does, and fixes are only saved with
+//! `--fix`.
+//!
+//! Files are polled for modification times instead of using file system
+//! notifications like inotify. Notifications need a platform-specific
+//! backend and a extra dependency, are not delivered for many network
+//! and container file systems where trees are often mounted, and may
+//! overflow on large trees. Polling is portable and only reads metadata,
+//! but every poll walks the whole directory tree, so the number of
+//! watched files is capped.
+
+use std::{
+	collections::{BTreeSet, HashMap, HashSet},
+	fs,
+	path::{Path, PathBuf},
+	process::ExitCode,
+	time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use console::style;
+use libabbs::{
+	apml::{
+		ApmlContext,
+		ast::{ApmlAst, AstNode},
+		lst::ApmlLst,
+	},
+	tree::AbbsSourcePackage,
+};
+use libpfu::Session;
+
+use crate::{
+	SelectedLinter, apply_linters, logger::LintReporter, save_session,
+	select_linters,
+};
+
+/// Names of APML files searched in watched directories.
+const APML_FILES: &[&str] = &["defines", "spec"];
+
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+	/// Path of a APML file, or a directory containing `defines` and
+	/// `spec` files.
+	path: PathBuf,
+	/// Polling interval in milliseconds.
+	#[arg(long, default_value_t = 500)]
+	interval: u64,
+	/// Maximum number of files to watch.
+	#[arg(long, default_value_t = 4096)]
+	max_files: usize,
+	/// Save fixes made by linters.
+	#[arg(long)]
+	fix: bool,
+	/// Run without network.
+	#[arg(long, env = "NO_NETWORK")]
+	offline: bool,
+	/// Linter selector directives.
+	#[arg(short = 'W')]
+	directives: Vec<String>,
+}
+
+/// Watches files until interrupted.
+///
+/// Exits with failure if the last validation produced errors.
+pub async fn run(args: WatchArgs) -> Result<ExitCode> {
+	let interval = Duration::from_millis(args.interval);
+	let (linters, reporter) = select_linters(&args.directives);
+	let mut mtimes = HashMap::<PathBuf, SystemTime>::new();
+	let mut errors = HashMap::<PathBuf, usize>::new();
+	let mut skipped = HashSet::<PathBuf>::new();
+	let mut truncated = false;
+	loop {
+		let (files, limited) = collect_files(&args.path, args.max_files)?;
+		if limited && !truncated {
+			warn(
+				&args.path,
+				&format!("too many files, only watching {}", args.max_files),
+			);
+		}
+		truncated = limited;
+		errors.retain(|path, _| files.contains(path));
+		mtimes.retain(|path, _| files.contains(path));
+		skipped.retain(|path| files.contains(path));
+		let mut changed = 0;
+		let mut packages = BTreeSet::new();
+		for path in &files {
+			// files may be removed between listing and reading
+			let mtime =
+				match fs::metadata(path).and_then(|meta| meta.modified()) {
+					Ok(mtime) => {
+						skipped.remove(path);
+						mtime
+					}
+					Err(err) => {
+						mtimes.remove(path);
+						errors.remove(path);
+						if skipped.insert(path.clone()) {
+							warn(path, &format!("skipped: {err}"));
+						}
+						continue;
+					}
+				};
+			if mtimes.insert(path.clone(), mtime) == Some(mtime) {
+				continue;
+			}
+			let count = validate(path);
+			errors.insert(path.clone(), count);
+			changed += 1;
+			if count == 0 {
+				packages.extend(find_package(path));
+			}
+		}
+		if changed != 0 {
+			let mut messages = 0;
+			for package in &packages {
+				messages += lint(package, &linters, &reporter, &args).await;
+			}
+			if args.fix {
+				// fixes saved by linters are not changes to validate
+				for path in files.iter().filter(|path| {
+					fs::canonicalize(path).is_ok_and(|path| {
+						packages.iter().any(|package| path.starts_with(package))
+					})
+				}) {
+					if let Ok(mtime) =
+						fs::metadata(path).and_then(|meta| meta.modified())
+					{
+						mtimes.insert(path.clone(), mtime);
+					}
+				}
+			}
+			let total = errors.values().sum::<usize>();
+			eprintln!(
+				"{} [{}] {} files changed, {} errors in {} files, {} lint \
+				 messages in {} packages",
+				if total == 0 {
+					style("   Validated").green().bold()
+				} else {
+					style("   Validated").red().bold()
+				},
+				chrono::Local::now().format("%H:%M:%S"),
+				changed,
+				total,
+				errors.len(),
+				messages,
+				packages.len(),
+			);
+		}
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => break,
+			_ = tokio::time::sleep(interval) => {}
+		}
+	}
+	Ok(if errors.values().all(|count| *count == 0) {
+		ExitCode::SUCCESS
+	} else {
+		ExitCode::FAILURE
+	})
+}
+
+/// Lists APML files to watch under a path, with at most `limit` files.
+///
+/// Returns the files and if some files are omitted because of the limit.
+/// Sub-directories which cannot be read are skipped.
+fn collect_files(path: &Path, limit: usize) -> Result<(Vec<PathBuf>, bool)> {
+	if !path.is_dir() {
+		return Ok((vec![path.to_path_buf()], false));
+	}
+	let mut result = Vec::new();
+	let mut dirs = vec![path.to_path_buf()];
+	while let Some(dir) = dirs.pop() {
+		let entries = match fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(err) if dir != path => {
+				warn(&dir, &format!("skipped: {err}"));
+				continue;
+			}
+			Err(err) => return Err(err.into()),
+		};
+		for entry in entries {
+			let Ok(entry) = entry else {
+				continue;
+			};
+			let path = entry.path();
+			if path.is_dir() {
+				dirs.push(path);
+			} else if path
+				.file_name()
+				.is_some_and(|name| APML_FILES.iter().any(|file| name == *file))
+			{
+				if result.len() == limit {
+					result.sort();
+					return Ok((result, true));
+				}
+				result.push(path);
+			}
+		}
+	}
+	result.sort();
+	Ok((result, false))
+}
+
+/// Finds the source package directory containing a APML file.
+///
+/// A package is a directory in a section of a tree, which contains
+/// `spec` and the `defines` files in sub-directories.
+fn find_package(path: &Path) -> Option<PathBuf> {
+	let path = fs::canonicalize(path).ok()?;
+	path.ancestors()
+		.skip(1)
+		.take(2)
+		.find(|dir| {
+			dir.join("spec").is_file()
+				&& dir.parent().and_then(Path::parent).is_some()
+		})
+		.map(Path::to_path_buf)
+}
+
+/// Runs the selected linters on a package, like the main command.
+///
+/// Fixes are saved only if `--fix` is given.
+/// Returns the number of reported lint messages, or 1 if the linters
+/// cannot be run.
+async fn lint(
+	path: &Path,
+	linters: &[SelectedLinter],
+	reporter: &LintReporter,
+	args: &WatchArgs,
+) -> usize {
+	let package = AbbsSourcePackage::new(path);
+	let mut sess = match Session::new(package.tree(), package) {
+		Ok(sess) => sess,
+		Err(err) => {
+			error(path, &format!("{err:#}"));
+			return 1;
+		}
+	};
+	sess.dry = !args.fix;
+	sess.offline = args.offline;
+	let result = async {
+		let count = apply_linters(&sess, linters, reporter).await?;
+		save_session(&sess)?;
+		Ok::<_, anyhow::Error>(count)
+	}
+	.await;
+	result.unwrap_or_else(|err| {
+		error(path, &format!("{err:#}"));
+		1
+	})
+}
+
+/// Prints a warning about a path to stderr.
+fn warn(path: &Path, message: &str) {
+	eprintln!(
+		"{}{}: {}",
+		style("warn:  ").yellow().bold(),
+		path.display(),
+		message
+	);
+}
+
+/// Prints a error about a path to stderr.
+fn error(path: &Path, message: &str) {
+	eprintln!(
+		"{}{}: {}",
+		style("error: ").red().bold(),
+		path.display(),
+		message
+	);
+}
+
+/// Validates a APML file, printing diagnostics to stderr.
+///
+/// Returns the number of errors.
+fn validate(path: &Path) -> usize {
+	let report = |message: String| error(path, &message);
+	let src = match fs::read_to_string(path) {
+		Ok(src) => src,
+		Err(err) => {
+			report(err.to_string());
+			return 1;
+		}
+	};
+	let lst = match ApmlLst::parse(&src) {
+		Ok(lst) => lst,
+		Err(err) => {
+			let before = &src[..err.span.start.min(src.len())];
+			let line = before.matches('\n').count() + 1;
+			let column =
+				before.len() - before.rfind('\n').map_or(0, |pos| pos + 1) + 1;
+			report(format!("{line}:{column}: {err}"));
+			return 1;
+		}
+	};
+	let ast = match ApmlAst::emit_from(&lst) {
+		Ok(ast) => ast,
+		Err(err) => {
+			report(err.to_string());
+			return 1;
+		}
+	};
+	if let Err(errors) = ast.validate() {
+		for err in &errors {
+			report(err.to_string());
+		}
+		return errors.len();
+	}
+	if let Err(err) = ApmlContext::eval_ast(&ast) {
+		report(err.to_string());
+		return 1;
+	}
+	0
+}